@@ -0,0 +1,217 @@
+use std::{collections::HashSet, str::FromStr};
+
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::{req_applies, res},
+    dep_types::{LockPackage, Req, Version},
+    util::{self, Os},
+    Config,
+};
+
+use super::tree::{find_dep, label};
+
+/// Check `pyflow.lock` is up to date with `pyproject.toml`, without resolving anything: each
+/// requirement and override is satisfied by a locked version, and each locked package is needed
+/// by a requirement. For `pyflow --offline lock --check`, eg in CI; unlike `lock --check`, this
+/// doesn't catch newer versions a re-lock would pick.
+pub fn check_lock(cfg: &Config, lockpacks: &[LockPackage], os: Os, py_vers: &Version) {
+    if lockpacks.is_empty() {
+        util::abort("There's no lock file to check; run `pyflow lock` to create one");
+    }
+
+    // The lock leaves out requirements that don't apply to this platform or Python version.
+    let reqs: Vec<Req> = cfg
+        .reqs
+        .iter()
+        .chain(cfg.dev_reqs.iter())
+        .filter(|r| req_applies(r, &[], os, py_vers))
+        .cloned()
+        .collect();
+
+    let problems = lock_problems(lockpacks, &reqs, &cfg.overrides);
+    if problems.is_empty() {
+        util::print_color("The lock file matches `pyproject.toml`", Color::Green);
+        return;
+    }
+    util::abort(&format!(
+        "`pyflow.lock` is out of date with `pyproject.toml`; run `pyflow lock` to update it:\n- {}",
+        problems.join("\n- ")
+    ));
+}
+
+fn satisfies(lp: &LockPackage, req: &Req) -> bool {
+    Version::from_str(&lp.version).is_ok_and(|v| res::is_compat(&req.constraints, &v))
+}
+
+/// Find how the lock differs from what `reqs` and `overrides` need.
+fn lock_problems(lockpacks: &[LockPackage], reqs: &[Req], overrides: &[Req]) -> Vec<String> {
+    let mut problems = vec![];
+    let mut roots = vec![];
+    // Packages built from git or a local path bring their own dependencies, which we can't see
+    // without building them.
+    let mut built = false;
+
+    for req in reqs {
+        if req.path.is_some() {
+            // Editable installs aren't locked, nor are their dependencies.
+            built |= !req.develop;
+            continue;
+        }
+        let locked: Vec<&LockPackage> = lockpacks
+            .iter()
+            .filter(|lp| util::compare_names(&lp.name, &req.name))
+            .collect();
+        if locked.is_empty() {
+            problems.push(format!("{} is required, but isn't locked", req.name));
+            continue;
+        }
+
+        if req.git.is_some() {
+            built = true;
+            roots.extend(locked);
+        } else if let Some(url) = &req.url {
+            let source = util::url_source(url);
+            match locked
+                .into_iter()
+                .find(|lp| lp.source.as_deref() == Some(&source))
+            {
+                Some(lp) => roots.push(lp),
+                None => problems.push(format!(
+                    "{} is required from {}, but is locked from elsewhere",
+                    req.name, url
+                )),
+            }
+        } else {
+            match locked.iter().find(|lp| satisfies(lp, req)) {
+                Some(lp) => roots.push(lp),
+                None => problems.push(format!(
+                    "{} is required, but {} is locked",
+                    req,
+                    locked
+                        .iter()
+                        .map(|lp| label(lp))
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )),
+            }
+        }
+    }
+
+    for over in overrides {
+        for lp in lockpacks
+            .iter()
+            .filter(|lp| util::compare_names(&lp.name, &over.name) && !satisfies(lp, over))
+        {
+            problems.push(format!(
+                "{} is locked, but the override {} excludes it",
+                label(lp),
+                over
+            ));
+        }
+    }
+
+    for lp in lockpacks {
+        for dep in lp.dependencies.iter().flatten() {
+            let mut parts = dep.split_whitespace();
+            let (name, version) = (parts.next().unwrap_or_default(), parts.next());
+            if !lockpacks
+                .iter()
+                .any(|o| util::compare_names(&o.name, name) && Some(o.version.as_str()) == version)
+            {
+                problems.push(format!(
+                    "{} depends on {}, which isn't locked",
+                    label(lp),
+                    dep
+                ));
+            }
+        }
+    }
+
+    if built {
+        // Their dependencies may be any package nothing else in the lock depends on.
+        roots.extend(lockpacks.iter().filter(|lp| {
+            !lockpacks
+                .iter()
+                .flat_map(|other| other.dependencies.iter().flatten())
+                .any(|dep| find_dep(lockpacks, dep).is_some_and(|d| label(d) == label(lp)))
+        }));
+    }
+    let mut needed = HashSet::new();
+    let mut to_visit = roots;
+    while let Some(lp) = to_visit.pop() {
+        if !needed.insert(label(lp)) {
+            continue;
+        }
+        to_visit.extend(
+            lp.dependencies
+                .iter()
+                .flatten()
+                .filter_map(|dep| find_dep(lockpacks, dep)),
+        );
+    }
+    for lp in lockpacks.iter().filter(|lp| !needed.contains(&label(lp))) {
+        problems.push(format!(
+            "{} is locked, but nothing in `pyproject.toml` needs it",
+            label(lp)
+        ));
+    }
+
+    problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dep_types::Constraint, tests::lock_pack};
+
+    fn req(name: &str, constraints: &str) -> Req {
+        Req::new(
+            name.into(),
+            Constraint::from_str_multiple(constraints).unwrap(),
+        )
+    }
+
+    #[test]
+    fn stale_lock_caught() {
+        let lockpacks = vec![
+            lock_pack(
+                "requests",
+                "2.25.1",
+                &["urllib3 1.26.4 pypi+x", "idna 2.10 pypi+x"],
+            ),
+            lock_pack("urllib3", "1.26.4", &[]),
+            lock_pack("idna", "2.10", &[]),
+        ];
+        let reqs = vec![req("Requests", ">=2.20")];
+        assert!(lock_problems(&lockpacks, &reqs, &[]).is_empty());
+
+        // Edited constraints, a new requirement, and an override the lock breaks.
+        let edited = vec![req("requests", ">=2.26"), req("black", "^20.8b1")];
+        let problems = lock_problems(&lockpacks, &edited, &[req("idna", ">=3.0")]);
+        assert_eq!(problems.len(), 6);
+        assert!(problems[0].contains("requests 2.25.1 is locked"));
+        assert_eq!(problems[1], "black is required, but isn't locked");
+        assert!(problems[2].starts_with("idna 2.10 is locked, but the override"));
+        // With `requests` no longer satisfied, nothing needs what's locked.
+        assert!(problems[3..]
+            .iter()
+            .all(|p| p.ends_with("but nothing in `pyproject.toml` needs it")));
+
+        // A requirement removed, and a dependency missing from the lock.
+        let mut lockpacks = lockpacks;
+        lockpacks.push(lock_pack("six", "1.15.0", &[]));
+        lockpacks[0]
+            .dependencies
+            .as_mut()
+            .unwrap()
+            .push("chardet 4.0.0 pypi+x".into());
+        assert_eq!(
+            lock_problems(&lockpacks, &reqs, &[]),
+            vec![
+                "requests 2.25.1 depends on chardet 4.0.0 pypi+x, which isn't locked",
+                "six 1.15.0 is locked, but nothing in `pyproject.toml` needs it",
+            ]
+        );
+    }
+}
@@ -54,6 +54,13 @@ pub fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
         }
         ClearChoice::PyInstalls => {}
         ClearChoice::All => {
+            // The dependency cache lives in the platform's cache directory, not with the rest.
+            if cache_path.exists() && fs::remove_dir_all(cache_path).is_err() {
+                abort(&format!(
+                    "Problem removing the dependency-cache path: {:?}",
+                    cache_path
+                ));
+            }
             if fs::remove_dir_all(&pyflow_path).is_err() {
                 abort(&format!(
                     "Problem removing the Pyflow path: {:?}",
@@ -64,3 +71,14 @@ pub fn clear(pyflow_path: &Path, cache_path: &Path, script_env_path: &Path) {
     }
     success("Cache is cleared")
 }
+
+/// Remove all downloaded package archives, without prompting.
+pub fn clean(cache_path: &Path) {
+    if cache_path.exists() && fs::remove_dir_all(cache_path).is_err() {
+        abort(&format!(
+            "Problem removing the dependency-cache path: {:?}",
+            cache_path
+        ));
+    }
+    success("Removed downloaded packages from the cache")
+}
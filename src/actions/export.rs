@@ -0,0 +1,116 @@
+use std::{fs, path::Path};
+
+use termcolor::Color;
+
+use crate::{dep_types::LockPackage, util};
+
+/// Write the locked packages as a pip `requirements.txt`, to `output`, or stdout. With `hashes`,
+/// each is pinned to the hash of the file installed, for `pip install --require-hashes`.
+pub fn export(lockpacks: &[LockPackage], dev: bool, hashes: bool, output: Option<&Path>) {
+    if lockpacks.is_empty() {
+        util::abort("There's no lock file to export; run `pyflow lock` first");
+    }
+
+    let (text, skipped) = requirements_txt(lockpacks, dev, hashes);
+    for msg in &skipped {
+        log::warn!("{}", msg);
+    }
+
+    match output {
+        Some(path) => {
+            if fs::write(path, text).is_err() {
+                util::abort(&format!("Problem writing {}", path.display()));
+            }
+            util::print_color(&format!("Exported to {}", path.display()), Color::Green);
+        }
+        None => print!("{}", text),
+    }
+}
+
+/// Render the requirements file, and explain any packages it can't represent exactly.
+fn requirements_txt(lockpacks: &[LockPackage], dev: bool, hashes: bool) -> (String, Vec<String>) {
+    let mut packs: Vec<&LockPackage> = lockpacks.iter().filter(|lp| dev || !lp.dev).collect();
+    packs.sort_by_key(|lp| util::normalize_name(&lp.name));
+
+    let mut lines = vec!["# Generated by `pyflow export`, from `pyflow.lock`".to_owned()];
+    let mut skipped = vec![];
+    for lp in packs {
+        // pip only installs one version of each package.
+        if lp.rename.is_some() {
+            skipped.push(format!(
+                "Left out {} {}, since pip can't install a second version of a package",
+                lp.name, lp.version
+            ));
+            continue;
+        }
+
+        let name = match &lp.extras {
+            Some(extras) if !extras.is_empty() => format!("{}[{}]", lp.name, extras.join(",")),
+            _ => lp.name.clone(),
+        };
+        let source = lp.source.as_deref().unwrap_or_default();
+        let mut line = if let Some(git) = source.strip_prefix("git+") {
+            // Our sources put the commit after `#`; pip expects it after `@`.
+            format!("{} @ git+{}", name, git.replacen('#', "@", 1))
+        } else if let Some(url) = source.strip_prefix("url+") {
+            format!("{} @ {}", name, url)
+        } else {
+            format!("{}=={}", name, lp.version)
+        };
+
+        if hashes {
+            match &lp.hash {
+                Some(hash) => line.push_str(&format!(" \\\n    --hash={}", hash)),
+                None => skipped.push(format!(
+                    "{} {} has no hash in the lock file, so `pip install --require-hashes` will \
+                     reject it. Reinstall it to record one",
+                    lp.name, lp.version
+                )),
+            }
+        }
+        lines.push(line);
+    }
+
+    (lines.join("\n") + "\n", skipped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lock_pack;
+
+    #[test]
+    fn requirements_exported() {
+        let mut requests = lock_pack("requests", "2.25.1", &[]);
+        requests.hash = Some("sha256:ab".into());
+        let mut black = lock_pack("black", "20.8b1", &[]);
+        black.hash = Some("sha256:cd".into());
+        black.dev = true;
+        let mut httpx = lock_pack("httpx", "0.16.1", &[]);
+        httpx.extras = Some(vec!["http2".into()]);
+        let mut mylib = lock_pack("mylib", "0.1.0", &[]);
+        mylib.source = Some("git+https://github.com/me/mylib#0123abc".into());
+        let lockpacks = vec![requests, black, httpx, mylib];
+
+        let (text, skipped) = requirements_txt(&lockpacks, false, false);
+        assert_eq!(
+            text,
+            "# Generated by `pyflow export`, from `pyflow.lock`\n\
+             httpx[http2]==0.16.1\n\
+             mylib @ git+https://github.com/me/mylib@0123abc\n\
+             requests==2.25.1\n"
+        );
+        assert!(skipped.is_empty());
+
+        let (text, skipped) = requirements_txt(&lockpacks, true, true);
+        assert_eq!(
+            text,
+            "# Generated by `pyflow export`, from `pyflow.lock`\n\
+             black==20.8b1 \\\n    --hash=sha256:cd\n\
+             httpx[http2]==0.16.1\n\
+             mylib @ git+https://github.com/me/mylib@0123abc\n\
+             requests==2.25.1 \\\n    --hash=sha256:ab\n"
+        );
+        assert_eq!(skipped.len(), 2);
+    }
+}
@@ -22,6 +22,8 @@ pub fn init(cfg_filename: &str) {
     cfg.py_version = Some(util::prompts::py_vers());
 
     files::parse_req_dot_text(&mut cfg, &PathBuf::from("requirements.txt"));
+    files::parse_setup_cfg(&mut cfg, &PathBuf::from("setup.cfg"));
+    files::parse_setup_py(&mut cfg, &PathBuf::from("setup.py"));
 
     cfg.write_file(&cfg_path);
     util::print_color("Created `pyproject.toml`", Color::Green);
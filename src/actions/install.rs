@@ -8,7 +8,7 @@ use crate::{
     Config,
 };
 
-use util::deps::sync;
+use util::deps::{plan, print_plan, registry_only, sync};
 
 // TODO: Refactor this function
 #[allow(clippy::too_many_arguments)]
@@ -24,7 +24,19 @@ pub fn install(
     os: &Os,
     py_vers: &Version,
     lock_path: &Path,
+    require_license: bool,
+    dry_run: bool,
+    json: bool,
+    upgrade: &[String],
 ) {
+    util::deps::check_locked(lockpacks, upgrade, "upgrade");
+
+    if dry_run {
+        return install_dry_run(
+            packages, dev, cfg, paths, lockpacks, os, py_vers, json, upgrade,
+        );
+    }
+
     if !cfg_path.exists() {
         cfg.write_file(cfg_path);
     }
@@ -34,22 +46,77 @@ pub fn install(
     }
 
     // Merge reqs added via cli with those in `pyproject.toml`.
-    let (updated_reqs, up_dev_reqs) = util::merge_reqs(packages, dev, cfg, cfg_path);
+    let (updated_reqs, up_dev_reqs) = util::merge_reqs(packages, dev, cfg, Some(cfg_path));
 
     let dont_uninstall = util::find_dont_uninstall(&updated_reqs, &up_dev_reqs);
 
-    let updated_reqs = process_reqs(updated_reqs, git_path, paths);
-    let up_dev_reqs = process_reqs(up_dev_reqs, git_path, paths);
+    let updated_reqs = process_reqs(updated_reqs, git_path, paths, lockpacks, true);
+    let up_dev_reqs = process_reqs(up_dev_reqs, git_path, paths, lockpacks, true);
 
     sync(
         paths,
         lockpacks,
         &updated_reqs,
         &up_dev_reqs,
+        &cfg.overrides,
         &dont_uninstall,
+        upgrade,
+        dev,
         *os,
         py_vers,
         lock_path,
+        &cfg.hashes,
+        cfg.post_resolve_hook.as_deref(),
     );
     util::print_color("Installation complete", Color::Green);
+
+    let unlicensed = util::find_unlicensed(&paths.lib);
+    if !unlicensed.is_empty() {
+        let names: Vec<String> = unlicensed
+            .iter()
+            .map(|(name, version)| format!("{} {}", name, version))
+            .collect();
+        let msg = format!(
+            "These packages don't specify a license: {}",
+            names.join(", ")
+        );
+        if require_license {
+            util::abort(&msg);
+        }
+        util::print_color(&msg, Color::Yellow);
+    }
+}
+
+/// Show what `install` would do, without writing the config or lock files, or changing
+/// installed packages. Git and path requirements are left as-is, since we'd need to
+/// install them to find their dependencies.
+#[allow(clippy::too_many_arguments)]
+fn install_dry_run(
+    packages: &[String],
+    dev: bool,
+    cfg: &Config,
+    paths: &Paths,
+    lockpacks: &[LockPackage],
+    os: &Os,
+    py_vers: &Version,
+    json: bool,
+    upgrade: &[String],
+) {
+    let (updated_reqs, up_dev_reqs) = util::merge_reqs(packages, dev, cfg, None);
+    let dont_uninstall = util::find_dont_uninstall(&updated_reqs, &up_dev_reqs);
+
+    let ops = plan(
+        paths,
+        lockpacks,
+        &registry_only(&updated_reqs),
+        &registry_only(&up_dev_reqs),
+        &cfg.overrides,
+        &dont_uninstall,
+        upgrade,
+        dev,
+        *os,
+        py_vers,
+    );
+
+    print_plan(&ops, json);
 }
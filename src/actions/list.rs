@@ -1,5 +1,9 @@
-use std::{path::Path, process};
+use std::{
+    path::{Path, PathBuf},
+    process,
+};
 
+use serde::Serialize;
 use termcolor::Color;
 
 use crate::{
@@ -8,9 +12,18 @@ use crate::{
     util::{self, abort, print_color, print_color_},
 };
 
+/// An installed package, as `pyflow list --json` reports it.
+#[derive(Debug, PartialEq, Serialize)]
+struct InstalledPackage {
+    name: String,
+    /// `None` for editable path requirements, which aren't installed under a version.
+    version: Option<String>,
+    location: PathBuf,
+}
+
 /// List all installed dependencies and console scripts, by examining the `libs` and `bin` folders.
-/// Also include path requirements, which won't appear in the `lib` folder.
-pub fn list(lib_path: &Path, path_reqs: &[Req]) {
+/// Also include editable path requirements, which won't appear in the `lib` folder.
+pub fn list(lib_path: &Path, path_reqs: &[Req], json: bool) {
     // This part check that project and venvs exists
     let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
     let num_venvs = util::find_venvs(&pcfg.pypackages_path).len();
@@ -21,6 +34,15 @@ pub fn list(lib_path: &Path, path_reqs: &[Req]) {
         abort("There's no python environment set up for this project")
     }
 
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&installed_packages(lib_path, path_reqs))
+                .expect("Problem serializing the installed packages")
+        );
+        return;
+    }
+
     let installed = util::find_installed(lib_path);
     let scripts = find_console_scripts(&lib_path.join("../bin"));
 
@@ -51,6 +73,24 @@ pub fn list(lib_path: &Path, path_reqs: &[Req]) {
     }
 }
 
+fn installed_packages(lib_path: &Path, path_reqs: &[Req]) -> Vec<InstalledPackage> {
+    let mut result: Vec<InstalledPackage> = util::find_installed(lib_path)
+        .into_iter()
+        .map(|(name, version, _tops)| InstalledPackage {
+            name,
+            version: Some(version.to_string()),
+            location: lib_path.to_owned(),
+        })
+        .collect();
+
+    result.extend(path_reqs.iter().map(|req| InstalledPackage {
+        name: req.name.clone(),
+        version: None,
+        location: PathBuf::from(req.path.as_ref().unwrap()),
+    }));
+    result
+}
+
 /// Find console scripts installed, by browsing the (custom) bin folder
 pub fn find_console_scripts(bin_path: &Path) -> Vec<String> {
     let mut result = vec![];
@@ -69,3 +109,32 @@ pub fn find_console_scripts(bin_path: &Path) -> Vec<String> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn installed_serialized() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lib_path = tmp.path();
+        fs::create_dir_all(lib_path.join("requests-2.25.1.dist-info")).unwrap();
+        fs::create_dir_all(lib_path.join("requests")).unwrap();
+
+        let mut local = Req::new("mylib".into(), vec![]);
+        local.path = Some("../mylib".into());
+        local.develop = true;
+
+        let packages = installed_packages(lib_path, &[local]);
+
+        let json: serde_json::Value = serde_json::to_value(&packages).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"name": "requests", "version": "2.25.1", "location": lib_path},
+                {"name": "mylib", "version": null, "location": "../mylib"},
+            ])
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use std::path::Path;
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::{LockPackage, Version},
+    util::{self, deps, process_reqs, Os, Paths},
+    Config,
+};
+
+/// Resolve `pyproject.toml`'s requirements and write the lock file, without installing anything.
+/// With `check`, leave the lock file alone, and fail with a diff if it's out of date; eg as a CI
+/// gate. Offline, `check_lock` handles `check` instead, without resolving.
+#[allow(clippy::too_many_arguments)]
+pub fn lock(
+    cfg: &Config,
+    git_path: &Path,
+    paths: &Paths,
+    lockpacks: &[LockPackage],
+    os: Os,
+    py_vers: &Version,
+    lock_path: &Path,
+    check: bool,
+) {
+    if check && lockpacks.is_empty() {
+        util::abort("There's no lock file to check; run `pyflow lock` to create one");
+    }
+
+    let reqs = process_reqs(cfg.reqs.clone(), git_path, paths, lockpacks, false);
+    let dev_reqs = process_reqs(cfg.dev_reqs.clone(), git_path, paths, lockpacks, false);
+    let resolved = deps::resolve_lock(
+        paths,
+        lockpacks,
+        &reqs,
+        &dev_reqs,
+        &cfg.overrides,
+        &[],
+        os,
+        py_vers,
+    );
+
+    if !check {
+        deps::write_lock_packs(lock_path, &resolved);
+        util::print_color("Updated the lock file", Color::Green);
+        return;
+    }
+
+    let diff = lock_diff(lockpacks, &resolved);
+    if diff.is_empty() {
+        util::print_color("The lock file is up to date", Color::Green);
+        return;
+    }
+    for line in &diff {
+        let color = if line.starts_with('+') {
+            Color::Green
+        } else {
+            Color::Red
+        };
+        util::print_color(line, color);
+    }
+    util::abort(
+        "The lock file is out of date with `pyproject.toml`; run `pyflow lock` to update it",
+    );
+}
+
+fn describe(lp: &LockPackage) -> String {
+    match &lp.extras {
+        Some(extras) => format!("{}[{}] {}", lp.name, extras.join(","), lp.version),
+        None => format!("{} {}", lp.name, lp.version),
+    }
+}
+
+/// Diff two locks by package, ordered by name, with a `-` line for each package only in
+/// `committed`, and a `+` line for each only in `resolved`. A changed version gets both.
+pub(super) fn lock_diff(committed: &[LockPackage], resolved: &[LockPackage]) -> Vec<String> {
+    let committed: Vec<(String, String)> = committed
+        .iter()
+        .map(|lp| (util::normalize_name(&lp.name), describe(lp)))
+        .collect();
+    let resolved: Vec<(String, String)> = resolved
+        .iter()
+        .map(|lp| (util::normalize_name(&lp.name), describe(lp)))
+        .collect();
+
+    let mut names: Vec<&String> = committed.iter().chain(&resolved).map(|(n, _)| n).collect();
+    names.sort();
+    names.dedup();
+
+    let mut result = vec![];
+    for name in names {
+        let old = committed.iter().find(|(n, _)| n == name).map(|(_, d)| d);
+        let new = resolved.iter().find(|(n, _)| n == name).map(|(_, d)| d);
+        if old == new {
+            continue;
+        }
+        if let Some(old) = old {
+            result.push(format!("- {}", old));
+        }
+        if let Some(new) = new {
+            result.push(format!("+ {}", new));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lock_pack;
+
+    #[test]
+    fn stale_lock_diffed() {
+        let committed = vec![
+            lock_pack("requests", "2.24.0", &[]),
+            lock_pack("idna", "2.10", &[]),
+        ];
+        // Re-resolved after bumping the constraint to `requests >= 2.25`, which also pulls in a
+        // new dependency. The resolver's output isn't in any particular order.
+        let resolved = vec![
+            lock_pack("idna", "2.10", &[]),
+            lock_pack("Requests", "2.25.1", &[]),
+            lock_pack("chardet", "4.0.0", &[]),
+        ];
+
+        assert_eq!(
+            lock_diff(&committed, &resolved),
+            vec![
+                "+ chardet 4.0.0".to_owned(),
+                "- requests 2.24.0".to_owned(),
+                "+ Requests 2.25.1".to_owned(),
+            ]
+        );
+        assert!(lock_diff(&committed, &committed).is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use std::{fs, path::Path};
+
+use termcolor::Color;
+
+use crate::{
+    cli_options::MigrateFrom,
+    files,
+    pyproject::{Config, CFG_FILENAME},
+    util::{self, abort},
+};
+
+/// Rename a file to `<name>.bak`, if it exists.
+fn archive_file(path: &Path) {
+    if !path.exists() {
+        return;
+    }
+    let mut archived = path.as_os_str().to_owned();
+    archived.push(".bak");
+    if fs::rename(path, &archived).is_err() {
+        abort(&format!("Problem archiving {}", path.display()));
+    }
+    util::print_color(
+        &format!(
+            "Moved {} to {}",
+            path.display(),
+            Path::new(&archived).display()
+        ),
+        Color::Green,
+    );
+}
+
+/// Convert a Poetry or Pipenv project in `project_path` to pyflow. Dependencies, dependency
+/// groups, scripts and metadata are translated into `[tool.pyflow]` sections; if `archive` is
+/// set, the other tool's files are renamed with a `.bak` suffix.
+pub fn migrate(project_path: &Path, from: &MigrateFrom, archive: bool) {
+    let cfg_path = project_path.join(CFG_FILENAME);
+
+    let (cfg, old_files) = match from {
+        MigrateFrom::Poetry => {
+            let data = fs::read_to_string(&cfg_path)
+                .unwrap_or_else(|_| abort("Can't find `pyproject.toml` to migrate from"));
+            if !data.contains("[tool.poetry") {
+                abort("`pyproject.toml` doesn't contain a `[tool.poetry]` section")
+            }
+            let cfg = match Config::from_file(&cfg_path) {
+                Ok(c) => c.unwrap_or_default(),
+                Err(e) => abort(&format!("Problem parsing `pyproject.toml`: {}", e)),
+            };
+            (cfg, vec![project_path.join("poetry.lock")])
+        }
+        MigrateFrom::Pipenv => {
+            let pipfile_path = project_path.join("Pipfile");
+            let cfg = Config::from_pipfile(&pipfile_path)
+                .unwrap_or_else(|| abort("Can't find a `Pipfile` to migrate from"));
+            (cfg, vec![pipfile_path, project_path.join("Pipfile.lock")])
+        }
+    };
+
+    let existing = fs::read_to_string(&cfg_path).unwrap_or_default();
+    let migrated = files::migrate_cfg(&existing, &cfg);
+
+    if archive {
+        if let MigrateFrom::Poetry = from {
+            // We edit `pyproject.toml` in place; keep the original around.
+            let backup = project_path.join(format!("{}.bak", CFG_FILENAME));
+            if fs::write(&backup, &existing).is_err() {
+                abort("Problem archiving `pyproject.toml`");
+            }
+        }
+        for path in &old_files {
+            archive_file(path);
+        }
+    }
+
+    if fs::write(&cfg_path, migrated).is_err() {
+        abort("Problem writing `pyproject.toml`");
+    }
+    util::print_color("Migrated the project to `pyproject.toml`", Color::Green);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dep_types::Version;
+
+    const POETRY_PROJECT: &str = r#"[tool.poetry]
+name = "everythingkiller"
+version = "0.1.0"
+description = "Small, but packs a punch!"
+authors = ["Fraa Erasmas <raz@edhar.math>"]
+
+[tool.poetry.dependencies]
+python = "^3.8"
+numpy = "^1.16.4"
+
+[tool.poetry.group.test.dependencies]
+pytest = "^6.2"
+
+[tool.poetry.group.docs.dependencies]
+sphinx = "^4.0"
+
+[tool.poetry.scripts]
+activate = "everythingkiller:activate"
+
+# Formatting settings
+[tool.black]
+line-length = 100
+
+[build-system]
+requires = ["poetry-core>=1.0.0"]
+build-backend = "poetry.core.masonry.api"
+"#;
+
+    #[test]
+    fn migrate_poetry() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(dir.join(CFG_FILENAME), POETRY_PROJECT).unwrap();
+        fs::write(dir.join("poetry.lock"), "").unwrap();
+
+        migrate(dir, &MigrateFrom::Poetry, true);
+
+        let migrated = fs::read_to_string(dir.join(CFG_FILENAME)).unwrap();
+        assert!(!migrated.contains("[tool.poetry"));
+        assert!(migrated.contains("# Formatting settings\n[tool.black]\nline-length = 100"));
+        assert!(migrated.contains(r#"build-backend = "setuptools.build_meta""#));
+
+        let cfg = Config::from_file(&dir.join(CFG_FILENAME)).unwrap().unwrap();
+        assert_eq!(cfg.name, Some("everythingkiller".into()));
+        assert_eq!(cfg.py_version, Some(Version::new_short(3, 8)));
+        assert_eq!(
+            cfg.reqs.iter().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+            vec!["numpy"]
+        );
+        let mut dev_names: Vec<&str> = cfg.dev_reqs.iter().map(|r| r.name.as_str()).collect();
+        dev_names.sort_unstable();
+        assert_eq!(dev_names, vec!["pytest", "sphinx"]);
+        assert_eq!(
+            cfg.scripts.get("activate"),
+            Some(&"everythingkiller:activate".to_owned())
+        );
+
+        assert!(dir.join("pyproject.toml.bak").exists());
+        assert!(dir.join("poetry.lock.bak").exists());
+        assert!(!dir.join("poetry.lock").exists());
+    }
+
+    #[test]
+    fn migrate_pipenv() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::write(
+            dir.join("Pipfile"),
+            r#"[packages]
+requests = ">=2.20"
+
+[dev-packages]
+black = "^19.0"
+
+[requires]
+python_version = "3.7"
+"#,
+        )
+        .unwrap();
+
+        migrate(dir, &MigrateFrom::Pipenv, false);
+
+        let cfg = Config::from_file(&dir.join(CFG_FILENAME)).unwrap().unwrap();
+        assert_eq!(cfg.py_version, Some(Version::new_short(3, 7)));
+        assert_eq!(cfg.reqs[0].name, "requests");
+        assert_eq!(cfg.dev_reqs[0].name, "black");
+        assert!(dir.join("Pipfile").exists());
+    }
+}
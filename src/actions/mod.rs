@@ -1,19 +1,39 @@
+mod check;
 mod clear;
+mod env;
+mod export;
 mod init;
 mod install;
 mod list;
+mod lock;
+mod migrate;
 mod new;
+mod outdated;
 mod package;
 mod reset;
 mod run;
+mod shell;
 mod switch;
+mod tree;
+mod update;
+mod why;
 
-pub use clear::clear;
+pub use check::check_lock;
+pub use clear::{clean, clear};
+pub use env::env;
+pub use export::export;
 pub use init::init;
 pub use install::install;
 pub use list::list;
+pub use lock::lock;
+pub use migrate::migrate;
 pub use new::new;
+pub use outdated::outdated;
 pub use package::package;
-pub use reset::reset;
+pub use reset::{reset, reset_keeping_lock};
 pub use run::run;
+pub use shell::shell;
 pub use switch::switch;
+pub use tree::tree;
+pub use update::update;
+pub use why::why;
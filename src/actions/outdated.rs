@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use termcolor::Color;
+
+use crate::{
+    dep_resolution::res,
+    dep_types::{Constraint, Extras, Req, ReqType, Version},
+    util::{self, print_color, print_color_},
+};
+
+/// One installed package with a newer release: name, installed version, newest version the
+/// `pyproject.toml` constraint allows, and newest version available.
+type Outdated = (String, Version, Version, Version);
+
+/// List installed packages that have newer releases, and flag ones where `pyproject.toml`'s
+/// constraint holds back the newest.
+pub fn outdated(lib_path: &Path, reqs: &[Req], py_vers: &Version) {
+    let installed = util::find_installed(lib_path);
+    if installed.is_empty() {
+        util::abort("No packages are installed; run `pyflow install` first");
+    }
+
+    let mut rows: Vec<Outdated> = vec![];
+    for (name, version, _tops) in installed {
+        let query = Req::new_with_extras(
+            name.clone(),
+            vec![Constraint::new_any()],
+            Extras::new_py(Constraint::new(ReqType::Exact, py_vers.clone())),
+        );
+        let (fmtd_name, _, available) = match res::get_version_info(&name, Some(query)) {
+            Ok(info) => info,
+            Err(_) => {
+                print_color(
+                    &format!("Problem getting versions for {}", name),
+                    Color::Red,
+                );
+                continue;
+            }
+        };
+
+        let constraints = reqs
+            .iter()
+            .find(|r| util::compare_names(&r.name, &name))
+            .map(|r| r.constraints.as_slice())
+            .unwrap_or(&[]);
+        let (compatible, latest) = newest_versions(&available, constraints, &version);
+
+        if let Some(latest) = latest {
+            if latest > version {
+                // If nothing's compatible, eg the package was yanked, report what's installed.
+                let compatible = compatible.unwrap_or_else(|| version.clone());
+                rows.push((fmtd_name, version, compatible, latest));
+            }
+        }
+    }
+
+    if rows.is_empty() {
+        util::print_color("All packages are up to date", Color::Green);
+        return;
+    }
+
+    let headers = ["Package", "Installed", "Compatible", "Latest"];
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for (name, installed, compatible, latest) in &rows {
+        let cols = [
+            name.len(),
+            installed.to_string().len(),
+            compatible.to_string().len(),
+            latest.to_string().len(),
+        ];
+        for (w, c) in widths.iter_mut().zip(cols.iter()) {
+            *w = (*w).max(*c);
+        }
+    }
+
+    let header: Vec<String> = headers
+        .iter()
+        .zip(&widths)
+        .map(|(h, w)| format!("{:w$}", h, w = w))
+        .collect();
+    print_color(header.join("  ").trim_end(), Color::Blue);
+
+    let mut blocked = false;
+    for (name, installed, compatible, latest) in &rows {
+        print_color_(&format!("{:w$}  ", name, w = widths[0]), Color::Cyan);
+        // `Version`'s `Display` ignores padding.
+        let (installed_str, compatible_str) = (installed.to_string(), compatible.to_string());
+        let line = format!(
+            "{:w1$}  {:w2$}  {}",
+            installed_str,
+            compatible_str,
+            latest,
+            w1 = widths[1],
+            w2 = widths[2]
+        );
+        if latest > compatible {
+            blocked = true;
+            print_color(&format!("{} *", line), Color::Yellow);
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    if blocked {
+        print_color(
+            "\n* The latest version is outside the constraint in `pyproject.toml`; change the \
+             constraint to upgrade.",
+            Color::Yellow,
+        );
+    }
+}
+
+/// Find the newest of `available` that satisfies `constraints`, and the newest overall.
+/// Pre-releases are skipped, unless `installed` is one.
+fn newest_versions(
+    available: &[Version],
+    constraints: &[Constraint],
+    installed: &Version,
+) -> (Option<Version>, Option<Version>) {
+    let candidates: Vec<&Version> = available
+        .iter()
+        .filter(|v| !v.is_prerelease() || installed.is_prerelease())
+        .collect();
+
+    let compatible = candidates
+        .iter()
+        .filter(|v| res::is_compat(constraints, v))
+        .max()
+        .map(|v| (*v).clone());
+    let latest = candidates.into_iter().max().cloned();
+
+    (compatible, latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    #[test]
+    fn blocked_by_constraint() {
+        let available: Vec<Version> = ["2.24.0", "2.25.1", "3.0.0", "3.1.0a1"]
+            .iter()
+            .map(|v| Version::from_str(v).unwrap())
+            .collect();
+        let installed = Version::new(2, 24, 0);
+
+        let constraints = vec![Constraint::from_str("^2.24.0").unwrap()];
+        assert_eq!(
+            newest_versions(&available, &constraints, &installed),
+            (Some(Version::new(2, 25, 1)), Some(Version::new(3, 0, 0)))
+        );
+
+        // Not in `pyproject.toml`, eg a sub-dependency.
+        assert_eq!(
+            newest_versions(&available, &[], &installed),
+            (Some(Version::new(3, 0, 0)), Some(Version::new(3, 0, 0)))
+        );
+    }
+}
@@ -6,6 +6,7 @@ use crate::{
     util::{self, deps::sync},
 };
 
+#[allow(clippy::too_many_arguments)]
 pub fn package(
     paths: &util::Paths,
     lockpacks: &[LockPackage],
@@ -14,17 +15,23 @@ pub fn package(
     lock_path: &Path,
     cfg: &crate::Config,
     extras: &[String],
+    check: bool,
 ) {
     sync(
         paths,
         lockpacks,
         &cfg.reqs,
         &cfg.dev_reqs,
+        &cfg.overrides,
         &util::find_dont_uninstall(&cfg.reqs, &cfg.dev_reqs),
+        &[],
+        false,
         os,
         py_vers,
         lock_path,
+        &cfg.hashes,
+        cfg.post_resolve_hook.as_deref(),
     );
 
-    build::build(lockpacks, paths, cfg, extras)
+    build::build(lockpacks, paths, cfg, extras, check)
 }
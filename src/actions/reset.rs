@@ -1,17 +1,101 @@
-use std::{fs, process};
+use std::{fs, path::Path, process};
 
+use termcolor::Color;
+
+use super::lock::lock_diff;
 use crate::{
+    dep_types::LockPackage,
     pyproject,
-    util::{abort, success},
+    util::{self, abort, success},
 };
 
-pub fn reset() {
+/// Remove the environment and `pyflow.lock`. With `keep_lock`, nothing's removed yet: once the
+/// project's set up, `reset_keeping_lock` checks the lock, and removes the environment.
+pub fn reset(keep_lock: bool) {
     let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
-    if (&pcfg.pypackages_path).exists() && fs::remove_dir_all(&pcfg.pypackages_path).is_err() {
-        abort("Problem removing `__pypackages__` directory")
+    if keep_lock {
+        if !pcfg.lock_path.exists() {
+            abort("There's no `pyflow.lock` to reinstall from; run `pyflow install` instead")
+        }
+        return;
     }
+    remove_pypackages(&pcfg.pypackages_path);
     if (&pcfg.lock_path).exists() && fs::remove_file(&pcfg.lock_path).is_err() {
         abort("Problem removing `pyflow.lock`")
     }
     success("`__pypackages__` folder and `pyflow.lock` removed")
 }
+
+fn remove_pypackages(pypackages_path: &Path) {
+    if pypackages_path.exists() && fs::remove_dir_all(pypackages_path).is_err() {
+        abort("Problem removing `__pypackages__` directory")
+    }
+}
+
+/// For `reset --keep-lock`: remove the environment, once we're sure reinstalling brings back
+/// exactly the locked packages. It's recreated, and they're reinstalled, after this returns.
+pub fn reset_keeping_lock(
+    lockpacks: &[LockPackage],
+    resolved: &[LockPackage],
+    pypackages_path: &Path,
+) {
+    check_lock_unchanged(lockpacks, resolved);
+    remove_pypackages(pypackages_path);
+    util::print_color(
+        "`__pypackages__` folder removed; reinstalling from `pyflow.lock`",
+        Color::Green,
+    );
+}
+
+/// Make sure reinstalling brings back exactly the locked packages, and not ones re-resolved
+/// after `pyproject.toml` was edited.
+fn check_lock_unchanged(lockpacks: &[LockPackage], resolved: &[LockPackage]) {
+    let diff = lock_diff(lockpacks, resolved);
+    if diff.is_empty() {
+        return;
+    }
+    for line in &diff {
+        println!("{}", line);
+    }
+    abort(
+        "`pyproject.toml` has changed since `pyflow.lock` was written, so reinstalling would \
+         change the packages above. Run `pyflow install` to update the lock and install them",
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lock_pack;
+
+    #[test]
+    fn changed_lock_keeps_environment() {
+        let (dir, _tmp) = crate::tests::child_test_dir();
+        let pypackages = dir.join("__pypackages__");
+        let name = "actions::reset::tests::changed_lock_keeps_environment";
+        fs::create_dir_all(pypackages.join("3.9/lib/six")).unwrap();
+        let output = match crate::tests::in_child_process(name, &dir) {
+            Some(output) => output,
+            None => {
+                return reset_keeping_lock(
+                    &[lock_pack("six", "1.15.0", &[])],
+                    &[lock_pack("six", "1.16.0", &[])],
+                    &pypackages,
+                )
+            }
+        };
+        let kept = pypackages.join("3.9/lib/six").exists();
+
+        assert_eq!(output.status.code(), Some(1));
+        assert!(kept);
+
+        // Unchanged, it's removed.
+        reset_keeping_lock(
+            &[lock_pack("six", "1.16.0", &[])],
+            &[lock_pack("six", "1.16.0", &[])],
+            &pypackages,
+        );
+        let removed = !pypackages.exists();
+        assert!(removed);
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    env,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::{self, Command},
+};
+
+use termcolor::Color;
+
+use crate::util::{self, abort, Paths};
+
+/// Start the user's shell inside the project's environment, so `python`, `pip`, and installed
+/// console scripts resolve to it. Exiting returns to the parent shell, unchanged.
+pub fn shell(paths: &Paths, vers_path: &Path, pythonpath: &[PathBuf]) {
+    // Environments without a venv, per PEP 582, only have the interpreter's folder to go on.
+    let venv_path = Some(vers_path.join(".venv")).filter(|p| p.exists());
+    let active = match &venv_path {
+        Some(venv) => env::var_os("VIRTUAL_ENV").is_some_and(|v| Path::new(&v) == venv),
+        None => env::var_os("PATH")
+            .is_some_and(|p| env::split_paths(&p).next().as_ref() == Some(&paths.bin)),
+    };
+    if active {
+        abort("This environment is already active; `exit` to leave it");
+    }
+
+    #[cfg(target_os = "windows")]
+    let shell = env::var_os("COMSPEC").unwrap_or_else(|| "cmd".into());
+    #[cfg(not(target_os = "windows"))]
+    let shell = env::var_os("SHELL").unwrap_or_else(|| "sh".into());
+
+    let vars = shell_env(paths, venv_path.as_deref(), pythonpath, env::var_os("PATH"));
+    util::print_color(
+        &format!(
+            "Entering the environment at {}; `exit` to leave",
+            venv_path.as_deref().unwrap_or(vers_path).display()
+        ),
+        Color::Green,
+    );
+
+    let status = match Command::new(&shell)
+        .envs(vars)
+        .env_remove("PYTHONHOME")
+        .status()
+    {
+        Ok(s) => s,
+        Err(_) => abort(&format!(
+            "Problem starting the shell {}",
+            shell.to_string_lossy()
+        )),
+    };
+    process::exit(status.code().unwrap_or(1));
+}
+
+/// The variables that put the environment first: its interpreter and console scripts on the
+/// `PATH`, the venv, if it has one, in `VIRTUAL_ENV`, and the installed packages on the
+/// `PYTHONPATH`.
+fn shell_env(
+    paths: &Paths,
+    venv_path: Option<&Path>,
+    pythonpath: &[PathBuf],
+    current_path: Option<OsString>,
+) -> Vec<(&'static str, OsString)> {
+    let mut path = vec![paths.bin.clone(), paths.entry_pt.clone()];
+    if let Some(current) = current_path {
+        path.extend(env::split_paths(&current));
+    }
+
+    let mut vars = vec![(
+        "PATH",
+        env::join_paths(path).expect("Problem joining the PATH"),
+    )];
+    if let Some(venv) = venv_path {
+        vars.push(("VIRTUAL_ENV", venv.as_os_str().to_owned()));
+    }
+    vars.push((
+        "PYTHONPATH",
+        env::join_paths(pythonpath).expect("Problem joining the PYTHONPATH"),
+    ));
+    vars
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_put_first() {
+        let vers_path = PathBuf::from("/proj/__pypackages__/3.9");
+        let paths = Paths {
+            bin: vers_path.join(".venv/bin"),
+            lib: vers_path.join("lib"),
+            entry_pt: vers_path.join("bin"),
+            cache: PathBuf::from("/cache"),
+        };
+        let current = env::join_paths(["/usr/bin", "/bin"]).unwrap();
+
+        let vars = shell_env(
+            &paths,
+            Some(&vers_path.join(".venv")),
+            &[paths.lib.clone(), PathBuf::from("/proj/local-dep")],
+            Some(current),
+        );
+        let path: Vec<PathBuf> = env::split_paths(&vars[0].1).collect();
+        assert_eq!(
+            path,
+            vec![
+                paths.bin.clone(),
+                paths.entry_pt.clone(),
+                PathBuf::from("/usr/bin"),
+                PathBuf::from("/bin"),
+            ]
+        );
+        assert_eq!(
+            vars[1],
+            ("VIRTUAL_ENV", vers_path.join(".venv").into_os_string())
+        );
+        let pythonpath: Vec<PathBuf> = env::split_paths(&vars[2].1).collect();
+        assert_eq!(
+            pythonpath,
+            vec![paths.lib.clone(), PathBuf::from("/proj/local-dep")]
+        );
+    }
+
+    #[test]
+    fn no_venv_without_one() {
+        let vers_path = PathBuf::from("/proj/__pypackages__/3.9");
+        let paths = Paths {
+            bin: vers_path.join(".python/bin"),
+            lib: vers_path.join("lib"),
+            entry_pt: vers_path.join("bin"),
+            cache: PathBuf::from("/cache"),
+        };
+
+        let vars = shell_env(&paths, None, std::slice::from_ref(&paths.lib), None);
+        let names: Vec<&str> = vars.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["PATH", "PYTHONPATH"]);
+        let path: Vec<PathBuf> = env::split_paths(&vars[0].1).collect();
+        assert_eq!(path, vec![paths.bin.clone(), paths.entry_pt.clone()]);
+    }
+}
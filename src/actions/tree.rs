@@ -0,0 +1,170 @@
+use crate::{
+    dep_types::{LockPackage, Req},
+    util,
+};
+
+/// Print the locked dependency graph as a tree, rooted at `package` if passed, or at each of
+/// `pyproject.toml`'s requirements otherwise.
+pub fn tree(lockpacks: &[LockPackage], reqs: &[Req], package: Option<&str>) {
+    if lockpacks.is_empty() {
+        util::abort("There's no lock file to show; run `pyflow install` first");
+    }
+
+    let roots: Vec<&LockPackage> = match package {
+        Some(name) => match lockpacks
+            .iter()
+            .find(|lp| util::compare_names(&lp.name, name))
+        {
+            Some(lp) => vec![lp],
+            None => util::abort(&format!("{} isn't in the lock file", name)),
+        },
+        None => find_roots(lockpacks, reqs),
+    };
+
+    for line in tree_lines(lockpacks, &roots) {
+        println!("{}", line);
+    }
+}
+
+/// The locked packages `reqs` name, or if there aren't any, the ones nothing depends on.
+pub(super) fn find_roots<'a>(lockpacks: &'a [LockPackage], reqs: &[Req]) -> Vec<&'a LockPackage> {
+    let mut roots: Vec<&LockPackage> = lockpacks
+        .iter()
+        .filter(|lp| reqs.iter().any(|r| util::compare_names(&r.name, &lp.name)))
+        .collect();
+
+    if roots.is_empty() {
+        roots = lockpacks
+            .iter()
+            .filter(|lp| {
+                !lockpacks
+                    .iter()
+                    .flat_map(|other| other.dependencies.iter().flatten())
+                    .filter_map(|dep| dep.split_whitespace().next())
+                    .any(|dep| util::compare_names(dep, &lp.name))
+            })
+            .collect();
+    }
+    roots.sort_by_key(|lp| util::normalize_name(&lp.name));
+    roots
+}
+
+pub(super) fn label(lp: &LockPackage) -> String {
+    format!("{} {}", lp.name, lp.version)
+}
+
+/// Find the lock entry for a dependency, eg `idna 2.10 pypi+https://...`. Match the version
+/// too, in case a package is locked more than once, eg when renamed.
+pub(super) fn find_dep<'a>(lockpacks: &'a [LockPackage], dep: &str) -> Option<&'a LockPackage> {
+    let mut parts = dep.split_whitespace();
+    let name = parts.next()?;
+    let version = parts.next();
+
+    let mut matches = lockpacks
+        .iter()
+        .filter(|lp| util::compare_names(&lp.name, name));
+    let first = matches.clone().next();
+    matches
+        .find(|lp| Some(lp.version.as_str()) == version)
+        .or(first)
+}
+
+/// Render the tree below each root, like `cargo tree`. Packages already shown are marked with
+/// `(*)`, instead of repeating their sub-dependencies.
+fn tree_lines(lockpacks: &[LockPackage], roots: &[&LockPackage]) -> Vec<String> {
+    let mut lines = vec![];
+    let mut shown = vec![];
+    for (i, root) in roots.iter().enumerate() {
+        if i > 0 {
+            lines.push(String::new());
+        }
+        lines.push(label(root));
+        shown.push(label(root));
+        add_children(lockpacks, root, "", &mut shown, &mut lines);
+    }
+    lines
+}
+
+fn add_children(
+    lockpacks: &[LockPackage],
+    parent: &LockPackage,
+    prefix: &str,
+    shown: &mut Vec<String>,
+    lines: &mut Vec<String>,
+) {
+    let deps: Vec<&String> = parent.dependencies.iter().flatten().collect();
+    for (i, dep) in deps.iter().enumerate() {
+        let last = i == deps.len() - 1;
+        let branch = if last { "└── " } else { "├── " };
+
+        let child = match find_dep(lockpacks, dep) {
+            Some(c) => c,
+            None => {
+                let text: Vec<&str> = dep.split_whitespace().take(2).collect();
+                lines.push(format!(
+                    "{}{}{} (not in the lock file)",
+                    prefix,
+                    branch,
+                    text.join(" ")
+                ));
+                continue;
+            }
+        };
+
+        if shown.contains(&label(child)) {
+            lines.push(format!("{}{}{} (*)", prefix, branch, label(child)));
+            continue;
+        }
+        lines.push(format!("{}{}{}", prefix, branch, label(child)));
+        shown.push(label(child));
+
+        let child_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+        add_children(lockpacks, child, &child_prefix, shown, lines);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lock_pack;
+
+    #[test]
+    fn shared_deps_marked() {
+        let lockpacks = vec![
+            lock_pack(
+                "requests",
+                "2.25.1",
+                &["idna 2.10 pypi+x", "urllib3 1.26.3 pypi+x"],
+            ),
+            lock_pack(
+                "httpx",
+                "0.16.1",
+                &["idna 2.10 pypi+x", "sniffio 1.2.0 pypi+x"],
+            ),
+            lock_pack("sniffio", "1.2.0", &["idna 2.10 pypi+x"]),
+            lock_pack("idna", "2.10", &[]),
+            lock_pack("urllib3", "1.26.3", &[]),
+        ];
+
+        let roots = find_roots(&lockpacks, &[]);
+        assert_eq!(
+            tree_lines(&lockpacks, &roots),
+            vec![
+                "httpx 0.16.1",
+                "├── idna 2.10",
+                "└── sniffio 1.2.0",
+                "    └── idna 2.10 (*)",
+                "",
+                "requests 2.25.1",
+                "├── idna 2.10 (*)",
+                "└── urllib3 1.26.3",
+            ]
+        );
+
+        let sniffio = &lockpacks[2];
+        assert_eq!(
+            tree_lines(&lockpacks, &[sniffio]),
+            vec!["sniffio 1.2.0", "└── idna 2.10"]
+        );
+    }
+}
@@ -0,0 +1,187 @@
+use std::path::Path;
+
+use termcolor::Color;
+
+use crate::{
+    dep_types::{Lock, LockPackage, Version},
+    util::{
+        self,
+        deps::{plan, print_plan, registry_only, sync},
+        process_reqs, Os, Paths,
+    },
+    CliConfig, Config,
+};
+
+/// Re-resolve the named packages, or all of them if none are named, to the newest versions
+/// `pyproject.toml` allows. Other packages stay at their locked versions, unless the updated
+/// ones need them changed.
+#[allow(clippy::too_many_arguments)]
+pub fn update(
+    cfg: &Config,
+    git_path: &Path,
+    paths: &Paths,
+    lockpacks: &[LockPackage],
+    packages: &[String],
+    os: Os,
+    py_vers: &Version,
+    lock_path: &Path,
+) {
+    if lockpacks.is_empty() {
+        util::abort("There's no lock file to update; run `pyflow install` first");
+    }
+    util::deps::check_locked(lockpacks, packages, "update");
+
+    let upgrade: Vec<String> = if packages.is_empty() {
+        lockpacks.iter().map(|lp| lp.name.clone()).collect()
+    } else {
+        packages.to_vec()
+    };
+
+    let dont_uninstall = util::find_dont_uninstall(&cfg.reqs, &cfg.dev_reqs);
+
+    // Show what would change, without building git or path requirements, which installs them.
+    if CliConfig::current().dry_run {
+        let ops = plan(
+            paths,
+            lockpacks,
+            &registry_only(&cfg.reqs),
+            &registry_only(&cfg.dev_reqs),
+            &cfg.overrides,
+            &dont_uninstall,
+            &upgrade,
+            false,
+            os,
+            py_vers,
+        );
+        return print_plan(&ops, CliConfig::current().json);
+    }
+
+    // Build git dependencies from the newest commit on their branch, unless they're held back.
+    let held: Vec<LockPackage> = lockpacks
+        .iter()
+        .filter(|lp| !upgrade.iter().any(|u| util::compare_names(u, &lp.name)))
+        .cloned()
+        .collect();
+    let reqs = process_reqs(cfg.reqs.clone(), git_path, paths, &held, true);
+    let dev_reqs = process_reqs(cfg.dev_reqs.clone(), git_path, paths, &held, true);
+
+    sync(
+        paths,
+        lockpacks,
+        &reqs,
+        &dev_reqs,
+        &cfg.overrides,
+        &dont_uninstall,
+        &upgrade,
+        false,
+        os,
+        py_vers,
+        lock_path,
+        &cfg.hashes,
+        cfg.post_resolve_hook.as_deref(),
+    );
+
+    let updated = util::read_lock(lock_path)
+        .ok()
+        .map(Lock::packages)
+        .unwrap_or_default();
+    let changes = version_changes(lockpacks, &updated);
+    if changes.is_empty() {
+        util::print_color("Everything is already up to date", Color::Green);
+    } else {
+        for (name, from, to) in &changes {
+            println!("{} {} → {}", name, from, to);
+        }
+        util::print_color("Update complete", Color::Green);
+    }
+}
+
+/// Find the packages whose locked version changed. Returns name, old version, new version.
+fn version_changes(old: &[LockPackage], new: &[LockPackage]) -> Vec<(String, String, String)> {
+    new.iter()
+        .filter_map(|lp| {
+            let prev = old
+                .iter()
+                .find(|o| util::compare_names(&o.name, &lp.name))?;
+            if prev.version == lp.version {
+                None
+            } else {
+                Some((lp.name.clone(), prev.version.clone(), lp.version.clone()))
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{dep_types::Req, tests::lock_pack};
+
+    #[test]
+    fn changes_listed() {
+        let old = vec![
+            lock_pack("requests", "2.24.0", &[]),
+            lock_pack("idna", "2.10", &[]),
+        ];
+        let new = vec![
+            lock_pack("requests", "2.25.1", &[]),
+            lock_pack("idna", "2.10", &[]),
+            lock_pack("chardet", "4.0.0", &[]),
+        ];
+
+        assert_eq!(
+            version_changes(&old, &new),
+            vec![("requests".into(), "2.24.0".into(), "2.25.1".into())]
+        );
+    }
+
+    #[test]
+    fn dry_run_builds_nothing() {
+        let (dir, _tmp) = crate::tests::child_test_dir();
+        let paths = Paths {
+            bin: dir.join("bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        };
+        let name = "actions::update::tests::dry_run_builds_nothing";
+        fs::create_dir_all(&paths.lib).unwrap();
+        let output = match crate::tests::in_child_process(name, &dir) {
+            Some(output) => output,
+            None => {
+                CliConfig {
+                    dry_run: true,
+                    offline: true,
+                    ..Default::default()
+                }
+                .make_current();
+                // A path requirement would be built, and added to the lock, by a real update.
+                let mut local = Req::new("mypkg".into(), vec![]);
+                local.path = Some(dir.join("mypkg").to_str().unwrap().to_owned());
+                let cfg = Config {
+                    reqs: vec![Req::from_str("six>=1.16", true).unwrap(), local],
+                    ..Default::default()
+                };
+                return update(
+                    &cfg,
+                    &dir.join("git"),
+                    &paths,
+                    &[lock_pack("six", "1.16.0", &[])],
+                    &[],
+                    Os::Linux,
+                    &Version::new(3, 9, 0),
+                    &dir.join("pyflow.lock"),
+                );
+            }
+        };
+        let installed = fs::read_dir(&paths.lib).unwrap().count();
+        let touched = dir.join("pyflow.lock").exists() || dir.join("git").exists();
+
+        assert!(output.status.success());
+        assert!(String::from_utf8_lossy(&output.stdout).contains("Install six 1.16.0"));
+        assert_eq!(installed, 0);
+        assert!(!touched);
+    }
+}
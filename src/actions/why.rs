@@ -0,0 +1,114 @@
+use crate::{
+    dep_types::{LockPackage, Req},
+    util,
+};
+
+use super::tree::{find_dep, find_roots, label};
+
+/// Explain why `package` is installed, by printing each chain of locked dependencies that leads
+/// to it from a requirement in `pyproject.toml`.
+pub fn why(lockpacks: &[LockPackage], reqs: &[Req], package: &str) {
+    if lockpacks.is_empty() {
+        util::abort("There's no lock file to check; run `pyflow install` first");
+    }
+    let target = match lockpacks
+        .iter()
+        .find(|lp| util::compare_names(&lp.name, package))
+    {
+        Some(lp) => lp,
+        None => util::abort(&format!("{} isn't in the lock file", package)),
+    };
+
+    let paths = find_paths(lockpacks, &find_roots(lockpacks, reqs), target);
+    if paths.is_empty() {
+        util::abort(&format!(
+            "Nothing in `pyproject.toml` depends on {}",
+            target.name
+        ));
+    }
+    for path in paths {
+        if path.len() == 1 {
+            println!("{} is required by `pyproject.toml`", label(path[0]));
+        } else {
+            let labels: Vec<String> = path.iter().map(|lp| label(lp)).collect();
+            println!("{}", labels.join(" -> "));
+        }
+    }
+}
+
+/// Find every chain of dependencies from one of `roots` to `target`.
+fn find_paths<'a>(
+    lockpacks: &'a [LockPackage],
+    roots: &[&'a LockPackage],
+    target: &LockPackage,
+) -> Vec<Vec<&'a LockPackage>> {
+    let mut result = vec![];
+    for root in roots {
+        walk(lockpacks, &mut vec![root], target, &mut result);
+    }
+    result
+}
+
+fn walk<'a>(
+    lockpacks: &'a [LockPackage],
+    path: &mut Vec<&'a LockPackage>,
+    target: &LockPackage,
+    result: &mut Vec<Vec<&'a LockPackage>>,
+) {
+    let current = path[path.len() - 1];
+    if label(current) == label(target) {
+        result.push(path.clone());
+        return;
+    }
+
+    for dep in current.dependencies.iter().flatten() {
+        if let Some(child) = find_dep(lockpacks, dep) {
+            // Don't loop on circular dependencies.
+            if path.iter().any(|lp| label(lp) == label(child)) {
+                continue;
+            }
+            path.push(child);
+            walk(lockpacks, path, target, result);
+            path.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lock_pack;
+
+    #[test]
+    fn paths_found() {
+        let lockpacks = vec![
+            lock_pack(
+                "requests",
+                "2.25.1",
+                &["urllib3 1.26.3 pypi+x", "certifi 2020.12.5 pypi+x"],
+            ),
+            lock_pack("urllib3", "1.26.3", &["certifi 2020.12.5 pypi+x"]),
+            lock_pack("certifi", "2020.12.5", &[]),
+            lock_pack("black", "20.8b1", &[]),
+        ];
+        let reqs = vec![
+            Req::from_str("requests", false).unwrap(),
+            Req::from_str("black", false).unwrap(),
+        ];
+        let roots = find_roots(&lockpacks, &reqs);
+
+        let paths: Vec<Vec<String>> = find_paths(&lockpacks, &roots, &lockpacks[2])
+            .iter()
+            .map(|p| p.iter().map(|lp| lp.name.clone()).collect())
+            .collect();
+        assert_eq!(
+            paths,
+            vec![
+                vec!["requests", "urllib3", "certifi"],
+                vec!["requests", "certifi"],
+            ]
+        );
+
+        assert!(find_paths(&lockpacks, &[&lockpacks[3]], &lockpacks[2]).is_empty());
+    }
+}
@@ -1,7 +1,19 @@
-use crate::{dep_types::Req, util};
+use crate::{
+    commands,
+    dep_types::{Constraint, Req, Version},
+    files::{self, BuildSystem},
+    util,
+};
+use flate2::read::GzDecoder;
 use regex::Regex;
-use std::{collections::HashMap, path::Path};
-use std::{env, fs, process::Command};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use std::{env, fs, process};
 use termcolor::Color;
 
 // https://packaging.python.org/tutorials/packaging-projects/
@@ -53,6 +65,23 @@ fn _serialize_py_dict(hm: &HashMap<String, Vec<String>>) -> String {
 //    result
 //}
 
+/// Split an `authors` entry, eg `Fraa Erasmas <raz@edhar.math>`, into its name and email.
+fn parse_author(author: &str) -> (String, String) {
+    let author_re = Regex::new(r"^(.*?)\s*(?:<(.*?)>)?\s*$").unwrap();
+    let caps = author_re.captures(author).unwrap_or_else(|| {
+        util::abort(&format!(
+            "Problem parsing the `authors` field in `pyproject.toml`: {:?}",
+            author
+        ))
+    });
+    (
+        caps.get(1).unwrap().as_str().to_owned(),
+        caps.get(2)
+            .map(|m| m.as_str().to_owned())
+            .unwrap_or_default(),
+    )
+}
+
 fn cfg_to_setup(cfg: &crate::Config) -> String {
     let cfg = cfg.clone();
 
@@ -69,25 +98,20 @@ fn cfg_to_setup(cfg: &crate::Config) -> String {
         keywords.push_str(kw);
     }
 
-    let author_re = Regex::new(r"^(.*?)\s*(?:<(.*?)>)?\s*$").unwrap();
-
-    let mut author = "".to_string();
-    let mut author_email = "".to_string();
-    if let Some(first) = cfg.authors.get(0) {
-        let caps = if let Some(c) = author_re.captures(first) {
-            c
-        } else {
-            util::abort(&format!(
-                "Problem parsing the `authors` field in `pyproject.toml`: {:?}",
-                &cfg.authors
-            ));
-        };
-        author = caps.get(1).unwrap().as_str().to_owned();
-        author_email = caps.get(2).unwrap().as_str().to_owned();
-    }
+    let (author, author_email) = match cfg.authors.first() {
+        Some(first) => parse_author(first),
+        None => ("".to_string(), "".to_string()),
+    };
 
     let deps: Vec<String> = cfg.reqs.iter().map(Req::to_setup_py_string).collect();
 
+    // `repo_url` is an older name for `repository`.
+    let repository = cfg.repository.or(cfg.repo_url);
+    let project_urls = match &repository {
+        Some(r) => format!("\n    project_urls={{\"Source\": \"{}\"}},", r),
+        None => "".into(),
+    };
+
     // todo: Entry pts!
     format!(
         r#"import setuptools
@@ -104,7 +128,7 @@ setuptools.setup(
     description="{}",
     long_description=long_description,
     long_description_content_type="text/markdown",
-    url="{}",
+    url="{}",{}
     packages=setuptools.find_packages(),
     keywords="{}",
     classifiers={},
@@ -122,7 +146,8 @@ setuptools.setup(
         author_email,
         cfg.license.unwrap_or_else(|| "".into()),
         cfg.description.unwrap_or_else(|| "".into()),
-        cfg.homepage.unwrap_or_else(|| "".into()),
+        cfg.homepage.or(repository).unwrap_or_else(|| "".into()),
+        project_urls,
         keywords,
         serialize_py_list(&cfg.classifiers, 1),
         //        serialize_py_list(&cfg.console_scripts),
@@ -138,23 +163,86 @@ setuptools.setup(
 }
 
 /// Creates a temporary file which imitates setup.py
-fn create_dummy_setup(cfg: &crate::Config, filename: &str) {
-    fs::write(filename, cfg_to_setup(cfg)).expect("Problem writing dummy setup.py");
-    if util::wait_for_dirs(&[env::current_dir()
-        .expect("Problem finding current dir")
-        .join(filename)])
-    .is_err()
-    {
+fn create_dummy_setup(cfg: &crate::Config, path: &Path) {
+    fs::write(path, cfg_to_setup(cfg)).expect("Problem writing dummy setup.py");
+    if util::wait_for_dirs(&[path.to_owned()]).is_err() {
         util::abort("Problem waiting for setup.py to be created.")
     };
 }
 
+/// Top-level classifier categories. See https://pypi.org/classifiers/
+const CLASSIFIER_CATEGORIES: [&str; 11] = [
+    "Development Status",
+    "Environment",
+    "Framework",
+    "Intended Audience",
+    "License",
+    "Natural Language",
+    "Operating System",
+    "Private",
+    "Programming Language",
+    "Topic",
+    "Typing",
+];
+
+/// Find problems with the package metadata that would make it unpublishable.
+pub fn check_metadata(cfg: &crate::Config) -> Vec<String> {
+    let mut problems = vec![];
+
+    // https://packaging.python.org/specifications/core-metadata/#name
+    let name_re = Regex::new(r"(?i)^([A-Z0-9]|[A-Z0-9][A-Z0-9._-]*[A-Z0-9])$").unwrap();
+    match cfg.name.as_deref() {
+        None | Some("") => problems.push("`name` is missing".to_string()),
+        Some(name) if !name_re.is_match(name) => {
+            problems.push(format!("`name` isn't a valid package name: {}", name))
+        }
+        Some(_) => (),
+    }
+
+    if cfg.version.is_none() {
+        problems.push("`version` is missing".to_string());
+    }
+
+    if cfg.description.is_none() && cfg.readme.is_none() {
+        problems.push("Neither `description` nor `readme` is specified".to_string());
+    }
+
+    for classifier in &cfg.classifiers {
+        let parts: Vec<&str> = classifier.split(" :: ").collect();
+        if parts.len() < 2
+            || parts.iter().any(|p| p.trim().is_empty())
+            || !CLASSIFIER_CATEGORIES.contains(&parts[0])
+        {
+            problems.push(format!("Invalid classifier: {}", classifier));
+        }
+    }
+
+    if let Some(requires) = &cfg.python_requires {
+        if Constraint::from_str_multiple(requires).is_err() {
+            problems.push(format!("Invalid `python_requires`: {}", requires));
+        }
+    }
+
+    problems
+}
+
 pub fn build(
     lockpacks: &[crate::dep_types::LockPackage],
     paths: &util::Paths,
     cfg: &crate::Config,
     _extras: &[String],
+    check: bool,
 ) {
+    if check {
+        let problems = check_metadata(cfg);
+        if !problems.is_empty() {
+            util::abort(&format!(
+                "Problems with the package metadata in `pyproject.toml`:\n- {}",
+                problems.join("\n- ")
+            ));
+        }
+    }
+
     for lp in lockpacks.iter() {
         if lp.rename.is_some() {
             //    if lockpacks.iter().any(|lp| lp.rename.is_some()) {
@@ -167,73 +255,720 @@ pub fn build(
         }
     }
 
-    let dummy_setup_fname = "setup_temp_pyflow.py";
-
-    // Twine has too many dependencies to install when the environment, like we do with `wheel`, and
-    // for now, it's easier to install using pip
-    // todo: Install using own tools instead of pip; this is the last dependence on pip.
-    let output = Command::new(paths.bin.join("python"))
-        .args(&["-m", "pip", "install", "twine"])
-        .output()
-        .expect("Problem installing Twine");
-    util::check_command_output(&output, "failed to install twine");
-
-    //    let twine_url = "https://files.pythonhosted.org/packages/c4/43/b9c56d378f5d0b9bee7be564b5c5fb65c65e5da6e82a97b6f50c2769249a/twine-2.0.0-py3-none-any.whl";
-    //    install::download_and_install_package(
-    //        "twine",
-    //        &Version::new(2, 0, 0),
-    //        twine_url,
-    //        "twine-2.0.0-py3-none-any.whl",
-    //        "5319dd3e02ac73fcddcd94f0…1f4699d57365199d85261e1",
-    //        &paths,
-    //        install::PackageType::Wheel,
-    //        &None,
-    //    )
-    //    .expect("Problem installing `twine`");
-
-    create_dummy_setup(cfg, dummy_setup_fname);
+    if cfg.license.is_none() {
+        util::print_color(
+            "Warning: No `license` is specified in `pyproject.toml`; the package won't list one.",
+            Color::Yellow,
+        );
+    }
 
     util::set_pythonpath(&[paths.lib.to_owned()]);
-    println!("🛠️️ Building the package...");
+    log::info!("🛠️️ Building the package...");
     // todo: Run build script first, right?
     if let Some(build_file) = &cfg.build {
-        let output = Command::new(paths.bin.join("python"))
+        let output = commands::python_command(paths.bin.join("python"))
             .arg(&build_file)
             .output()
             .unwrap_or_else(|_| panic!("Problem building using {}", build_file));
         util::check_command_output(&output, "failed to run build script");
     }
 
-    //    Command::new(paths.bin.join("python"))
-    //        .args(&[dummy_setup_fname, "sdist", "bdist_wheel"])
-    //        .status()
-    //        .expect("Problem building");
+    let project_path = env::current_dir().expect("Problem finding current dir");
+    let build_system = cfg.build_system.clone().unwrap_or_else(legacy_build_system);
+    let build_path = env::temp_dir().join(format!("pyflow-build-{}", process::id()));
+
+    // Setuptools needs a `setup.py`; if the project doesn't have one, build a copy of the project
+    // with one made from the metadata in `pyproject.toml`, so we don't leave it behind.
+    let source_path = if backend(&build_system).starts_with("setuptools")
+        && !project_path.join("setup.py").exists()
+    {
+        let copy_path = build_path.join("src");
+        copy_project(&project_path, &copy_path);
+        create_dummy_setup(cfg, &copy_path.join("setup.py"));
+        copy_path
+    } else {
+        project_path.clone()
+    };
+
+    let env_py = create_build_env(
+        &build_path.join("env"),
+        &paths.bin.join("python"),
+        &build_system,
+    );
+
+    let dist_path = project_path.join("dist");
+    fs::create_dir_all(&dist_path).expect("Problem creating the dist folder");
+    for kind in &["sdist", "wheel"] {
+        let filename = build_dist(&env_py, &source_path, &build_system, kind, &dist_path);
+        println!("Built the {}: dist/{}", kind, filename);
+    }
+
+    if fs::remove_dir_all(&build_path).is_err() {
+        log::warn!("Problem removing the build environment at {:?}", build_path)
+    }
 
     util::print_color("Build complete.", Color::Green);
+}
+
+/// Build a wheel from a source tree, eg a dependency's unpacked sdist, with the backend its
+/// `pyproject.toml` declares, in an isolated environment. Returns the wheel's path.
+pub fn build_wheel(source_path: &Path, py_path: &Path, out_path: &Path) -> PathBuf {
+    let build_system = read_build_system(source_path).unwrap_or_else(legacy_build_system);
+    let source_name = source_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    let env_path = env::temp_dir().join(format!("pyflow-build-{}-{}", process::id(), source_name));
+    let env_py = create_build_env(&env_path, py_path, &build_system);
+
+    fs::create_dir_all(out_path).expect("Problem creating the folder to build a wheel in");
+    let filename = build_dist(&env_py, source_path, &build_system, "wheel", out_path);
+
+    if fs::remove_dir_all(&env_path).is_err() {
+        log::warn!("Problem removing the build environment at {:?}", env_path)
+    }
+    out_path.join(filename)
+}
+
+/// Build an sdist or wheel, as `kind` says, into `out_path`, and return its filename.
+fn build_dist(
+    env_py: &Path,
+    source_path: &Path,
+    build_system: &BuildSystem,
+    kind: &str,
+    out_path: &Path,
+) -> String {
+    let requires: Vec<String> = serde_json::from_value(run_hook(
+        env_py,
+        source_path,
+        build_system,
+        &format!("get_requires_for_build_{}", kind),
+        None,
+    ))
+    .unwrap_or_default();
+    install_build_reqs(env_py, &requires);
+
+    let built = run_hook(
+        env_py,
+        source_path,
+        build_system,
+        &format!("build_{}", kind),
+        Some(out_path),
+    );
+    match built.as_str() {
+        Some(filename) => filename.to_owned(),
+        None => util::abort(&format!("The build backend didn't build a {}", kind)),
+    }
+}
+
+/// Read a source tree's `[build-system]` from its `pyproject.toml`, if it has one.
+fn read_build_system(source_path: &Path) -> Option<BuildSystem> {
+    #[derive(Deserialize)]
+    struct Pyproject {
+        #[serde(rename = "build-system")]
+        build_system: Option<BuildSystem>,
+    }
 
-    if fs::remove_file(dummy_setup_fname).is_err() {
-        println!("Problem removing temporary setup file while building ")
+    let data = fs::read_to_string(source_path.join("pyproject.toml")).ok()?;
+    toml::from_str::<Pyproject>(&data).ok()?.build_system
+}
+
+/// Copy the project's files to build them, leaving out the environment, and what we build.
+fn copy_project(project_path: &Path, copy_path: &Path) {
+    let skip = ["__pypackages__", "dist", ".git", ".venv"];
+    let items: Vec<PathBuf> = fs::read_dir(project_path)
+        .expect("Problem reading the project folder")
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            !path
+                .file_name()
+                .is_some_and(|name| skip.iter().any(|s| name == *s))
+        })
+        .collect();
+
+    if copy_path.exists() {
+        fs::remove_dir_all(copy_path).expect("Problem removing an old copy of the project");
+    }
+    fs::create_dir_all(copy_path).expect("Problem creating a folder to build in");
+    fs_extra::copy_items(&items, copy_path, &fs_extra::dir::CopyOptions::new())
+        .expect("Problem copying the project to build it");
+}
+
+/// The build system [PEP 517](https://www.python.org/dev/peps/pep-0517/#source-trees) says to
+/// use for projects without a `[build-system]` table.
+fn legacy_build_system() -> BuildSystem {
+    BuildSystem {
+        requires: vec!["setuptools>=40.8.0".into(), "wheel".into()],
+        build_backend: Some("setuptools.build_meta:__legacy__".into()),
+        backend_path: None,
+    }
+}
+
+/// The backend to build with. PEP 517 falls back to `setuptools` if only `requires` is set.
+fn backend(build_system: &BuildSystem) -> &str {
+    build_system
+        .build_backend
+        .as_deref()
+        .unwrap_or("setuptools.build_meta:__legacy__")
+}
+
+/// Create a virtual environment at `env_path`, isolated from the project's dependencies, with
+/// the build system's requirements installed. Returns its Python's path.
+fn create_build_env(env_path: &Path, py_path: &Path, build_system: &BuildSystem) -> PathBuf {
+    if env_path.exists() {
+        fs::remove_dir_all(env_path).expect("Problem removing old build environment");
+    }
+    let output = commands::python_command(py_path)
+        .args(["-m", "venv"])
+        .arg(env_path)
+        .env_remove("PYTHONPATH")
+        .output()
+        .expect("Problem creating the build environment");
+    util::check_command_output_with(&output, |e| {
+        util::abort(&format!("Problem creating the build environment: {}", e))
+    });
+
+    #[cfg(target_os = "windows")]
+    let env_py = env_path.join("Scripts/python");
+    #[cfg(not(target_os = "windows"))]
+    let env_py = env_path.join("bin/python");
+
+    install_build_reqs(&env_py, &build_system.requires);
+    env_py
+}
+
+fn install_build_reqs(env_py: &Path, requires: &[String]) {
+    if requires.is_empty() {
+        return;
+    }
+    let output = commands::python_command(env_py)
+        .args(["-m", "pip", "install", "--quiet"])
+        .args(requires)
+        .env_remove("PYTHONPATH")
+        .output()
+        .expect("Problem installing build requirements");
+    util::check_command_output_with(&output, |e| {
+        util::abort(&format!(
+            "Problem installing the build requirements {}: {}",
+            requires.join(", "),
+            e
+        ))
+    });
+}
+
+/// A Python script that calls `hook` on the build backend, eg `build_wheel`, and prints its
+/// result as JSON on the last line. Hooks that build are passed `out_path`. If the backend
+/// doesn't have the hook, eg the optional `get_requires_for_build_wheel`, it prints `[]`.
+fn hook_script(build_system: &BuildSystem, hook: &str, out_path: Option<&Path>) -> String {
+    // JSON strings and lists are valid Python literals.
+    let to_py = |v: &serde_json::Value| v.to_string();
+    let backend_path = build_system.backend_path.clone().unwrap_or_default();
+    let args = match out_path {
+        Some(p) => to_py(&p.to_string_lossy().into()),
+        None => String::new(),
+    };
+
+    format!(
+        r#"import importlib, json, os, sys
+sys.path[:0] = [os.path.abspath(p) for p in {}]
+module, _, obj = {}.partition(":")
+backend = importlib.import_module(module)
+for attr in filter(None, obj.split(".")):
+    backend = getattr(backend, attr)
+hook = getattr(backend, {}, None)
+result = hook({}) if hook else []
+print()
+print(json.dumps(result))
+"#,
+        to_py(&backend_path.into()),
+        to_py(&backend(build_system).into()),
+        to_py(&hook.into()),
+        args
+    )
+}
+
+/// Run a build backend hook in the build environment, and return its result.
+fn run_hook(
+    env_py: &Path,
+    project_path: &Path,
+    build_system: &BuildSystem,
+    hook: &str,
+    out_path: Option<&Path>,
+) -> serde_json::Value {
+    let output = commands::python_command(env_py)
+        .args(["-c", &hook_script(build_system, hook, out_path)])
+        .current_dir(project_path)
+        .env_remove("PYTHONPATH")
+        .output()
+        .unwrap_or_else(|_| panic!("Problem running the build backend's {}", hook));
+    util::check_command_output_with(&output, |e| {
+        util::abort(&format!(
+            "Problem running the build backend's {}: {}",
+            hook, e
+        ))
+    });
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let result = stdout.lines().last().unwrap_or_default();
+    serde_json::from_str(result).unwrap_or_else(|_| {
+        util::abort(&format!(
+            "Problem reading the result of the build backend's {}: {}",
+            hook, result
+        ))
+    })
+}
+
+const PYPI_URL: &str = "https://upload.pypi.org/legacy/";
+const TEST_PYPI_URL: &str = "https://test.pypi.org/legacy/";
+
+/// Where to upload, and who as.
+#[derive(Debug, PartialEq)]
+struct Repository {
+    url: String,
+    username: String,
+    password: String,
+}
+
+/// Choose the repository to upload to: the `--repository` section of `~/.pypirc`, or
+/// `package_url`, or Test PyPI. The token comes from `PYFLOW_PYPI_TOKEN`, or the `password` in the
+/// repository's `.pypirc` section.
+fn find_repository(
+    name: Option<&str>,
+    package_url: Option<&str>,
+    pypirc: &[(String, String, String)],
+    env_token: Option<String>,
+) -> Result<Repository, String> {
+    let get = |section: &str, key: &str| {
+        pypirc
+            .iter()
+            .find(|(s, k, v)| s == section && k == key && !v.is_empty())
+            .map(|(_, _, v)| v.clone())
+    };
+    let with_slash = |url: &str| {
+        if url.ends_with('/') {
+            url.to_owned()
+        } else {
+            format!("{}/", url)
+        }
+    };
+
+    let (section, url) = match name {
+        Some(name) => {
+            let url = match (get(name, "repository"), name) {
+                (Some(url), _) => url,
+                (None, "pypi") => PYPI_URL.to_owned(),
+                (None, "testpypi") => TEST_PYPI_URL.to_owned(),
+                (None, _) => {
+                    return Err(format!(
+                        "There's no `repository` for `{}` in `~/.pypirc`",
+                        name
+                    ))
+                }
+            };
+            (name.to_owned(), with_slash(&url))
+        }
+        None => {
+            let url = with_slash(package_url.unwrap_or(TEST_PYPI_URL));
+            // Find the `.pypirc` section with this URL, to take its credentials from.
+            let configured = pypirc
+                .iter()
+                .find(|(_, k, v)| k == "repository" && with_slash(v) == url)
+                .map(|(s, _, _)| s.clone());
+            let section = match configured {
+                Some(section) => section,
+                None if url == PYPI_URL => "pypi".to_owned(),
+                None if url == TEST_PYPI_URL => "testpypi".to_owned(),
+                None => String::new(),
+            };
+            (section, url)
+        }
     };
+
+    if let Some(token) = env_token.filter(|t| !t.is_empty()) {
+        return Ok(Repository {
+            url,
+            username: "__token__".into(),
+            password: token,
+        });
+    }
+    match get(&section, "password") {
+        Some(password) => Ok(Repository {
+            url,
+            username: get(&section, "username").unwrap_or_else(|| "__token__".into()),
+            password,
+        }),
+        None => Err(format!(
+            "No API token to upload to {} with. Set `PYFLOW_PYPI_TOKEN`, or add a `password` \
+             to the `[{}]` section of `~/.pypirc`",
+            url,
+            if section.is_empty() { "pypi" } else { &section }
+        )),
+    }
 }
 
-pub(crate) fn publish(bin_path: &Path, cfg: &crate::Config) {
-    let repo_url = match cfg.package_url.clone() {
-        Some(pu) => {
-            let mut r = pu;
-            if !r.ends_with('/') {
-                r.push('/');
+/// The built distributions in `dist_path` for this version, with their upload `filetype` and
+/// `pyversion`, eg `("bdist_wheel", "py3")`. Older versions' files are left out.
+fn find_dists(
+    dist_path: &Path,
+    name: &str,
+    version: &Version,
+) -> Vec<(PathBuf, &'static str, String)> {
+    let mut result = vec![];
+    for entry in fs::read_dir(dist_path).into_iter().flatten().flatten() {
+        let path = entry.path();
+        let filename = entry.file_name().to_string_lossy().into_owned();
+
+        // eg `everythingkiller-0.1.0-py3-none-any.whl`, and `everythingkiller-0.1.0.tar.gz`
+        let (dist_name, dist_vers, filetype, pyversion) =
+            if let Some(stem) = filename.strip_suffix(".whl") {
+                let parts: Vec<&str> = stem.split('-').collect();
+                if parts.len() < 5 {
+                    continue;
+                }
+                let pyversion = parts[parts.len() - 3].to_owned();
+                (parts[0], parts[1], "bdist_wheel", pyversion)
+            } else if let Some(stem) = filename.strip_suffix(".tar.gz") {
+                match stem.rsplit_once('-') {
+                    Some((n, v)) => (n, v, "sdist", "source".to_owned()),
+                    None => continue,
+                }
+            } else {
+                continue;
+            };
+
+        if util::compare_names(dist_name, name)
+            && Version::from_str(dist_vers).is_ok_and(|v| &v == version)
+        {
+            result.push((path, filetype, pyversion));
+        }
+    }
+    result.sort();
+    result
+}
+
+/// The core metadata PyPI's upload API expects alongside each file.
+/// https://packaging.python.org/specifications/core-metadata/
+fn upload_metadata(cfg: &crate::Config) -> Vec<(&'static str, String)> {
+    let mut fields = vec![
+        ("metadata_version", "2.1".to_owned()),
+        ("name", cfg.name.clone().unwrap_or_default()),
+        (
+            "version",
+            cfg.version
+                .as_ref()
+                .map(Version::to_string)
+                .unwrap_or_default(),
+        ),
+    ];
+    let mut optional = |key, value: Option<String>| {
+        if let Some(v) = value.filter(|v| !v.is_empty()) {
+            fields.push((key, v));
+        }
+    };
+
+    let (author, author_email) = match cfg.authors.first() {
+        Some(a) => parse_author(a),
+        None => (String::new(), String::new()),
+    };
+    optional("summary", cfg.description.clone());
+    optional(
+        "home_page",
+        cfg.homepage.clone().or_else(|| cfg.repository.clone()),
+    );
+    optional("author", Some(author));
+    optional("author_email", Some(author_email));
+    optional("license", cfg.license.clone());
+    optional("keywords", Some(cfg.keywords.join(",")));
+    optional("requires_python", cfg.python_requires.clone());
+
+    let readme = cfg.readme.clone().unwrap_or_else(|| "README.md".into());
+    if let Ok(description) = fs::read_to_string(&readme) {
+        let content_type = if readme.ends_with(".rst") {
+            "text/x-rst"
+        } else if readme.ends_with(".md") {
+            "text/markdown"
+        } else {
+            "text/plain"
+        };
+        fields.push(("description", description));
+        fields.push(("description_content_type", content_type.to_owned()));
+    }
+
+    for classifier in &cfg.classifiers {
+        fields.push(("classifiers", classifier.clone()));
+    }
+    for req in &cfg.reqs {
+        fields.push(("requires_dist", req.to_setup_py_string()));
+    }
+    fields
+}
+
+/// Find problems with the readme that would stop PyPI rendering it as the project's description.
+fn check_readme(cfg: &crate::Config) -> Vec<String> {
+    let path = cfg.readme.clone().unwrap_or_else(|| "README.md".into());
+    let text = match fs::read(&path) {
+        Ok(bytes) => match String::from_utf8(bytes) {
+            Ok(t) => t,
+            Err(_) => return vec![format!("The readme, {}, isn't valid UTF-8", path)],
+        },
+        // Without a `readme` specified, the description is optional.
+        Err(_) if cfg.readme.is_none() => return vec![],
+        Err(_) => return vec![format!("Can't find the readme, {}", path)],
+    };
+
+    if text.trim().is_empty() {
+        return vec![format!("The readme, {}, is empty", path)];
+    }
+    if !path.ends_with(".rst") {
+        return vec![];
+    }
+
+    // PyPI won't render reStructuredText with warnings, of which this is the most common.
+    let mut problems = vec![];
+    let lines: Vec<&str> = text.lines().collect();
+    for (i, pair) in lines.windows(2).enumerate() {
+        let (title, underline) = (pair[0].trim_end(), pair[1].trim_end());
+        let mut chars = underline.chars();
+        let is_underline = chars.next().is_some_and(|c| {
+            c.is_ascii_punctuation() && underline.len() >= 2 && chars.all(|d| d == c)
+        });
+        if is_underline
+            && !title.trim().is_empty()
+            && !title.starts_with(char::is_whitespace)
+            && underline.len() < title.chars().count()
+        {
+            problems.push(format!(
+                "{}, line {}: the title underline is shorter than the title",
+                path,
+                i + 2
+            ));
+        }
+    }
+    problems
+}
+
+/// Read a built distribution's core metadata: a wheel's `METADATA`, or an sdist's `PKG-INFO`.
+pub(crate) fn read_dist_metadata(path: &Path, filetype: &str) -> Result<String, String> {
+    let file = fs::File::open(path).map_err(|e| e.to_string())?;
+    read_dist_metadata_from(file, filetype)
+}
+
+/// Like `read_dist_metadata`, but from an archive that's already open, or in memory.
+pub(crate) fn read_dist_metadata_from(
+    file: impl Read + Seek,
+    filetype: &str,
+) -> Result<String, String> {
+    let mut text = String::new();
+
+    if filetype == "bdist_wheel" {
+        let mut archive = zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+        let name = archive
+            .file_names()
+            .find(|n| n.ends_with(".dist-info/METADATA") && n.matches('/').count() == 1)
+            .map(str::to_owned)
+            .ok_or("it has no `.dist-info/METADATA`")?;
+        let mut metadata = archive.by_name(&name).map_err(|e| e.to_string())?;
+        metadata
+            .read_to_string(&mut text)
+            .map_err(|e| e.to_string())?;
+    } else {
+        let mut archive = tar::Archive::new(GzDecoder::new(file));
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            let entry_path = entry.path().map_err(|e| e.to_string())?.into_owned();
+            if entry_path.components().count() == 2 && entry_path.ends_with("PKG-INFO") {
+                entry.read_to_string(&mut text).map_err(|e| e.to_string())?;
+                return Ok(text);
+            }
+        }
+        return Err("it has no `PKG-INFO`".into());
+    }
+    Ok(text)
+}
+
+/// Find problems with a distribution's core metadata: malformed headers, missing required fields,
+/// or a name and version that don't match `pyproject.toml`.
+/// https://packaging.python.org/specifications/core-metadata/
+fn check_core_metadata(text: &str, name: &str, version: &Version) -> Vec<String> {
+    let mut problems = vec![];
+    let mut fields: HashMap<String, String> = HashMap::new();
+
+    // The headers end at the first blank line; the long description may follow.
+    for (i, line) in text.lines().take_while(|l| !l.is_empty()).enumerate() {
+        if line.starts_with(char::is_whitespace) {
+            if i == 0 {
+                problems.push(format!("line 1 is indented: {}", line.trim()));
+            }
+            continue;
+        }
+        match line.split_once(':') {
+            Some((key, value)) if !key.is_empty() && !key.contains(char::is_whitespace) => {
+                fields
+                    .entry(key.to_lowercase())
+                    .or_insert_with(|| value.trim().to_owned());
+            }
+            _ => problems.push(format!(
+                "line {} isn't a `Field: value` header: {}",
+                i + 1,
+                line
+            )),
+        }
+    }
+
+    for required in &["Metadata-Version", "Name", "Version"] {
+        if !fields.contains_key(&required.to_lowercase()) {
+            problems.push(format!("`{}` is missing", required));
+        }
+    }
+    if let Some(n) = fields.get("name") {
+        if !util::compare_names(n, name) {
+            problems.push(format!("`Name` is {}, instead of {}", n, name));
+        }
+    }
+    if let Some(v) = fields.get("version") {
+        if Version::from_str(v).ok().as_ref() != Some(version) {
+            problems.push(format!("`Version` is {}, instead of {}", v, version));
+        }
+    }
+    if let Some(content_type) = fields.get("description-content-type") {
+        let mime = content_type.split(';').next().unwrap_or_default().trim();
+        if !["text/plain", "text/x-rst", "text/markdown"].contains(&mime) {
+            problems.push(format!(
+                "`Description-Content-Type` {} isn't one PyPI can render",
+                content_type
+            ));
+        }
+    }
+    problems
+}
+
+/// Check the package like `twine check`: its metadata in `pyproject.toml`, its readme, and the
+/// metadata of what's in `dist` for this version. Returns the problems found.
+fn check_package(cfg: &crate::Config) -> Vec<String> {
+    let mut problems = check_metadata(cfg);
+    problems.extend(check_readme(cfg));
+
+    if let (Some(name), Some(version)) = (&cfg.name, &cfg.version) {
+        for (path, filetype, _) in find_dists(Path::new("dist"), name, version) {
+            let filename = path.file_name().unwrap().to_string_lossy().into_owned();
+            match read_dist_metadata(&path, filetype) {
+                Ok(text) => problems.extend(
+                    check_core_metadata(&text, name, version)
+                        .into_iter()
+                        .map(|p| format!("{}: {}", filename, p)),
+                ),
+                Err(e) => {
+                    problems.push(format!("{}: Problem reading its metadata: {}", filename, e))
+                }
             }
-            r
         }
-        None => "https://test.pypi.org/legacy/".to_string(),
+    }
+    problems
+}
+
+/// Check the package can be published, without uploading it; for `pyflow check`.
+pub(crate) fn check(cfg: &crate::Config) {
+    let problems = check_package(cfg);
+    if !problems.is_empty() {
+        util::abort(&format!(
+            "Problems with the package:\n- {}",
+            problems.join("\n- ")
+        ));
+    }
+
+    let built = match (&cfg.name, &cfg.version) {
+        (Some(name), Some(version)) => !find_dists(Path::new("dist"), name, version).is_empty(),
+        _ => false,
     };
+    if built {
+        util::print_color("The package passed its checks", Color::Green);
+    } else {
+        util::print_color(
+            "The package metadata passed its checks. Run `pyflow package`, then check again to \
+             check the built distributions too",
+            Color::Green,
+        );
+    }
+}
 
-    println!("Uploading to {}", repo_url);
-    let output = Command::new(bin_path.join("twine"))
-        .args(&["upload", "--repository-url", &repo_url, "dist/*"])
-        .output()
-        .expect("Problem publishing");
-    util::check_command_output(&output, "publishing");
+/// Upload the built package to PyPI, or the repository chosen with `--repository` or
+/// `package_url`, using an API token.
+pub(crate) fn publish(cfg: &crate::Config, repository: Option<&str>) {
+    let pypirc = directories::BaseDirs::new()
+        .and_then(|dirs| fs::read_to_string(dirs.home_dir().join(".pypirc")).ok())
+        .map(|data| files::parse_ini_entries(&data))
+        .unwrap_or_default();
+    let repo = find_repository(
+        repository,
+        cfg.package_url.as_deref(),
+        &pypirc,
+        env::var("PYFLOW_PYPI_TOKEN").ok(),
+    )
+    .unwrap_or_else(|e| util::abort(&e));
+
+    let (name, version) = match (&cfg.name, &cfg.version) {
+        (Some(n), Some(v)) => (n, v),
+        _ => util::abort("`name` and `version` must be specified in `pyproject.toml` to publish"),
+    };
+    let dists = find_dists(Path::new("dist"), name, version);
+    if dists.is_empty() {
+        util::abort(&format!(
+            "There's nothing in `dist` for {} {}; run `pyflow package` first",
+            name, version
+        ));
+    }
+
+    // A rejected upload, or one PyPI can't render, uses up the version number.
+    let problems = check_package(cfg);
+    if !problems.is_empty() {
+        util::abort(&format!(
+            "Not publishing, due to problems with the package:\n- {}",
+            problems.join("\n- ")
+        ));
+    }
+
+    let metadata = upload_metadata(cfg);
+    println!("Uploading to {}", repo.url);
+    for (path, filetype, pyversion) in dists {
+        let filename = path.file_name().unwrap().to_string_lossy().into_owned();
+        let data = fs::read(&path)
+            .unwrap_or_else(|_| util::abort(&format!("Problem reading {}", path.display())));
+        let digest = ring::digest::digest(&ring::digest::SHA256, &data);
+
+        let mut form = reqwest::multipart::Form::new()
+            .text(":action", "file_upload")
+            .text("protocol_version", "1")
+            .text("filetype", filetype)
+            .text("pyversion", pyversion)
+            .text(
+                "sha256_digest",
+                data_encoding::HEXLOWER.encode(digest.as_ref()),
+            );
+        for (key, value) in &metadata {
+            form = form.text(*key, value.clone());
+        }
+        form = form.part(
+            "content",
+            reqwest::multipart::Part::bytes(data).file_name(filename.clone()),
+        );
+
+        let request = util::http::client()
+            .post(&repo.url)
+            .basic_auth(&repo.username, Some(&repo.password))
+            .multipart(form);
+        let mut resp = util::http::send(request)
+            .unwrap_or_else(|e| util::abort(&format!("Problem uploading {}: {}", filename, e)));
+        if !resp.status().is_success() {
+            let body = resp.text().unwrap_or_default();
+            util::abort(&format!(
+                "Problem uploading {}: {}\n{}",
+                filename,
+                resp.status(),
+                body.trim()
+            ));
+        }
+        util::print_color(&format!("Uploaded {}", filename), Color::Green);
+    }
 }
 
 #[cfg(test)]
@@ -253,6 +988,7 @@ pub mod test {
         let cfg = crate::Config {
             name: Some("everythingkiller".into()),
             py_version: Some(Version::new_short(3, 6)),
+            py_version_range: vec![],
             version: Some(Version::new(0, 1, 0)),
             authors: vec!["Fraa Erasmas <raz@edhar.math>".into()],
             homepage: Some("https://everything.math".into()),
@@ -289,6 +1025,13 @@ pub mod test {
             extras: HashMap::new(),
             repo_url: None,
             build: None,
+            index_url: None,
+            proxy: None,
+            use_pep582: false,
+            post_resolve_hook: None,
+            hashes: HashMap::new(),
+            overrides: vec![],
+            build_system: None,
         };
 
         let expected = r#"import setuptools
@@ -306,6 +1049,7 @@ setuptools.setup(
     long_description=long_description,
     long_description_content_type="text/markdown",
     url="https://everything.math",
+    project_urls={"Source": "https://github.com/raz/everythingkiller"},
     packages=setuptools.find_packages(),
     keywords="nanotech weapons",
     classifiers=[
@@ -344,6 +1088,226 @@ setuptools.setup(
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn backend_hook_script() {
+        let build_system = BuildSystem {
+            requires: vec!["flit_core >=3.2,<4".into()],
+            build_backend: Some("flit_core.buildapi".into()),
+            backend_path: Some(vec!["build_tools".into()]),
+        };
+        let script = hook_script(&build_system, "build_wheel", Some(Path::new("/proj/dist")));
+        assert!(script.contains("sys.path[:0] = [os.path.abspath(p) for p in [\"build_tools\"]]"));
+        assert!(script.contains("module, _, obj = \"flit_core.buildapi\".partition(\":\")"));
+        assert!(script.contains("hook = getattr(backend, \"build_wheel\", None)"));
+        assert!(script.contains("result = hook(\"/proj/dist\") if hook else []"));
+
+        // Without `build-backend`, setuptools is used.
+        let script = hook_script(&legacy_build_system(), "get_requires_for_build_sdist", None);
+        assert!(script.contains("\"setuptools.build_meta:__legacy__\".partition"));
+        assert!(script.contains("result = hook() if hook else []"));
+    }
+
+    #[test]
+    fn build_system_read() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        // Other tables, eg another tool's, don't get in the way.
+        fs::write(
+            dir.join("pyproject.toml"),
+            "[tool.poetry]\nname = 1\n\n[build-system]\nrequires = [\"hatchling\"]\n\
+             build-backend = \"hatchling.build\"\n",
+        )
+        .unwrap();
+        let build_system = read_build_system(dir);
+        fs::remove_file(dir.join("pyproject.toml")).unwrap();
+        let missing = read_build_system(dir);
+
+        assert_eq!(
+            build_system,
+            Some(BuildSystem {
+                requires: vec!["hatchling".into()],
+                build_backend: Some("hatchling.build".into()),
+                backend_path: None,
+            })
+        );
+        assert_eq!(missing, None);
+    }
+
+    #[test]
+    fn project_copied() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let project = dir.join("proj");
+        fs::create_dir_all(project.join("mypkg")).unwrap();
+        fs::create_dir_all(project.join("__pypackages__/3.9/lib")).unwrap();
+        fs::create_dir_all(project.join("dist")).unwrap();
+        fs::write(project.join("mypkg/__init__.py"), "").unwrap();
+        fs::write(project.join("pyproject.toml"), "").unwrap();
+
+        let copy = dir.join("copy");
+        copy_project(&project, &copy);
+        let mut copied: Vec<String> = fs::read_dir(&copy)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        copied.sort();
+        let module_copied = copy.join("mypkg/__init__.py").exists();
+
+        assert_eq!(copied, vec!["mypkg", "pyproject.toml"]);
+        assert!(module_copied);
+    }
+
+    #[test]
+    fn metadata_checked() {
+        let cfg = crate::Config {
+            name: Some("-everythingkiller".into()),
+            classifiers: vec![
+                "Topic :: System :: Hardware".into(),
+                "Weapons :: Nanotech".into(),
+                "Topic".into(),
+            ],
+            python_requires: Some(">=three".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            check_metadata(&cfg),
+            vec![
+                "`name` isn't a valid package name: -everythingkiller",
+                "`version` is missing",
+                "Neither `description` nor `readme` is specified",
+                "Invalid classifier: Weapons :: Nanotech",
+                "Invalid classifier: Topic",
+                "Invalid `python_requires`: >=three",
+            ]
+        );
+
+        let cfg = crate::Config {
+            name: Some("everythingkiller".into()),
+            version: Some(Version::new(0, 1, 0)),
+            readme: Some("README.md".into()),
+            python_requires: Some(">=3.6".into()),
+            ..Default::default()
+        };
+        assert!(check_metadata(&cfg).is_empty());
+    }
+
+    #[test]
+    fn repository_found() {
+        let pypirc = files::parse_ini_entries(
+            "[distutils]\nindex-servers =\n    pypi\n    private\n\n\
+             [pypi]\nusername = __token__\npassword = pypi-abc#1\n\n\
+             [private]\nrepository: https://pypi.example.com/legacy\n\
+             username = me\npassword = secret\n",
+        );
+
+        assert_eq!(
+            find_repository(Some("pypi"), None, &pypirc, None),
+            Ok(Repository {
+                url: PYPI_URL.into(),
+                username: "__token__".into(),
+                password: "pypi-abc#1".into(),
+            })
+        );
+        // `package_url` picks the `.pypirc` section with that URL.
+        assert_eq!(
+            find_repository(
+                None,
+                Some("https://pypi.example.com/legacy/"),
+                &pypirc,
+                None
+            ),
+            Ok(Repository {
+                url: "https://pypi.example.com/legacy/".into(),
+                username: "me".into(),
+                password: "secret".into(),
+            })
+        );
+        // The environment's token wins.
+        assert_eq!(
+            find_repository(Some("testpypi"), None, &pypirc, Some("pypi-env".into())),
+            Ok(Repository {
+                url: TEST_PYPI_URL.into(),
+                username: "__token__".into(),
+                password: "pypi-env".into(),
+            })
+        );
+        assert!(find_repository(None, None, &pypirc, None).is_err());
+        assert!(find_repository(Some("other"), None, &[], Some("pypi-env".into())).is_err());
+    }
+
+    #[test]
+    fn package_checked() {
+        let version = Version::new(0, 1, 0);
+        let metadata = "Metadata-Version: 2.1\nName: everything-killer\nVersion: 0.1.0\n\
+                        Summary: Small, but\n  packs a punch!\n\
+                        Description-Content-Type: text/markdown\n\n# Not: a header\n";
+        assert!(check_core_metadata(metadata, "everything_killer", &version).is_empty());
+
+        let metadata = "Metadata-Version: 2.1\nName: everythingkiller\nVersion: 0.2.0\n\
+                        Description-Content-Type: text/html\nnot a header\n";
+        assert_eq!(
+            check_core_metadata(metadata, "everything_killer", &version),
+            vec![
+                "line 5 isn't a `Field: value` header: not a header",
+                "`Name` is everythingkiller, instead of everything_killer",
+                "`Version` is 0.2.0, instead of 0.1.0",
+                "`Description-Content-Type` text/html isn't one PyPI can render",
+            ]
+        );
+        assert_eq!(
+            check_core_metadata("Name: everything_killer\n", "everything_killer", &version),
+            vec!["`Metadata-Version` is missing", "`Version` is missing"]
+        );
+
+        let tmp = tempfile::tempdir().unwrap();
+        let readme = tmp.path().join("README.rst");
+        fs::write(&readme, "Everything killer\n=======\n\nUsage\n-----\n").unwrap();
+        let cfg = crate::Config {
+            readme: Some(readme.to_string_lossy().into_owned()),
+            ..Default::default()
+        };
+        let problems = check_readme(&cfg);
+        assert_eq!(
+            problems,
+            vec![format!(
+                "{}, line 2: the title underline is shorter than the title",
+                readme.display()
+            )]
+        );
+    }
+
+    #[test]
+    fn dists_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dist_path = tmp.path();
+        for f in &[
+            "everything_killer-0.1.0-py3-none-any.whl",
+            "everything-killer-0.1.0.tar.gz",
+            "everything_killer-0.0.9-py3-none-any.whl",
+            "other-0.1.0.tar.gz",
+        ] {
+            fs::write(dist_path.join(f), "").unwrap();
+        }
+
+        let dists = find_dists(dist_path, "everything-killer", &Version::new(0, 1, 0));
+        assert_eq!(
+            dists,
+            vec![
+                (
+                    dist_path.join("everything-killer-0.1.0.tar.gz"),
+                    "sdist",
+                    "source".to_owned()
+                ),
+                (
+                    dist_path.join("everything_killer-0.1.0-py3-none-any.whl"),
+                    "bdist_wheel",
+                    "py3".to_owned()
+                ),
+            ]
+        );
+    }
+
     // todo: Re-impl if you end up using this
     //    #[test]
     //    fn py_dict() {
@@ -1,9 +1,9 @@
-use crate::util;
+use crate::{util, CliConfig};
 use regex::Regex;
-use std::{error::Error, fmt};
+use std::{error::Error, ffi::OsStr, fmt, io::Write};
 use std::{
     path::{Path, PathBuf},
-    process::{Command, Stdio},
+    process::{Command, Output, Stdio},
 };
 
 #[derive(Debug)]
@@ -79,11 +79,41 @@ pub fn find_py_version(alias: &str) -> Option<crate::Version> {
     }
 }
 
+/// Build a command that runs a Python interpreter. If an architecture was requested with
+/// `--arch`, launch it under that architecture using `arch`, eg via Rosetta.
+pub fn python_command<S: AsRef<OsStr>>(py: S) -> Command {
+    python_command_for(py.as_ref(), CliConfig::current().arch)
+}
+
+fn python_command_for(py: &OsStr, arch: Option<util::Arch>) -> Command {
+    match arch {
+        Some(arch) if cfg!(target_os = "macos") => {
+            let mut cmd = Command::new("arch");
+            cmd.arg(format!("-{}", arch)).arg(py);
+            cmd
+        }
+        _ => Command::new(py),
+    }
+}
+
+/// Find the full path of the interpreter `alias` runs, eg `/usr/bin/python3.9` for `python3`.
+pub fn find_py_executable(alias: &str) -> Option<PathBuf> {
+    let output = python_command(alias)
+        .args(["-c", "import sys; print(sys.executable)"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let path = String::from_utf8(output.stdout).ok()?;
+    Some(PathBuf::from(path.trim())).filter(|p| p.is_absolute())
+}
+
 /// Create the virtual env. Assume we're running Python 3.3+, where `venv` is included.
 /// Additionally, create the __pypackages__ directory if not already created.
 pub fn create_venv(py_alias: &str, lib_path: &Path, name: &str) -> Result<(), Box<dyn Error>> {
     // While creating the lib path, we're creating the __pypackages__ structure.
-    let output = Command::new(py_alias)
+    let output = python_command(py_alias)
         .args(&["-m", "venv", name])
         .current_dir(lib_path.join("../"))
         .output()?;
@@ -95,7 +125,7 @@ pub fn create_venv(py_alias: &str, lib_path: &Path, name: &str) -> Result<(), Bo
 // todo: DRY for using a path instead of str. use impl Into<PathBuf> ?
 pub fn create_venv2(py_alias: &Path, lib_path: &Path, name: &str) -> Result<(), Box<dyn Error>> {
     // While creating the lib path, we're creating the __pypackages__ structure.
-    let output = Command::new(py_alias)
+    let output = python_command(py_alias)
         .args(&["-m", "venv", name])
         .current_dir(lib_path.join("../"))
         .output()?;
@@ -110,7 +140,7 @@ pub fn run_python(
     args: &[String],
 ) -> Result<(), Box<dyn Error>> {
     util::set_pythonpath(lib_paths);
-    Command::new(bin_path.join("python"))
+    python_command(bin_path.join("python"))
         .args(args)
         .stdin(Stdio::inherit())
         .stdout(Stdio::inherit())
@@ -119,21 +149,108 @@ pub fn run_python(
     Ok(())
 }
 
+/// Clone `repo` into `dest_path`, or if it's already there, fetch its latest commits.
 pub fn download_git_repo(repo: &str, dest_path: &Path) -> Result<(), Box<dyn Error>> {
     // todo: Download directly instead of using git clone?
-    // todo: Suppress this output.
-    if Command::new("git").arg("--version").status().is_err() {
+    if Command::new("git").arg("--version").output().is_err() {
         util::abort("Can't find Git on the PATH. Is it installed?");
     }
 
-    let output = Command::new("git")
-        .current_dir(dest_path)
-        .args(&["clone", repo])
-        .output()?;
+    let output = if dest_path.join(".git").exists() {
+        // The checkout may be of another repo, eg if the requirement's URL changed.
+        let origin = Command::new("git")
+            .current_dir(dest_path)
+            .args(["remote", "get-url", "origin"])
+            .output()?;
+        let same_repo = String::from_utf8_lossy(&origin.stdout).trim() == repo;
+        if CliConfig::current().offline {
+            if same_repo {
+                return Ok(());
+            }
+            return Err(
+                format!("The checkout of {} is out of date, but we're offline", repo).into(),
+            );
+        }
+        if !same_repo {
+            let output = Command::new("git")
+                .current_dir(dest_path)
+                .args(["remote", "set-url", "origin", repo])
+                .output()?;
+            util::check_command_output(&output, "updating the repo's URL");
+        }
+        Command::new("git")
+            .current_dir(dest_path)
+            .args(["fetch", "--quiet", "--tags", "origin"])
+            .output()?
+    } else {
+        Command::new("git")
+            .args(["clone", "--quiet", repo])
+            .arg(dest_path)
+            .output()?
+    };
     util::check_command_output(&output, "cloning repo");
     Ok(())
 }
 
+/// Check out `rev`, eg a commit hash or `origin/main`, and return the commit it points to.
+/// If `origin/<name>` isn't a branch, try `<name>` as a tag or commit.
+pub fn git_checkout(repo_path: &Path, rev: &str) -> Result<String, Box<dyn Error>> {
+    let checkout = |rev: &str| {
+        Command::new("git")
+            .current_dir(repo_path)
+            .args(["checkout", "--quiet", "--detach", rev])
+            .output()
+    };
+    let mut output = checkout(rev)?;
+    if let Some(name) = rev.strip_prefix("origin/") {
+        if !output.status.success() {
+            output = checkout(name)?;
+        }
+    }
+    if !output.status.success() {
+        return Err(format!(
+            "Can't check out `{}`: {}",
+            rev,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+
+    let output = Command::new("git")
+        .current_dir(repo_path)
+        .args(["rev-parse", "HEAD"])
+        .output()?;
+    util::check_command_output(&output, "finding the checked out commit");
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+}
+
+/// Run a user-configured command through the shell, passing `input` on its stdin.
+pub fn run_hook(hook: &str, input: &str) -> Result<Output, Box<dyn Error>> {
+    #[cfg(target_os = "windows")]
+    let mut cmd = {
+        let mut c = Command::new("cmd");
+        c.args(["/C", hook]);
+        c
+    };
+    #[cfg(not(target_os = "windows"))]
+    let mut cmd = {
+        let mut c = Command::new("sh");
+        c.args(["-c", hook]);
+        c
+    };
+
+    let mut child = cmd
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // The hook may exit without reading its input; that's not an error on our end.
+    if let Some(mut stdin) = child.stdin.take() {
+        let _ = stdin.write_all(input.as_bytes());
+    }
+    Ok(child.wait_with_output()?)
+}
+
 /// Initialize a new git repo.
 pub fn git_init(dir: &Path) -> Result<(), Box<dyn Error>> {
     let output = Command::new("git")
@@ -143,3 +260,64 @@ pub fn git_init(dir: &Path) -> Result<(), Box<dyn Error>> {
     util::check_command_output(&output, "initializing git repository");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .current_dir(dir)
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap().trim().to_owned()
+    }
+
+    #[test]
+    fn git_checkout_follows_url() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut heads = vec![];
+        for name in &["a", "b"] {
+            let repo = dir.join(name);
+            fs::create_dir_all(&repo).unwrap();
+            git(&repo, &["init", "--quiet"]);
+            git(&repo, &["commit", "--quiet", "--allow-empty", "-m", name]);
+            heads.push(git(&repo, &["rev-parse", "HEAD"]));
+        }
+        let (a, b) = (dir.join("a"), dir.join("b"));
+        let dest = dir.join("checkout");
+
+        download_git_repo(a.to_str().unwrap(), &dest).unwrap();
+        // The requirement now points at another repo.
+        download_git_repo(b.to_str().unwrap(), &dest).unwrap();
+        let origin = git(&dest, &["remote", "get-url", "origin"]);
+        let found = git(&dest, &["cat-file", "-t", &heads[1]]);
+
+        assert_eq!(origin, b.to_str().unwrap());
+        assert_eq!(found, "commit");
+    }
+
+    #[test]
+    fn python_native() {
+        let cmd = python_command_for(OsStr::new("python3"), None);
+        assert_eq!(cmd.get_program(), "python3");
+        assert_eq!(cmd.get_args().count(), 0);
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn python_under_arch() {
+        let cmd = python_command_for(OsStr::new("python3"), Some(util::Arch::X86_64));
+        assert_eq!(cmd.get_program(), "arch");
+        assert_eq!(
+            cmd.get_args().collect::<Vec<_>>(),
+            vec![OsStr::new("-x86_64"), OsStr::new("python3")]
+        );
+    }
+}
@@ -1,14 +1,17 @@
 use std::str::FromStr;
 
-use nom::bytes::complete::{tag, take, take_till};
-use nom::character::complete::{digit1, space0, space1};
-use nom::combinator::{flat_map, map, map_parser, map_res, opt, value};
-use nom::multi::separated_list;
-use nom::sequence::{delimited, preceded, separated_pair, tuple};
+use nom::bytes::complete::{tag, tag_no_case, take_till, take_while1};
+use nom::character::complete::{digit1, one_of, space0, space1};
+use nom::combinator::{all_consuming, flat_map, map, map_res, opt, value, verify};
+use nom::multi::{many0, separated_list};
+use nom::sequence::{delimited, pair, preceded, separated_pair, terminated, tuple};
 use nom::{branch::alt, character::is_alphabetic};
 use nom::{AsChar, IResult, InputTakeAtPosition};
 
-use crate::dep_types::{Constraint, Extras, Req, ReqType, Version, VersionModifier};
+use crate::dep_types::{
+    Constraint, Extras, Marker, MarkerOp, MarkerValue, MarkerVar, Req, ReqType, Version,
+    VersionModifier,
+};
 use crate::util::Os;
 
 enum ExtrasPart {
@@ -18,17 +21,28 @@ enum ExtrasPart {
 }
 
 pub fn parse_req(input: &str) -> IResult<&str, Req> {
-    // eg saturn = ">=0.3.4", as in pyproject.toml
+    // eg saturn = ">=0.3.4", as in pyproject.toml, or requests[socks] from the CLI
     map(
-        alt((
-            separated_pair(
-                parse_package_name,
-                tuple((space0, tag("="), space0)),
-                delimited(quote, parse_constraints, quote),
-            ),
-            map(parse_package_name, |x| (x, vec![])),
-        )),
-        |(name, constraints)| Req::new(name.to_string(), constraints),
+        pair(
+            alt((
+                separated_pair(
+                    pair(parse_package_name, opt(parse_install_with_extras)),
+                    tuple((space0, tag("="), space0)),
+                    delimited(quote, delimited(space0, parse_constraints, space0), quote),
+                ),
+                map(
+                    pair(parse_package_name, opt(parse_install_with_extras)),
+                    |x| (x, vec![]),
+                ),
+            )),
+            opt(parse_marker_suffix),
+        ),
+        |(((name, install_with_extras), constraints), markers)| {
+            let mut r = Req::new(name.to_string(), constraints);
+            r.install_with_extras = install_with_extras;
+            r.markers = markers;
+            r
+        },
     )(input)
 }
 
@@ -44,37 +58,163 @@ pub fn parse_req_pypi_fmt(input: &str) -> IResult<&str, Req> {
                 tuple((parse_package_name, opt(parse_install_with_extras))),
                 alt((
                     preceded(space0, delimited(tag("("), parse_constraints, tag(")"))),
-                    preceded(space1, parse_constraints),
+                    preceded(space0, parse_constraints),
+                )),
+                opt(preceded(
+                    tuple((space0, tag(";"), space0)),
+                    parse_pypi_marker,
                 )),
-                opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
             )),
             map(
                 tuple((
                     tuple((parse_package_name, opt(parse_install_with_extras))),
-                    opt(preceded(tuple((space0, tag(";"), space0)), parse_extras)),
+                    opt(preceded(
+                        tuple((space0, tag(";"), space0)),
+                        parse_pypi_marker,
+                    )),
                 )),
                 |(x, y)| (x, vec![], y),
             ),
         )),
-        |((name, install_with_extras), constraints, extras_opt)| {
-            let mut r = if let Some(extras) = extras_opt {
-                Req::new_with_extras(name.to_string(), constraints, extras)
-            } else {
-                Req::new(name.to_string(), constraints)
+        |((name, install_with_extras), constraints, marker_opt)| {
+            let mut r = match &marker_opt {
+                Some((Some(extras), _)) => {
+                    Req::new_with_extras(name.to_string(), constraints, extras.clone())
+                }
+                _ => Req::new(name.to_string(), constraints),
             };
             r.install_with_extras = install_with_extras;
+            r.markers = marker_opt.map(|(_, m)| m);
             r
         },
     )(input)
 }
 
+/// The marker of a requirement in package metadata, eg `extra == 'argon2'`. Markers simple
+/// enough to split into `Extras` are split too; the full marker's kept either way.
+fn parse_pypi_marker(input: &str) -> IResult<&str, (Option<Extras>, Marker)> {
+    let (rest, marker) = parse_marker(input)?;
+    let marker_str = &input[..input.len() - rest.len()];
+    let extras = all_consuming(parse_extras)(marker_str).ok().map(|(_, e)| e);
+    Ok((rest, (extras, marker)))
+}
+
 pub fn parse_pip_str(input: &str) -> IResult<&str, Req> {
     map(
-        tuple((parse_package_name, opt(parse_constraint))),
-        |(name, constraint)| Req::new(name.to_string(), constraint.into_iter().collect()),
+        tuple((
+            parse_package_name,
+            opt(parse_install_with_extras),
+            preceded(space0, parse_constraints),
+            opt(parse_marker_suffix),
+        )),
+        |(name, install_with_extras, constraints, markers)| {
+            let mut r = Req::new(name.to_string(), constraints);
+            r.install_with_extras = install_with_extras;
+            r.markers = markers;
+            r
+        },
+    )(input)
+}
+
+/// A PEP 508 environment marker following a requirement, eg `; sys_platform == "win32"`
+fn parse_marker_suffix(input: &str) -> IResult<&str, Marker> {
+    preceded(tuple((space0, tag(";"), space0)), parse_marker)(input)
+}
+
+/// Parse a PEP 508 environment marker. `and` binds more tightly than `or`.
+pub fn parse_marker(input: &str) -> IResult<&str, Marker> {
+    map(
+        pair(
+            parse_marker_and,
+            many0(preceded(
+                tuple((space0, tag("or"), space0)),
+                parse_marker_and,
+            )),
+        ),
+        |(first, rest)| {
+            rest.into_iter()
+                .fold(first, |acc, m| Marker::Or(Box::new(acc), Box::new(m)))
+        },
     )(input)
 }
 
+fn parse_marker_and(input: &str) -> IResult<&str, Marker> {
+    map(
+        pair(
+            parse_marker_atom,
+            many0(preceded(
+                tuple((space0, tag("and"), space0)),
+                parse_marker_atom,
+            )),
+        ),
+        |(first, rest)| {
+            rest.into_iter()
+                .fold(first, |acc, m| Marker::And(Box::new(acc), Box::new(m)))
+        },
+    )(input)
+}
+
+fn parse_marker_atom(input: &str) -> IResult<&str, Marker> {
+    alt((
+        delimited(pair(tag("("), space0), parse_marker, pair(space0, tag(")"))),
+        map(
+            tuple((
+                parse_marker_value,
+                delimited(space0, parse_marker_op, space0),
+                parse_marker_value,
+            )),
+            |(lhs, op, rhs)| Marker::Compare(lhs, op, rhs),
+        ),
+    ))(input)
+}
+
+fn parse_marker_value(input: &str) -> IResult<&str, MarkerValue> {
+    alt((
+        map(parse_marker_var, MarkerValue::Var),
+        map(
+            alt((
+                delimited(tag("\""), take_till(|c| c == '"'), tag("\"")),
+                delimited(tag("'"), take_till(|c| c == '\''), tag("'")),
+            )),
+            |s: &str| MarkerValue::Literal(s.to_owned()),
+        ),
+    ))(input)
+}
+
+fn parse_marker_var(input: &str) -> IResult<&str, MarkerVar> {
+    alt((
+        value(MarkerVar::SysPlatform, tag("sys_platform")),
+        value(MarkerVar::PythonFullVersion, tag("python_full_version")),
+        value(MarkerVar::PythonVersion, tag("python_version")),
+        value(MarkerVar::OsName, tag("os_name")),
+        value(MarkerVar::PlatformMachine, tag("platform_machine")),
+        value(MarkerVar::PlatformSystem, tag("platform_system")),
+        value(MarkerVar::Extra, tag("extra")),
+    ))(input)
+}
+
+fn parse_marker_op(input: &str) -> IResult<&str, MarkerOp> {
+    alt((
+        value(MarkerOp::NotIn, tuple((tag("not"), space1, tag("in")))),
+        value(MarkerOp::In, tag("in")),
+        map(
+            map_res(
+                alt((
+                    tag("=="),
+                    tag("!="),
+                    tag("<="),
+                    tag(">="),
+                    tag("~="),
+                    tag("<"),
+                    tag(">"),
+                )),
+                ReqType::from_str,
+            ),
+            MarkerOp::Cmp,
+        ),
+    ))(input)
+}
+
 pub fn parse_wh_py_vers(input: &str) -> IResult<&str, Vec<Constraint>> {
     alt((
         map(tag("any"), |_| {
@@ -93,12 +233,19 @@ fn parse_wh_py_ver(input: &str) -> IResult<&str, Constraint> {
         tuple((
             alt((tag("cp"), tag("py"), tag("pp"))),
             alt((tag("2"), tag("3"), tag("4"))),
-            opt(map_parser(take(1u8), digit1)),
             opt(digit1),
         )),
-        |(_, major, minor, patch): (_, &str, Option<&str>, Option<&str>)| {
+        |(_, major, rest): (_, &str, Option<&str>)| {
             let major: u32 = major.parse().unwrap();
-            let patch = patch.map(|p| p.parse().unwrap());
+            // Python 3 tags put the whole minor version after the major, eg `cp310` is 3.10.
+            // Old Python 2 tags may include a patch version, eg `pp257` is 2.5.7.
+            let (minor, patch) = match rest {
+                Some(r) if major == 2 && r.len() > 1 => {
+                    (Some(&r[..1]), Some(r[1..].parse().unwrap()))
+                }
+                Some(r) => (Some(r), None),
+                None => (None, None),
+            };
             match minor {
                 Some(mi) => Constraint::new(
                     ReqType::Exact,
@@ -120,12 +267,13 @@ fn quote(input: &str) -> IResult<&str, &str> {
     alt((tag("\""), tag("'")))(input)
 }
 
+/// The extras to install a package with, eg `[redis, auth]` in `celery[redis, auth]`
 fn parse_install_with_extras(input: &str) -> IResult<&str, Vec<String>> {
     map(
         delimited(
-            tag("["),
-            separated_list(tag(","), parse_package_name),
-            tag("]"),
+            pair(tag("["), space0),
+            separated_list(delimited(space0, tag(","), space0), parse_package_name),
+            pair(space0, tag("]")),
         ),
         |extras| extras.iter().map(|x| x.to_string()).collect(),
     )(input)
@@ -196,30 +344,78 @@ fn parse_extra_part(input: &str) -> IResult<&str, ExtrasPart> {
 }
 
 pub fn parse_constraints(input: &str) -> IResult<&str, Vec<Constraint>> {
-    separated_list(tuple((space0, tag(","), space0)), parse_constraint)(input)
+    // Constraints are normally comma-separated, but we also accept a missing comma
+    // before an explicit operator, eg `>=1.0<2.0` or `>= 1.0 < 2.0`.
+    map(
+        opt(pair(
+            parse_constraint,
+            many0(alt((
+                preceded(tuple((space0, tag(","), space0)), parse_constraint),
+                preceded(space0, parse_bounded_constraint),
+            ))),
+        )),
+        |constraints| match constraints {
+            Some((first, rest)) => {
+                let mut result = vec![first];
+                result.extend(rest);
+                result
+            }
+            None => vec![],
+        },
+    )(input)
 }
 
 pub fn parse_constraint(input: &str) -> IResult<&str, Constraint> {
     map(
         alt((
             value((Some(ReqType::Gte), Version::new(0, 0, 0)), tag("*")),
-            tuple((opt(parse_req_type), parse_version)),
+            tuple((opt(terminated(parse_req_type, space0)), parse_version)),
         )),
         |(r, v)| Constraint::new(r.unwrap_or(ReqType::Exact), v),
     )(input)
 }
 
+/// A constraint with an explicit operator; used where there's no comma to separate it from
+/// the previous one.
+fn parse_bounded_constraint(input: &str) -> IResult<&str, Constraint> {
+    map(
+        tuple((terminated(parse_req_type, space0), parse_version)),
+        |(r, v)| Constraint::new(r, v),
+    )(input)
+}
+
 pub fn parse_version(input: &str) -> IResult<&str, Version> {
-    let (remain, (major, minor, patch, extra_num)) = tuple((
+    let (remain, (epoch, major, minor, patch, extra_num)) = tuple((
+        opt(terminated(
+            map(digit1, |n: &str| n.parse().unwrap()),
+            tag("!"),
+        )),
         parse_digit_or_wildcard,
         opt(preceded(tag("."), parse_digit_or_wildcard)),
         opt(preceded(tag("."), parse_digit_or_wildcard)),
         opt(preceded(tag("."), parse_digit_or_wildcard)),
     ))(input)?;
+    // Any number of release numbers may follow, eg `1.2.3.4.5.6`.
+    let (remain, more_nums) = if extra_num.is_some() {
+        many0(preceded(
+            tag("."),
+            map(digit1, |n: &str| n.parse().unwrap()),
+        ))(remain)?
+    } else {
+        (remain, vec![])
+    };
     let (remain, modifire) = parse_modifier(remain)?;
+    let (remain, post) = parse_post(remain)?;
+    let (remain, dev) = parse_dev(remain)?;
+    let (remain, local) = opt(preceded(tag("+"), parse_local))(remain)?;
     let mut version = Version::new_opt(Some(major), minor, patch);
+    version.epoch = epoch;
     version.extra_num = extra_num;
+    version.more_nums = more_nums;
     version.modifier = modifire;
+    version.post = post;
+    version.dev = dev;
+    version.local = local;
     // check if u32::MAX in any version. (marker for `*`). then set that field
     // and any subsequent fields to `None`
     version.star = vec![Some(major), minor, patch, extra_num].contains(&Some(u32::MAX));
@@ -243,6 +439,8 @@ pub fn parse_version(input: &str) -> IResult<&str, Version> {
             version.extra_num = None;
             version.modifier = None;
         }
+        version.post = None;
+        version.dev = None;
     }
 
     Ok((remain, version))
@@ -251,6 +449,7 @@ pub fn parse_version(input: &str) -> IResult<&str, Version> {
 pub fn parse_req_type(input: &str) -> IResult<&str, ReqType> {
     map_res(
         alt((
+            tag("==="),
             tag("=="),
             tag(">="),
             tag("<="),
@@ -285,21 +484,71 @@ fn parse_digit_or_wildcard(input: &str) -> IResult<&str, u32> {
     )(input)
 }
 
+/// A PEP 440 local label, eg `cpu` or `ubuntu-1`. Normalized to lowercase with `.` separators.
+fn parse_local(input: &str) -> IResult<&str, String> {
+    map(
+        take_while1(|c: char| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_'),
+        |l: &str| l.to_lowercase().replace(['-', '_'], "."),
+    )(input)
+}
+
 fn parse_modifier(input: &str) -> IResult<&str, Option<(VersionModifier, u32)>> {
     opt(map(
-        tuple((opt(tag(".")), parse_modifier_version, digit1)),
+        verify(
+            tuple((opt(one_of(".-_")), parse_modifier_version, digit1)),
+            // A number alone isn't a modifier; eg `1.0.5` is a release number.
+            |(_, version_modifier, _)| *version_modifier != VersionModifier::Other(String::new()),
+        ),
         |(_, version_modifier, n)| (version_modifier, n.parse().unwrap()),
     ))(input)
 }
 
 fn parse_modifier_version(input: &str) -> IResult<&str, VersionModifier> {
-    map(take_till(|c| !is_alphabetic(c as u8)), |x| match x {
-        "a" => VersionModifier::Alpha,
-        "b" => VersionModifier::Beta,
-        "rc" => VersionModifier::ReleaseCandidate,
-        "dep" => VersionModifier::Dep,
-        x => VersionModifier::Other(x.to_string()),
-    })(input)
+    map(
+        // `dev` and post-releases are their own segments, after any pre-release; eg
+        // `1.0rc1.post1.dev2`.
+        verify(take_till(|c| !is_alphabetic(c as u8)), |x: &str| {
+            !["dev", "post", "rev", "r"]
+                .iter()
+                .any(|seg| x.eq_ignore_ascii_case(seg))
+        }),
+        |x: &str| match x.to_lowercase().as_str() {
+            "a" | "alpha" => VersionModifier::Alpha,
+            "b" | "beta" => VersionModifier::Beta,
+            "rc" | "c" | "pre" | "preview" => VersionModifier::ReleaseCandidate,
+            "dep" => VersionModifier::Dep,
+            x => VersionModifier::Other(x.to_string()),
+        },
+    )(input)
+}
+
+/// Parse a post-release segment, eg `.post1`, or `-r2`. The number defaults to 0. A number
+/// after a dash alone, eg `1.0-1`, is short for `.post1`.
+fn parse_post(input: &str) -> IResult<&str, Option<u32>> {
+    opt(alt((
+        map(
+            preceded(
+                pair(
+                    opt(one_of(".-_")),
+                    alt((tag_no_case("post"), tag_no_case("rev"), tag_no_case("r"))),
+                ),
+                opt(preceded(opt(one_of(".-_")), digit1)),
+            ),
+            |n: Option<&str>| n.map_or(0, |n| n.parse().unwrap()),
+        ),
+        map(preceded(tag("-"), digit1), |n: &str| n.parse().unwrap()),
+    )))(input)
+}
+
+/// Parse a developmental release segment, eg `.dev4`. The number defaults to 0.
+fn parse_dev(input: &str) -> IResult<&str, Option<u32>> {
+    opt(map(
+        preceded(
+            pair(opt(one_of(".-_")), alt((tag("dev"), tag("DEV")))),
+            opt(digit1),
+        ),
+        |n: Option<&str>| n.map_or(0, |n| n.parse().unwrap()),
+    ))(input)
 }
 
 #[cfg(test)]
@@ -318,83 +567,153 @@ mod tests {
         case("==1.9.2", Ok(("", Constraint::new(ReqType::Exact, Version::new(1, 9, 2))))),
         case("1.9.2", Ok(("", Constraint::new(ReqType::Exact, Version::new(1, 9, 2))))),
         case("~=1.9.2", Ok(("", Constraint::new(ReqType::TildeEq, Version::new(1, 9, 2))))),
+        case(">= 1.9.2", Ok(("", Constraint::new(ReqType::Gte, Version::new(1, 9, 2))))),
+        case(">=1.0<2.0", Ok(("<2.0", Constraint::new(ReqType::Gte, Version::new(1, 0, 0))))),
     )]
     fn test_parse_constraint(input: &str, expected: IResult<&str, Constraint>) {
         assert_eq!(parse_constraint(input), expected);
     }
 
+    #[rstest(input, expected,
+        case::comma(">=1.0,<2.0", vec![
+            Constraint::new(ReqType::Gte, Version::new(1, 0, 0)),
+            Constraint::new(ReqType::Lt, Version::new(2, 0, 0)),
+        ]),
+        case::spaced(">= 1.0 , < 2.0", vec![
+            Constraint::new(ReqType::Gte, Version::new(1, 0, 0)),
+            Constraint::new(ReqType::Lt, Version::new(2, 0, 0)),
+        ]),
+        case::no_comma(">=1.0<2.0", vec![
+            Constraint::new(ReqType::Gte, Version::new(1, 0, 0)),
+            Constraint::new(ReqType::Lt, Version::new(2, 0, 0)),
+        ]),
+        case::no_comma_spaced(">= 1.0 !=1.5 < 2.0", vec![
+            Constraint::new(ReqType::Gte, Version::new(1, 0, 0)),
+            Constraint::new(ReqType::Ne, Version::new(1, 5, 0)),
+            Constraint::new(ReqType::Lt, Version::new(2, 0, 0)),
+        ]),
+    )]
+    fn test_parse_constraints(input: &str, expected: Vec<Constraint>) {
+        assert_eq!(parse_constraints(input), Ok(("", expected)));
+    }
+
     #[rstest(input, expected,
         case("3.12.5", Ok(("", Version {
             major: Some(3),
             minor: Some(12),
             patch: Some(5),
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("0.1.0", Ok(("", Version {
             major: Some(0),
             minor: Some(1),
             patch: Some(0),
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("3.7", Ok(("", Version {
             major: Some(3),
             minor: Some(7),
             patch: Some(0),
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("1", Ok(("", Version {
             major: Some(1),
             minor: Some(0),
             patch: Some(0),
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("3.2.*", Ok(("", Version {
             major: Some(3),
             minor: Some(2),
             patch: None,
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: true,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("1.*", Ok(("", Version {
             major: Some(1),
             minor: None,
             patch: None,
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: true,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("1.*.*", Ok(("", Version {
             major: Some(1),
             minor: None,
             patch: None,
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: true,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("19.3", Ok(("", Version {
             major: Some(19),
             minor: Some(3),
             patch: Some(0),
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }))),
         case("19.3b0", Ok(("", Version {
                  major: Some(19),
                  minor: Some(3),
                  patch: Some(0),
                  extra_num: None,
+                 more_nums: vec![],
                  modifier: Some((VersionModifier::Beta, 0)),
                  star: false,
+                 dev: None,
+                 epoch: None,
+                 post: None,
+                 local: None,
         }))),
         // This package version showed up in boltons history
         case("0.4.3.dev0", Ok(("", Version {
@@ -402,8 +721,13 @@ mod tests {
                  minor: Some(4),
                  patch: Some(3),
                  extra_num: None,
-                 modifier: Some((VersionModifier::Other("dev".to_string()), 0)),
+                 more_nums: vec![],
+                 modifier: None,
                  star: false,
+                 dev: Some(0),
+                 epoch: None,
+                 post: None,
+                 local: None,
         }))),
     )]
     fn test_parse_version(input: &str, expected: IResult<&str, Version>) {
@@ -478,12 +802,30 @@ mod tests {
                      Constraint::new(ReqType::Exact, Version::new_star(Some(0), Some(3), None, true))
                  ]
              )))),
+             case::spaced("saturn = \" >= 0.3.4, < 1.0 \"", Ok(("", Req::new(
+                 "saturn".to_string(),
+                 vec![
+                     Constraint::new(ReqType::Gte, Version::new(0, 3, 4)),
+                     Constraint::new(ReqType::Lt, Version::new(1, 0, 0)),
+                 ]
+             )))),
              case::star_extra_num("saturn = \"0.3.4.*\"", Ok(("", Req::new(
                  "saturn".to_string(),
                  vec![
                      Constraint::new(ReqType::Exact, Version::new_star(Some(0), Some(3), Some(4), true))
                  ]
-             ))))
+             )))),
+             case::extras("celery[redis, auth]", Ok(("", Req {
+                 install_with_extras: Some(vec!["redis".into(), "auth".into()]),
+                 ..Req::new("celery".to_string(), vec![])
+             }))),
+             case::extras_version("requests[socks] = \"^2.25\"", Ok(("", Req {
+                 install_with_extras: Some(vec!["socks".into()]),
+                 ..Req::new(
+                     "requests".to_string(),
+                     vec![Constraint::new(ReqType::Caret, Version::new_short(2, 25))],
+                 )
+             })))
     )]
     fn test_parse_req(input: &str, expected: IResult<&str, Req>) {
         assert_eq!(parse_req(input), expected);
@@ -495,4 +837,44 @@ mod tests {
     fn test_parse_req_pypi(input: &str, expected: IResult<&str, Req>) {
         assert_eq!(parse_req_pypi_fmt(input), expected);
     }
+
+    #[rstest(input, expected,
+        case::single("sys_platform == 'win32'", Marker::Compare(
+            MarkerValue::Var(MarkerVar::SysPlatform),
+            MarkerOp::Cmp(ReqType::Exact),
+            MarkerValue::Literal("win32".into()),
+        )),
+        case::reversed("\"arm\" in platform_machine", Marker::Compare(
+            MarkerValue::Literal("arm".into()),
+            MarkerOp::In,
+            MarkerValue::Var(MarkerVar::PlatformMachine),
+        )),
+        case::precedence("os_name=='nt' or os_name == 'posix' and python_version<'3.8'", Marker::Or(
+            Box::new(Marker::Compare(
+                MarkerValue::Var(MarkerVar::OsName),
+                MarkerOp::Cmp(ReqType::Exact),
+                MarkerValue::Literal("nt".into()),
+            )),
+            Box::new(Marker::And(
+                Box::new(Marker::Compare(
+                    MarkerValue::Var(MarkerVar::OsName),
+                    MarkerOp::Cmp(ReqType::Exact),
+                    MarkerValue::Literal("posix".into()),
+                )),
+                Box::new(Marker::Compare(
+                    MarkerValue::Var(MarkerVar::PythonVersion),
+                    MarkerOp::Cmp(ReqType::Lt),
+                    MarkerValue::Literal("3.8".into()),
+                )),
+            )),
+        )),
+        case::parens("( platform_machine not in 'x86_64' )", Marker::Compare(
+            MarkerValue::Var(MarkerVar::PlatformMachine),
+            MarkerOp::NotIn,
+            MarkerValue::Literal("x86_64".into()),
+        )),
+    )]
+    fn test_parse_marker(input: &str, expected: Marker) {
+        assert_eq!(parse_marker(input), Ok(("", expected)));
+    }
 }
@@ -1,13 +1,18 @@
 use crate::{
+    build,
     dep_types::{
-        self, Constraint, Dependency, DependencyError, Package, Rename, Req, ReqType, Version,
+        self, Constraint, Dependency, DependencyError, MarkerEnv, Package, Rename, Req, ReqType,
+        Version,
     },
-    util,
+    util, CliConfig,
 };
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::cmp::min;
 use std::collections::HashMap;
+use std::fmt;
 use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use termcolor::Color;
 
 #[cfg(test)]
@@ -38,6 +43,15 @@ pub struct WarehouseRelease {
     pub requires_python: Option<String>,
     pub url: String,
     pub dependencies: Option<Vec<String>>,
+    /// Pulled by the maintainer, per [PEP 592](https://www.python.org/dev/peps/pep-0592/);
+    /// only install it if it's pinned.
+    #[serde(default)]
+    pub yanked: bool,
+    /// The index serves the file's `METADATA` at its URL plus `.metadata`, per
+    /// [PEP 658](https://www.python.org/dev/peps/pep-0658/), so we can read its dependencies
+    /// without downloading it.
+    #[serde(default)]
+    pub core_metadata: bool,
 }
 
 /// Only deserialize the info we need to resolve dependencies etc.
@@ -48,6 +62,82 @@ struct WarehouseData {
     urls: Vec<WarehouseRelease>,
 }
 
+/// Index data fetched this run, by index and package name. Resolving asks for the same package
+/// several times; eg for its versions, then its files, and for each package that depends on it.
+static WAREHOUSE_CACHE: Mutex<Option<WarehouseCache>> = Mutex::new(None);
+
+type WarehouseCache = HashMap<(Option<String>, String), Arc<WarehouseData>>;
+
+/// Return the index data for `name` fetched earlier in this run, or `fetch` and keep it. Failures
+/// aren't kept, so they're retried.
+fn memoized_warehouse_data(
+    index_url: Option<&str>,
+    name: &str,
+    fetch: impl FnOnce() -> Result<WarehouseData, DependencyError>,
+) -> Result<Arc<WarehouseData>, DependencyError> {
+    let key = (index_url.map(str::to_owned), util::normalize_name(name));
+    if let Some(data) = WAREHOUSE_CACHE
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|c| c.get(&key))
+    {
+        return Ok(data.clone());
+    }
+
+    // Don't hold the lock while fetching, so lookups of other packages can run alongside.
+    let data = Arc::new(fetch()?);
+    WAREHOUSE_CACHE
+        .lock()
+        .unwrap()
+        .get_or_insert_with(HashMap::new)
+        .insert(key, data.clone());
+    Ok(data)
+}
+
+/// Dependency data fetched this run, by normalized name and version. Resolving asks for the same
+/// versions again; eg for each package that depends on them.
+static REQ_DATA_CACHE: Mutex<Option<HashMap<(String, Version), ReqCache>>> = Mutex::new(None);
+
+/// Return the dependency data for `packages` fetched earlier in this run, and `fetch` the rest,
+/// keeping it.
+fn memoized_req_data(
+    packages: &HashMap<String, Vec<Version>>,
+    fetch: impl FnOnce(&HashMap<String, Vec<Version>>) -> Result<Vec<ReqCache>, DependencyError>,
+) -> Result<Vec<ReqCache>, DependencyError> {
+    let mut result = vec![];
+    let mut missing: HashMap<String, Vec<Version>> = HashMap::new();
+    {
+        let cache = REQ_DATA_CACHE.lock().unwrap();
+        for (name, versions) in packages {
+            for version in versions {
+                let key = (util::normalize_name(name), version.clone());
+                match cache.as_ref().and_then(|c| c.get(&key)) {
+                    Some(data) => result.push(data.clone()),
+                    None => missing
+                        .entry(name.clone())
+                        .or_default()
+                        .push(version.clone()),
+                }
+            }
+        }
+    }
+    if missing.is_empty() {
+        return Ok(result);
+    }
+
+    let fetched = fetch(&missing)?;
+    let mut cache = REQ_DATA_CACHE.lock().unwrap();
+    let cache = cache.get_or_insert_with(HashMap::new);
+    for data in &fetched {
+        if let (Some(name), Ok(version)) = (&data.name, Version::from_str(&data.version)) {
+            cache.insert((util::normalize_name(name), version), data.clone());
+        }
+    }
+    result.extend(fetched);
+    Ok(result)
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct ReqCache {
     // Name is present from pydeps if gestruct packagetting deps for multiple package names. Otherwise, we commit
@@ -74,6 +164,415 @@ struct MultipleBody {
     packages: HashMap<String, Vec<String>>,
 }
 
+/// A file listed on a [PEP 503](https://www.python.org/dev/peps/pep-0503/) project page, or in
+/// a [PEP 691](https://www.python.org/dev/peps/pep-0691/) JSON response.
+#[derive(Debug, Deserialize)]
+struct SimpleFile {
+    filename: String,
+    url: String,
+    #[serde(default)]
+    hashes: HashMap<String, String>,
+    #[serde(rename = "requires-python")]
+    requires_python: Option<String>,
+    #[serde(default, deserialize_with = "yanked_flag")]
+    yanked: bool,
+    /// Whether the index serves the file's `METADATA` separately. PEP 714 renamed the field, and
+    /// indexes may send both.
+    #[serde(default, rename = "core-metadata", deserialize_with = "metadata_flag")]
+    core_metadata: bool,
+    #[serde(
+        default,
+        rename = "dist-info-metadata",
+        deserialize_with = "metadata_flag"
+    )]
+    dist_info_metadata: bool,
+}
+
+/// PEP 691 marks yanked files with either `true`, or a string giving the reason.
+fn yanked_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(b) => b,
+        serde_json::Value::String(_) => true,
+        _ => false,
+    })
+}
+
+/// PEP 658 gives either `true`, or the hashes of the metadata file.
+fn metadata_flag<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    Ok(match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::Bool(b) => b,
+        serde_json::Value::Object(_) => true,
+        _ => false,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleProject {
+    name: String,
+    files: Vec<SimpleFile>,
+}
+
+const SIMPLE_JSON_TYPE: &str = "application/vnd.pypi.simple.v1+json";
+
+/// Find a project's page on a Simple index. Names are normalized per PEP 503, and we always
+/// use a trailing slash, since that's the canonical form; some servers 404 or redirect without it.
+fn simple_project_url(index_url: &str, name: &str) -> String {
+    format!(
+        "{}/{}/",
+        index_url.trim_end_matches('/'),
+        util::normalize_name(name)
+    )
+}
+
+/// Links on project pages may be absolute, relative to the host, or relative to the page,
+/// including `..` segments.
+fn join_url(page_url: &str, href: &str) -> String {
+    match reqwest::Url::parse(page_url).and_then(|base| base.join(href)) {
+        Ok(url) => url.to_string(),
+        Err(_) => href.to_owned(),
+    }
+}
+
+/// Parse the anchors on an HTML project page. The filename is the link text, and hashes are
+/// in the URL fragment, eg `#sha256=...`.
+fn parse_simple_html(html: &str, page_url: &str) -> Vec<SimpleFile> {
+    let re_anchor = Regex::new(r#"(?is)<a\s([^>]*)>(.*?)</a>"#).unwrap();
+    let re_href = Regex::new(r#"(?i)href\s*=\s*["']([^"']*)["']"#).unwrap();
+    let re_requires = Regex::new(r#"(?i)data-requires-python\s*=\s*["']([^"']*)["']"#).unwrap();
+    let re_yanked = Regex::new(r#"(?i)\sdata-yanked\b"#).unwrap();
+    let re_metadata =
+        Regex::new(r#"(?i)\sdata-(?:core|dist-info)-metadata\b(?:\s*=\s*["']([^"']*)["'])?"#)
+            .unwrap();
+
+    let unescape = |s: &str| {
+        s.replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&")
+    };
+
+    let mut result = vec![];
+    for caps in re_anchor.captures_iter(html) {
+        let attrs = caps.get(1).unwrap().as_str();
+        let href = match re_href.captures(attrs) {
+            Some(h) => unescape(h.get(1).unwrap().as_str()),
+            None => continue,
+        };
+
+        let mut hashes = HashMap::new();
+        let url = match href.find('#') {
+            Some(i) => {
+                if let Some((alg, digest)) = href[i + 1..].split_once('=') {
+                    hashes.insert(alg.to_owned(), digest.to_owned());
+                }
+                join_url(page_url, &href[..i])
+            }
+            None => join_url(page_url, &href),
+        };
+
+        result.push(SimpleFile {
+            filename: unescape(caps.get(2).unwrap().as_str().trim()),
+            url,
+            hashes,
+            requires_python: re_requires
+                .captures(attrs)
+                .map(|r| unescape(r.get(1).unwrap().as_str())),
+            // `attrs` starts after the tag name's whitespace, so pad it for the match.
+            yanked: re_yanked.is_match(&format!(" {}", attrs)),
+            core_metadata: re_metadata
+                .captures(&format!(" {}", attrs))
+                .is_some_and(|m| m.get(1).is_none_or(|v| v.as_str() != "false")),
+            dist_info_metadata: false,
+        });
+    }
+    result
+}
+
+/// Convert Simple API file listings to the shape the Warehouse JSON API uses, so the rest of
+/// resolution and installation doesn't need to know which API the index supports.
+fn simple_to_warehouse(name: &str, files: Vec<SimpleFile>) -> WarehouseData {
+    let mut releases: HashMap<String, Vec<WarehouseRelease>> = HashMap::new();
+
+    for file in files {
+        let (version, packagetype, python_version) = if file.filename.ends_with(".whl") {
+            // Format is "name-version(-build)?-pythontag-abitag-platformtag.whl"
+            let parts: Vec<&str> = file.filename.trim_end_matches(".whl").split('-').collect();
+            if parts.len() < 5 {
+                continue;
+            }
+            (
+                parts[1].to_owned(),
+                "bdist_wheel",
+                parts[parts.len() - 3].to_owned(),
+            )
+        } else {
+            let stem = [".tar.gz", ".tar.bz2", ".tgz", ".zip"]
+                .iter()
+                .find(|ext| file.filename.ends_with(*ext))
+                .map(|ext| file.filename.trim_end_matches(ext));
+            match stem.and_then(|s| s.rsplit_once('-')) {
+                Some((_, v)) => (v.to_owned(), "sdist", "source".to_owned()),
+                // Eggs, Windows installers etc.
+                None => continue,
+            }
+        };
+
+        releases.entry(version).or_default().push(WarehouseRelease {
+            filename: file.filename,
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: file.hashes.get("md5").cloned().unwrap_or_default(),
+                sha256: file.hashes.get("sha256").cloned().unwrap_or_default(),
+            },
+            packagetype: packagetype.to_owned(),
+            python_version,
+            requires_python: file.requires_python,
+            url: file.url,
+            dependencies: None,
+            yanked: file.yanked,
+            core_metadata: file.core_metadata || file.dist_info_metadata,
+        });
+    }
+
+    let latest = releases
+        .keys()
+        .filter_map(|v| Version::from_str(v).ok().map(|parsed| (parsed, v)))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .map(|(_, v)| v.clone())
+        .unwrap_or_default();
+
+    WarehouseData {
+        info: WarehouseInfo {
+            name: name.to_owned(),
+            requires_dist: None,
+            requires_python: None,
+            version: latest,
+        },
+        releases,
+        urls: vec![],
+    }
+}
+
+/// Fetch package data from a [Simple Repository API](https://www.python.org/dev/peps/pep-0503/)
+/// index. We ask for the PEP 691 JSON format, and fall back to parsing HTML if that's what the
+/// index serves.
+fn get_simple_data(index_url: &str, name: &str) -> Result<WarehouseData, DependencyError> {
+    get_simple_page(
+        &simple_project_url(&util::http::strip_credentials(index_url), name),
+        name,
+    )
+}
+
+/// Fetch and parse a Simple index project page.
+fn get_simple_page(url: &str, name: &str) -> Result<WarehouseData, DependencyError> {
+    let request = util::http::get(url).header(
+        reqwest::header::ACCEPT,
+        format!("{}, text/html;q=0.1", SIMPLE_JSON_TYPE),
+    );
+    // Redirects are followed, eg to the trailing-slash form of the URL, or to where the index
+    // has moved. Relative links are relative to the final URL.
+    let mut resp = util::http::send(request)?.error_for_status()?;
+    let page_url = resp.url().to_string();
+
+    let is_json = resp
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|ct| ct.to_str().ok())
+        .is_some_and(|ct| ct.starts_with(SIMPLE_JSON_TYPE));
+
+    if is_json {
+        let project: SimpleProject = resp.json()?;
+        let files = project
+            .files
+            .into_iter()
+            .map(|f| SimpleFile {
+                url: join_url(&page_url, &f.url),
+                ..f
+            })
+            .collect();
+        Ok(simple_to_warehouse(&project.name, files))
+    } else {
+        Ok(simple_to_warehouse(
+            name,
+            parse_simple_html(&resp.text()?, &page_url),
+        ))
+    }
+}
+
+/// Check if a sub-dependency applies to this environment, and to the extras its parent is
+/// installed with. Eg `PySocks; extra == "socks"` only applies to `requests[socks]`. This
+/// includes its environment markers, if it has any.
+pub(crate) fn req_applies(req: &Req, extras: &[String], os: util::Os, py_vers: &Version) -> bool {
+    if let Some(markers) = &req.markers {
+        if !markers.evaluate(&MarkerEnv::new(os, py_vers).with_extras(extras)) {
+            return false;
+        }
+    }
+
+    let extra_ok = match &req.extra {
+        Some(ex) => extras.iter().any(|e| util::compare_names(e, ex)),
+        None => true,
+    };
+    let os_ok = match req.sys_platform {
+        Some((rt, os_)) => match rt {
+            // A specified win32 req could apply to 64-bit windows too; markers don't
+            // specify architecture.
+            ReqType::Exact => os_.family() == os.family(),
+            ReqType::Ne => os_.family() != os.family(),
+            _ => {
+                util::abort("Reqtypes for Os must be == or !=");
+            }
+        },
+        None => true,
+    };
+    let py_ok = match &req.python_version {
+        Some(v) => res::is_compat(v, py_vers),
+        None => true,
+    };
+    extra_ok && os_ok && py_ok
+}
+
+/// Why resolution failed.
+#[derive(Debug)]
+pub enum ResolutionError {
+    /// No version of `name` satisfies everything that requires it. `demands` is each requirer,
+    /// eg `requests 2.25.1` or `pyproject.toml`, with the constraints it puts on `name`.
+    Conflict {
+        name: String,
+        demands: Vec<(String, Vec<Constraint>)>,
+    },
+    /// Eg we couldn't fetch package data.
+    Other(DependencyError),
+}
+
+impl From<DependencyError> for ResolutionError {
+    fn from(e: DependencyError) -> Self {
+        Self::Other(e)
+    }
+}
+
+fn fmt_constraints(constraints: &[Constraint]) -> String {
+    if constraints.is_empty() {
+        return "any version".to_owned();
+    }
+    let constraints: Vec<String> = constraints.iter().map(|c| c.to_string()).collect();
+    constraints.join(", ")
+}
+
+impl fmt::Display for ResolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Conflict { name, demands } if demands.len() == 1 => write!(
+                f,
+                "No version of {} supports this Python version and satisfies {}, which needs {}",
+                name,
+                demands[0].0,
+                fmt_constraints(&demands[0].1)
+            ),
+            Self::Conflict { name, demands } => {
+                write!(
+                    f,
+                    "Can't find a version of {} that satisfies everything requiring it:",
+                    name
+                )?;
+                for (requirer, constraints) in demands {
+                    write!(
+                        f,
+                        "\n  {} needs {} {}",
+                        requirer,
+                        name,
+                        fmt_constraints(constraints)
+                    )?;
+                }
+                Ok(())
+            }
+            Self::Other(e) => write!(f, "Problem resolving dependencies: {}", e),
+        }
+    }
+}
+
+/// Describe what requires a package, for error messages.
+fn requirer(parent_id: u32, deps: &[Dependency]) -> String {
+    match deps.iter().find(|d| d.id == parent_id) {
+        Some(parent) => format!("{} {}", parent.name, parent.version),
+        // ie top-level
+        None => "pyproject.toml".to_owned(),
+    }
+}
+
+/// The chain of dependencies that led to `parent_id`, starting from a top-level requirement.
+fn resolution_path(parent_id: u32, deps: &[Dependency]) -> Vec<&Dependency> {
+    let mut path = vec![];
+    let mut id = parent_id;
+    while let Some(dep) = deps.iter().find(|d| d.id == id) {
+        // Ids only point up the chain, but don't trust that blindly.
+        if path.iter().any(|d: &&Dependency| d.id == dep.id) {
+            break;
+        }
+        path.push(dep);
+        id = dep.parent;
+    }
+    path.reverse();
+    path
+}
+
+/// Check if `req` points back to a package earlier on the path that led to it, eg
+/// `a -> b -> a`, and that package's version satisfies it. If so, we don't need to resolve it
+/// again, and return the packages in the loop. If the version doesn't satisfy `req`, it's a
+/// conflict, not a loop; we let resolution handle that like any other.
+fn find_cycle(req: &Req, parent_id: u32, deps: &[Dependency]) -> Option<Vec<String>> {
+    let path = resolution_path(parent_id, deps);
+    let start = path
+        .iter()
+        .position(|d| util::compare_names(&d.name, &req.name))?;
+    if !res::is_compat(&req.constraints, &path[start].version) {
+        return None;
+    }
+
+    let mut cycle: Vec<String> = path[start..]
+        .iter()
+        .map(|d| format!("{} {}", d.name, d.version))
+        .collect();
+    cycle.push(path[start].name.clone());
+    Some(cycle)
+}
+
+/// Combine the extras requested for each version of a package, without duplicates.
+fn merge_extras(deps: &[Dependency]) -> Vec<String> {
+    let mut result: Vec<String> = vec![];
+    for extra in deps.iter().flat_map(|d| &d.extras) {
+        if !result.contains(extra) {
+            result.push(extra.clone());
+        }
+    }
+    result.sort();
+    result
+}
+
+/// Whether the lock file pins this version of the package.
+fn is_locked(locked: &[crate::Package], name: &str, version: &Version) -> bool {
+    locked
+        .iter()
+        .any(|p| util::compare_names(&p.name, name) && p.version == *version)
+}
+
+/// Add the constraints from `[tool.pyflow.overrides]` to each requirement they name, wherever
+/// in the graph it comes from.
+fn apply_overrides(reqs: &[Req], overrides: &[Req]) -> Vec<Req> {
+    reqs.iter()
+        .map(|req| {
+            let mut req = req.clone();
+            for over in overrides {
+                if util::compare_names(&over.name, &req.name) {
+                    req.constraints.extend(over.constraints.iter().cloned());
+                }
+            }
+            req
+        })
+        .collect()
+}
+
 // TODO: figure out lifetimes so we can automock this function
 // guess_graph removed from mod res because of lifetime issue with automock
 // Build a graph: Start by assuming we can pick the newest compatible dependency at each step.
@@ -83,6 +582,7 @@ fn guess_graph(
     parent_id: u32,
     reqs: &[Req],
     locked: &[crate::Package],
+    overrides: &[Req],
     os: util::Os,
     extras: &[String],
     py_vers: &Version,
@@ -90,19 +590,20 @@ fn guess_graph(
     cache: &mut HashMap<(String, Version), Vec<&ReqCache>>,
     vers_cache: &mut HashMap<String, (String, Version, Vec<Version>)>,
     reqs_searched: &mut Vec<Req>,
-) -> Result<(), DependencyError> {
+) -> Result<(), ResolutionError> {
+    let raw_reqs = reqs;
     // Sometimes requirements are specified on separate lines; combine them if so, or we'll
-    // have problems resolving.
+    // have problems resolving. Only combine the ones that apply, eg leaving out
+    // `importlib-metadata; python_version < "3.8"` on Python 3.9.
 
     let mut cleaned_reqs: Vec<Req> = vec![];
-    for req in reqs {
+    for req in reqs.iter().filter(|r| req_applies(r, extras, os, py_vers)) {
         if cleaned_reqs
             .iter()
-            .map(|cr| cr.name.clone())
-            .any(|x| x == req.name)
+            .any(|cr| util::compare_names(&cr.name, &req.name))
         {
             for c in cleaned_reqs.iter_mut() {
-                if c.name == req.name {
+                if util::compare_names(&c.name, &req.name) {
                     for constr in req.constraints.iter() {
                         c.constraints.push(constr.clone());
                     }
@@ -112,7 +613,16 @@ fn guess_graph(
                     if req.extra.is_none() && c.extra.is_some() {
                         c.extra = None
                     }
-                    // todo: Should merge sys_platform, python_version, install_with_extras too.
+                    // Install with the extras from each, eg `celery[redis]` and `celery[auth]`.
+                    if let Some(extras) = &req.install_with_extras {
+                        let existing = c.install_with_extras.get_or_insert_with(Vec::new);
+                        for extra in extras {
+                            if !existing.contains(extra) {
+                                existing.push(extra.clone());
+                            }
+                        }
+                    }
+                    // todo: Should merge sys_platform, python_version too.
                 }
             }
 
@@ -126,25 +636,19 @@ fn guess_graph(
         // If we've already satisfied this req, don't query it again. Otherwise we'll make extra
         // http calls, and could end up in infinite loops.
         .filter(|r| !reqs_searched.contains(*r))
-        .filter(|r| match &r.extra {
-            Some(ex) => extras.contains(ex),
-            None => true,
-        })
-        .filter(|r| match r.sys_platform {
-            Some((rt, os_)) => match rt {
-                // A specified win32 req could apply to 64-bit windows too.
-                ReqType::Exact => {
-                    os_ == os || (os_ == util::Os::Windows32 && os == util::Os::Windows)
-                }
-                ReqType::Ne => os_ != os,
-                _ => {
-                    util::abort("Reqtypes for Os must be == or !=");
-                }
-            },
-            None => true,
-        })
-        .filter(|r| match &r.python_version {
-            Some(v) => res::is_compat(v, py_vers),
+        .filter(|r| match find_cycle(r, parent_id, result) {
+            Some(cycle) => {
+                util::print_color(
+                    &format!(
+                        "Found a circular dependency: {}. Using the version of {} already \
+                         being installed.",
+                        cycle.join(" -> "),
+                        r.name
+                    ),
+                    Color::Yellow,
+                );
+                false
+            }
             None => true,
         })
         .collect();
@@ -153,7 +657,8 @@ fn guess_graph(
     let mut locked_reqs: Vec<Req> = vec![];
 
     // Partition reqs into ones we have lock-file data for, and ones where we need to make
-    // http calls to the pypi warehouse (for versions) and pydeps (for deps).
+    // http calls to the pypi warehouse (for versions) and pydeps (for deps), or to the custom
+    // index for both.
     for req in &reqs {
         reqs_searched.push((*req).clone());
 
@@ -163,8 +668,40 @@ fn guess_graph(
                 continue;
             }
 
-            if res::is_compat(&req.constraints, &package.version) {
-                locked_reqs.push((*req).clone());
+            // A locked package installed without one of the extras we need doesn't list
+            // that extra's dependencies, so we query it again.
+            let has_extras = req
+                .install_with_extras
+                .iter()
+                .flatten()
+                .all(|ex| package.extras.contains(ex));
+
+            // Its locked dependencies must satisfy the overrides too, or we'd keep pinning a
+            // version they rule out.
+            let deps_allowed = package.deps.iter().all(|(_, name, vers)| {
+                overrides
+                    .iter()
+                    .filter(|o| util::compare_names(&o.name, name))
+                    .all(|o| res::is_compat(&o.constraints, vers))
+            });
+
+            if res::is_compat(&req.constraints, &package.version) && has_extras && deps_allowed {
+                // A dependency that's being upgraded isn't locked, and the package's locked pin
+                // on it no longer holds. Keep the package's version, but look up what it really
+                // requires.
+                let deps_locked = package
+                    .deps
+                    .iter()
+                    .all(|(_, name, _)| locked.iter().any(|p| util::compare_names(&p.name, name)));
+                if deps_locked {
+                    locked_reqs.push((*req).clone());
+                } else {
+                    let mut pinned = (*req).clone();
+                    pinned
+                        .constraints
+                        .push(Constraint::new(ReqType::Exact, package.version.clone()));
+                    non_locked_reqs.push(pinned);
+                }
                 found_in_locked = true;
                 break;
             }
@@ -248,7 +785,8 @@ fn guess_graph(
                         id: result.iter().map(|d| d.id).max().unwrap_or(0) + 1,
                         name: req.name.to_owned(),
                         version: Version::from_str(&r.version).expect("Problem parsing vers"),
-                        reqs: r.reqs(),
+                        reqs: apply_overrides(&r.reqs(), overrides),
+                        extras: req.install_with_extras.clone().unwrap_or_default(),
                         parent: parent_id,
                     })
                 } else {
@@ -258,7 +796,14 @@ fn guess_graph(
             .collect();
 
         if deps.is_empty() {
-            util::abort(&format!("Can't find a compatible package for {:?}", &req));
+            return Err(ResolutionError::Conflict {
+                name: req.name.clone(),
+                demands: raw_reqs
+                    .iter()
+                    .filter(|r| util::compare_names(&r.name, &req.name))
+                    .map(|r| (requirer(parent_id, result), r.constraints.clone()))
+                    .collect(),
+            });
         }
 
         let newest_compat = deps
@@ -268,10 +813,11 @@ fn guess_graph(
 
         result.push(newest_compat.clone());
 
-        if let Err(e) = guess_graph(
+        guess_graph(
             newest_compat.id,
             &newest_compat.reqs,
             locked,
+            overrides,
             os,
             req.install_with_extras.as_ref().unwrap_or(&vec![]),
             py_vers,
@@ -279,10 +825,7 @@ fn guess_graph(
             cache,
             vers_cache,
             reqs_searched,
-        ) {
-            println!("Problem pulling dependency info for {}", &req.name);
-            util::abort(&e.details)
-        }
+        )?;
     }
     Ok(())
 }
@@ -294,17 +837,32 @@ pub(super) mod res {
     /// Format a name based on how it's listed on `PyPi`. Ie capitalize or convert - to _'
     /// a required.
     fn format_name(name: &str, cache: &HashMap<String, (String, Version, Vec<Version>)>) -> String {
-        match cache.get(name) {
+        match cache.get(&util::normalize_name(name)) {
             Some(vc) => vc.0.clone(),
             None => name.to_owned(), // ie this is from a locked dep.
         }
     }
 
-    /// Fetch data about a package from the [Pypi Warehouse](https://warehouse.pypa.io/api-reference/json/).
-    fn get_warehouse_data(name: &str) -> Result<WarehouseData, reqwest::Error> {
-        let url = format!("https://pypi.org/pypi/{}/json", name);
-        let resp = reqwest::get(&url)?.json()?;
-        Ok(resp)
+    /// Fetch data about a package from the [Pypi Warehouse](https://warehouse.pypa.io/api-reference/json/),
+    /// or from a custom index, if one is set.
+    /// Each package is only fetched once per run.
+    fn get_warehouse_data(name: &str) -> Result<Arc<WarehouseData>, DependencyError> {
+        let cfg = CliConfig::current();
+        if cfg.offline {
+            return Err(DependencyError::new(&format!(
+                "Can't look up {} while offline",
+                name
+            )));
+        }
+        memoized_warehouse_data(cfg.index_url.as_deref(), name, || {
+            if let Some(index_url) = &cfg.index_url {
+                return get_simple_data(index_url, name);
+            }
+
+            let url = format!("https://pypi.org/pypi/{}/json", name);
+            let resp = util::http::send(util::http::client().get(&url))?.json()?;
+            Ok(resp)
+        })
     }
 
     /// Find the latest version of a package by querying the warehouse.  Also return
@@ -315,85 +873,65 @@ pub(super) mod res {
         req: Option<Req>,
     ) -> Result<(String, Version, Vec<Version>), DependencyError> {
         let data = get_warehouse_data(name)?;
+        let yanked = yanked_versions(&data.releases);
+        let constraints = req
+            .as_ref()
+            .map(|r| r.constraints.clone())
+            .unwrap_or_default();
+        let selectable = |v: &Version| !yanked.contains(v) || pinned(&constraints, v);
 
-        let all_versions = data
-            .releases
-            .keys()
-            .filter_map(|v| {
-                data.releases.get(v).map(|releases| {
-                    releases.iter().map(move |release| {
-                        Req::from_warehouse_release(
-                            name.to_string(),
-                            v.to_string(),
-                            release.clone(),
-                        )
-                    })
-                })
-            })
-            .flatten();
         let py_vers = if let Some(ref r) = req {
             r.py_ver_or_default()
         } else {
             Version::new_star(None, None, None, true)
         };
-        let select_version = if let Some(ref r) = req {
-            let av: Vec<Req> = all_versions.clone().collect();
-            let compat_av: Vec<Version> = av
-                .iter()
-                .filter_map(|x: &Req| {
-                    if is_compat(&r.constraints, &x.constraints[0].version) {
-                        if let Some(ref pv) = x.python_version {
-                            if is_compat(pv, &py_vers) {
-                                Some(x.constraints[0].version.clone())
-                            } else {
-                                None
-                            }
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
-                    }
-                })
-                .collect();
-            let v = compat_av.iter().max();
-            v.cloned()
-        } else {
-            None
-        };
 
         #[cfg(not(debug_assertions))]
         let all_compat: Vec<Version>;
         #[cfg(debug_assertions)]
         let mut all_compat: Vec<Version>;
 
-        all_compat = all_versions
-            .filter_map(|x| {
-                if let Some(y) = x.python_version {
-                    if is_compat(&y, &py_vers) {
-                        Some(x.constraints.first().unwrap().version.clone())
-                    } else {
-                        None
-                    }
-                } else {
-                    None
-                }
-            })
+        // Skip versions that don't support our Python, so we fall back to an older one that
+        // does, instead of failing once we go to install it.
+        all_compat = data
+            .releases
+            .iter()
+            .filter(|(_, files)| files.iter().any(|f| supports_python(f, &py_vers)))
+            .filter_map(|(v, _)| Version::from_str(v).ok())
+            .filter(|v| selectable(v))
             .collect();
 
+        let select_version = if let Some(ref r) = req {
+            let compat_av: Vec<Version> = all_compat
+                .iter()
+                .filter(|v| is_compat(&r.constraints, v))
+                .cloned()
+                .collect();
+            newest(&compat_av, allows_prereleases(&r.constraints))
+        } else {
+            None
+        };
+
         #[cfg(debug_assertions)]
         all_compat.sort();
 
         if let Some(v) = select_version {
-            Ok((data.info.name, v, all_compat))
+            if yanked.contains(&v) {
+                util::print_color(
+                    &format!(
+                        "Warning: {} {} was yanked from the index, but is pinned, so \
+                         installing it anyway",
+                        data.info.name, v
+                    ),
+                    Color::Yellow,
+                );
+            }
+            Ok((data.info.name.clone(), v, all_compat))
         } else {
             Ok((
-                data.info.name,
-                all_compat
-                    .iter()
-                    .max()
-                    .unwrap_or_else(|| panic!("Can't find a valid version for {}", name))
-                    .clone(),
+                data.info.name.clone(),
+                newest(&all_compat, false)
+                    .unwrap_or_else(|| panic!("Can't find a valid version for {}", name)),
                 all_compat,
             ))
         }
@@ -403,7 +941,7 @@ pub(super) mod res {
     pub fn get_warehouse_release(
         name: &str,
         version: &Version,
-    ) -> Result<Vec<WarehouseRelease>, reqwest::Error> {
+    ) -> Result<Vec<WarehouseRelease>, DependencyError> {
         let data = get_warehouse_data(name)?;
         // some packages 0-pad their version numbers or have less digits. Lets map
         // the parsed version to the key.
@@ -411,8 +949,8 @@ pub(super) mod res {
         for key in data.releases.keys() {
             if let Ok(ver) = Version::from_str(key) {
                 version_map.insert(ver, key.as_str());
-            } else if cfg!(debug_assertions) {
-                eprintln!("Unable to parse \"{}\" version \"{}\"; skipped.", name, key);
+            } else {
+                log::debug!("Unable to parse \"{}\" version \"{}\"; skipped.", name, key);
             }
         }
 
@@ -430,7 +968,12 @@ pub(super) mod res {
     /// Fetch items from multiple packages; cuts down on API calls.
     fn get_req_cache_multiple(
         packages: &HashMap<String, Vec<Version>>,
-    ) -> Result<Vec<ReqCache>, reqwest::Error> {
+    ) -> Result<Vec<ReqCache>, DependencyError> {
+        // pydeps only knows about PyPI, and we'd leak the names of private packages to it.
+        if CliConfig::current().index_url.is_some() {
+            return get_index_req_cache(packages);
+        }
+
         // input tuple is name, min version, max version.
         // parse strings here.
         let mut packages2 = HashMap::new();
@@ -442,17 +985,138 @@ pub(super) mod res {
         let url = "https://pydeps.herokuapp.com/multiple/";
         //                let url = "http://localhost:8000/multiple/";
 
-        reqwest::Client::new()
-            .post(url)
-            .json(&MultipleBody {
-                packages: packages2,
-            })
-            .send()?
-            .json()
+        let request = util::http::client().post(url).json(&MultipleBody {
+            packages: packages2,
+        });
+        Ok(util::http::send(request)?.json()?)
+    }
+
+    /// Read dependency data from the custom index itself. It may serve each file's `METADATA`
+    /// separately, per [PEP 658](https://www.python.org/dev/peps/pep-0658/); otherwise, we
+    /// download a wheel, or failing that, an sdist, and read it from there.
+    fn get_index_req_cache(
+        packages: &HashMap<String, Vec<Version>>,
+    ) -> Result<Vec<ReqCache>, DependencyError> {
+        let wanted: Vec<(&String, &Version)> = packages
+            .iter()
+            .flat_map(|(name, versions)| versions.iter().map(move |v| (name, v)))
+            .collect();
+
+        util::http::parallel_map(
+            &wanted,
+            CliConfig::current().max_concurrent_downloads,
+            |(name, version)| get_index_metadata(name, version),
+        )
+        .into_iter()
+        .collect()
+    }
+
+    fn get_index_metadata(name: &str, version: &Version) -> Result<ReqCache, DependencyError> {
+        let releases = get_warehouse_release(name, version)?;
+        let file = releases
+            .iter()
+            .find(|r| r.core_metadata)
+            .or_else(|| releases.iter().find(|r| r.packagetype == "bdist_wheel"))
+            .or_else(|| releases.first())
+            .ok_or_else(|| {
+                DependencyError::new(&format!("{} {} has no files on the index", name, version))
+            })?;
+
+        let metadata = if file.core_metadata {
+            let url = format!("{}.metadata", file.url);
+            util::http::send(util::http::get(&url))?
+                .error_for_status()?
+                .text()?
+        } else {
+            let mut archive = vec![];
+            util::http::send(util::http::get(&file.url))?
+                .error_for_status()?
+                .copy_to(&mut archive)?;
+            build::read_dist_metadata_from(std::io::Cursor::new(archive), &file.packagetype)
+                .map_err(|e| {
+                    DependencyError::new(&format!(
+                        "Problem reading the metadata of {}: {}",
+                        file.filename, e
+                    ))
+                })?
+        };
+
+        // The headers end at the first blank line; the description follows.
+        let headers: Vec<&str> = metadata
+            .lines()
+            .take_while(|l| !l.trim().is_empty())
+            .collect();
+        let field = |key: &str| -> Vec<String> {
+            headers
+                .iter()
+                .filter_map(|l| l.strip_prefix(key))
+                .map(|v| v.trim().to_owned())
+                .collect()
+        };
+
+        Ok(ReqCache {
+            name: Some(name.to_owned()),
+            version: version.to_string(),
+            requires_python: file
+                .requires_python
+                .clone()
+                .or_else(|| field("Requires-Python:").pop()),
+            requires_dist: field("Requires-Dist:"),
+        })
+    }
+
+    /// Versions whose files were all yanked. If only some were, eg a broken wheel for one
+    /// platform, the version's still usable.
+    pub(super) fn yanked_versions(
+        releases: &HashMap<String, Vec<WarehouseRelease>>,
+    ) -> Vec<Version> {
+        releases
+            .iter()
+            .filter(|(_, files)| !files.is_empty() && files.iter().all(|f| f.yanked))
+            .filter_map(|(v, _)| Version::from_str(v).ok())
+            .collect()
+    }
+
+    /// Check if a release file can be installed on `py_vers`. Use its `requires_python` if
+    /// set; otherwise, for wheels, the Python tag, eg `cp38` or `py2.py3`, any of which may match.
+    pub(super) fn supports_python(release: &WarehouseRelease, py_vers: &Version) -> bool {
+        if let Some(rp) = release.requires_python.as_ref().filter(|rp| !rp.is_empty()) {
+            if let Ok(constraints) = Constraint::from_str_multiple(rp) {
+                return is_compat(&constraints, py_vers);
+            }
+        }
+        match Constraint::from_wh_py_vers(&release.python_version) {
+            Ok(constraints) => constraints.iter().any(|c| c.is_compatible(py_vers)),
+            // Eg sdists with an empty `python_version`.
+            Err(_) => true,
+        }
+    }
+
+    /// Per PEP 592, a yanked version is only used if an exact constraint, eg `==2.0.1`, names it.
+    pub(super) fn pinned(constraints: &[Constraint], vers: &Version) -> bool {
+        constraints
+            .iter()
+            .any(|c| c.type_ == ReqType::Exact && c.is_compatible(vers))
     }
 
     /// Helper fn for `guess_graph`.
-    pub(super) fn is_compat(constraints: &[Constraint], vers: &Version) -> bool {
+    /// Pick the newest version. Per PEP 440, skip pre-releases unless `allow_pre`, or there's
+    /// nothing else.
+    pub(super) fn newest(versions: &[Version], allow_pre: bool) -> Option<Version> {
+        let finals = versions
+            .iter()
+            .filter(|v| allow_pre || !v.is_prerelease())
+            .max();
+        finals.or_else(|| versions.iter().max()).cloned()
+    }
+
+    /// A constraint that names a pre-release, eg `>=2.0b1`, opts in to them.
+    pub(super) fn allows_prereleases(constraints: &[Constraint]) -> bool {
+        constraints.iter().any(|c| c.version.is_prerelease())
+    }
+
+    /// Check that `vers` meets every constraint, eg each of `>=1.21.1, !=1.25.0, <1.27`.
+    pub(crate) fn is_compat(constraints: &[Constraint], vers: &Version) -> bool {
         for constraint in constraints.iter() {
             if !constraint.is_compatible(vers) {
                 return false;
@@ -461,7 +1125,7 @@ pub(super) mod res {
         true
     }
 
-    /// Pull data on pydeps for a req. Only pull what we need.
+    /// Pull data on pydeps, or the custom index, for a req. Only pull what we need.
     /// todo: Group all reqs and pull with a single call to pydeps to improve speed?
     pub(super) fn fetch_req_data(
         reqs: &[Req],
@@ -473,23 +1137,31 @@ pub(super) mod res {
         let mut query_data = HashMap::new();
         for req in reqs {
             // todo: cache version info; currently may get this multiple times.
-            let (_, latest_version, all_versions) = match vers_cache.get(&req.name) {
-                Some(c) => c.clone(),
-                None => {
-                    if let Ok(data) =
-                        get_version_info(&req.name, Some(req.clone_or_default_py(py_vers)))
-                    {
-                        vers_cache.insert(req.name.clone(), data.clone());
-                        data
-                    } else {
-                        util::abort(&format!(
-                            "Can't get version info for the dependency `{}`. \
+            let (_, latest_version, all_versions) =
+                match vers_cache.get(&util::normalize_name(&req.name)) {
+                    Some(c) => c.clone(),
+                    None => {
+                        // Pass our Python version, not one from the req's markers, eg
+                        // `python_version < "3.8"`, so we pick a version that supports it.
+                        let query = Req {
+                            python_version: Some(vec![Constraint::new(
+                                ReqType::Exact,
+                                py_vers.clone(),
+                            )]),
+                            ..req.clone()
+                        };
+                        if let Ok(data) = get_version_info(&req.name, Some(query)) {
+                            vers_cache.insert(util::normalize_name(&req.name), data.clone());
+                            data
+                        } else {
+                            util::abort(&format!(
+                                "Can't get version info for the dependency `{}`. \
                          Is it spelled correctly? Is the internet connection ok?",
-                            &req.name
-                        ))
+                                &req.name
+                            ))
+                        }
                     }
-                }
-            };
+                };
 
             let mut max_v_to_query = latest_version;
 
@@ -509,11 +1181,11 @@ pub(super) mod res {
             }
 
             // To minimimize request time, only query the latest compatible version.
-            let best_version = match all_versions
+            let candidates: Vec<Version> = all_versions
                 .into_iter()
                 .filter(|v| *v <= max_v_to_query)
-                .max()
-            {
+                .collect();
+            let best_version = match newest(&candidates, allows_prereleases(&req.constraints)) {
                 Some(v) => vec![v],
                 None => vec![],
             };
@@ -525,7 +1197,33 @@ pub(super) mod res {
             return Ok(vec![]);
         }
 
-        Ok(get_req_cache_multiple(&query_data)?)
+        memoized_req_data(&query_data, get_req_cache_multiple)
+    }
+
+    /// Like `find_constraints`, but keep track of what each constraint comes from.
+    pub(super) fn find_demands(
+        all_reqs: &[Req],
+        all_deps: &[Dependency],
+        relevant_deps: &[Dependency],
+    ) -> Vec<(String, Vec<Constraint>)> {
+        let mut result: Vec<(String, Vec<Constraint>)> = vec![];
+        for dep in relevant_deps {
+            let reqs = match all_deps.iter().find(|d| d.id == dep.parent) {
+                Some(p) => &p.reqs,
+                None => all_reqs,
+            };
+            let who = requirer(dep.parent, all_deps);
+            if result.iter().any(|(w, _)| *w == who) {
+                continue;
+            }
+            let constraints = reqs
+                .iter()
+                .filter(|r| util::compare_names(&r.name, &dep.name))
+                .flat_map(|r| r.constraints.clone())
+                .collect();
+            result.push((who, constraints));
+        }
+        result
     }
 
     fn find_constraints(
@@ -544,6 +1242,7 @@ pub(super) mod res {
                     name: "top".to_owned(),
                     version: Version::new(0, 0, 0),
                     reqs: all_reqs.to_vec(),
+                    extras: vec![],
                     parent: 0,
                 },
             };
@@ -586,7 +1285,7 @@ pub(super) mod res {
                 )
             })
             .collect();
-        println!("Installing these versions: {:#?}", &dep_display);
+        log::trace!("Installing these versions: {:#?}", &dep_display);
 
         let mut result = vec![];
         // We were unable to resolve using the newest version; add and rename packages.
@@ -605,17 +1304,26 @@ pub(super) mod res {
                 version: dep.version.clone(),
                 deps: vec![], // to be filled in after resolution
                 rename,
+                extras: dep.extras.clone(),
             });
         }
         result
     }
 
     /// Assign dependencies to packages-to-install, for use in the lock file.
-    /// Do this only after the dependencies are resolved.
-    fn assign_subdeps(packages: &mut Vec<Package>, updated_ids: &HashMap<u32, u32>) {
+    /// Do this only after the dependencies are resolved. Besides the packages we found
+    /// through each package, we use its requirements, since a dependency shared by several
+    /// packages is only searched for through one of them.
+    pub(super) fn assign_subdeps(
+        packages: &mut [Package],
+        updated_ids: &HashMap<u32, u32>,
+        all_deps: &[Dependency],
+        os: util::Os,
+        py_vers: &Version,
+    ) {
         // We run through the non-cleaned deps first, since the parent may point to
         // one that didn't make the cut, including cases where the versions were identical.
-        let packs2 = packages.clone(); // to search
+        let packs2 = packages.to_vec(); // to search
         for package in packages.iter_mut() {
             let mut children: Vec<(u32, String, Version)> = packs2
                 .iter()
@@ -632,28 +1340,55 @@ pub(super) mod res {
                     }
                 })
                 .collect();
+
+            if let Some(dep) = all_deps.iter().find(|d| d.id == package.id) {
+                for req in dep
+                    .reqs
+                    .iter()
+                    .filter(|r| req_applies(r, &dep.extras, os, py_vers))
+                {
+                    if children
+                        .iter()
+                        .any(|(_, name, _)| util::compare_names(name, &req.name))
+                    {
+                        continue;
+                    }
+                    let found = packs2.iter().find(|p| {
+                        util::compare_names(&p.name, &req.name)
+                            && is_compat(&req.constraints, &p.version)
+                    });
+                    if let Some(p) = found {
+                        children.push((p.id, p.name.clone(), p.version.clone()));
+                    }
+                }
+            }
+
             package.deps.append(&mut children);
         }
     }
 
     /// Determine which dependencies we need to install, using the newest ones which meet
     /// all constraints. Gets data from a cached repo, and Pypi. Returns name, version, and name/version of its deps.
+    /// `overrides` constrain packages wherever they appear, including ones nothing depends on
+    /// directly.
     pub fn resolve(
         reqs: &[Req],
         locked: &[crate::Package],
+        overrides: &[Req],
         os: util::Os,
         py_vers: &Version,
-        //) -> Result<Vec<(String, Version, Vec<Req>)>, reqwest::Error> {
-    ) -> Result<Vec<crate::Package>, reqwest::Error> {
+    ) -> Result<Vec<crate::Package>, ResolutionError> {
+        let reqs = &apply_overrides(reqs, overrides);
         let mut result = Vec::new();
         let mut cache = HashMap::new();
         let mut reqs_searched = Vec::new();
 
         let mut version_cache = HashMap::new();
-        if guess_graph(
+        guess_graph(
             0,
             reqs,
             locked,
+            overrides,
             os,
             &[],
             py_vers,
@@ -661,11 +1396,7 @@ pub(super) mod res {
             &mut cache,
             &mut version_cache,
             &mut reqs_searched,
-        )
-        .is_err()
-        {
-            util::abort("Problem resolving dependencies");
-        }
+        )?;
 
         let mut by_name: HashMap<String, Vec<Dependency>> = HashMap::new();
         for mut dep in result.clone() {
@@ -673,11 +1404,11 @@ pub(super) mod res {
             let fmtd_name = format_name(&dep.name, &version_cache);
             dep.name = fmtd_name.clone();
 
-            if let Some(k) = by_name.get_mut(&dep.name) {
-                k.push(dep)
-            } else {
-                by_name.insert(fmtd_name, vec![dep]);
-            }
+            // Group by the normalized name, so different spellings of a package are one package.
+            by_name
+                .entry(util::normalize_name(&fmtd_name))
+                .or_default()
+                .push(dep);
         }
 
         // Deal with duplicates, conflicts etc. The code above assumed no conflicts, and that
@@ -702,6 +1433,7 @@ pub(super) mod res {
                         version: dep.version.clone(),
                         deps: vec![], // to be filled in after resolution
                         rename: Rename::No,
+                        extras: dep.extras.clone(),
                     });
                 }
                 x if x > 1 => {
@@ -712,6 +1444,11 @@ pub(super) mod res {
                     let inter = dep_types::intersection_many(&constraints);
 
                     if inter.is_empty() {
+                        let conflict = ResolutionError::Conflict {
+                            name: fmtd_name.clone(),
+                            demands: find_demands(reqs, &result, deps),
+                        };
+                        util::print_color(&conflict.to_string(), Color::Yellow);
                         result_cleaned.append(&mut make_renamed_packs(
                             &version_cache,
                             deps,
@@ -732,14 +1469,23 @@ pub(super) mod res {
                     // dealing with cycles etc. There may be ways around this in some cases.
                     // todo: Renaming may not work if the renamed dep uses compiled code.
 
-                    let newest_compatible = deps
+                    // The ranges don't exclude `!=` versions, so check those separately.
+                    let compatible: Vec<&Dependency> = deps
                         .iter()
                         .filter(|dep| {
                             inter
                                 .iter()
                                 .any(|i| i.0 <= dep.version && dep.version <= i.1)
+                                && res::is_compat(&constraints, &dep.version)
                         })
-                        .max_by(|a, b| a.version.cmp(&b.version));
+                        .collect();
+                    // Keep the locked version while it still fits, so adding a package doesn't
+                    // shift pins it doesn't need to.
+                    let newest_compatible = compatible
+                        .iter()
+                        .find(|dep| is_locked(locked, &dep.name, &dep.version))
+                        .or_else(|| compatible.iter().max_by(|a, b| a.version.cmp(&b.version)))
+                        .copied();
 
                     if let Some(best) = newest_compatible {
                         result_cleaned.push(Package {
@@ -749,6 +1495,7 @@ pub(super) mod res {
                             version: best.version.clone(),
                             deps: vec![], // to be filled in after resolution
                             rename: Rename::No,
+                            extras: merge_extras(deps),
                         });
 
                         // Indicate we need to update the parent. We can't do it here, since
@@ -763,9 +1510,12 @@ pub(super) mod res {
                     } else {
                         // We consider the possibility there's a compatible version
                         // that wasn't one of the best-per-req we queried.
-                        println!("⛏️ Digging deeper to resolve dependencies for {}...", name);
+                        log::info!(
+                            "⛏️ Digging deeper to resolve dependencies for {}...",
+                            fmtd_name
+                        );
 
-                        // I think we should query with the raw name, not fmted?
+                        // `by_name` and `version_cache` are both keyed by the normalized name.
                         let versions = &version_cache.get(name).unwrap().2;
 
                         if versions.is_empty() {
@@ -779,23 +1529,35 @@ pub(super) mod res {
                         }
 
                         // Generate dependencies here for all avail versions.
-                        let unresolved_deps = versions.iter().filter_map(|vers| {
-                            if inter.iter().any(|i| i.0 <= *vers && *vers <= i.1) {
-                                Some(Dependency {
-                                    id: 0, // placeholder; we'll assign an id to the one we pick.
-                                    name: fmtd_name.clone(),
-                                    version: vers.clone(),
-                                    reqs: vec![], // todo
-                                    parent: 0,    // todo
-                                })
-                            } else {
-                                None
-                            }
-                        });
-
-                        let mut newest_unresolved = unresolved_deps
-                            .max_by(|a, b| a.version.cmp(&b.version))
-                            .unwrap();
+                        let unresolved_deps: Vec<Dependency> = versions
+                            .iter()
+                            .filter_map(|vers| {
+                                if inter.iter().any(|i| i.0 <= *vers && *vers <= i.1) {
+                                    Some(Dependency {
+                                        id: 0, // placeholder; we'll assign an id to the one we pick.
+                                        name: fmtd_name.clone(),
+                                        version: vers.clone(),
+                                        reqs: vec![], // todo
+                                        extras: vec![],
+                                        parent: 0, // todo
+                                    })
+                                } else {
+                                    None
+                                }
+                            })
+                            .collect();
+
+                        // As above, prefer the locked version.
+                        let mut newest_unresolved = match unresolved_deps
+                            .iter()
+                            .position(|d| is_locked(locked, &d.name, &d.version))
+                        {
+                            Some(i) => unresolved_deps[i].clone(),
+                            None => unresolved_deps
+                                .into_iter()
+                                .max_by(|a, b| a.version.cmp(&b.version))
+                                .unwrap(),
+                        };
 
                         newest_unresolved.id = result.iter().map(|d| d.id).max().unwrap_or(0) + 1;
 
@@ -806,6 +1568,7 @@ pub(super) mod res {
                             version: newest_unresolved.version,
                             deps: vec![], // to be filled in after resolution
                             rename: Rename::No,
+                            extras: merge_extras(deps),
                         });
 
                         // todo: Do a check on newest_unresolved! If fails, execute renamed plan
@@ -821,7 +1584,7 @@ pub(super) mod res {
         }
 
         // Now, assign subdeps, so we can store them in the lock.
-        assign_subdeps(&mut result_cleaned, &updated_ids);
+        assign_subdeps(&mut result_cleaned, &updated_ids, &result, os, py_vers);
 
         let mut a = result;
         for b in &mut a {
@@ -835,6 +1598,424 @@ pub(super) mod res {
 pub mod tests {
     use super::res::*;
     use super::*;
+    use crate::tests::lock_pack;
+
+    #[test]
+    fn overrides_applied() {
+        let lt2 = Constraint::new(ReqType::Lt, Version::new_short(2, 0));
+        let overrides = vec![Req::new("urllib3".into(), vec![lt2.clone()])];
+        let gte = Constraint::new(ReqType::Gte, Version::new(1, 21, 1));
+        let reqs = vec![
+            Req::new("URLLib3".into(), vec![gte.clone()]),
+            Req::new("idna".into(), vec![gte.clone()]),
+        ];
+
+        let applied = apply_overrides(&reqs, &overrides);
+        assert_eq!(applied[0].constraints, vec![gte.clone(), lt2]);
+        assert_eq!(applied[1].constraints, vec![gte]);
+        assert!(!res::is_compat(
+            &applied[0].constraints,
+            &Version::new(2, 0, 2)
+        ));
+    }
+
+    #[test]
+    fn warehouse_data_memoized() {
+        let fetches = std::cell::Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok(WarehouseData {
+                info: WarehouseInfo {
+                    name: "Memo_Test".into(),
+                    requires_dist: None,
+                    requires_python: None,
+                    version: "1.0".into(),
+                },
+                releases: HashMap::new(),
+                urls: vec![],
+            })
+        };
+
+        let first = memoized_warehouse_data(None, "Memo_Test", fetch).unwrap();
+        let second = memoized_warehouse_data(None, "memo-test", fetch).unwrap();
+        assert_eq!(fetches.get(), 1);
+        assert!(Arc::ptr_eq(&first, &second));
+
+        // Another index may have different files.
+        memoized_warehouse_data(Some("https://pypi.example.com/simple/"), "memo-test", fetch)
+            .unwrap();
+        assert_eq!(fetches.get(), 2);
+
+        let failing = || Err(DependencyError::new("Not found"));
+        assert!(memoized_warehouse_data(None, "memo-test-missing", failing).is_err());
+        memoized_warehouse_data(None, "memo-test-missing", fetch).unwrap();
+        assert_eq!(fetches.get(), 3);
+    }
+
+    /// Make index and dependency data available to the resolver without fetching it. Each test
+    /// using this needs its own package names, since the data's kept for the whole run.
+    fn seed_index(name: &str, versions: &[(&str, &[&str])]) {
+        let releases = versions
+            .iter()
+            .map(|(v, _)| {
+                let file = WarehouseRelease {
+                    filename: format!("{}-{}-py3-none-any.whl", name, v),
+                    has_sig: false,
+                    digests: WarehouseDigests {
+                        md5: "".into(),
+                        sha256: "".into(),
+                    },
+                    packagetype: "bdist_wheel".into(),
+                    python_version: "py3".into(),
+                    requires_python: None,
+                    url: "".into(),
+                    dependencies: None,
+                    yanked: false,
+                    core_metadata: false,
+                };
+                ((*v).to_owned(), vec![file])
+            })
+            .collect();
+        memoized_warehouse_data(None, name, || {
+            Ok(WarehouseData {
+                info: WarehouseInfo {
+                    name: name.into(),
+                    requires_dist: None,
+                    requires_python: None,
+                    version: versions.last().unwrap().0.into(),
+                },
+                releases,
+                urls: vec![],
+            })
+        })
+        .unwrap();
+
+        let mut cache = REQ_DATA_CACHE.lock().unwrap();
+        let cache = cache.get_or_insert_with(HashMap::new);
+        for (v, deps) in versions {
+            cache.insert(
+                (util::normalize_name(name), Version::from_str(v).unwrap()),
+                ReqCache {
+                    name: Some(name.into()),
+                    version: (*v).to_owned(),
+                    requires_python: None,
+                    requires_dist: deps.iter().map(|d| (*d).to_owned()).collect(),
+                },
+            );
+        }
+    }
+
+    #[test]
+    fn transitive_upgrade_unpinned() {
+        seed_index(
+            "upgrade-parent",
+            &[
+                ("1.0", &["upgrade-child (<2)"]),
+                ("2.0", &["upgrade-child (<2)"]),
+            ],
+        );
+        seed_index("upgrade-child", &[("1.0", &[]), ("1.1", &[]), ("2.0", &[])]);
+
+        // As locked, with `upgrade-child` left out, since it's being upgraded.
+        let locked = vec![Package {
+            id: 1,
+            parent: 0,
+            name: "upgrade-parent".into(),
+            version: Version::new_short(1, 0),
+            deps: vec![(2, "upgrade-child".into(), Version::new_short(1, 0))],
+            rename: Rename::No,
+            extras: vec![],
+        }];
+        let reqs = vec![Req::from_str("upgrade-parent>=1.0", true).unwrap()];
+
+        let resolved =
+            res::resolve(&reqs, &locked, &[], util::Os::Linux, &Version::new(3, 9, 0)).unwrap();
+        let version_of = |name: &str| {
+            resolved
+                .iter()
+                .find(|p| p.name == name)
+                .map(|p| p.version.to_string())
+        };
+        // The parent's locked pin on the child doesn't hold it back, but the parent's own
+        // requirement does; the parent itself stays locked.
+        assert_eq!(version_of("upgrade-child"), Some("1.1".to_owned()));
+        assert_eq!(version_of("upgrade-parent"), Some("1.0".to_owned()));
+    }
+
+    #[test]
+    fn update_of_transitive_dep() {
+        seed_index(
+            "update-parent",
+            &[
+                ("1.0", &["update-child (>=1.0,<2)"]),
+                ("2.0", &["update-child"]),
+            ],
+        );
+        seed_index("update-child", &[("1.0", &[]), ("1.2", &[]), ("2.0", &[])]);
+
+        let lockpacks = vec![
+            lock_pack(
+                "update-parent",
+                "1.0",
+                &["update-child 1.0 pypi+https://pypi.org/pypi/update-child/1.0/json"],
+            ),
+            lock_pack("update-child", "1.0", &[]),
+        ];
+
+        // As for `pyflow update update-child`.
+        let locked = util::deps::locked_packages(&lockpacks, &["update-child".to_owned()]);
+        let reqs = vec![Req::from_str("update-parent>=1.0", true).unwrap()];
+        let resolved =
+            res::resolve(&reqs, &locked, &[], util::Os::Linux, &Version::new(3, 9, 0)).unwrap();
+
+        let mut versions: Vec<String> = resolved
+            .iter()
+            .map(|p| format!("{} {}", p.name, p.version))
+            .collect();
+        versions.sort();
+        assert_eq!(versions, vec!["update-child 1.2", "update-parent 1.0"]);
+    }
+
+    #[test]
+    fn transitive_markers_evaluated() {
+        seed_index(
+            "marker-parent",
+            &[(
+                "1.0",
+                &[
+                    "marker-backport (>=1.0) ; extra == \"x\" and python_version < \"3.8\"",
+                    "marker-win ; os_name == \"nt\" or platform_system == \"Windows\"",
+                    "marker-child (>=1.0)",
+                ],
+            )],
+        );
+        seed_index("marker-backport", &[("1.0", &[])]);
+        seed_index("marker-win", &[("1.0", &[])]);
+        seed_index("marker-child", &[("1.0", &[])]);
+
+        let resolved_names = |req: &str, os, py_vers: Version| {
+            let reqs = vec![Req::from_str(req, true).unwrap()];
+            let mut names: Vec<String> = res::resolve(&reqs, &[], &[], os, &py_vers)
+                .unwrap()
+                .into_iter()
+                .map(|p| p.name)
+                .collect();
+            names.sort();
+            names
+        };
+
+        assert_eq!(
+            resolved_names("marker-parent[x]", util::Os::Linux, Version::new(3, 7, 0)),
+            vec!["marker-backport", "marker-child", "marker-parent"]
+        );
+        // The extra's requested, but the Python version's excluded.
+        assert_eq!(
+            resolved_names("marker-parent[x]", util::Os::Linux, Version::new(3, 9, 0)),
+            vec!["marker-child", "marker-parent"]
+        );
+        assert_eq!(
+            resolved_names("marker-parent", util::Os::Windows, Version::new(3, 7, 0)),
+            vec!["marker-child", "marker-parent", "marker-win"]
+        );
+    }
+
+    #[test]
+    fn excluded_versions() {
+        let req = Req::from_str("urllib3>=1.21.1,!=1.25.0,!=1.25.1,<1.27", true).unwrap();
+        let compat = |v: &str| is_compat(&req.constraints, &Version::from_str(v).unwrap());
+
+        assert!(compat("1.21.1"));
+        assert!(compat("1.25.2"));
+        assert!(compat("1.26.18"));
+        assert!(!compat("1.21.0"));
+        assert!(!compat("1.25.0"));
+        assert!(!compat("1.25.1"));
+        assert!(!compat("1.27"));
+    }
+
+    #[test]
+    fn prereleases_skipped() {
+        let versions: Vec<Version> = ["1.0.0", "1.1.0", "2.0.0rc1", "2.1.0.dev3"]
+            .iter()
+            .map(|v| Version::from_str(v).unwrap())
+            .collect();
+        assert_eq!(newest(&versions, false), Some(Version::new(1, 1, 0)));
+        assert_eq!(newest(&versions, true), versions.get(3).cloned());
+        // If there's only pre-releases, use them.
+        assert_eq!(newest(&versions[2..], false), versions.get(3).cloned());
+
+        assert!(!allows_prereleases(&[
+            Constraint::from_str(">=1.0").unwrap()
+        ]));
+        assert!(allows_prereleases(&[
+            Constraint::from_str(">=2.0rc1").unwrap()
+        ]));
+    }
+
+    #[test]
+    fn yanked_skipped() {
+        let file = |filename: &str, yanked| WarehouseRelease {
+            filename: filename.into(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: "".into(),
+                sha256: "".into(),
+            },
+            packagetype: "bdist_wheel".into(),
+            python_version: "py3".into(),
+            requires_python: None,
+            url: "".into(),
+            dependencies: None,
+            yanked,
+            core_metadata: false,
+        };
+        let mut releases = HashMap::new();
+        releases.insert("2.0.0".to_owned(), vec![file("pkg-2.0.0.tar.gz", false)]);
+        releases.insert(
+            "2.0.1".to_owned(),
+            vec![
+                file("pkg-2.0.1.tar.gz", true),
+                file("pkg-2.0.1-py3-none-any.whl", true),
+            ],
+        );
+        // Only the sdist's yanked; the wheel's still fine to use.
+        releases.insert(
+            "2.0.2".to_owned(),
+            vec![
+                file("pkg-2.0.2.tar.gz", true),
+                file("pkg-2.0.2-py3-none-any.whl", false),
+            ],
+        );
+        assert_eq!(yanked_versions(&releases), vec![Version::new(2, 0, 1)]);
+
+        let v = Version::new(2, 0, 1);
+        assert!(pinned(&[Constraint::from_str("==2.0.1").unwrap()], &v));
+        assert!(!pinned(&[Constraint::from_str(">=2.0").unwrap()], &v));
+        assert!(!pinned(&[Constraint::from_str("==2.0.2").unwrap()], &v));
+
+        let html = r#"<a href="pkg-2.0.0.tar.gz">pkg-2.0.0.tar.gz</a>
+            <a href="pkg-2.0.1.tar.gz" data-yanked="Broken build">pkg-2.0.1.tar.gz</a>"#;
+        let files = parse_simple_html(html, "https://example.com/simple/pkg/");
+        assert!(!files[0].yanked);
+        assert!(files[1].yanked);
+
+        let json = r#"{"name": "pkg", "files": [
+            {"filename": "a.whl", "url": "a.whl", "yanked": false},
+            {"filename": "b.whl", "url": "b.whl", "yanked": "Broken build"},
+            {"filename": "c.whl", "url": "c.whl"}]}"#;
+        let project: SimpleProject = serde_json::from_str(json).unwrap();
+        let yanked: Vec<bool> = project.files.iter().map(|f| f.yanked).collect();
+        assert_eq!(yanked, vec![false, true, false]);
+    }
+
+    #[test]
+    fn requires_python_honored() {
+        let file = |python_version: &str, requires_python: Option<&str>| WarehouseRelease {
+            filename: "".into(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: "".into(),
+                sha256: "".into(),
+            },
+            packagetype: "bdist_wheel".into(),
+            python_version: python_version.into(),
+            requires_python: requires_python.map(|r| r.to_owned()),
+            url: "".into(),
+            dependencies: None,
+            yanked: false,
+            core_metadata: false,
+        };
+        let py37 = Version::new(3, 7, 0);
+
+        assert!(supports_python(&file("py3", Some(">=3.6")), &py37));
+        assert!(!supports_python(&file("py3", Some(">=3.8")), &py37));
+        assert!(supports_python(
+            &file("py3", Some(">=2.7, !=3.0.*, !=3.1.*")),
+            &py37
+        ));
+        // Without `requires_python`, any of the wheel's tags can match.
+        assert!(supports_python(&file("py2.py3", None), &py37));
+        assert!(supports_python(&file("cp36.cp37.cp38", None), &py37));
+        assert!(!supports_python(&file("cp38", None), &py37));
+        assert!(supports_python(&file("source", Some("")), &py37));
+    }
+
+    #[test]
+    fn conflict_described() {
+        let dep = |id, name: &str, version, reqs: &[&str], parent| Dependency {
+            id,
+            name: name.into(),
+            version,
+            reqs: reqs
+                .iter()
+                .map(|r| Req::from_str(r, true).unwrap())
+                .collect(),
+            extras: vec![],
+            parent,
+        };
+        let top = vec![
+            Req::from_str("a", true).unwrap(),
+            Req::from_str("b", true).unwrap(),
+        ];
+        let all_deps = vec![
+            dep(1, "a", Version::new(1, 0, 0), &["c<2"], 0),
+            dep(2, "b", Version::new(3, 1, 0), &["c>=2"], 0),
+            dep(3, "c", Version::new(1, 9, 0), &[], 1),
+            dep(4, "c", Version::new(2, 4, 0), &[], 2),
+        ];
+
+        let conflict = ResolutionError::Conflict {
+            name: "c".into(),
+            demands: find_demands(&top, &all_deps, &all_deps[2..]),
+        };
+        assert_eq!(
+            conflict.to_string(),
+            "Can't find a version of c that satisfies everything requiring it:\n  \
+             a 1.0.0 needs c <2\n  \
+             b 3.1.0 needs c >=2"
+        );
+
+        let unsatisfiable = ResolutionError::Conflict {
+            name: "c".into(),
+            demands: vec![(requirer(0, &all_deps), vec![])],
+        };
+        assert_eq!(
+            unsatisfiable.to_string(),
+            "No version of c supports this Python version and satisfies pyproject.toml, \
+             which needs any version"
+        );
+    }
+
+    #[test]
+    fn cycle_found() {
+        let dep = |id, name: &str, version, parent| Dependency {
+            id,
+            name: name.into(),
+            version,
+            reqs: vec![],
+            extras: vec![],
+            parent,
+        };
+        let deps = vec![
+            dep(1, "sphinx", Version::new(3, 5, 0), 0),
+            dep(2, "sphinxcontrib-applehelp", Version::new(1, 0, 2), 1),
+            dep(3, "docutils", Version::new(0, 16, 0), 0),
+        ];
+
+        let cycle = find_cycle(&Req::from_str("sphinx>=3", true).unwrap(), 2, &deps);
+        assert_eq!(
+            cycle,
+            Some(vec![
+                "sphinx 3.5.0".to_owned(),
+                "sphinxcontrib-applehelp 1.0.2".to_owned(),
+                "sphinx".to_owned(),
+            ])
+        );
+        // The version on the path doesn't work, so there's no loop to break.
+        assert!(find_cycle(&Req::from_str("sphinx>=4", true).unwrap(), 2, &deps).is_none());
+        // `docutils` isn't on this path.
+        assert!(find_cycle(&Req::from_str("docutils", true).unwrap(), 2, &deps).is_none());
+    }
 
     #[test]
     fn warehouse_versions() {
@@ -859,6 +2040,412 @@ pub mod tests {
         );
     }
 
+    const SIMPLE_PAGE: &str = r#"<!DOCTYPE html>
+<html><body>
+<a href="../../packages/scinot-0.0.10.tar.gz#sha256=abc123">scinot-0.0.10.tar.gz</a>
+<a href="/packages/scinot-0.0.11-py3-none-any.whl#sha256=def456" data-requires-python="&gt;=3.6">scinot-0.0.11-py3-none-any.whl</a>
+</body></html>"#;
+
+    /// Serve canned responses to a fixed number of requests on localhost. Returns the base URL.
+    fn serve_mock_index(responses: Vec<String>) -> String {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 4096];
+                let _ = stream.read(&mut buf);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    /// Serve canned responses by path on localhost, to any number of requests. Unknown paths get
+    /// a 404. Returns the base URL, and the requests made.
+    pub fn serve_mock_routes(
+        routes: HashMap<&'static str, (&'static str, Vec<u8>)>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(vec![]));
+        let requests_ = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut buf = [0; 4096];
+                let n = stream.read(&mut buf).unwrap_or(0);
+                let request = String::from_utf8_lossy(&buf[..n]).into_owned();
+                let path = request.split(' ').nth(1).unwrap_or_default().to_owned();
+                requests_.lock().unwrap().push(request);
+
+                let (status, content_type, body) = match routes.get(path.as_str()) {
+                    Some((content_type, body)) => ("200 OK", *content_type, body.clone()),
+                    None => ("404 Not Found", "text/plain", vec![]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    content_type,
+                    body.len()
+                );
+                let _ = stream.write_all(head.as_bytes());
+                let _ = stream.write_all(&body);
+            }
+        });
+        (format!("http://{}", addr), requests)
+    }
+
+    /// A wheel's contents, with `metadata` as its `METADATA`.
+    fn wheel_bytes(dist_info: &str, metadata: &str) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut zip = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        zip.start_file(format!("{}/METADATA", dist_info), Default::default())
+            .unwrap();
+        zip.write_all(metadata.as_bytes()).unwrap();
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn resolved_from_index_metadata() {
+        let parent_page = r#"{"meta": {"api-version": "1.0"}, "name": "idx-parent", "files": [
+            {"filename": "idx_parent-1.0-py3-none-any.whl", "url": "../../files/idx_parent-1.0-py3-none-any.whl",
+             "hashes": {}, "core-metadata": {"sha256": "abc"}, "dist-info-metadata": {"sha256": "abc"}}
+        ]}"#;
+        // This page doesn't offer separate metadata, so the wheel's downloaded.
+        let child_page = r#"<a href="/files/idx_child-1.0-py3-none-any.whl">idx_child-1.0-py3-none-any.whl</a>
+<a href="/files/idx_child-1.1-py3-none-any.whl">idx_child-1.1-py3-none-any.whl</a>
+<a href="/files/idx_child-2.0-py3-none-any.whl">idx_child-2.0-py3-none-any.whl</a>"#;
+
+        let routes = vec![
+            (
+                "/simple/idx-parent/",
+                (SIMPLE_JSON_TYPE, parent_page.as_bytes().to_vec()),
+            ),
+            (
+                "/files/idx_parent-1.0-py3-none-any.whl.metadata",
+                (
+                    "text/plain",
+                    b"Metadata-Version: 2.1\nName: idx-parent\nVersion: 1.0\n\
+                      Requires-Dist: idx-child (<2,>=1.0)\n"
+                        .to_vec(),
+                ),
+            ),
+            (
+                "/simple/idx-child/",
+                ("text/html", child_page.as_bytes().to_vec()),
+            ),
+            (
+                "/files/idx_child-1.1-py3-none-any.whl",
+                (
+                    "application/octet-stream",
+                    wheel_bytes(
+                        "idx_child-1.1.dist-info",
+                        "Metadata-Version: 2.1\nName: idx-child\nVersion: 1.1\n\
+                         Requires-Dist: idx-extra ; extra == 'x'\n\n\
+                         Requires-Dist: isn't a header in the description\n",
+                    ),
+                ),
+            ),
+        ];
+        let (base, requests) = serve_mock_routes(routes.into_iter().collect());
+        CliConfig {
+            index_url: Some(format!("{}/simple/", base)),
+            ..Default::default()
+        }
+        .make_current();
+
+        let reqs = vec![Req::from_str("idx-parent", true).unwrap()];
+        let resolved =
+            res::resolve(&reqs, &[], &[], util::Os::Linux, &Version::new(3, 9, 0)).unwrap();
+        let mut versions: Vec<String> = resolved
+            .iter()
+            .map(|p| format!("{} {}", p.name, p.version))
+            .collect();
+        versions.sort();
+        assert_eq!(versions, vec!["idx-child 1.1", "idx-parent 1.0"]);
+
+        // The parent's metadata was read without downloading its wheel.
+        let paths: Vec<String> = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|r| r.split(' ').nth(1).unwrap().to_owned())
+            .collect();
+        assert!(paths.contains(&"/files/idx_parent-1.0-py3-none-any.whl.metadata".to_owned()));
+        assert!(!paths.contains(&"/files/idx_parent-1.0-py3-none-any.whl".to_owned()));
+    }
+
+    #[test]
+    fn private_index_metadata_authenticated() {
+        let page = r#"{"meta": {"api-version": "1.0"}, "name": "auth-pkg", "files": [
+            {"filename": "auth_pkg-1.0-py3-none-any.whl", "url": "/files/auth_pkg-1.0-py3-none-any.whl",
+             "hashes": {}, "core-metadata": true}
+        ]}"#;
+        let routes = vec![
+            (
+                "/simple/auth-pkg/",
+                (SIMPLE_JSON_TYPE, page.as_bytes().to_vec()),
+            ),
+            (
+                "/files/auth_pkg-1.0-py3-none-any.whl.metadata",
+                ("text/plain", b"Name: auth-pkg\nVersion: 1.0\n".to_vec()),
+            ),
+        ];
+        let (base, requests) = serve_mock_routes(routes.into_iter().collect());
+        CliConfig {
+            index_url: Some(format!("{}/simple/", base.replace("://", "://user:pass@"))),
+            ..Default::default()
+        }
+        .make_current();
+
+        let reqs = vec![Req::from_str("auth-pkg", true).unwrap()];
+        let resolved =
+            res::resolve(&reqs, &[], &[], util::Os::Linux, &Version::new(3, 9, 0)).unwrap();
+        assert_eq!(resolved[0].name, "auth-pkg");
+
+        // Metadata came from the index, with the credentials, rather than from pydeps.
+        let requests = requests.lock().unwrap();
+        assert!(requests
+            .iter()
+            .any(|r| r.starts_with("GET /files/auth_pkg-1.0-py3-none-any.whl.metadata ")));
+        for request in requests.iter() {
+            assert!(request
+                .to_lowercase()
+                .contains("authorization: basic dxnlcjpwyxnz"));
+        }
+    }
+
+    #[test]
+    fn extra_reqs_pulled_in() {
+        let data = ReqCache {
+            name: Some("requests".into()),
+            version: "2.25.1".into(),
+            requires_python: None,
+            requires_dist: vec![
+                "idna (<3,>=2.5)".into(),
+                "PySocks!=1.5.7,>=1.5.6; extra == \"socks\"".into(),
+                "pyOpenSSL (>=0.14) ; extra == 'security'".into(),
+            ],
+        };
+        let py_vers = Version::new(3, 8, 0);
+
+        let applicable = |extras: &[String]| -> Vec<String> {
+            data.reqs()
+                .into_iter()
+                .filter(|r| req_applies(r, extras, util::Os::Linux, &py_vers))
+                .map(|r| r.name)
+                .collect()
+        };
+
+        assert_eq!(applicable(&[]), vec!["idna"]);
+        assert_eq!(applicable(&["socks".into()]), vec!["idna", "PySocks"]);
+    }
+
+    #[test]
+    fn extras_merged() {
+        let dep = |extras: &[&str]| Dependency {
+            id: 1,
+            name: "celery".into(),
+            version: Version::new(5, 0, 5),
+            reqs: vec![],
+            extras: extras.iter().map(|e| e.to_string()).collect(),
+            parent: 0,
+        };
+
+        assert_eq!(
+            merge_extras(&[dep(&["redis"]), dep(&["auth", "redis"]), dep(&[])]),
+            vec!["auth".to_owned(), "redis".to_owned()]
+        );
+    }
+
+    #[test]
+    fn shared_subdeps_assigned() {
+        let dep = |id, name: &str, version, reqs: &[&str], parent| Dependency {
+            id,
+            name: name.into(),
+            version,
+            reqs: reqs
+                .iter()
+                .map(|r| Req::from_str(r, true).unwrap())
+                .collect(),
+            extras: vec![],
+            parent,
+        };
+        let pack = |d: &Dependency| Package {
+            id: d.id,
+            parent: d.parent,
+            name: d.name.clone(),
+            version: d.version.clone(),
+            deps: vec![],
+            rename: Rename::No,
+            extras: vec![],
+        };
+
+        // `urllib3` is required by both, but was only searched for through `requests`.
+        let deps = vec![
+            dep(
+                1,
+                "requests",
+                Version::new(2, 25, 1),
+                &["urllib3 (<1.27,>=1.21.1)", "idna (<3,>=2.5)"],
+                0,
+            ),
+            dep(
+                2,
+                "botocore",
+                Version::new(1, 20, 0),
+                &["urllib3 (<1.27,>=1.25.4)"],
+                0,
+            ),
+            dep(3, "urllib3", Version::new(1, 26, 4), &[], 1),
+            dep(4, "idna", Version::new(2, 10, 0), &[], 1),
+        ];
+        let mut packages: Vec<Package> = deps.iter().map(pack).collect();
+
+        assign_subdeps(
+            &mut packages,
+            &HashMap::new(),
+            &deps,
+            util::Os::Linux,
+            &Version::new(3, 8, 0),
+        );
+
+        let names = |p: &Package| -> Vec<String> { p.deps.iter().map(|d| d.1.clone()).collect() };
+        assert_eq!(names(&packages[0]), vec!["urllib3", "idna"]);
+        assert_eq!(
+            packages[1].deps,
+            vec![(3, "urllib3".to_owned(), Version::new(1, 26, 4))]
+        );
+        assert!(packages[2].deps.is_empty());
+    }
+
+    #[test]
+    fn simple_url_normalized() {
+        assert_eq!(
+            simple_project_url("https://example.com/simple", "Zope.Interface"),
+            "https://example.com/simple/zope-interface/"
+        );
+        assert_eq!(
+            simple_project_url("https://example.com/simple/", "typing_extensions"),
+            "https://example.com/simple/typing-extensions/"
+        );
+    }
+
+    #[test]
+    fn simple_html() {
+        let files = parse_simple_html(SIMPLE_PAGE, "https://example.com/simple/scinot/");
+        assert_eq!(files.len(), 2);
+        assert_eq!(
+            files[0].url,
+            "https://example.com/packages/scinot-0.0.10.tar.gz"
+        );
+        assert_eq!(files[0].hashes.get("sha256").unwrap(), "abc123");
+        assert_eq!(
+            files[1].url,
+            "https://example.com/packages/scinot-0.0.11-py3-none-any.whl"
+        );
+        assert_eq!(files[1].requires_python, Some(">=3.6".to_owned()));
+
+        let data = simple_to_warehouse("scinot", files);
+        assert_eq!(data.info.version, "0.0.11");
+        assert_eq!(data.releases["0.0.10"][0].packagetype, "sdist");
+        assert_eq!(data.releases["0.0.11"][0].packagetype, "bdist_wheel");
+        assert_eq!(data.releases["0.0.11"][0].python_version, "py3");
+    }
+
+    #[test]
+    fn relative_links_joined() {
+        let page = "https://example.com/simple/scinot/";
+        assert_eq!(
+            join_url(page, "../../packages/a/b.whl"),
+            "https://example.com/packages/a/b.whl"
+        );
+        assert_eq!(
+            join_url(page, "./scinot-1.0.tar.gz"),
+            "https://example.com/simple/scinot/scinot-1.0.tar.gz"
+        );
+        assert_eq!(
+            join_url(page, "//files.example.com/x.whl"),
+            "https://files.example.com/x.whl"
+        );
+        assert_eq!(
+            join_url(page, "https://other.com/x.whl"),
+            "https://other.com/x.whl"
+        );
+    }
+
+    #[test]
+    fn simple_index_html() {
+        let body = SIMPLE_PAGE.to_owned();
+        let base = serve_mock_index(vec![format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )]);
+
+        let data = get_simple_data(&format!("{}/simple", base), "scinot").unwrap();
+        let mut versions: Vec<&String> = data.releases.keys().collect();
+        versions.sort();
+        assert_eq!(versions, vec!["0.0.10", "0.0.11"]);
+        assert_eq!(
+            data.releases["0.0.11"][0].url,
+            format!("{}/packages/scinot-0.0.11-py3-none-any.whl", base)
+        );
+    }
+
+    #[test]
+    fn simple_index_json() {
+        let body = r#"{"meta": {"api-version": "1.0"}, "name": "scinot", "files": [
+            {"filename": "scinot-0.0.11-py3-none-any.whl", "url": "https://files.example.com/scinot-0.0.11-py3-none-any.whl",
+             "hashes": {"sha256": "def456"}, "requires-python": ">=3.6"}
+        ]}"#;
+        let base = serve_mock_index(vec![format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            SIMPLE_JSON_TYPE,
+            body.len(),
+            body
+        )]);
+
+        let data = get_simple_data(&base, "scinot").unwrap();
+        let release = &data.releases["0.0.11"][0];
+        assert_eq!(release.digests.sha256, "def456");
+        assert_eq!(release.requires_python, Some(">=3.6".to_owned()));
+    }
+
+    #[test]
+    fn simple_index_redirect() {
+        let body = r#"{"meta": {"api-version": "1.0"}, "name": "scinot", "files": [
+            {"filename": "scinot-0.0.11-py3-none-any.whl", "url": "files/scinot-0.0.11-py3-none-any.whl",
+             "hashes": {"sha256": "def456"}}
+        ]}"#;
+        // The index redirects the project page to its canonical, trailing-slash URL.
+        let base = serve_mock_index(vec![
+            "HTTP/1.1 301 Moved Permanently\r\nLocation: /simple/scinot/\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into(),
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                SIMPLE_JSON_TYPE,
+                body.len(),
+                body
+            ),
+        ]);
+
+        let data = get_simple_page(&format!("{}/simple/scinot", base), "scinot").unwrap();
+        // The relative link is resolved against the page we were redirected to.
+        assert_eq!(
+            data.releases["0.0.11"][0].url,
+            format!(
+                "{}/simple/scinot/files/scinot-0.0.11-py3-none-any.whl",
+                base
+            )
+        );
+    }
+
     //    #[test]
     //    fn warehouse_deps() {
     //        // Makes API call
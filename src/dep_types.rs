@@ -1,11 +1,13 @@
 use crate::dep_parser::{
-    parse_constraint, parse_pip_str, parse_req, parse_req_pypi_fmt, parse_version, parse_wh_py_vers,
+    parse_constraint, parse_constraints, parse_marker, parse_pip_str, parse_req,
+    parse_req_pypi_fmt, parse_version, parse_wh_py_vers,
 };
 #[mockall_double::double]
 use crate::dep_resolution::res;
-use crate::dep_resolution::WarehouseRelease;
 use crate::{util, CliConfig};
+use nom::character::complete::space0;
 use nom::combinator::all_consuming;
+use nom::sequence::delimited;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::error::Error;
@@ -22,6 +24,7 @@ pub struct Dependency {
     pub name: String,
     pub version: Version,
     pub reqs: Vec<Req>,
+    pub extras: Vec<String>,
     // Identify what constraints drove this, and by what package name/version.
     // The latter is so we know which package to mangle the inputs for, if
     // we need to rename this one.
@@ -39,6 +42,24 @@ impl DependencyError {
             details: details.to_owned(),
         }
     }
+
+    /// Describe a failed parse of `input`, pointing at the column where it went wrong.
+    fn from_parse(what: &str, input: &str, err: nom::Err<(&str, nom::error::ErrorKind)>) -> Self {
+        let rest = match err {
+            nom::Err::Error((rest, _)) | nom::Err::Failure((rest, _)) => rest,
+            nom::Err::Incomplete(_) => "",
+        };
+        let col = input.len() - rest.len() + 1;
+        let found = if rest.is_empty() {
+            "unexpected end of input".to_owned()
+        } else {
+            format!("unexpected `{}`", rest)
+        };
+        Self::new(&format!(
+            "Problem parsing {}: `{}`: {} at column {}",
+            what, input, found, col
+        ))
+    }
 }
 
 impl Error for DependencyError {
@@ -138,13 +159,26 @@ impl PartialOrd for VersionModifier {
 /// An exact, 3-number Semver version. With some possible extras.
 #[derive(Clone, Default, Deserialize, Eq)]
 pub struct Version {
+    /// PEP 440 epoch, eg `1` in `1!2.0`, for when a project changes its versioning scheme.
+    /// Versions without one are in epoch 0.
+    pub epoch: Option<u32>,
     pub major: Option<u32>,
     pub minor: Option<u32>,
     pub patch: Option<u32>,
-    pub extra_num: Option<u32>,                   // eg 4.2.3.1
+    pub extra_num: Option<u32>, // eg 4.2.3.1
+    /// Any release numbers after `extra_num`, eg `5, 6` in `1.2.3.4.5.6`.
+    #[serde(default)]
+    pub more_nums: Vec<u32>,
     pub modifier: Option<(VersionModifier, u32)>, // eg a1
+    /// PEP 440 developmental release, eg `4` in `1.5.0.dev4`.
+    pub dev: Option<u32>,
+    /// PEP 440 post-release, eg `1` in `1.0.post1`.
+    pub post: Option<u32>,
     /// if `true` the star goes in the first `None` slot. Remaining slots should be `None`
     pub star: bool,
+    /// PEP 440 local label, eg `cpu` in `1.0+cpu`. A version with one comes after the same
+    /// version without; constraints that don't specify one ignore it.
+    pub local: Option<String>,
 }
 
 impl Version {
@@ -154,8 +188,13 @@ impl Version {
             minor: Some(minor),
             patch: Some(patch),
             extra_num: None,
+            more_nums: Vec::new(),
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }
     }
 
@@ -165,8 +204,13 @@ impl Version {
             minor: None,
             patch: None,
             extra_num: None,
+            more_nums: Vec::new(),
             modifier: None,
             star: true,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }
     }
 
@@ -177,8 +221,13 @@ impl Version {
             minor: Some(minor),
             patch: None,
             extra_num: None,
+            more_nums: Vec::new(),
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }
     }
 
@@ -188,8 +237,13 @@ impl Version {
             minor,
             patch,
             extra_num: None,
+            more_nums: Vec::new(),
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }
     }
 
@@ -205,8 +259,13 @@ impl Version {
             minor,
             patch,
             extra_num: None,
+            more_nums: Vec::new(),
             modifier: None,
             star,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         }
     }
 
@@ -217,23 +276,105 @@ impl Version {
             minor: Some(self.minor.unwrap_or(0)),
             patch: Some(self.patch.unwrap_or(0)),
             extra_num: self.extra_num,
+            more_nums: self.more_nums.clone(),
             modifier: self.modifier.clone(),
             star: false,
+            dev: self.dev,
+            epoch: self.epoch,
+            post: self.post,
+            local: self.local.clone(),
         }
     }
 
+    /// A PEP 440 pre-release, ie an alpha, beta, release candidate, or dev release. These aren't
+    /// picked unless a constraint asks for one.
+    pub fn is_prerelease(&self) -> bool {
+        self.dev.is_some()
+            || matches!(
+                self.modifier,
+                Some((
+                    VersionModifier::Alpha
+                        | VersionModifier::Beta
+                        | VersionModifier::ReleaseCandidate,
+                    _
+                ))
+            )
+    }
+
+    /// How this ranks against versions with the same release numbers, per PEP 440. Eg
+    /// `1.0.dev1 < 1.0a1.dev1 < 1.0a1 < 1.0rc1 < 1.0 < 1.0.post1.dev1 < 1.0.post1`.
+    fn pre_key(&self) -> (u8, u32, u32, u32) {
+        let (rank, num) = match (&self.modifier, self.dev) {
+            (Some((modifier, num)), _) => (modifier.clone().orderval() + 1, *num),
+            // A dev release of the final version comes before its pre-releases.
+            (None, Some(_)) if self.post.is_none() => (0, 0),
+            (None, _) => (VersionModifier::Null.orderval() + 1, 0),
+        };
+        // A post-release comes after the release it's of, and its own dev releases.
+        let post = self.post.map_or(0, |p| p.saturating_add(1));
+        (rank, num, post, self.dev.unwrap_or(u32::MAX))
+    }
+
+    /// How the local label ranks, per PEP 440: segment by segment, with numbers compared as
+    /// such, and above any letters. Eg `1.0 < 1.0+abc < 1.0+abc.2 < 1.0+1`.
+    fn local_key(&self) -> Option<Vec<(bool, u64, String)>> {
+        self.local.as_ref().map(|local| {
+            local
+                .split('.')
+                .map(|seg| match seg.parse::<u64>() {
+                    Ok(n) => (true, n, String::new()),
+                    Err(_) => (false, 0, seg.to_lowercase()),
+                })
+                .collect()
+        })
+    }
+
     pub const fn _max() -> Self {
         Self::new_opt(Some(MAX_VER), None, None)
     }
 
+    /// Release numbers after the first four, eg `[5, 6]` in `1.2.3.4.5.6`, without trailing
+    /// zeros; `1.0.0.0.0` is the same release as `1.0`.
+    fn more_nums_trimmed(&self) -> &[u32] {
+        let len = self
+            .more_nums
+            .iter()
+            .rposition(|n| *n != 0)
+            .map_or(0, |i| i + 1);
+        &self.more_nums[..len]
+    }
+
     /// Prevents repetition.
     fn add_str_mod(&self, s: &mut String) {
+        if let Some(epoch) = self.epoch.filter(|e| *e > 0) {
+            s.insert_str(0, &format!("{}!", epoch));
+        }
         if let Some(extra_num) = self.extra_num {
             s.push_str(&format!(".{}", extra_num.to_string()));
         }
+        for num in &self.more_nums {
+            s.push_str(&format!(".{}", num));
+        }
+        s.push_str(&self.suffix());
+    }
+
+    /// The PEP 440 segments after the release numbers, eg `rc1.post2.dev3+cpu`.
+    fn suffix(&self) -> String {
+        let mut suffix = String::new();
         if let Some((modifier, num)) = self.modifier.clone() {
-            s.push_str(&format!("{}{}", modifier.to_string(), num.to_string()));
+            suffix.push_str(&format!("{}{}", modifier.to_string(), num));
+        }
+        if let Some(post) = self.post {
+            suffix.push_str(&format!(".post{}", post));
         }
+        if let Some(dev) = self.dev {
+            suffix.push_str(&format!(".dev{}", dev));
+        }
+        if let Some(local) = &self.local {
+            suffix.push('+');
+            suffix.push_str(local);
+        }
+        suffix
     }
 
     pub fn to_string_med(&self) -> String {
@@ -247,13 +388,6 @@ impl Version {
         result
     }
 
-    /// unlike Display, which overwrites to_string, don't add colors.
-    pub fn to_string_no_patch(&self) -> String {
-        let mut result = format!("{}.{}", self.major.unwrap_or(0), self.minor.unwrap_or(0));
-        self.add_str_mod(&mut result);
-        result
-    }
-
     pub fn to_string_color(&self) -> String {
         self.colorize().unwrap_or_else(|_| self.to_string())
     }
@@ -265,15 +399,15 @@ impl Version {
         let dot_c = Some(Color::Yellow); // Dark
 
         let mut suffix = "".to_string();
-        if let Some(num) = self.extra_num {
+        for num in self.extra_num.iter().chain(&self.more_nums) {
             suffix.push('.');
             suffix.push_str(&num.to_string());
         }
-        if let Some((modifier, num)) = self.modifier.clone() {
-            suffix.push_str(&modifier.to_string());
-            suffix.push_str(&num.to_string());
-        }
+        suffix.push_str(&self.suffix());
         buf.set_color(ColorSpec::new().set_fg(num_c))?;
+        if let Some(epoch) = self.epoch.filter(|e| *e > 0) {
+            write!(buf, "{}!", epoch)?;
+        }
         write!(buf, "{}", self.major.unwrap_or(0))?;
         if let Some(x) = self.minor {
             buf.set_color(ColorSpec::new().set_fg(dot_c))?;
@@ -306,9 +440,6 @@ impl FromStr for Version {
 
 impl Ord for Version {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
-        // None version modifiers should rank highest. Ie 17.0 > 17.0rc1
-        let self_mod = self.modifier.clone().unwrap_or((VersionModifier::Null, 0));
-        let other_mod = other.modifier.clone().unwrap_or((VersionModifier::Null, 0));
         // Mirror the set field if we have a star present
         let cmp_star = |obj: Option<u32>, oth: Option<u32>, star: bool| -> cmp::Ordering {
             let none_val = if star {
@@ -324,11 +455,27 @@ impl Ord for Version {
             };
             obj.unwrap_or(none_val).cmp(&oth.unwrap_or(none_val))
         };
+        // Versions in a later epoch are newer, whatever their numbers.
+        let epoch = self.epoch.unwrap_or(0).cmp(&other.epoch.unwrap_or(0));
+        if !matches!(epoch, cmp::Ordering::Equal) {
+            return epoch;
+        }
+
         let star = self.star || other.star;
         let maj = cmp_star(self.major, other.major, star);
         let min = cmp_star(self.minor, other.minor, star);
         let pat = cmp_star(self.patch, other.patch, star);
-        let ext = cmp_star(self.extra_num, other.extra_num, star);
+        let ext = cmp_star(self.extra_num, other.extra_num, star).then_with(|| {
+            // Shorter releases are padded with zeros.
+            let (ours, theirs) = (self.more_nums_trimmed(), other.more_nums_trimmed());
+            let len = ours.len().max(theirs.len());
+            let padded = |nums: &[u32]| {
+                let mut nums = nums.to_vec();
+                nums.resize(len, 0);
+                nums
+            };
+            padded(ours).cmp(&padded(theirs))
+        });
         if !matches!(maj, cmp::Ordering::Equal) {
             maj
         } else if !matches!(min, cmp::Ordering::Equal) {
@@ -338,11 +485,9 @@ impl Ord for Version {
         } else if !matches!(ext, cmp::Ordering::Equal) {
             ext
         } else if !star {
-            if self_mod.0 == other_mod.0 {
-                self_mod.1.cmp(&other_mod.1)
-            } else {
-                self_mod.0.cmp(&other_mod.0)
-            }
+            self.pre_key()
+                .cmp(&other.pre_key())
+                .then_with(|| self.local_key().cmp(&other.local_key()))
         } else {
             cmp::Ordering::Equal
         }
@@ -363,14 +508,18 @@ impl PartialEq for Version {
 
 impl Hash for Version {
     fn hash<H: Hasher>(&self, state: &mut H) {
+        self.epoch.unwrap_or(0).hash(state);
         self.major.hash(state);
         self.minor.unwrap_or(0).hash(state);
         self.patch.unwrap_or(0).hash(state);
         self.extra_num.unwrap_or(0).hash(state);
+        self.more_nums_trimmed().hash(state);
         self.modifier
             .clone()
             .unwrap_or((VersionModifier::Null, 0))
             .hash(state);
+        self.post.hash(state);
+        self.dev.hash(state);
         self.star.hash(state);
     }
 }
@@ -383,14 +532,19 @@ impl Hash for Version {
 
 impl fmt::Display for Version {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let mut version = if let Some(x) = self.major {
-            x.to_string()
-        } else {
-            "*".to_string()
+        let mut version = match self.epoch {
+            Some(epoch) if epoch > 0 => format!("{}!", epoch),
+            _ => String::new(),
         };
+        if let Some(x) = self.major {
+            version.push_str(&x.to_string());
+        } else {
+            version.push('*');
+        }
         if self.major.is_some() {
             let mut star_handled = false;
-            let parts = vec![self.minor, self.patch, self.extra_num];
+            let mut parts = vec![self.minor, self.patch, self.extra_num];
+            parts.extend(self.more_nums.iter().map(|n| Some(*n)));
             for part in parts.iter() {
                 if let Some(p) = part {
                     version.push('.');
@@ -406,10 +560,20 @@ impl fmt::Display for Version {
                     version.push_str(&modifier.to_string());
                     version.push_str(&num.to_string());
                 }
+                if let Some(post) = self.post {
+                    version.push_str(&format!(".post{}", post));
+                }
+                if let Some(dev) = self.dev {
+                    version.push_str(&format!(".dev{}", dev));
+                }
             }
             if self.star && !star_handled {
                 version.push('*');
             }
+            if let Some(local) = &self.local {
+                version.push('+');
+                version.push_str(local);
+            }
         }
         write!(f, "{}", version)
     }
@@ -433,6 +597,7 @@ pub enum ReqType {
     Caret,
     Tilde,
     TildeEq, // PEP440 ~= is different from ~
+    ArbitraryEq, // PEP440 ===
              // todo wildcard
 }
 
@@ -449,6 +614,7 @@ impl ToString for ReqType {
             Self::Caret => "^".into(),
             Self::Tilde => "~".into(),
             Self::TildeEq => "~=".into(),
+            Self::ArbitraryEq => "===".into(),
         }
     }
 }
@@ -467,6 +633,7 @@ impl FromStr for ReqType {
             "^" => Ok(Self::Caret),
             "~" => Ok(Self::Tilde),
             "~=" => Ok(Self::TildeEq),
+            "===" => Ok(Self::ArbitraryEq),
             _ => Err(DependencyError::new("Problem parsing ReqType")),
         }
     }
@@ -485,7 +652,7 @@ impl FromStr for Constraint {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         all_consuming(parse_constraint)(s)
-            .map_err(|_| DependencyError::new(&format!("Problem parsing constraint: {}", s)))
+            .map_err(|e| DependencyError::from_parse("constraint", s, e))
             .map(|(_, c)| c)
     }
 }
@@ -503,23 +670,16 @@ impl Constraint {
         }
     }
 
-    /// From a comma-separated list
+    /// From a comma-separated list. Whitespace around operators and versions is ignored, and
+    /// a missing comma before an operator is tolerated, eg `>=1.0<2.0`.
     pub fn from_str_multiple(vers: &str) -> Result<Vec<Self>, DependencyError> {
-        let mut result = vec![];
-        let vers = vers.replace(" ", "");
-        let vers = if vers.is_empty() {
-            ">=2.0".to_string()
-        } else {
-            vers
-        };
-
-        for req in vers.split(',') {
-            match Self::from_str(req) {
-                Ok(r) => result.push(r),
-                Err(e) => return Err(e),
-            }
+        if vers.trim().is_empty() {
+            return Ok(vec![Self::new(ReqType::Gte, Version::new_short(2, 0))]);
         }
-        Ok(result)
+
+        all_consuming(delimited(space0, parse_constraints, space0))(vers)
+            .map_err(|e| DependencyError::from_parse("constraints", vers, e))
+            .map(|(_, cs)| cs)
     }
 
     /// ie cp37, a version from Pypi. Eg: "py3", "cp35.cp36.cp37.cp38", "cp26", "py2.py3",
@@ -555,7 +715,12 @@ impl Constraint {
     /// Find the lowest and highest compatible versions. Return a vec, since the != requirement type
     /// has two ranges.
     pub fn compatible_range(&self) -> Vec<(Version, Version)> {
-        let highest = Version::_max();
+        // Versions we build from the constraint's are in its epoch.
+        let in_epoch = |version: Version| Version {
+            epoch: self.version.epoch,
+            ..version
+        };
+        let highest = in_epoch(Version::_max());
         let lowest = Version::new(0, 0, 0);
         let max;
 
@@ -585,32 +750,33 @@ impl Constraint {
         // Note that other than for not-equals, the the resulting Vec has len 1.
         match self.type_ {
             ReqType::Exact => vec![(self.version.new_unstar(), self.get_max_version())],
+            ReqType::ArbitraryEq => vec![(self.version.clone(), self.version.clone())],
             ReqType::Gte => vec![(self.version.new_unstar(), highest)],
             ReqType::Lte => vec![(lowest, self.version.new_unstar())],
             ReqType::Gt => vec![(
-                Version::new(
+                in_epoch(Version::new(
                     self.version.major.unwrap_or(0),
                     self.version.minor.unwrap_or(0),
                     self.version.patch.unwrap_or(0) + 1,
-                ),
+                )),
                 highest,
             )],
             ReqType::Lt => {
                 let (major, minor, patch) =
                     safely_subtract(self.version.major, self.version.minor, self.version.patch);
-                vec![(lowest, Version::new(major, minor, patch))]
+                vec![(lowest, in_epoch(Version::new(major, minor, patch)))]
             }
             ReqType::Ne => {
                 let (major, minor, patch) =
                     safely_subtract(self.version.major, self.version.minor, self.version.patch);
                 vec![
-                    (lowest, Version::new(major, minor, patch)),
+                    (lowest, in_epoch(Version::new(major, minor, patch))),
                     (
-                        Version::new(
+                        in_epoch(Version::new(
                             self.version.major.unwrap_or(0),
                             self.version.minor.unwrap_or(0),
                             self.version.patch.unwrap_or(0) + 1,
-                        ),
+                        )),
                         highest,
                     ),
                 ]
@@ -619,17 +785,26 @@ impl Constraint {
                 max = self.get_max_version();
                 // We need to use Lt logic for ^ and ~.
                 let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version.clone(), Version::new(major, minor, patch))]
+                vec![(
+                    self.version.clone(),
+                    in_epoch(Version::new(major, minor, patch)),
+                )]
             }
             ReqType::Tilde => {
                 max = self.get_max_version();
                 let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version.clone(), Version::new(major, minor, patch))]
+                vec![(
+                    self.version.clone(),
+                    in_epoch(Version::new(major, minor, patch)),
+                )]
             }
             ReqType::TildeEq => {
                 max = self.get_max_version();
                 let (major, minor, patch) = safely_subtract(max.major, max.minor, max.patch);
-                vec![(self.version.clone(), Version::new(major, minor, patch))]
+                vec![(
+                    self.version.clone(),
+                    in_epoch(Version::new(major, minor, patch)),
+                )]
             }
         }
     }
@@ -638,6 +813,20 @@ impl Constraint {
         let min = self.version.clone();
         let max;
 
+        let full = version;
+        // Local labels only count if the constraint specifies one; eg `==1.0` and `<=1.0` match
+        // `1.0+cpu`.
+        let public;
+        let version = if self.version.local.is_none() && version.local.is_some() {
+            public = Version {
+                local: None,
+                ..version.clone()
+            };
+            &public
+        } else {
+            version
+        };
+
         match self.type_ {
             ReqType::Exact => {
                 if !self.version.star && !version.star {
@@ -652,6 +841,8 @@ impl Constraint {
             ReqType::Gt => self.version < *version,
             ReqType::Lt => self.version > *version,
             ReqType::Ne => self.version != *version,
+            // Arbitrary equality is a plain string match, local label included.
+            ReqType::ArbitraryEq => self.version.to_string() == full.to_string(),
             ReqType::Caret => {
                 max = self.get_max_version();
                 min <= *version && *version < max
@@ -672,7 +863,7 @@ impl Constraint {
 
     /// This internal function is to DRY Caret and Tilde max versions
     fn get_max_version(&self) -> Version {
-        match self.type_ {
+        let mut max = match self.type_ {
             ReqType::Exact => {
                 if self.version.star {
                     if self.version.major.is_none() {
@@ -724,7 +915,13 @@ impl Constraint {
 
              */
             ReqType::TildeEq => {
-                if self.version.patch.is_some() {
+                if self.version.extra_num.is_some() {
+                    Version::new(
+                        self.version.major.unwrap_or(0),
+                        self.version.minor.unwrap_or(0),
+                        self.version.patch.unwrap_or(0) + 1,
+                    )
+                } else if self.version.patch.is_some() {
                     Version::new(
                         self.version.major.unwrap_or(0),
                         self.version.minor.unwrap_or(0) + 1,
@@ -739,7 +936,10 @@ impl Constraint {
             // Not sure we would ever actually use this with other types. So
             // just return a clone
             _ => self.version.clone(),
-        }
+        };
+        // Eg `^1!2.0` allows up to `1!3.0`, not `3.0`.
+        max.epoch = self.version.epoch;
+        max
     }
 }
 
@@ -824,6 +1024,233 @@ impl Extras {
     }
 }
 
+/// A variable that can appear in a PEP 508 environment marker.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerVar {
+    SysPlatform,
+    PythonVersion,
+    PythonFullVersion,
+    OsName,
+    PlatformMachine,
+    PlatformSystem,
+    Extra,
+}
+
+impl MarkerVar {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::SysPlatform => "sys_platform",
+            Self::PythonVersion => "python_version",
+            Self::PythonFullVersion => "python_full_version",
+            Self::OsName => "os_name",
+            Self::PlatformMachine => "platform_machine",
+            Self::PlatformSystem => "platform_system",
+            Self::Extra => "extra",
+        }
+    }
+
+    fn is_version(self) -> bool {
+        matches!(self, Self::PythonVersion | Self::PythonFullVersion)
+    }
+}
+
+/// One side of a marker comparison.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MarkerValue {
+    Var(MarkerVar),
+    Literal(String),
+}
+
+impl MarkerValue {
+    fn resolve(&self, env: &MarkerEnv) -> String {
+        match self {
+            Self::Var(var) => env.get(*var),
+            Self::Literal(s) => s.clone(),
+        }
+    }
+}
+
+impl fmt::Display for MarkerValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Var(var) => write!(f, "{}", var.as_str()),
+            // Single quotes, so the marker can be placed in a TOML string without escaping.
+            Self::Literal(s) => write!(f, "'{}'", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MarkerOp {
+    Cmp(ReqType),
+    In,
+    NotIn,
+}
+
+impl fmt::Display for MarkerOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Cmp(r) => write!(f, "{}", r.to_string()),
+            Self::In => write!(f, "in"),
+            Self::NotIn => write!(f, "not in"),
+        }
+    }
+}
+
+/// A PEP 508 environment marker, eg the part after `;` in
+/// `pywin32; sys_platform == 'win32' and python_version >= '3.6'`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Marker {
+    Compare(MarkerValue, MarkerOp, MarkerValue),
+    And(Box<Marker>, Box<Marker>),
+    Or(Box<Marker>, Box<Marker>),
+}
+
+impl Marker {
+    /// Determine if the marker applies to an environment.
+    pub fn evaluate(&self, env: &MarkerEnv) -> bool {
+        match self {
+            Self::And(a, b) => a.evaluate(env) && b.evaluate(env),
+            Self::Or(a, b) => a.evaluate(env) || b.evaluate(env),
+            Self::Compare(lhs, op, rhs) => {
+                // `extra == 'socks'` holds if any of the extras is `socks`.
+                if let (MarkerValue::Var(MarkerVar::Extra), MarkerValue::Literal(extra))
+                | (MarkerValue::Literal(extra), MarkerValue::Var(MarkerVar::Extra)) = (lhs, rhs)
+                {
+                    let installed = env.extras.iter().any(|e| util::compare_names(e, extra));
+                    return match op {
+                        MarkerOp::Cmp(ReqType::Exact) => installed,
+                        MarkerOp::Cmp(ReqType::Ne) => !installed,
+                        _ => false,
+                    };
+                }
+
+                let (l, r) = (lhs.resolve(env), rhs.resolve(env));
+                let is_version = [lhs, rhs]
+                    .iter()
+                    .any(|v| matches!(v, MarkerValue::Var(var) if var.is_version()));
+
+                match op {
+                    MarkerOp::In => r.contains(&l),
+                    MarkerOp::NotIn => !r.contains(&l),
+                    MarkerOp::Cmp(type_) => {
+                        if is_version {
+                            if let (Ok(lv), Ok(rv)) = (Version::from_str(&l), Version::from_str(&r))
+                            {
+                                return Constraint::new(*type_, rv).is_compatible(&lv);
+                            }
+                        }
+                        // Non-version values are compared as strings, per PEP 508.
+                        match type_ {
+                            ReqType::Exact | ReqType::ArbitraryEq => l == r,
+                            ReqType::Ne => l != r,
+                            ReqType::Lt => l < r,
+                            ReqType::Lte => l <= r,
+                            ReqType::Gt => l > r,
+                            ReqType::Gte => l >= r,
+                            _ => false,
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl FromStr for Marker {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        all_consuming(delimited(space0, parse_marker, space0))(s)
+            .map_err(|e| DependencyError::from_parse("environment marker", s, e))
+            .map(|(_, m)| m)
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Compare(lhs, op, rhs) => write!(f, "{} {} {}", lhs, op, rhs),
+            Self::Or(a, b) => write!(f, "{} or {}", a, b),
+            Self::And(a, b) => {
+                // `and` binds tighter than `or`, so parenthesize `or`s inside it.
+                for (i, m) in [a, b].iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " and ")?;
+                    }
+                    match m.as_ref() {
+                        Self::Or(_, _) => write!(f, "({})", m)?,
+                        _ => write!(f, "{}", m)?,
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The environment that markers are evaluated against.
+#[derive(Clone, Debug)]
+pub struct MarkerEnv {
+    pub os: util::Os,
+    pub python_version: Version,
+    /// The extras the package requiring this is installed with, eg `socks` for `requests[socks]`.
+    pub extras: Vec<String>,
+}
+
+impl MarkerEnv {
+    pub fn new(os: util::Os, python_version: &Version) -> Self {
+        Self {
+            os,
+            python_version: python_version.clone(),
+            extras: vec![],
+        }
+    }
+
+    pub fn with_extras(mut self, extras: &[String]) -> Self {
+        self.extras = extras.to_vec();
+        self
+    }
+
+    fn get(&self, var: MarkerVar) -> String {
+        use util::Os;
+
+        match var {
+            MarkerVar::SysPlatform => match self.os.family() {
+                Os::Windows => "win32",
+                Os::Mac => "darwin",
+                _ => "linux",
+            }
+            .into(),
+            MarkerVar::OsName => match self.os.family() {
+                Os::Windows => "nt",
+                _ => "posix",
+            }
+            .into(),
+            MarkerVar::PlatformSystem => match self.os.family() {
+                Os::Windows => "Windows",
+                Os::Mac => "Darwin",
+                _ => "Linux",
+            }
+            .into(),
+            MarkerVar::PlatformMachine => match self.os {
+                Os::Linux32 => "i686",
+                Os::LinuxArm64 | Os::LinuxMuslArm64 => "aarch64",
+                Os::LinuxArmv7 => "armv7l",
+                Os::Windows32 => "x86",
+                Os::Windows => "AMD64",
+                Os::MacArm64 => "arm64",
+                _ => "x86_64",
+            }
+            .into(),
+            MarkerVar::PythonVersion => self.python_version.to_string_med(),
+            MarkerVar::PythonFullVersion => self.python_version.to_string(),
+            // Extras are compared in `Marker::evaluate`, since there may be several.
+            MarkerVar::Extra => "".into(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, PartialEq)]
 pub struct Req {
     pub name: String,
@@ -833,7 +1260,16 @@ pub struct Req {
     pub python_version: Option<Vec<Constraint>>,
     pub install_with_extras: Option<Vec<String>>,
     pub path: Option<String>,
-    pub git: Option<String>, // String is the git repo. // todo: Branch
+    pub git: Option<String>, // String is the git repo.
+    /// The git branch to check out. Once installed, the commit we built.
+    pub branch: Option<String>,
+    /// For path requirements: use the project in place, instead of building and installing it.
+    #[serde(default)]
+    pub develop: bool,
+    /// A wheel or sdist to download directly, instead of finding one on an index.
+    pub url: Option<String>,
+    #[serde(skip)]
+    pub markers: Option<Marker>,
 }
 
 impl Req {
@@ -847,6 +1283,10 @@ impl Req {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: None,
         }
     }
 
@@ -860,6 +1300,10 @@ impl Req {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: None,
         }
     }
 
@@ -869,7 +1313,7 @@ impl Req {
         } else {
             all_consuming(parse_req)(s)
         })
-        .map_err(|_| DependencyError::new(&format!("Problem parsing version requirement: {}", s)))
+        .map_err(|e| DependencyError::from_parse("version requirement", s, e))
         .map(|x| x.1)
     }
 
@@ -879,64 +1323,34 @@ impl Req {
         all_consuming(parse_pip_str)(s).ok().map(|x| x.1)
     }
 
-    pub fn from_warehouse_release(
-        name: String,
-        version: String,
-        release: WarehouseRelease,
-    ) -> Self {
-        let ver = Version::from_str(&version)
-            .ok()
-            .unwrap_or_else(|| Version::new_star(None, None, None, true));
-        let constraint = Constraint::new(ReqType::Exact, ver);
-        let py_ver = Constraint::from_wh_py_vers(&release.python_version);
-        let requires = if let Some(x) = release.requires_python {
-            if x.as_str() > "" {
-                if let Ok(c) = Constraint::from_str_multiple(&x) {
-                    Some(c)
-                } else {
-                    None
-                }
-            } else {
-                None
+    /// eg `saturn = "^0.3.1"` or `matplotlib = "3.1.1"`
+    pub fn to_cfg_string(&self) -> String {
+        let (name, value) = self.cfg_entry(false);
+        format!("{} = {}", name, value)
+    }
+
+    /// The name and value for `to_cfg_string`. With `as_table`, the value is always an inline
+    /// table, as in a list of alternatives, eg `{ version = "^1.0", python = "<3.8" }`.
+    pub fn cfg_entry(&self, as_table: bool) -> (String, String) {
+        // Where to get the package, if not from the index.
+        let mut fields = vec![];
+        if let Some(path) = &self.path {
+            fields.push(format!(r#"path = "{}""#, path));
+            if self.develop {
+                fields.push("develop = true".to_owned());
             }
-        } else {
-            None
-        };
-        let py_req = requires.unwrap_or_else(|| py_ver.unwrap());
-
-        Self {
-            name,
-            constraints: vec![constraint],
-            extra: None,
-            sys_platform: None,
-            python_version: Some(py_req),
-            install_with_extras: None,
-            path: None,
-            git: None,
-        }
-    }
-
-    /// Clone the Req but set a python requirement if python_version.is_none()
-    pub fn clone_or_default_py(&self, python_version: &Version) -> Self {
-        Self {
-            name: self.name.clone(),
-            constraints: self.constraints.clone(),
-            extra: self.extra.clone(),
-            sys_platform: self.sys_platform,
-            python_version: if let Some(ref pv) = self.python_version {
-                Some(pv.clone())
-            } else {
-                Some(vec![Constraint::new(ReqType::Gte, python_version.clone())])
-            },
-            install_with_extras: self.install_with_extras.clone(),
-            path: self.path.clone(),
-            git: self.path.clone(),
+        } else if let Some(git) = &self.git {
+            fields.push(format!(r#"git = "{}""#, git));
+            if let Some(branch) = &self.branch {
+                fields.push(format!(r#"branch = "{}""#, branch));
+            }
+        } else if let Some(url) = &self.url {
+            fields.push(format!(r#"url = "{}""#, url));
         }
-    }
 
-    /// eg `saturn = "^0.3.1"` or `matplotlib = "3.1.1"`
-    pub fn to_cfg_string(&self) -> String {
-        match self.constraints.len() {
+        let (name, constraints) = match self.constraints.len() {
+            // These don't need a version.
+            0 if !fields.is_empty() => (self.name.clone(), String::new()),
             0 => {
                 let (name, latest_version) = if let Ok((fmtd_name, version, _)) =
                     res::get_version_info(
@@ -954,21 +1368,40 @@ impl Req {
                 } else {
                     util::abort(&format!("Unable to find version info for {:?}", &self.name));
                 };
-                format!(
-                    r#"{} = "{}""#,
+                (
                     name,
-                    Constraint::new(ReqType::Caret, latest_version).to_string2(true, false)
+                    Constraint::new(ReqType::Caret, latest_version).to_string2(true, false),
                 )
             }
-            _ => format!(
-                r#"{} = "{}""#,
-                self.name,
+            _ => (
+                self.name.clone(),
                 self.constraints
                     .iter()
                     .map(|r| r.to_string2(true, false))
                     .collect::<Vec<String>>()
-                    .join(", ")
+                    .join(", "),
             ),
+        };
+
+        if !constraints.is_empty() {
+            fields.insert(0, format!(r#"version = "{}""#, constraints));
+        }
+        if let Some(extras) = self.install_with_extras.as_ref().filter(|e| !e.is_empty()) {
+            let extras: Vec<String> = extras.iter().map(|e| format!(r#""{}""#, e)).collect();
+            fields.push(format!("extras = [{}]", extras.join(", ")));
+        }
+        if let Some(py_vers) = &self.python_version {
+            let py_vers: Vec<String> = py_vers.iter().map(|c| c.to_string2(true, false)).collect();
+            fields.push(format!(r#"python = "{}""#, py_vers.join(", ")));
+        }
+        if let Some(m) = &self.markers {
+            fields.push(format!(r#"markers = "{}""#, m));
+        }
+
+        if fields.len() == 1 && !constraints.is_empty() && !as_table {
+            (name, format!(r#""{}""#, constraints))
+        } else {
+            (name, format!("{{ {} }}", fields.join(", ")))
         }
     }
 
@@ -1036,6 +1469,8 @@ pub struct Package {
     pub version: Version,
     pub deps: Vec<(u32, String, Version)>,
     pub rename: Rename,
+    /// Extras this package is installed with, eg `socks` for `requests[socks]`
+    pub extras: Vec<String>,
 }
 
 /// Similar to that used by Cargo.lock. Represents an exact package to download. // todo(Although
@@ -1051,16 +1486,50 @@ pub struct LockPackage {
     pub source: Option<String>,
     pub dependencies: Option<Vec<String>>,
     pub rename: Option<String>,
+    pub extras: Option<Vec<String>>,
+    /// The SHA256 of the file installed, eg `sha256:ab12...`
+    pub hash: Option<String>,
+    /// Set if only dev dependencies need this package. These are kept in the lock file's
+    /// `dev-package` section, and only installed with `install --dev`.
+    #[serde(skip)]
+    pub dev: bool,
 }
 
 /// Modelled after [Cargo.lock](https://doc.rust-lang.org/cargo/guide/cargo-toml-vs-cargo-lock.html)
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Lock {
     pub package: Option<Vec<LockPackage>>,
+    /// Packages only dev dependencies need.
+    #[serde(rename = "dev-package")]
+    pub dev_package: Option<Vec<LockPackage>>,
     //    pub metadata: Option<Vec<String>>, // ie checksums
     pub metadata: HashMap<String, String>, // ie checksums
 }
 
+impl Lock {
+    /// Split packages into the `package` and `dev-package` sections.
+    pub fn new(lock_packs: &[LockPackage]) -> Self {
+        let (dev, main): (Vec<LockPackage>, Vec<LockPackage>) =
+            lock_packs.iter().cloned().partition(|lp| lp.dev);
+        Self {
+            package: Some(main),
+            dev_package: if dev.is_empty() { None } else { Some(dev) },
+            //        metadata: Some(lock_metadata),
+            metadata: HashMap::new(), // todo: Problem with toml conversion.
+        }
+    }
+
+    /// All locked packages, with those from the `dev-package` section marked as dev-only.
+    pub fn packages(self) -> Vec<LockPackage> {
+        let mut packages = self.package.unwrap_or_default();
+        for mut lp in self.dev_package.unwrap_or_default() {
+            lp.dev = true;
+            packages.push(lp);
+        }
+        packages
+    }
+}
+
 #[cfg(test)]
 pub mod tests {
     use rstest::rstest;
@@ -1068,6 +1537,7 @@ pub mod tests {
     use VersionModifier::*;
 
     use super::*;
+    use crate::util::Os;
 
     #[rstest(
         req,
@@ -1203,6 +1673,16 @@ pub mod tests {
             Version::new(1, MAX_VER, MAX_VER),
             Version::new(2, 0, 0)
         ),
+        case::tilde_eq_four_segments_max(
+            Constraint::new(TildeEq, Version::from_str("1.4.5.2").unwrap()),
+            Version::from_str("1.4.5.9").unwrap(),
+            Version::new(1, 4, 6)
+        ),
+        case::tilde_eq_four_segments_min(
+            Constraint::new(TildeEq, Version::from_str("1.4.5.2").unwrap()),
+            Version::from_str("1.4.5.9").unwrap(),
+            Version::from_str("1.4.5.1").unwrap()
+        ),
         case::tilde_eq_minor_version_min(
             Constraint::new(TildeEq, Version::new_short(1, 2)),
             Version::new(1, MAX_VER, MAX_VER),
@@ -1228,8 +1708,13 @@ pub mod tests {
                 minor: Some(2),
                 patch: Some(3),
                 extra_num: Some(MAX_VER),
+                more_nums: vec![],
                 modifier: Some((VersionModifier::Beta, 1)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             },
             Version::new_star(None, None, None, false)
         ),
@@ -1259,15 +1744,25 @@ pub mod tests {
                 minor: Some(2),
                 patch: Some(3),
                 extra_num: None,
+                more_nums: vec![],
                 modifier: None,
-                star:true}),
+                star:true,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None}),
             Version{
                 major: Some(1),
                 minor: Some(2),
                 patch: Some(3),
                 extra_num: Some(MAX_VER),
+                more_nums: vec![],
                 modifier: Some((VersionModifier::Beta, 1)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             },
             Version::new(1, 3, 0)
         ),
@@ -1329,12 +1824,70 @@ pub mod tests {
         assert_eq!(constraint.is_compatible(&ver_match), is_compat);
     }
 
+    #[rstest(
+        ver_str,
+        is_compat,
+        case::exact("==1.0", true),
+        case::gte(">=1.0", true),
+        case::lt("<1.0", false),
+        case::exact_local("==1.0+cpu", true),
+        case::exact_other_local("==1.0+gpu", false),
+        case::lte("<=1.0", true),
+        case::ne("!=1.0", false),
+        case::gt_local(">1.0", false),
+        case::arbitrary("===1.0", false),
+        case::arbitrary_local("===1.0+cpu", true)
+    )]
+    fn pep440_local(ver_str: &str, is_compat: bool) {
+        let ver_match = Version::from_str("1.0+cpu").unwrap();
+        let constraint = Constraint::from_str(ver_str).unwrap();
+        assert_eq!(constraint.is_compatible(&ver_match), is_compat);
+    }
+
+    #[rstest(
+        lower,
+        higher,
+        case::rc_final("1.0.0rc1", "1.0.0"),
+        case::beta_rc("2.0b3", "2.0rc1"),
+        case::dev_alpha("1.5.0.dev4", "1.5.0a1"),
+        case::dev_numbers("1.5.0.dev4", "1.5.0.dev5"),
+        case::pre_dev("1.0rc1.dev2", "1.0rc1"),
+        case::aliases("1.0-alpha2", "1.0.beta1"),
+        case::prev_final("0.9", "1.0.dev0")
+    )]
+    fn pep440_prerelease_order(lower: &str, higher: &str) {
+        let lower = Version::from_str(lower).unwrap();
+        let higher = Version::from_str(higher).unwrap();
+        assert!(lower < higher, "{} should be lower than {}", lower, higher);
+    }
+
+    #[test]
+    fn prerelease_parse() {
+        let dev = Version::from_str("1.5.0.dev4").unwrap();
+        assert_eq!(dev.dev, Some(4));
+        assert_eq!(dev.modifier, None);
+        assert_eq!(dev.to_string(), "1.5.0.dev4");
+        assert!(dev.is_prerelease());
+
+        let rc = Version::from_str("1.0rc1.dev2").unwrap();
+        assert_eq!(rc.modifier, Some((VersionModifier::ReleaseCandidate, 1)));
+        assert_eq!(rc.to_string(), "1.0rc1.dev2");
+        assert_eq!(Version::from_str("2.0c1").unwrap().to_string(), "2.0rc1");
+
+        assert!(!Version::from_str("1.0").unwrap().is_prerelease());
+        assert!(!Version::from_str("1.0.post1").unwrap().is_prerelease());
+    }
+
     #[test]
     fn version_parse() {
         assert_eq!(Version::from_str("3.12.5").unwrap(), Version::new(3, 12, 5));
         assert_eq!(Version::from_str("0.1.0").unwrap(), Version::new(0, 1, 0));
         assert_eq!(Version::from_str("3.7").unwrap(), Version::new(3, 7, 0));
         assert_eq!(Version::from_str("1").unwrap(), Version::new(1, 0, 0));
+        assert_eq!(
+            Version::from_str("1.0+Ubuntu-1").unwrap().to_string(),
+            "1.0+ubuntu.1"
+        );
     }
 
     #[test]
@@ -1362,8 +1915,13 @@ pub mod tests {
                 minor: Some(3),
                 patch: Some(0),
                 extra_num: None,
+                more_nums: vec![],
                 modifier: Some((Beta, 0)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             }
         );
 
@@ -1374,8 +1932,13 @@ pub mod tests {
                 minor: Some(3),
                 patch: Some(5),
                 extra_num: None,
+                more_nums: vec![],
                 modifier: Some((ReleaseCandidate, 0)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             }
         );
 
@@ -1386,8 +1949,13 @@ pub mod tests {
                 minor: Some(3),
                 patch: Some(5),
                 extra_num: Some(11),
+                more_nums: vec![],
                 modifier: None,
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             }
         );
 
@@ -1398,8 +1966,13 @@ pub mod tests {
                 minor: Some(2),
                 patch: Some(5),
                 extra_num: Some(11),
+                more_nums: vec![],
                 modifier: Some((Beta, 3)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             }
         );
     }
@@ -1407,11 +1980,32 @@ pub mod tests {
     #[test]
     fn bad_version() {
         assert_eq!(
-            Version::from_str("3-7"),
+            Version::from_str("3-x"),
             Err(DependencyError {
-                details: "Problem parsing version: 3-7".to_owned()
+                details: "Problem parsing version: 3-x".to_owned()
             })
         );
+        // A number after a dash is a post-release.
+        assert_eq!(Version::from_str("3-7").unwrap().to_string(), "3.post7");
+        assert_eq!(
+            Version::from_str("1.0-1").unwrap(),
+            Version::from_str("1.0.post1").unwrap()
+        );
+    }
+
+    #[test]
+    fn epoch_kept_in_ranges() {
+        let v = Version::from_str("1!2.5").unwrap();
+        for constraint in &["^1!2.0", "~=1!2.0", "~1!2", "==1!2.*"] {
+            let constraint = Constraint::from_str(constraint).unwrap();
+            assert!(constraint.is_compatible(&v), "{}", constraint);
+            let (min, max) = constraint.compatible_range()[0].clone();
+            assert!(min <= v && v <= max, "{}", constraint);
+        }
+        // Still bounded, in that epoch.
+        assert!(!Constraint::from_str("^1!2.0")
+            .unwrap()
+            .is_compatible(&Version::from_str("1!3.0").unwrap()));
     }
 
     #[test]
@@ -1427,8 +2021,13 @@ pub mod tests {
                 minor: Some(3),
                 patch: Some(0),
                 extra_num: None,
+                more_nums: vec![],
                 modifier: Some((Beta, 3)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             },
         );
         let req_b = Constraint::new(
@@ -1438,8 +2037,13 @@ pub mod tests {
                 minor: Some(3),
                 patch: Some(32),
                 extra_num: None,
+                more_nums: vec![],
                 modifier: Some((ReleaseCandidate, 1)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             },
         );
         let req_c = Constraint::new(
@@ -1449,8 +2053,13 @@ pub mod tests {
                 minor: Some(3),
                 patch: Some(32),
                 extra_num: None,
+                more_nums: vec![],
                 modifier: Some((Dep, 1)),
                 star: false,
+                dev: None,
+                epoch: None,
+                post: None,
+                local: None,
             },
         );
 
@@ -1474,6 +2083,42 @@ pub mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    fn constraint_multiple_spaced() {
+        let expected = vec![
+            Constraint::new(Gte, Version::new(1, 0, 0)),
+            Constraint::new(Lt, Version::new(2, 0, 0)),
+        ];
+
+        assert_eq!(
+            Constraint::from_str_multiple(" >= 1.0, < 2.0 ").unwrap(),
+            expected
+        );
+        assert_eq!(
+            Constraint::from_str_multiple(">=1.0<2.0").unwrap(),
+            expected
+        );
+        assert_eq!(
+            Constraint::from_str_multiple(">= 1.0 < 2.0").unwrap(),
+            expected
+        );
+    }
+
+    #[test]
+    fn constraint_malformed() {
+        let err = Constraint::from_str_multiple(">=1.0, <<2.0").unwrap_err();
+        assert_eq!(
+            err.details,
+            "Problem parsing constraints: `>=1.0, <<2.0`: unexpected `, <<2.0` at column 6"
+        );
+
+        let err = Constraint::from_str(">=1.0 2.0").unwrap_err();
+        assert_eq!(
+            err.details,
+            "Problem parsing constraint: `>=1.0 2.0`: unexpected ` 2.0` at column 6"
+        );
+    }
+
     #[test]
     fn constraint_to_string() {
         let a = "!=2.3";
@@ -1520,6 +2165,10 @@ pub mod tests {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: Some(Marker::from_str("extra == 'security'").unwrap()),
         };
 
         let actual2 = Req::from_str(
@@ -1537,6 +2186,10 @@ pub mod tests {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: Some(Marker::from_str("extra == 'test' and python_version == '2.7'").unwrap()),
         };
 
         let actual3 = Req::from_str(
@@ -1554,6 +2207,12 @@ pub mod tests {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: Some(
+                Marker::from_str("sys_platform == 'win32' and python_version < '3.6'").unwrap(),
+            ),
         };
 
         let actual4 = Req::from_str("envisage ; extra == 'app'", true).unwrap();
@@ -1568,12 +2227,28 @@ pub mod tests {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: Some(Marker::from_str("extra == 'app'").unwrap()),
         };
 
         assert_eq!(actual, expected);
         assert_eq!(actual2, expected2);
         assert_eq!(actual3, expected3);
         assert_eq!(actual4, expected4);
+
+        // Markers that don't split into extras are kept whole.
+        let actual5 = Req::from_str(
+            "colorama ; os_name == \"nt\" or python_version < \"3.8\"",
+            true,
+        )
+        .unwrap();
+        assert_eq!(actual5.extra, None);
+        assert_eq!(
+            actual5.markers.map(|m| m.to_string()),
+            Some("os_name == 'nt' or python_version < '3.8'".to_owned())
+        );
     }
 
     // Non-standard format I've come across; more like the non-pypi fmt.
@@ -1594,6 +2269,10 @@ pub mod tests {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: None,
         };
 
         let expected2 = Req {
@@ -1608,6 +2287,10 @@ pub mod tests {
             install_with_extras: None,
             path: None,
             git: None,
+            branch: None,
+            develop: false,
+            url: None,
+            markers: None,
         };
 
         assert_eq!(actual1, expected1);
@@ -1662,6 +2345,72 @@ pub mod tests {
         )
     }
 
+    #[test]
+    fn parse_req_markers() {
+        let p = Req::from_str("pywin32; sys_platform == 'win32'", false).unwrap();
+        assert_eq!(p.name, "pywin32");
+        assert_eq!(
+            p.markers.map(|m| m.to_string()),
+            Some("sys_platform == 'win32'".to_owned())
+        );
+
+        let p = Req::from_pip_str("colorama>=0.4 ; os_name == \"nt\"").unwrap();
+        assert_eq!(
+            p.constraints,
+            vec![Constraint::new(Gte, Version::new(0, 4, 0))]
+        );
+        assert!(p.markers.is_some());
+
+        assert!(Req::from_str("pywin32; sys_platform = 'win32'", false).is_err());
+    }
+
+    #[rstest(
+        marker,
+        os,
+        py_vers,
+        expected,
+        case("sys_platform == 'win32'", Os::Windows, Version::new_short(3, 8), true),
+        case("sys_platform == 'win32'", Os::Linux, Version::new_short(3, 8), false),
+        case("os_name != 'nt'", Os::Mac, Version::new_short(3, 8), true),
+        case(
+            "platform_machine == 'arm64'",
+            Os::MacArm64,
+            Version::new_short(3, 8),
+            true
+        ),
+        case(
+            "platform_machine == 'arm64'",
+            Os::Mac,
+            Version::new_short(3, 8),
+            false
+        ),
+        case("python_version < '3.8'", Os::Linux, Version::new_short(3, 7), true),
+        case("python_version >= '3.8'", Os::Linux, Version::new_short(3, 10), true),
+        case(
+            "python_version >= '3.8' and sys_platform == 'darwin'",
+            Os::Linux,
+            Version::new_short(3, 10),
+            false
+        ),
+        case(
+            "sys_platform == 'darwin' or python_version >= '3.8'",
+            Os::Linux,
+            Version::new_short(3, 10),
+            true
+        ),
+        case("extra == 'test'", Os::Linux, Version::new_short(3, 8), false)
+    )]
+    fn evaluate_markers(marker: &str, os: Os, py_vers: Version, expected: bool) {
+        let env = MarkerEnv::new(os, &py_vers);
+        assert_eq!(Marker::from_str(marker).unwrap().evaluate(&env), expected);
+    }
+
+    #[test]
+    fn marker_display_round_trip() {
+        let s = "(os_name == 'nt' or os_name == 'posix') and python_version < '3.8'";
+        assert_eq!(Marker::from_str(s).unwrap().to_string(), s);
+    }
+
     #[test]
     fn parse_req_pypi() {
         let p = Req::from_str("pytz (>=2016.3)", true).unwrap();
@@ -1776,6 +2525,11 @@ pub mod tests {
                                  Constraint::new(Tilde, Version::new(1,2,3))
                              ]),
                     r#"package = "~1.2.3""#),
+        case::tilde_eq(Req::new("package".to_string(),
+                                vec![
+                                    Constraint::new(TildeEq, Version::new_short(2, 2))
+                                ]),
+                       r#"package = "~=2.2""#),
         case::multi_ne_gte(Req::new("package".to_string(),
                                     vec![
                                         Constraint::new(Ne, Version::new(1,2,3)),
@@ -1802,6 +2556,41 @@ pub mod tests {
         assert_eq!(req.to_cfg_string(), expected.to_string());
     }
 
+    #[rstest(
+        constraint,
+        version,
+        compat,
+        case::bare_in("2.*", "2.0", true),
+        case::bare_top("2.*", "2.9.1", true),
+        case::bare_below("2.*", "1.9.9", false),
+        case::bare_above("2.*", "3.0", false),
+        case::exact_in("==1.4.*", "1.4.9", true),
+        case::exact_above("==1.4.*", "1.5.0", false),
+        case::ne_in("!=1.25.*", "1.25.3", false),
+        case::ne_out("!=1.25.*", "1.26.0", true)
+    )]
+    fn wildcards(constraint: &str, version: &str, compat: bool) {
+        let constraint = Constraint::from_str(constraint).unwrap();
+        let version = Version::from_str(version).unwrap();
+        assert_eq!(constraint.is_compatible(&version), compat);
+    }
+
+    #[test]
+    fn wildcard_round_trip() {
+        let req = Req::new(
+            "django".to_string(),
+            Constraint::from_str_multiple("2.*").unwrap(),
+        );
+        assert_eq!(req.to_cfg_string(), r#"django = "2.*""#);
+        assert_eq!(
+            req.constraints[0].compatible_range(),
+            vec![(
+                Version::new(2, 0, 0),
+                Version::new_star(Some(2), Some(MAX_VER), Some(MAX_VER), true)
+            )]
+        );
+    }
+
     #[test]
     fn version_ordering() {
         let a = Version::new(4, 9, 4);
@@ -1816,6 +2605,132 @@ pub mod tests {
         assert!(f < g);
     }
 
+    #[test]
+    fn pep440_ordering() {
+        let ordered = [
+            "1.0.dev1",
+            "1.0a1.dev1",
+            "1.0a1",
+            "1.0b2",
+            "1.0rc1.dev3",
+            "1.0rc1",
+            "1.0",
+            "1.0.0.0.1",
+            "1.0.0.1",
+            "1.0.0.1.2",
+            "1.0.0.1.10",
+            "1.9",
+            "1.10",
+            "1.10.1",
+        ];
+        let mut versions: Vec<Version> = ordered
+            .iter()
+            .rev()
+            .map(|v| Version::from_str(v).unwrap())
+            .collect();
+        versions.sort();
+        let sorted: Vec<String> = versions.iter().map(Version::to_string).collect();
+        assert_eq!(sorted, ordered);
+
+        // Trailing zeros don't matter, however many release numbers there are.
+        assert_eq!(
+            Version::from_str("1.0").unwrap(),
+            Version::from_str("1.0.0").unwrap()
+        );
+        let long = Version::from_str("1.0.0.0.0").unwrap();
+        assert_eq!(long, Version::new_short(1, 0));
+        let hashed: std::collections::HashSet<Version> =
+            vec![long, Version::new_short(1, 0)].into_iter().collect();
+        assert_eq!(hashed.len(), 1);
+
+        for v in &["1.2.3.4.5rc1", "1.2.3.4.5.6", "1.2.3.4.5.6.post1.dev2"] {
+            assert_eq!(Version::from_str(v).unwrap().to_string(), *v);
+        }
+        assert!(
+            Version::from_str("1.2.3.4.5rc1").unwrap() < Version::from_str("1.2.3.4.5").unwrap()
+        );
+        assert!(
+            Version::from_str("1.2.3.4.5.6").unwrap() > Version::from_str("1.2.3.4.5").unwrap()
+        );
+        assert_eq!(
+            ordered.iter().map(|v| Version::from_str(v).unwrap()).max(),
+            Some(Version::new(1, 10, 1))
+        );
+    }
+
+    #[test]
+    fn epochs_and_post_releases() {
+        let epoch = Version::from_str("1!1.0").unwrap();
+        let post = Version::from_str("1.0.post1").unwrap();
+        assert_eq!(epoch.epoch, Some(1));
+        assert_eq!(post.post, Some(1));
+        assert_eq!(epoch.to_string(), "1!1.0");
+        assert_eq!(post.to_string(), "1.0.post1");
+        assert_eq!(
+            Version::from_str("1.0rc1.post2.dev3").unwrap().to_string(),
+            "1.0rc1.post2.dev3"
+        );
+        // Alternative spellings normalize to `.postN`.
+        assert_eq!(
+            Version::from_str("1.0-r2").unwrap().to_string(),
+            "1.0.post2"
+        );
+        assert_eq!(Version::from_str("1.0.post").unwrap().post, Some(0));
+
+        // The epoch dominates; a post-release comes after its release.
+        assert!(epoch > Version::from_str("2020.12").unwrap());
+        assert!(epoch > post);
+        assert!(Version::from_str("0!1.0").unwrap() == Version::new_short(1, 0));
+        assert!(post > Version::new_short(1, 0));
+        assert!(post < Version::from_str("1.0.1").unwrap());
+        assert!(Version::from_str("1.0.post1.dev1").unwrap() < post);
+        assert!(Version::from_str("1.0.post1.dev1").unwrap() > Version::new_short(1, 0));
+        assert!(Version::from_str("1.0a1.post1").unwrap() < Version::from_str("1.0a2").unwrap());
+        assert!(!post.is_prerelease());
+    }
+
+    #[test]
+    fn all_segments_rendered() {
+        CliConfig {
+            color_choice: termcolor::ColorChoice::Never,
+            ..Default::default()
+        }
+        .make_current();
+
+        let s = "1!2.0.post1.dev3+abc";
+        let v = Version::from_str(s).unwrap();
+        assert_eq!(v.to_string(), s);
+        assert_eq!(v.to_string_med(), s);
+        assert_eq!(v.to_string_color(), s);
+        assert_eq!(Version::from_str(&v.to_string_med()).unwrap(), v);
+    }
+
+    #[test]
+    fn local_versions_ordered() {
+        let ordered = [
+            "1.0",
+            "1.0+abc",
+            "1.0+abc.2",
+            "1.0+abc.10",
+            "1.0+1",
+            "1.0.post1",
+        ];
+        let mut versions: Vec<Version> = ordered
+            .iter()
+            .rev()
+            .map(|v| Version::from_str(v).unwrap())
+            .collect();
+        versions.sort();
+        let sorted: Vec<String> = versions.iter().map(Version::to_string).collect();
+        assert_eq!(sorted, ordered);
+
+        // Labels are normalized, and kept when written, eg to the lock file.
+        let v = Version::from_str("1.2.3+Company_Build-2").unwrap();
+        assert_eq!(v.to_string(), "1.2.3+company.build.2");
+        assert_eq!(Version::from_str(&v.to_string()).unwrap(), v);
+        assert!(v != Version::new(1, 2, 3));
+    }
+
     #[test]
     fn version_ordering_modded() {
         let a = Version {
@@ -1823,8 +2738,13 @@ pub mod tests {
             minor: Some(9),
             patch: Some(4),
             extra_num: Some(2),
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         };
         let b = Version::new(4, 9, 4);
 
@@ -1833,32 +2753,52 @@ pub mod tests {
             minor: Some(9),
             patch: Some(4),
             extra_num: None,
+            more_nums: vec![],
             modifier: Some((VersionModifier::ReleaseCandidate, 2)),
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         };
         let d = Version {
             major: Some(4),
             minor: Some(9),
             patch: Some(4),
             extra_num: None,
+            more_nums: vec![],
             modifier: Some((VersionModifier::ReleaseCandidate, 1)),
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         };
         let e = Version {
             major: Some(4),
             minor: Some(9),
             patch: Some(4),
             extra_num: None,
+            more_nums: vec![],
             modifier: Some((VersionModifier::Beta, 6)),
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         };
         let f = Version {
             major: Some(4),
             minor: Some(9),
             patch: Some(4),
             extra_num: None,
+            more_nums: vec![],
             modifier: Some((VersionModifier::Alpha, 7)),
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         };
         let g = Version::new(4, 9, 2);
 
@@ -2004,7 +2944,18 @@ pub mod tests {
              case::pp36("pp36", vec![Constraint::new(Exact, Version::new(3, 6, 0))]),
              case::any("any", vec![Constraint::new(Gte, Version::new(2, 0, 0))]),
              case::semver("2.7", vec![Constraint::new(Caret, Version::new(2, 7, 0))]),
-             case::pp257("pp257", vec![Constraint::new(Exact, Version::new(2, 5, 7))])
+             case::pp257("pp257", vec![Constraint::new(Exact, Version::new(2, 5, 7))]),
+             case::cp38("cp38", vec![Constraint::new(Exact, Version::new(3, 8, 0))]),
+             case::cp39("cp39", vec![Constraint::new(Exact, Version::new(3, 9, 0))]),
+             case::cp310("cp310", vec![Constraint::new(Exact, Version::new(3, 10, 0))]),
+             case::cp311("cp311", vec![Constraint::new(Exact, Version::new(3, 11, 0))]),
+             case::cp312("cp312", vec![Constraint::new(Exact, Version::new(3, 12, 0))]),
+             case::pp310("pp310", vec![Constraint::new(Exact, Version::new(3, 10, 0))]),
+             case::cp_chain_310("cp39.cp310",
+                                vec![
+                                    Constraint::new(Exact, Version::new(3, 9, 0)),
+                                    Constraint::new(Exact, Version::new(3, 10, 0)),
+                                ])
     )]
     fn python_version_from_warehouse(input: &str, expected: Vec<Constraint>) {
         let a1 = Constraint::from_wh_py_vers(input).unwrap();
@@ -7,43 +7,184 @@ use serde::Deserialize;
 use std::collections::HashMap;
 use std::fs;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use termcolor::Color;
+use toml_edit::{Document, Item, Table};
 
 #[derive(Debug, Deserialize)]
 pub struct Pipfile {
+    /// Package indexes. The first is the default.
+    pub source: Option<Vec<PipfileSource>>,
     // Pipfile doesn't use a prefix; assume `[packages]` and [`dev-packages`] sections
-    // are from it, and use the same format as this tool and `Poetry`.
-    pub packages: Option<HashMap<String, DepComponentWrapper>>,
+    // are from it.
+    pub packages: Option<HashMap<String, DepComponentWrapperPipfile>>,
     #[serde(rename = "dev-packages")]
-    pub dev_packages: Option<HashMap<String, DepComponentWrapper>>,
+    pub dev_packages: Option<HashMap<String, DepComponentWrapperPipfile>>,
+    pub requires: Option<PipfileRequires>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipfileSource {
+    pub name: Option<String>,
+    pub url: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PipfileRequires {
+    pub python_version: Option<String>,
+    pub python_full_version: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum DepComponentWrapperPipfile {
+    A(String),
+    B(Box<DepComponentPipfile>),
+}
+
+/// A package in a `Pipfile`. Environment markers can be given as their own fields, eg
+/// `os_name = "== 'nt'"`, as well as in `markers`.
+#[derive(Debug, Default, Deserialize)]
+pub struct DepComponentPipfile {
+    pub version: Option<String>,
+    pub extras: Option<Vec<String>>,
+    pub markers: Option<String>,
+    pub path: Option<String>,
+    pub editable: Option<bool>,
+    pub git: Option<String>,
+    #[serde(rename = "ref")]
+    pub ref_: Option<String>,
+    /// A URL or local path to a wheel or sdist.
+    pub file: Option<String>,
+    /// The name of the `[[source]]` to install from.
+    pub index: Option<String>,
+    pub os_name: Option<String>,
+    pub sys_platform: Option<String>,
+    pub platform_machine: Option<String>,
+    pub platform_system: Option<String>,
+    pub python_version: Option<String>,
+    pub python_full_version: Option<String>,
+}
+
+impl DepComponentPipfile {
+    /// Combine `markers`, and the markers given as fields, into one marker expression.
+    pub fn combined_markers(&self) -> Option<String> {
+        let fields = [
+            ("os_name", &self.os_name),
+            ("sys_platform", &self.sys_platform),
+            ("platform_machine", &self.platform_machine),
+            ("platform_system", &self.platform_system),
+            ("python_version", &self.python_version),
+            ("python_full_version", &self.python_full_version),
+        ];
+        let mut markers: Vec<String> = self.markers.iter().cloned().collect();
+        for (var, val) in fields.iter() {
+            if let Some(v) = val {
+                markers.push(format!("{} {}", var, v.trim()));
+            }
+        }
+        match markers.len() {
+            0 => None,
+            1 => markers.pop(),
+            // Parenthesize, so an `or` in one doesn't swallow the others.
+            _ => Some(
+                markers
+                    .iter()
+                    .map(|m| format!("({})", m))
+                    .collect::<Vec<String>>()
+                    .join(" and "),
+            ),
+        }
+    }
 }
 
 /// This nested structure is required based on how the `toml` crate handles dots.
 #[derive(Debug, Deserialize)]
 pub struct Pyproject {
+    #[serde(default)]
     pub tool: Tool,
+    pub project: Option<Project>,
+    #[serde(rename = "build-system")]
+    pub build_system: Option<BuildSystem>,
 }
 
-#[derive(Debug, Deserialize)]
+/// How to build the package: the packages needed to build it, and the
+/// [PEP 517](https://www.python.org/dev/peps/pep-0517/) backend that does so, eg
+/// `setuptools.build_meta` or `flit_core.buildapi`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct BuildSystem {
+    #[serde(default)]
+    pub requires: Vec<String>,
+    #[serde(rename = "build-backend")]
+    pub build_backend: Option<String>,
+    /// Folders in the project to import the backend from, for projects that build themselves.
+    #[serde(rename = "backend-path")]
+    pub backend_path: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
 pub struct Tool {
     pub pyflow: Option<Pyflow>,
     pub poetry: Option<Poetry>,
 }
 
+/// The standard `[project]` table, from [PEP 621](https://www.python.org/dev/peps/pep-0621/).
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Project {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    pub description: Option<String>,
+    pub readme: Option<ProjectFile>,
+    pub requires_python: Option<String>,
+    pub license: Option<ProjectFile>,
+    pub authors: Option<Vec<ProjectPerson>>,
+    pub keywords: Option<Vec<String>>,
+    pub classifiers: Option<Vec<String>>,
+    pub urls: Option<HashMap<String, String>>,
+    pub scripts: Option<HashMap<String, String>>,
+    /// PEP 508 strings, eg `requests[security] >=2.8.1`
+    pub dependencies: Option<Vec<String>>,
+    pub optional_dependencies: Option<HashMap<String, Vec<String>>>,
+}
+
+/// Allows both `readme = "README.md"`, and `readme = {file = "README.md"}` or
+/// `license = {text = "MIT"}`.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum ProjectFile {
+    A(String),
+    B {
+        file: Option<String>,
+        text: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProjectPerson {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 /// Allows use of both Strings, ie "ipython = "^7.7.0", and maps: "ipython = {version = "^7.7.0", extras=["qtconsole"]}"
 pub enum DepComponentWrapper {
     A(String),
     B(DepComponent),
+    /// Several specifications, each for a different Python version or platform.
+    C(Vec<DepComponent>),
 }
 
 #[derive(Debug, Deserialize)]
 #[serde(untagged)]
 pub enum DepComponentWrapperPoetry {
     A(String),
-    B(DepComponentPoetry),
+    B(Box<DepComponentPoetry>),
+    /// Different specifications by Python version or platform, eg
+    /// `foo = [{ version = "^1.0", python = "<3.8" }, { version = "^2.0", python = ">=3.8" }]`
+    C(Vec<DepComponentPoetry>),
 }
 
 #[derive(Debug, Deserialize)]
@@ -52,23 +193,36 @@ pub struct DepComponent {
     pub constrs: Option<String>,
     pub extras: Option<Vec<String>>,
     pub path: Option<String>,
+    /// For path dependencies, use the project in place, ie an editable install.
+    pub develop: Option<bool>,
     pub git: Option<String>,
     pub branch: Option<String>,
+    /// A direct link to a `.whl` or `.tar.gz` file.
+    pub url: Option<String>,
     pub service: Option<String>,
     pub python: Option<String>,
+    /// A PEP 508 environment marker, eg `sys_platform == 'win32'`
+    pub markers: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct DepComponentPoetry {
     #[serde(rename = "version")]
-    pub constrs: String,
+    pub constrs: Option<String>,
     pub python: Option<String>,
     pub extras: Option<Vec<String>>,
+    /// Only installed with one of the `[tool.poetry.extras]` that lists it.
     pub optional: Option<bool>,
+    pub markers: Option<String>,
+    pub path: Option<String>,
+    pub develop: Option<bool>,
+    pub git: Option<String>,
+    pub branch: Option<String>,
+    pub rev: Option<String>,
+    pub tag: Option<String>,
+    pub url: Option<String>,
     // todo: more fields
-    //    pub repository: Option<String>,
-    //    pub branch: Option<String>,
-    //    pub service: Option<String>,
+    //    pub source: Option<String>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -77,6 +231,9 @@ pub struct Pyflow {
     pub name: Option<String>,
     pub version: Option<String>,
     pub authors: Option<Vec<String>>,
+    // `author` and `author_email` are accepted as an alternative to `authors`, as in `setup.py`.
+    pub author: Option<String>,
+    pub author_email: Option<String>,
     pub license: Option<String>,
     pub description: Option<String>,
     pub classifiers: Option<Vec<String>>, // https://pypi.org/classifiers/
@@ -90,10 +247,16 @@ pub struct Pyflow {
     //    pub entry_points: Option<HashMap<String, Vec<String>>>,
     pub scripts: Option<HashMap<String, String>>,
     pub python_requires: Option<String>,
+    pub index_url: Option<String>,
+    pub proxy: Option<String>,
+    pub use_pep582: Option<bool>,
+    pub post_resolve_hook: Option<String>,
     pub dependencies: Option<HashMap<String, DepComponentWrapper>>,
     #[serde(rename = "dev-dependencies")]
     pub dev_dependencies: Option<HashMap<String, DepComponentWrapper>>,
     pub extras: Option<HashMap<String, String>>,
+    pub hashes: Option<HashMap<String, Vec<String>>>,
+    pub overrides: Option<HashMap<String, String>>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -113,144 +276,157 @@ pub struct Poetry {
     pub packages: Option<Vec<HashMap<String, String>>>,
     pub include: Option<Vec<String>>,
     pub exclude: Option<Vec<String>>,
-    pub extras: Option<HashMap<String, String>>,
+    /// The optional dependencies each extra installs, by name.
+    pub extras: Option<HashMap<String, Vec<String>>>,
 
     pub dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+    #[serde(rename = "dev-dependencies")]
     pub dev_dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+    /// Dependency groups, eg `[tool.poetry.group.test.dependencies]`
+    pub group: Option<HashMap<String, PoetryGroup>>,
     // todo: Include these
     //    pub source: Option<HashMap<String, String>>,
     pub scripts: Option<HashMap<String, String>>,
     //    pub extras: Option<HashMap<String, String>>,
 }
 
-/// Encapsulate one section of the `pyproject.toml`.
-///
-/// # Attributes:
-/// * lines: A vector containing each line of the section
-/// * i_start: Zero-indexed indicating the line of the header.
-/// * i_end: Zero-indexed indicating the line number of the next section header,
-///     or the last line of the file.
-struct Section {
-    lines: Vec<String>,
-    i_start: usize,
-    i_end: usize,
-}
-
-/// Identify the start index, end index, and lines of a particular section.
-fn collect_section(cfg_lines: &[String], title: &str) -> Option<Section> {
-    // This will tell us when we've reached a new section
-    let section_re = Regex::new(r"^\[.*\]$").unwrap();
-
-    let mut existing_entries = Vec::new();
-    let mut in_section = false;
-    let mut i_start = 0usize;
-
-    for (i, line) in cfg_lines.iter().enumerate() {
-        if in_section && section_re.is_match(line) {
-            return Some(Section {
-                lines: existing_entries,
-                i_start,
-                i_end: i,
-            });
-        }
+#[derive(Debug, Deserialize)]
+pub struct PoetryGroup {
+    pub dependencies: Option<HashMap<String, DepComponentWrapperPoetry>>,
+}
 
-        if in_section {
-            existing_entries.push(line.parse().unwrap())
-        }
+/// A table for use as a parent, that doesn't need its own header, eg `[tool]`.
+fn implicit_table() -> Item {
+    let mut table = Table::new();
+    table.set_implicit(true);
+    Item::Table(table)
+}
 
-        // This must be the last step of the loop to work properly
-        if line.replace(" ", "") == title {
-            existing_entries.push(title.into());
-            i_start = i;
-            in_section = true;
-        }
-    }
-    // We've reached the end of the file without detecting a new section
-    if in_section {
-        Some(Section {
-            lines: existing_entries,
-            i_start,
-            i_end: cfg_lines.len(),
+/// Find a table under `[tool.pyflow]`, eg `dependencies`, creating it and its parents if needed.
+fn pyflow_table<'a>(doc: &'a mut Document, name: &str) -> &'a mut Table {
+    doc.as_table_mut()
+        .entry("tool")
+        .or_insert_with(implicit_table)
+        .as_table_mut()
+        .and_then(|tool| {
+            tool.entry("pyflow")
+                .or_insert_with(implicit_table)
+                .as_table_mut()
+        })
+        .and_then(|pyflow| {
+            pyflow
+                .entry(name)
+                .or_insert_with(|| Item::Table(Table::new()))
+                .as_table_mut()
+        })
+        .unwrap_or_else(|| {
+            util::abort(&format!(
+                "`[tool.pyflow.{}]` in `pyproject.toml` must be a table",
+                name
+            ))
         })
-    } else {
-        None
-    }
 }
 
-/// Main logic for adding dependencies to a particular section.
-///
-/// If the section is detected, then the dependencies are appended to that section. Otherwise,
-/// a new section is appended to the end of the file.
-fn extend_or_insert(mut cfg_lines: Vec<String>, section_header: &str, reqs: &[Req]) -> Vec<String> {
-    let collected = collect_section(&cfg_lines, section_header);
-
-    match collected {
-        // The section already exists, so we can just add the new reqs
-        Some(section) => {
-            // To enforce proper spacing we first remove any empty lines,
-            // and later we append a trailing empty line
-            let mut all_deps: Vec<String> = section
-                .lines
-                .to_owned()
-                .into_iter()
-                .filter(|x| !x.is_empty())
-                .collect();
+fn parse_cfg_doc(cfg_data: &str) -> Document {
+    cfg_data
+        .parse()
+        .unwrap_or_else(|_| util::abort("Problem parsing `pyproject.toml`"))
+}
 
-            for req in reqs {
-                all_deps.push(req.to_cfg_string())
-            }
-            all_deps.push("".into());
+/// Add dependencies and dev-dependencies to `cfg-data`, creating the sections if necessary.
+///
+/// We edit the document in place, so comments, formatting and other sections are unchanged.
+/// Split from `add_reqs_to_cfg` to accommodate testing.
+fn update_cfg(cfg_data: &str, added: &[Req], added_dev: &[Req]) -> String {
+    let mut doc = parse_cfg_doc(cfg_data);
 
-            // Replace the original lines with our new updated lines
-            cfg_lines.splice(section.i_start..section.i_end, all_deps);
-            cfg_lines
+    for (section, reqs) in &[("dependencies", added), ("dev-dependencies", added_dev)] {
+        if reqs.is_empty() {
+            continue;
         }
-        // The section did not already exist, so we must create it
-        None => {
-            // A section is composed of its header, followed by all the requirements
-            // and then an empty line
-            let mut section = vec![section_header.to_string()];
-            section.extend(reqs.iter().map(|r| r.to_cfg_string()));
-            section.push("".into());
-
-            // We want an empty line before adding the new section
-            if let Some(last) = cfg_lines.last() {
-                if !last.is_empty() {
-                    cfg_lines.push("".into())
+        let table = pyflow_table(&mut doc, section);
+
+        for req in reqs.iter() {
+            // `to_cfg_string` formats a key/value line, which may contain an inline table.
+            let entry: Document = req
+                .to_cfg_string()
+                .parse()
+                .expect("Problem formatting a requirement for `pyproject.toml`");
+            for (key, value) in entry.iter() {
+                // Replace an existing entry, even if its name is written differently, eg `Django`.
+                let existing: Vec<String> = table
+                    .iter()
+                    .map(|(k, _)| k.to_owned())
+                    .filter(|k| k != key && util::compare_names(k, key))
+                    .collect();
+                for k in existing {
+                    table.remove(&k);
                 }
+                table.insert(key, value.clone());
             }
-            cfg_lines.extend(section);
-            cfg_lines
         }
     }
+
+    doc.to_string()
 }
 
-/// Add dependencies and dev-dependencies to `cfg-data`, creating the sections if necessary.
-///
-/// The added sections are appended to the end of the file. Split from `add_reqs_to_cfg`
-/// to accommodate testing.
-fn update_cfg(cfg_data: &str, added: &[Req], added_dev: &[Req]) -> String {
-    let cfg_lines: Vec<String> = cfg_data.lines().map(str::to_string).collect();
+/// Remove dependencies and dev-dependencies from `cfg_data`, leaving everything else untouched.
+fn remove_from_cfg(cfg_data: &str, removed: &[String]) -> String {
+    let mut doc = parse_cfg_doc(cfg_data);
 
-    // First we update the dependencies section
-    let cfg_lines_with_reqs = if !added.is_empty() {
-        extend_or_insert(cfg_lines, "[tool.pyflow.dependencies]", added)
-    } else {
-        cfg_lines
-    };
+    for section in &["dependencies", "dev-dependencies"] {
+        let table = doc
+            .get_mut("tool")
+            .and_then(|t| t.get_mut("pyflow"))
+            .and_then(|t| t.get_mut(section))
+            .and_then(Item::as_table_like_mut);
 
-    // Then we move onto the dev-dependencies
-    let cfg_lines_with_all_reqs = if !added_dev.is_empty() {
-        extend_or_insert(
-            cfg_lines_with_reqs,
-            "[tool.pyflow.dev-dependencies]",
-            added_dev,
-        )
-    } else {
-        cfg_lines_with_reqs
-    };
+        if let Some(table) = table {
+            let keys: Vec<String> = table
+                .iter()
+                .map(|(k, _)| k.to_owned())
+                .filter(|k| removed.iter().any(|r| util::compare_names(r, k)))
+                .collect();
+            for key in keys {
+                table.remove(&key);
+            }
+        }
+    }
 
-    cfg_lines_with_all_reqs.join("\n")
+    doc.to_string()
+}
+
+/// Replace Poetry's config in `cfg_data`, if present, with pyflow's, generated from `cfg`.
+/// Other tools' sections are unchanged.
+pub fn migrate_cfg(cfg_data: &str, cfg: &Config) -> String {
+    let mut doc = parse_cfg_doc(cfg_data);
+    let generated = parse_cfg_doc(&cfg.to_toml_string());
+
+    let tool = doc
+        .as_table_mut()
+        .entry("tool")
+        .or_insert_with(implicit_table)
+        .as_table_mut()
+        .unwrap_or_else(|| util::abort("`[tool]` in `pyproject.toml` must be a table"));
+    if tool.contains_key("pyflow") {
+        util::abort("`pyproject.toml` already has a `[tool.pyflow]` section")
+    }
+    tool.remove("poetry");
+    tool.insert("pyflow", generated["tool"]["pyflow"].clone());
+
+    // Poetry's build backend requires Poetry; `pyflow package` builds with setuptools.
+    let uses_poetry_backend = doc
+        .get("build-system")
+        .and_then(|bs| bs.get("build-backend"))
+        .and_then(Item::as_str)
+        .is_some_and(|backend| backend.starts_with("poetry"));
+    if uses_poetry_backend {
+        let requires: toml_edit::Array = ["setuptools", "wheel"].iter().copied().collect();
+        doc["build-system"]["requires"] = toml_edit::value(requires);
+        doc["build-system"]["build-backend"] = toml_edit::value("setuptools.build_meta");
+    }
+
+    doc.to_string()
 }
 
 /// Write dependencies to pyproject.toml. If an entry for that package already exists, ask if
@@ -267,90 +443,399 @@ pub fn add_reqs_to_cfg(cfg_path: &Path, added: &[Req], added_dev: &[Req]) {
 
 /// Remove dependencies from pyproject.toml.
 pub fn remove_reqs_from_cfg(cfg_path: &Path, reqs: &[String]) {
-    // todo: Handle removing dev deps.
-    // todo: DRY from parsing the config.
-    let mut result = String::new();
     let data = fs::read_to_string(cfg_path)
-        .expect("Unable to read pyproject.toml while attempting to add a dependency");
+        .expect("Unable to read pyproject.toml while attempting to remove a dependency");
 
-    let mut in_dep = false;
-    let mut _in_dev_dep = false;
-    let sect_re = Regex::new(r"^\[.*\]$").unwrap();
+    let updated = remove_from_cfg(&data, reqs);
+    fs::write(cfg_path, updated)
+        .expect("Unable to write to pyproject.toml while attempting to remove a dependency");
+}
 
-    for line in data.lines() {
-        if line.starts_with('#') || line.is_empty() {
-            // todo handle mid-line comements
-            result.push_str(line);
-            result.push('\n');
-            continue;
+/// Read the requirements from a pip `requirements.txt`. Follows `-r` and `-c` includes, turns
+/// `-e` editables into path or git dependencies, and takes the index from `--index-url`. Other
+/// pip options can't be represented in `pyproject.toml`, so are skipped with a warning.
+pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
+    let mut constraints = vec![];
+    let root = path.parent().unwrap_or_else(|| Path::new(""));
+    parse_req_file(cfg, path, root, false, &mut vec![], &mut constraints);
+
+    // Like pip, only use a constraints file's versions for packages something else requires.
+    // They narrow down any versions the requirement itself specifies.
+    for c in constraints {
+        for r in cfg.reqs.iter_mut().filter(|r| {
+            util::compare_names(&r.name, &c.name) && r.path.is_none() && r.git.is_none()
+        }) {
+            for constraint in &c.constraints {
+                if !r.constraints.contains(constraint) {
+                    r.constraints.push(constraint.clone());
+                }
+            }
         }
+    }
+}
 
-        if line == "[tool.pyflow.dependencies]" {
-            in_dep = true;
-            _in_dev_dep = false;
-            result.push_str(line);
-            result.push('\n');
+/// Parse a requirements file, and the ones it includes. Paths in it are relative to its folder;
+/// we make editable ones relative to `root`, the first file's.
+fn parse_req_file(
+    cfg: &mut Config,
+    path: &Path,
+    root: &Path,
+    is_constraints: bool,
+    visited: &mut Vec<PathBuf>,
+    constraints: &mut Vec<Req>,
+) {
+    // Don't loop on files that include each other.
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_owned());
+    if visited.contains(&canonical) {
+        return;
+    }
+    visited.push(canonical);
+
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(_) => {
+            if visited.len() > 1 {
+                util::print_color(
+                    &format!(
+                        "Can't find {}, included from requirements.txt",
+                        path.display()
+                    ),
+                    Color::Red,
+                );
+            }
+            return;
+        }
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    // A comment starts with a `#` at the start of the line, or after whitespace; not eg
+    // `#egg=` in a URL.
+    let re_comment = Regex::new(r"(^|\s)#.*$").unwrap();
+
+    // Hashes are usually on continuation lines, eg `requests==2.25.1 \ --hash=sha256:...`
+    for line in data.replace("\\\n", " ").lines() {
+        let line = re_comment.replace(line, "");
+        let mut hashes = vec![];
+        let mut req_part = vec![];
+        for part in line.split_whitespace() {
+            match part.strip_prefix("--hash=") {
+                Some(h) => hashes.push(h.to_owned()),
+                None => req_part.push(part),
+            }
+        }
+        let line = req_part.join(" ");
+        if line.is_empty() {
             continue;
         }
 
-        if line == "[tool.pyflow.dev-dependencies]" {
-            in_dep = true;
-            _in_dev_dep = false;
-            result.push_str(line);
-            result.push('\n');
+        if line.starts_with('-') {
+            let (option, value) = split_pip_option(&line);
+            match option {
+                "-r" | "--requirement" => parse_req_file(
+                    cfg,
+                    &dir.join(value),
+                    root,
+                    is_constraints,
+                    visited,
+                    constraints,
+                ),
+                "-c" | "--constraint" => {
+                    parse_req_file(cfg, &dir.join(value), root, true, visited, constraints)
+                }
+                "-e" | "--editable" => match editable_req(value) {
+                    Some(mut r) => {
+                        if let Some(p) = &r.path {
+                            let rel_dir = dir.strip_prefix(root).unwrap_or(dir);
+                            if rel_dir != Path::new("") && Path::new(p).is_relative() {
+                                let joined = util::normalize_path(&rel_dir.join(p));
+                                r.path = Some(joined.to_string_lossy().replace('\\', "/"));
+                            }
+                        }
+                        cfg.reqs.push(r)
+                    }
+                    None => util::print_color(
+                        &format!(
+                            "Skipping `{}` from requirements.txt: add `#egg=<name>` to name it",
+                            line
+                        ),
+                        Color::Yellow,
+                    ),
+                },
+                "-i" | "--index-url" => cfg.index_url = Some(value.to_owned()),
+                _ => util::print_color(
+                    &format!(
+                        "Skipping `{}` from requirements.txt; pyflow doesn't support this option",
+                        line
+                    ),
+                    Color::Yellow,
+                ),
+            }
             continue;
         }
 
-        if in_dep {
-            if sect_re.is_match(line) {
-                in_dep = false;
+        match Req::from_pip_str(&line) {
+            Some(r) => {
+                if !hashes.is_empty() {
+                    cfg.hashes.insert(r.name.clone(), hashes);
+                }
+                if is_constraints {
+                    constraints.push(r);
+                } else {
+                    cfg.reqs.push(r);
+                }
             }
-            // todo: handle comments
-            let req_line = if let Ok(r) = Req::from_str(line, false) {
-                r
-            } else {
-                result.push_str(line);
-                result.push('\n');
-                continue; // Could be caused by a git etc req.
-                          //                util::abort(&format!(
-                          //                    "Can't parse this line in `pyproject.toml`: {}",
-                          //                    line
-                          //                ));
-                          //                unreachable!()
-            };
-
-            if reqs
-                .iter()
-                .map(|r| r.to_lowercase())
-                .any(|x| x == req_line.name.to_lowercase())
-            {
-                continue; // ie don't append this line to result.
+            None => util::print_color(
+                &format!("Problem parsing {} from requirements.txt", line),
+                Color::Red,
+            ),
+        };
+    }
+}
+
+/// Split a pip option from its value, eg `-r base.txt`, `-rbase.txt`, or
+/// `--requirement=base.txt`.
+fn split_pip_option(line: &str) -> (&str, &str) {
+    if line.starts_with("--") {
+        match line.find(|c: char| c == '=' || c.is_whitespace()) {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        }
+    } else if line.len() > 2 {
+        (&line[..2], line[2..].trim())
+    } else {
+        (line, "")
+    }
+}
+
+/// Turn an editable install, eg `-e ./mypkg` or `-e git+https://github.com/me/lib@dev#egg=lib`,
+/// into a path or git dependency. Paths are named by `#egg=`, or their folder.
+fn editable_req(value: &str) -> Option<Req> {
+    let (location, egg) = match value.split_once("#egg=") {
+        Some((l, e)) => (l, Some(e.to_owned())),
+        None => (value, None),
+    };
+
+    if let Some(url) = location.strip_prefix("git+") {
+        // A ref follows an `@` in the URL's path, ie not eg `git@` in `ssh://git@github.com`.
+        let path_start = url.find("://").map(|i| i + 3).unwrap_or(0);
+        let path_start = path_start + url[path_start..].find('/').unwrap_or(0);
+        let (repo, branch) = match url[path_start..].rfind('@') {
+            Some(i) => (
+                &url[..path_start + i],
+                Some(url[path_start + i + 1..].to_owned()),
+            ),
+            None => (url, None),
+        };
+        let mut req = Req::new(egg?, vec![]);
+        req.git = Some(repo.to_owned());
+        req.branch = branch;
+        return Some(req);
+    }
+
+    let name = egg.or_else(|| {
+        Path::new(location)
+            .file_name()
+            .map(|n| n.to_string_lossy().into_owned())
+    })?;
+    let mut req = Req::new(name, vec![]);
+    req.path = Some(location.to_owned());
+    req.develop = true;
+    Some(req)
+}
+
+/// Add requirements from another file, skipping packages already required; eg by
+/// `requirements.txt`, whose pins are more specific than setuptools' ranges.
+fn push_new_reqs(cfg: &mut Config, lines: &[String], filename: &str) {
+    for line in lines {
+        match Req::from_pip_str(line) {
+            Some(r) => {
+                if !cfg
+                    .reqs
+                    .iter()
+                    .any(|e| util::compare_names(&e.name, &r.name))
+                {
+                    cfg.reqs.push(r);
+                }
             }
+            None => util::print_color(
+                &format!("Problem parsing {} from {}", line, filename),
+                Color::Red,
+            ),
+        }
+    }
+}
+
+/// Split an INI-style file into `(section, key, value)` entries. Indented lines continue the
+/// previous value, on a new line, as setuptools writes lists.
+pub(crate) fn parse_ini_entries(data: &str) -> Vec<(String, String, String)> {
+    let mut result: Vec<(String, String, String)> = vec![];
+    let mut section = String::new();
+    for line in data.lines() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+            continue;
+        }
+        if line.starts_with(char::is_whitespace) {
+            if let Some((_, _, value)) = result.last_mut() {
+                value.push('\n');
+                value.push_str(trimmed);
+            }
+            continue;
+        }
+        if trimmed.starts_with('[') && trimmed.ends_with(']') {
+            section = trimmed[1..trimmed.len() - 1].trim().to_owned();
+            continue;
+        }
+        if let Some(i) = trimmed.find(['=', ':']) {
+            result.push((
+                section.clone(),
+                trimmed[..i].trim().to_owned(),
+                trimmed[i + 1..].trim().to_owned(),
+            ));
         }
-        result.push_str(line);
-        result.push('\n');
     }
+    result
+}
 
-    fs::write(cfg_path, result)
-        .expect("Unable to write to pyproject.toml while attempting to add a dependency");
+/// The non-empty lines of a setuptools list value.
+fn cfg_list(value: &str) -> Vec<String> {
+    value
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty())
+        .map(str::to_owned)
+        .collect()
 }
 
-pub fn parse_req_dot_text(cfg: &mut Config, path: &Path) {
-    let file = match fs::File::open(path) {
-        Ok(f) => f,
+/// Read a setuptools `setup.cfg`'s metadata, `[options] install_requires`, and
+/// `[options.extras_require]`. Only fills in what's not already set.
+pub fn parse_setup_cfg(cfg: &mut Config, path: &Path) {
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
         Err(_) => return,
     };
 
-    for line in BufReader::new(file).lines().flatten() {
-        match Req::from_pip_str(&line) {
-            Some(r) => {
-                cfg.reqs.push(r.clone());
+    let mut author = None;
+    let mut author_email = None;
+    for (section, key, value) in parse_ini_entries(&data) {
+        // eg `version = attr: mypkg.__version__`, which we'd need to run Python for.
+        if value.starts_with("attr:") || value.starts_with("file:") {
+            continue;
+        }
+        match (section.as_ref(), key.replace('-', "_").as_ref()) {
+            ("metadata", "name") if cfg.name.is_none() => cfg.name = Some(value),
+            ("metadata", "version") if cfg.version.is_none() => {
+                cfg.version = Version::from_str(&value).ok()
+            }
+            ("metadata", "description") if cfg.description.is_none() => {
+                cfg.description = Some(value)
+            }
+            ("metadata", "author") => author = Some(value),
+            ("metadata", "author_email") => author_email = Some(value),
+            ("metadata", "license") if cfg.license.is_none() => cfg.license = Some(value),
+            ("metadata", "url") if cfg.homepage.is_none() => cfg.homepage = Some(value),
+            ("metadata", "keywords") if cfg.keywords.is_empty() => {
+                cfg.keywords = value
+                    .split([',', '\n'])
+                    .map(str::trim)
+                    .filter(|k| !k.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            }
+            ("metadata", "classifiers") if cfg.classifiers.is_empty() => {
+                cfg.classifiers = cfg_list(&value)
+            }
+            ("options", "python_requires") if cfg.python_requires.is_none() => {
+                cfg.python_requires = Some(value)
+            }
+            ("options", "install_requires") => push_new_reqs(cfg, &cfg_list(&value), "setup.cfg"),
+            ("options.extras_require", _) => {
+                // We store extras as requirements, like `[project.optional-dependencies]`.
+                cfg.extras
+                    .entry(key)
+                    .or_insert_with(|| cfg_list(&value).join(", "));
+            }
+            _ => (),
+        }
+    }
+
+    if cfg.authors.is_empty() {
+        match (author, author_email) {
+            (Some(name), Some(email)) => cfg.authors = vec![format!("{} <{}>", name, email)],
+            (Some(name), None) => cfg.authors = vec![name],
+            _ => (),
+        }
+    }
+}
+
+/// The string literals in a Python list, from just after its `[` to its `]`. `None` if it
+/// contains anything else, eg a variable, or a call.
+fn py_str_list(src: &str) -> Option<Vec<String>> {
+    let mut result = vec![];
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ']' => return Some(result),
+            '\'' | '"' => {
+                let mut s = String::new();
+                loop {
+                    match chars.next()? {
+                        q if q == c => break,
+                        ch => s.push(ch),
+                    }
+                }
+                result.push(s);
             }
+            '#' => {
+                chars.find(|&ch| ch == '\n');
+            }
+            ',' => (),
+            ch if ch.is_whitespace() => (),
+            _ => return None,
+        }
+    }
+    None
+}
+
+/// Best-effort: read the literal `name`, `version`, and `install_requires` passed to `setup()`
+/// in a `setup.py`, without running it. Only fills in what's not already set.
+pub fn parse_setup_py(cfg: &mut Config, path: &Path) {
+    let data = match fs::read_to_string(path) {
+        Ok(d) => d,
+        Err(_) => return,
+    };
+    // Skip eg a `name` variable defined above the call.
+    let call = match data.find("setup(") {
+        Some(i) => &data[i..],
+        None => &data[..],
+    };
+
+    let str_arg = |arg: &str| {
+        let re = Regex::new(&format!(r#"\b{}\s*=\s*(?:"([^"]*)"|'([^']*)')"#, arg)).unwrap();
+        re.captures(call)
+            .and_then(|caps| caps.get(1).or_else(|| caps.get(2)))
+            .map(|m| m.as_str().to_owned())
+    };
+    if cfg.name.is_none() {
+        cfg.name = str_arg("name");
+    }
+    if cfg.version.is_none() {
+        cfg.version = str_arg("version").and_then(|v| Version::from_str(&v).ok());
+    }
+
+    let re_reqs = Regex::new(r"\binstall_requires\s*=\s*\[").unwrap();
+    match re_reqs.find(call) {
+        Some(m) => match py_str_list(&call[m.end()..]) {
+            Some(reqs) => push_new_reqs(cfg, &reqs, "setup.py"),
             None => util::print_color(
-                &format!("Problem parsing {} from requirements.txt", line),
-                Color::Red,
+                "Skipping `install_requires` from setup.py, since it isn't a list of strings",
+                Color::Yellow,
             ),
-        };
+        },
+        None if call.contains("install_requires") => util::print_color(
+            "Skipping `install_requires` from setup.py, since it isn't a list of strings",
+            Color::Yellow,
+        ),
+        None => (),
     }
 }
 
@@ -452,6 +937,25 @@ dev_b = "^0.0.1"
         assert_eq!(expected, &actual);
     }
 
+    #[test]
+    fn cli_versions_written() {
+        let cfg = r#"
+[tool.pyflow.dependencies]
+Django = "^3.2"
+"#;
+        let added = ["django==4.2", "requests>=2.28,<3"]
+            .iter()
+            .map(|r| Req::from_pip_str(r).unwrap())
+            .collect::<Vec<_>>();
+
+        let expected = r#"
+[tool.pyflow.dependencies]
+django = "4.2"
+requests = ">=2.28, <3"
+"#;
+        assert_eq!(expected, update_cfg(cfg, &added, &[]));
+    }
+
     #[test]
     fn add_deps_no_dev_deps_sect() {
         let actual = update_cfg(
@@ -530,4 +1034,247 @@ dev_b = "^0.0.1"
 "#;
         assert_eq!(expected, &actual);
     }
+
+    const BASELINE_COMMENTS: &str = r#"
+[tool.pyflow]
+name = ""   # Set before publishing
+
+[tool.pyflow.dependencies]
+# pin for CVE fix
+a = "^0.3.5"
+
+b = "^0.0.1"  # Keep in sync with c
+c = "^0.0.1"
+
+[tool.pyflow.dev-dependencies]
+dev_a = "^1.17.2"
+
+[tool.black]
+line-length = 100
+"#;
+
+    #[test]
+    fn add_deps_preserves_comments() {
+        let actual = update_cfg(
+            BASELINE_COMMENTS,
+            &[Req::new("d".into(), base_constrs())],
+            &[],
+        );
+
+        let expected = r#"
+[tool.pyflow]
+name = ""   # Set before publishing
+
+[tool.pyflow.dependencies]
+# pin for CVE fix
+a = "^0.3.5"
+
+b = "^0.0.1"  # Keep in sync with c
+c = "^0.0.1"
+d = "^0.0.1"
+
+[tool.pyflow.dev-dependencies]
+dev_a = "^1.17.2"
+
+[tool.black]
+line-length = 100
+"#;
+        assert_eq!(expected, &actual);
+    }
+
+    #[test]
+    fn remove_deps_preserves_comments() {
+        let actual = remove_from_cfg(BASELINE_COMMENTS, &["B".into(), "dev-a".into()]);
+
+        let expected = r#"
+[tool.pyflow]
+name = ""   # Set before publishing
+
+[tool.pyflow.dependencies]
+# pin for CVE fix
+a = "^0.3.5"
+c = "^0.0.1"
+
+[tool.pyflow.dev-dependencies]
+
+[tool.black]
+line-length = 100
+"#;
+        assert_eq!(expected, &actual);
+    }
+
+    #[test]
+    fn req_dot_text_hashes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("requirements.txt");
+        fs::write(
+            &path,
+            "# Pinned\n\
+             requests==2.25.1 \\\n    --hash=sha256:abcd \\\n    --hash=sha256:ef01\n\
+             urllib3==1.26.4\n",
+        )
+        .unwrap();
+
+        let mut cfg = Config::default();
+        parse_req_dot_text(&mut cfg, &path);
+
+        assert_eq!(cfg.reqs.len(), 2);
+        assert_eq!(
+            cfg.hashes.get("requests"),
+            Some(&vec!["sha256:abcd".to_owned(), "sha256:ef01".to_owned()])
+        );
+        assert!(!cfg.hashes.contains_key("urllib3"));
+    }
+
+    #[test]
+    fn req_dot_text_includes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("reqs")).unwrap();
+        fs::write(
+            dir.join("requirements.txt"),
+            "--index-url https://pypi.example.com/simple/\n\
+             -r reqs/base.txt\n\
+             -c reqs/constraints.txt\n\
+             --extra-index-url https://other.example.com/simple/\n\
+             -e ./libs/mylib\n\
+             -e git+https://github.com/me/saturn.git@dev#egg=saturn\n\
+             pywin32>=300; sys_platform == \"win32\"  # Only on Windows\n",
+        )
+        .unwrap();
+        // Includes are relative to the file including them, and may loop.
+        fs::write(
+            dir.join("reqs/base.txt"),
+            "requests\nidna>=2.5\n-r ../requirements.txt\n-e ../libs/other\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("reqs/constraints.txt"),
+            "requests==2.25.1\nurllib3==1.26.4\nidna<3\n",
+        )
+        .unwrap();
+
+        let mut cfg = Config::default();
+        parse_req_dot_text(&mut cfg, &dir.join("requirements.txt"));
+
+        let names: Vec<&str> = cfg.reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["requests", "idna", "other", "mylib", "saturn", "pywin32"]
+        );
+        assert_eq!(
+            cfg.index_url,
+            Some("https://pypi.example.com/simple/".to_owned())
+        );
+
+        assert_eq!(cfg.reqs[0].to_cfg_string(), r#"requests = "2.25.1""#);
+        // Constraints add to a requirement's own.
+        assert_eq!(cfg.reqs[1].to_cfg_string(), r#"idna = ">=2.5, <3""#);
+        assert_eq!(
+            cfg.reqs[2].to_cfg_string(),
+            r#"other = { path = "libs/other", develop = true }"#
+        );
+        assert_eq!(
+            cfg.reqs[3].to_cfg_string(),
+            r#"mylib = { path = "./libs/mylib", develop = true }"#
+        );
+        assert_eq!(
+            cfg.reqs[4].to_cfg_string(),
+            r#"saturn = { git = "https://github.com/me/saturn.git", branch = "dev" }"#
+        );
+        assert!(cfg.reqs[5].markers.is_some());
+    }
+
+    #[test]
+    fn setup_cfg_parsed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("setup.cfg");
+        fs::write(
+            &path,
+            "[metadata]\n\
+             name = saturn\n\
+             version = attr: saturn.__version__\n\
+             author = Jane Doe\n\
+             author_email = jane@example.com\n\
+             classifiers =\n    \
+                 Programming Language :: Python :: 3\n    \
+                 Operating System :: OS Independent\n\
+             \n\
+             [options]\n\
+             python_requires = >=3.7\n\
+             # Pinned in requirements.txt\n\
+             install_requires =\n    \
+                 requests>=2.20\n    \
+                 numpy>=1.18; python_version >= \"3.7\"\n\
+             \n\
+             [options.extras_require]\n\
+             test =\n    \
+                 pytest\n    \
+                 pytest-cov\n",
+        )
+        .unwrap();
+
+        let mut cfg = Config::default();
+        cfg.reqs
+            .push(Req::from_pip_str("requests==2.25.1").unwrap());
+        parse_setup_cfg(&mut cfg, &path);
+
+        assert_eq!(cfg.name, Some("saturn".to_owned()));
+        assert_eq!(cfg.version, None);
+        assert_eq!(cfg.authors, vec!["Jane Doe <jane@example.com>".to_owned()]);
+        assert_eq!(cfg.classifiers.len(), 2);
+        assert_eq!(cfg.python_requires, Some(">=3.7".to_owned()));
+
+        let names: Vec<&str> = cfg.reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["requests", "numpy"]);
+        assert_eq!(cfg.reqs[0].to_cfg_string(), r#"requests = "2.25.1""#);
+        assert!(cfg.reqs[1].markers.is_some());
+        assert_eq!(
+            cfg.extras.get("test"),
+            Some(&"pytest, pytest-cov".to_owned())
+        );
+    }
+
+    #[test]
+    fn setup_py_parsed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("setup.py");
+        fs::write(
+            &path,
+            "from setuptools import setup\n\
+             \n\
+             name = 'unused'\n\
+             \n\
+             setup(\n    \
+                 name=\"saturn\",\n    \
+                 version='0.3.1',\n    \
+                 install_requires=[\n        \
+                     'httpx[http2]>=0.16',  # For HTTP/2\n        \
+                     \"toml\",\n    \
+                 ],\n\
+             )\n",
+        )
+        .unwrap();
+
+        let mut cfg = Config::default();
+        parse_setup_py(&mut cfg, &path);
+
+        assert_eq!(cfg.name, Some("saturn".to_owned()));
+        assert_eq!(cfg.version, Some(Version::new(0, 3, 1)));
+        let names: Vec<&str> = cfg.reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["httpx", "toml"]);
+        assert_eq!(
+            cfg.reqs[0].install_with_extras,
+            Some(vec!["http2".to_owned()])
+        );
+
+        // Computed requirements can't be read without running it.
+        fs::write(&path, "setup(name='saturn', install_requires=REQS)\n").unwrap();
+        let mut cfg = Config::default();
+        parse_setup_py(&mut cfg, &path);
+        assert!(cfg.reqs.is_empty());
+    }
 }
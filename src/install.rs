@@ -1,10 +1,10 @@
 use crate::util::print_color;
-use crate::{commands, dep_types::Version, util};
+use crate::{build, commands, dep_types::Version, util};
 use flate2::read::GzDecoder;
 use regex::Regex;
 use ring::digest;
 use std::path::PathBuf;
-use std::{fs, io, io::BufRead, path::Path, process::Command};
+use std::{fs, io, io::BufRead, io::Read, path::Path};
 use tar::Archive;
 use termcolor::Color;
 
@@ -30,6 +30,51 @@ fn sha256_digest<R: io::Read>(mut reader: R) -> Result<digest::Digest, std::io::
     Ok(context.finish())
 }
 
+/// Where a downloaded archive is cached. This is keyed by the file's hash as well as its name,
+/// so a file that's re-uploaded with the same name isn't mistaken for the old one.
+pub fn cached_archive_path(cache_path: &Path, filename: &str, sha256: &str) -> PathBuf {
+    cache_path.join(sha256.to_lowercase()).join(filename)
+}
+
+/// Find the lowercase hex SHA256 of a file.
+pub fn file_sha256(path: &Path) -> io::Result<String> {
+    let file = fs::File::open(path)?;
+    let digest = sha256_digest(io::BufReader::new(file))?;
+    Ok(data_encoding::HEXLOWER.encode(digest.as_ref()))
+}
+
+/// Check if a cached archive is present and intact. A damaged one, eg from an interrupted
+/// download, is removed so it's downloaded again.
+pub fn cache_hit(archive_path: &Path, sha256: &str) -> bool {
+    if !archive_path.exists() {
+        return false;
+    }
+    let matches = file_sha256(archive_path).is_ok_and(|h| h == sha256.to_lowercase());
+
+    if !matches {
+        let _ = fs::remove_file(archive_path);
+    }
+    matches
+}
+
+/// Check an archive is complete and readable, before extracting anything from it: a wheel or
+/// zip's central directory, or a whole `.tar.gz`, which also checks the gzip CRC.
+fn check_archive(archive_path: &Path) -> Result<(), String> {
+    let file = fs::File::open(archive_path).map_err(|e| e.to_string())?;
+    let filename = archive_path.to_string_lossy();
+
+    if filename.ends_with(".whl") || filename.ends_with(".zip") {
+        zip::ZipArchive::new(file).map_err(|e| e.to_string())?;
+    } else if filename.ends_with(".tar.gz") || filename.ends_with(".tgz") {
+        let mut archive = Archive::new(GzDecoder::new(file));
+        for entry in archive.entries().map_err(|e| e.to_string())? {
+            let mut entry = entry.map_err(|e| e.to_string())?;
+            io::copy(&mut entry, &mut io::sink()).map_err(|e| e.to_string())?;
+        }
+    }
+    Ok(())
+}
+
 /// If the setup.py file uses `distutils.core`, replace with `setuptools`. This is required to build
 /// a wheel. Eg, replace `from distutils.core import setup` with `from setuptools import setup`.
 fn replace_distutils(setup_path: &Path) {
@@ -53,6 +98,64 @@ fn replace_distutils(setup_path: &Path) {
     }
 }
 
+/// Pure-Python wheels contain no compiled code, so their contents are the same for every
+/// Python version and OS. Eg `six-1.16.0-py2.py3-none-any.whl`.
+fn is_pure_wheel(filename: &str) -> bool {
+    let re = Regex::new(r"-py[\d.py]*-none-any\.whl$").unwrap();
+    re.is_match(filename)
+}
+
+/// Recreate the directory tree at `src` in `dest`, hardlinking files instead of copying them.
+/// Fall back to copying if we can't link, eg if the cache is on a different filesystem.
+pub(crate) fn link_tree(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            link_tree(&entry.path(), &target)?;
+            continue;
+        }
+        if target.exists() {
+            fs::remove_file(&target)?;
+        }
+        if fs::hard_link(entry.path(), &target).is_err() {
+            fs::copy(entry.path(), &target)?;
+        }
+    }
+    Ok(())
+}
+
+/// Extract a pure-Python wheel into the shared cache once, then link its contents into `lib`,
+/// so environments for different Python versions don't each store a copy. It's cached by the
+/// archive's SHA256, since different indexes may serve different files with the same name.
+fn install_pure_wheel(archive_file: &fs::File, filename: &str, sha256: &str, paths: &util::Paths) {
+    let sha256 = sha256.to_lowercase();
+    let unpacked_path = paths.cache.join("unpacked").join(&sha256);
+
+    if !unpacked_path.exists() {
+        // Extract to a temporary folder first, so an interrupted extraction doesn't leave
+        // an incomplete copy in the cache.
+        let partial_path = paths
+            .cache
+            .join("unpacked")
+            .join(format!("{}.partial", sha256));
+        if partial_path.exists() {
+            fs::remove_dir_all(&partial_path).expect("Problem removing partially-unpacked wheel");
+        }
+        util::extract_zip(archive_file, &partial_path, &None, &None);
+        fs::rename(&partial_path, &unpacked_path).expect("Problem moving unpacked wheel to cache");
+    }
+
+    if let Err(e) = link_tree(&unpacked_path, &paths.lib) {
+        util::abort(&format!(
+            "Problem linking {} from the cache into the lib folder: {}",
+            filename, e
+        ));
+    }
+}
+
 /// Remove scripts. Used when uninstalling.
 fn remove_scripts(scripts: &[String], scripts_path: &Path) {
     // todo: Likely not a great approach. QC.
@@ -73,25 +176,121 @@ fn remove_scripts(scripts: &[String], scripts_path: &Path) {
     }
 }
 
-pub fn make_script(path: &Path, name: &str, module: &str, func: &str) {
+/// Parse the `console_scripts` section of an `entry_points.txt`, into each script's name,
+/// module and function. Eg `black = black:patched_main`.
+fn parse_console_scripts(entry_points: &str) -> Vec<(String, String, String)> {
+    let re = Regex::new(r"^(.*?)\s*=\s*(.*?)\s*:\s*([\w.]*)").unwrap();
+    let mut result = vec![];
+    let mut in_scripts_section = false;
+    for line in entry_points.lines() {
+        // Remove potential leading spaces; have seen indents included.
+        let line = line.trim();
+        if line.starts_with('[') {
+            in_scripts_section = line == "[console_scripts]";
+            continue;
+        }
+        if !in_scripts_section {
+            continue;
+        }
+        // Any extras, eg `[d]` in `blackd = blackd:patched_main [d]`, are dropped.
+        if let Some(caps) = re.captures(line) {
+            result.push((
+                caps.get(1).unwrap().as_str().to_owned(),
+                caps.get(2).unwrap().as_str().to_owned(),
+                caps.get(3).unwrap().as_str().to_owned(),
+            ));
+        }
+    }
+    result
+}
+
+/// Write a `.bat` launcher next to a Python script, since Windows doesn't read shebangs.
+#[cfg(target_os = "windows")]
+fn make_launcher(script: &Path, py_path: &Path) -> io::Result<PathBuf> {
+    // Eg `pip3.9` becomes `pip3.9.bat`, but `mytool.py` becomes `mytool.bat`.
+    let launcher = if script.extension().is_some_and(|e| e == "py") {
+        script.with_extension("bat")
+    } else {
+        let mut name = script.as_os_str().to_owned();
+        name.push(".bat");
+        PathBuf::from(name)
+    };
+    let contents = format!(
+        "@echo off\r\n\"{}\" \"%~dp0{}\" %*\r\n",
+        py_path.display(),
+        script.file_name().unwrap_or_default().to_string_lossy()
+    );
+    fs::write(&launcher, contents)?;
+    Ok(launcher)
+}
+
+/// Write a console script that runs `module:func` with the environment's interpreter. It's
+/// valid as both a shell script and Python, so it can be run directly, or by `pyflow run`.
+/// On Windows, which can't run it directly, we add a `.bat` launcher next to it. Returns
+/// the files created.
+pub fn make_script(
+    path: &Path,
+    name: &str,
+    module: &str,
+    func: &str,
+    lib_path: &Path,
+    py_path: &Path,
+) -> Vec<PathBuf> {
+    // `func` may be an attribute of an object in the module, eg `main.run`.
+    let import = func.split('.').next().unwrap_or(func);
     let contents = format!(
-        r"import re
+        r#"#!/bin/sh
+'''exec' "{py}" "$0" "$@"
+' '''
+import re
 import sys
 
-from {} import {}
+sys.path.insert(0, r"{lib}")
+
+from {module} import {import}
 
 if __name__ == '__main__':
-    sys.argv[0] = re.sub(r'(-script\.pyw?|\.exe)?$', '', sys.argv[0])
-    sys.exit({}())",
-        module, func, func
+    sys.argv[0] = re.sub(r'(-script\.pyw?|\.exe|\.bat)?$', '', sys.argv[0])
+    sys.exit({func}())
+"#,
+        py = py_path.display(),
+        lib = lib_path.display(),
+        module = module,
+        import = import,
+        func = func,
     );
 
     fs::write(path, contents)
         .unwrap_or_else(|_| util::abort(&format!("Problem creating script file for {}", name)));
+    #[allow(unused_mut)]
+    let mut created = vec![path.to_owned()];
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap_or_else(|_| {
+            util::abort(&format!(
+                "Problem making the script for {} executable",
+                name
+            ))
+        });
+    }
+
+    #[cfg(target_os = "windows")]
+    {
+        let launcher = make_launcher(path, py_path).unwrap_or_else(|_| {
+            util::abort(&format!("Problem creating the launcher for {}", name))
+        });
+        created.push(launcher);
+    }
+    created
 }
 
 /// Find `dist-info` folder for package.
 fn find_dist_info_path(name: &str, version: &Version, lib_path: &Path) -> PathBuf {
+    if let Some(path) = util::find_dist_info(lib_path, name, version) {
+        return path;
+    }
     let mut dist_info_path = lib_path.join(format!("{}-{}.dist-info", name, version.to_string()));
     // If we can't find the dist_info path, it may be due to it not using a full 3-digit semver format.
     if !dist_info_path.exists() && (version.patch == Some(0) || version.patch == None) {
@@ -104,66 +303,322 @@ fn find_dist_info_path(name: &str, version: &Version, lib_path: &Path) -> PathBu
     dist_info_path
 }
 
-/// Set up entry points (ie scripts like `ipython`, `black` etc) in a single file.
-/// Alternatively, we could just parse all `dist-info` folders every run; this should
-/// be faster.
-pub fn setup_scripts(name: &str, version: &Version, lib_path: &Path, entry_pt_path: &Path) {
-    let mut scripts = vec![];
-    let dist_info_path = find_dist_info_path(name, version, lib_path);
+/// Move the files in `src` into `dest`, merging with any folders already there. Returns each
+/// file's old and new path.
+fn move_tree(src: &Path, dest: &Path) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+    let mut moved = vec![];
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            moved.append(&mut move_tree(&entry.path(), &target)?);
+            continue;
+        }
+        if target.exists() {
+            fs::remove_file(&target)?;
+        }
+        fs::rename(entry.path(), &target)?;
+        moved.push((entry.path(), target));
+    }
+    Ok(moved)
+}
+
+/// The shebang to replace `line` with, if it runs Python: `#!python`, as
+/// [the wheel spec](https://www.python.org/dev/peps/pep-0427/#installing-a-wheel-distribution-1-0-py32-none-any-whl)
+/// uses, or the path of the interpreter on the machine that built the wheel, eg
+/// `#!/usr/bin/python3` or `#!/usr/bin/env python3`. Any arguments are kept.
+fn new_shebang(line: &str, py_path: &Path) -> Option<String> {
+    let re =
+        Regex::new(r"^#!\s*(?:\S*/env\s+)?(?:\S*/)?python[\d.]*w?(?:\.exe)?(\s.*)?\r?$").unwrap();
+    let caps = re.captures(line)?;
+    let args = caps.get(1).map(|a| a.as_str().trim_end()).unwrap_or("");
+    Some(format!("#!{}{}", py_path.display(), args))
+}
+
+/// Point a script's shebang at the environment's interpreter, if it runs Python, so the script
+/// works when run directly. Returns whether the script changed.
+fn rewrite_shebang(script: &Path, py_path: &Path) -> io::Result<bool> {
+    let data = fs::read(script)?;
+    let first_line_end = data.iter().position(|b| *b == b'\n').unwrap_or(data.len());
+    let shebang = match std::str::from_utf8(&data[..first_line_end])
+        .ok()
+        .and_then(|line| new_shebang(line, py_path))
+    {
+        Some(s) => s,
+        None => return Ok(false),
+    };
 
-    if let Ok(ep_file) = fs::File::open(&dist_info_path.join("entry_points.txt")) {
-        let mut in_scripts_section = false;
-        for line in io::BufReader::new(ep_file).lines().flatten() {
-            if line.contains("[console_scripts]") {
-                in_scripts_section = true;
+    let mut new_data = shebang.into_bytes();
+    new_data.extend_from_slice(&data[first_line_end..]);
+    // Remove the file before writing, in case it's hardlinked to the wheel cache.
+    fs::remove_file(script)?;
+    fs::write(script, new_data)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(script, fs::Permissions::from_mode(0o755))?;
+    }
+    Ok(true)
+}
+
+/// Move each folder in a wheel's `.data` folder where it belongs, then remove it. Returns the
+/// files moved, and the scripts whose shebangs we rewrote.
+#[allow(clippy::type_complexity)]
+fn move_data(
+    name: &str,
+    data_path: &Path,
+    paths: &util::Paths,
+) -> io::Result<(Vec<(PathBuf, PathBuf)>, Vec<PathBuf>)> {
+    let env_path = paths.lib.parent().unwrap_or(&paths.lib);
+    let mut moved = vec![];
+    let mut rewritten = vec![];
+    for entry in fs::read_dir(data_path)? {
+        let entry = entry?;
+        let kind = entry.file_name().to_string_lossy().into_owned();
+        let dest = match kind.as_ref() {
+            "purelib" | "platlib" => paths.lib.clone(),
+            "scripts" => paths.entry_pt.clone(),
+            "headers" => env_path.join("include").join(name),
+            "data" => env_path.to_owned(),
+            _ => {
+                print_color(
+                    &format!("Skipping unknown wheel data folder {} in {}", kind, name),
+                    Color::Yellow,
+                );
                 continue;
             }
-            if line.starts_with('[') {
-                // no longer in scripts section.
-                break;
+        };
+
+        let mut files = move_tree(&entry.path(), &dest)?;
+        if kind == "scripts" {
+            for (_, script) in &files {
+                if rewrite_shebang(script, &paths.bin.join("python"))? {
+                    rewritten.push(script.clone());
+                }
             }
-            if in_scripts_section && !line.is_empty() {
-                // Remove potential leading spaces; have seen indents included.
-                scripts.push(line.clone().replace(" ", ""));
+        }
+        moved.append(&mut files);
+    }
+    fs::remove_dir_all(data_path)?;
+    Ok((moved, rewritten))
+}
+
+/// Install the contents of a wheel's `.data` folder, which we've extracted into `lib` along with
+/// the rest of the wheel: `purelib` and `platlib` go in `lib`, `scripts` in the environment's
+/// `bin`, `headers` in its `include`, and `data` in the environment itself. `RECORD` is updated
+/// to match.
+pub fn install_data(name: &str, version: &Version, paths: &util::Paths) {
+    let dist_info_path = find_dist_info_path(name, version, &paths.lib);
+    let data_path = dist_info_path.with_extension("data");
+    if !data_path.exists() {
+        return;
+    }
+    let (moved, rewritten) = match move_data(name, &data_path, paths) {
+        Ok(m) => m,
+        Err(e) => util::abort(&format!(
+            "Problem installing the data files from {}: {}",
+            name, e
+        )),
+    };
+
+    // Rewritten scripts no longer match the hash the wheel lists, so we drop it.
+    let record_file = dist_info_path.join("RECORD");
+    if let Ok(record) = fs::read_to_string(&record_file) {
+        let mut new_record = String::new();
+        for line in record.lines() {
+            let (path, rest) = line.split_at(line.find(',').unwrap_or(line.len()));
+            match moved.iter().find(|(old, _)| *old == paths.lib.join(path)) {
+                Some((_, new)) if rewritten.contains(new) => {
+                    new_record.push_str(&format!("{},,", record_path(&paths.lib, new)))
+                }
+                Some((_, new)) => {
+                    new_record.push_str(&format!("{}{}", record_path(&paths.lib, new), rest))
+                }
+                None => new_record.push_str(line),
             }
+            new_record.push('\n');
+        }
+        // Remove the file before writing, in case it's hardlinked to the wheel cache.
+        if fs::remove_file(&record_file).is_err() || fs::write(&record_file, new_record).is_err() {
+            print_color(
+                &format!("Problem updating RECORD for {}", name),
+                Color::Yellow,
+            );
         }
-    } // else: Probably no scripts.
+    }
 
-    // Now that we've found scripts, add them to our unified file.
-    // Note that normally, python uses a bin directory.
-    //    // todo: Currently we're setting up the unified file, and the bin/script file.
-    //    let scripts_file = &lib_path.join("../console_scripts.txt");
-    //    if !scripts_file.exists() {
-    //        fs::File::create(scripts_file).expect("Problem creating console_scripts.txt");
-    //    }
-    //
-    //    let mut existing_scripts =
-    //        fs::read_to_string(scripts_file).expect("Can't find console_scripts.txt");
+    #[cfg(target_os = "windows")]
+    {
+        let py_path = paths.bin.join("python");
+        let launchers: Vec<PathBuf> = rewritten
+            .iter()
+            .filter_map(|script| make_launcher(script, &py_path).ok())
+            .collect();
+        if add_to_record(&dist_info_path, &paths.lib, &launchers).is_err() {
+            print_color(
+                &format!("Problem adding the script launchers for {} to RECORD", name),
+                Color::Yellow,
+            );
+        }
+    }
+}
+
+/// Set up entry points (ie scripts like `ipython`, `black` etc) as executable scripts in the
+/// environment's `bin` folder, from the package's `entry_points.txt`.
+pub fn setup_scripts(name: &str, version: &Version, paths: &util::Paths) {
+    let dist_info_path = find_dist_info_path(name, version, &paths.lib);
+
+    // If there's no `entry_points.txt`, there are probably no scripts.
+    let scripts = match fs::read_to_string(dist_info_path.join("entry_points.txt")) {
+        Ok(data) => parse_console_scripts(&data),
+        Err(_) => return,
+    };
+    if scripts.is_empty() {
+        return;
+    }
 
-    if !entry_pt_path.exists() && fs::create_dir(&entry_pt_path).is_err() {
+    if !paths.entry_pt.exists() && fs::create_dir_all(&paths.entry_pt).is_err() {
         util::abort("Problem creating script path")
     }
 
-    for new_script in scripts {
-        let re = Regex::new(r"^(.*?)\s*=\s*(.*?):(.*)$").unwrap();
-        if let Some(caps) = re.captures(&new_script) {
-            let name = caps.get(1).unwrap().as_str();
-            let module = caps.get(2).unwrap().as_str();
-            let func = caps.get(3).unwrap().as_str();
-            let path = entry_pt_path.join(name);
-            make_script(&path, name, module, func);
-            // `wheel` is a dependency required internally, but the user doesn't care.
-            if name != "wheel" {
-                util::print_color(&format!("Added a console script: {}", name), Color::Green);
+    let py_path = paths.bin.join("python");
+    let mut created = vec![];
+    for (script, module, func) in scripts {
+        let path = paths.entry_pt.join(&script);
+        created.append(&mut make_script(
+            &path, &script, &module, &func, &paths.lib, &py_path,
+        ));
+        // `wheel` is a dependency required internally, but the user doesn't care.
+        if script != "wheel" {
+            util::print_color(&format!("Added a console script: {}", script), Color::Green);
+        }
+    }
+
+    // List the scripts in `RECORD`, so uninstalling removes them.
+    if add_to_record(&dist_info_path, &paths.lib, &created).is_err() {
+        print_color(
+            &format!("Problem adding the console scripts for {} to RECORD", name),
+            Color::Yellow,
+        );
+    }
+}
+
+/// The path of `file` as listed in `RECORD`: relative to `lib_path` if it's in the same
+/// environment, eg `../bin/black`, or absolute otherwise.
+fn record_path(lib_path: &Path, file: &Path) -> String {
+    let path = if let Ok(rel) = file.strip_prefix(lib_path) {
+        rel.to_owned()
+    } else {
+        match lib_path.parent().map(|env| file.strip_prefix(env)) {
+            Some(Ok(rel)) => Path::new("..").join(rel),
+            _ => file.to_owned(),
+        }
+    };
+    path.to_string_lossy().replace('\\', "/")
+}
+
+/// Add files we've created outside the wheel, eg console scripts, to a package's `RECORD`.
+/// If there's no `RECORD`, leave it alone, since one that only lists these files would make
+/// `uninstall` miss the rest of the package.
+fn add_to_record(dist_info_path: &Path, lib_path: &Path, files: &[PathBuf]) -> io::Result<()> {
+    let record_file = dist_info_path.join("RECORD");
+    if !record_file.exists() {
+        return Ok(());
+    }
+    let mut data = fs::read_to_string(&record_file)?;
+    if !data.is_empty() && !data.ends_with('\n') {
+        data.push('\n');
+    }
+    for file in files {
+        let line = format!("{},,", record_path(lib_path, file));
+        if !data.lines().any(|l| l == line) {
+            data.push_str(&line);
+            data.push('\n');
+        }
+    }
+    // Remove the file before writing, in case it's hardlinked to the wheel cache.
+    fs::remove_file(&record_file)?;
+    fs::write(&record_file, data)
+}
+
+/// Read the file paths from a `RECORD`, relative to `lib_path`. Each line is a CSV row
+/// of path, hash and size; the path is quoted if it contains a comma.
+fn read_record(record_file: &Path, lib_path: &Path) -> io::Result<Vec<PathBuf>> {
+    let data = fs::read_to_string(record_file)?;
+    let mut result = vec![];
+    for line in data.lines() {
+        let path = match line.strip_prefix('"') {
+            Some(quoted) => quoted.split('"').next().unwrap_or_default(),
+            None => line.split(',').next().unwrap_or_default(),
+        };
+        if !path.is_empty() {
+            result.push(util::normalize_path(&lib_path.join(path)));
+        }
+    }
+    Ok(result)
+}
+
+/// Remove exactly the files a package's `RECORD` lists, along with their compiled bytecode,
+/// then any folders this leaves empty. Folders other packages still have files in, eg
+/// namespace packages, are left alone.
+fn uninstall_record(record_file: &Path, lib_path: &Path) -> io::Result<()> {
+    let lib_path = &util::normalize_path(lib_path);
+    let mut dirs = vec![];
+    for file in read_record(record_file, lib_path)? {
+        if file.is_file() {
+            fs::remove_file(&file)?;
+        }
+        if file.extension().is_some_and(|e| e == "py") {
+            remove_bytecode(&file);
+        }
+        let mut parent = file.parent();
+        while let Some(dir) = parent {
+            if !dir.starts_with(lib_path) || dir == lib_path {
+                break;
             }
+            dirs.push(dir.to_owned());
+            parent = dir.parent();
         }
     }
+    dirs.push(record_file.parent().unwrap().to_owned());
+
+    // Deepest first, so a folder's emptied sub-folders are gone before we check it.
+    dirs.sort_by_key(|d| std::cmp::Reverse(d.components().count()));
+    dirs.dedup();
+    for dir in dirs {
+        let pycache = dir.join("__pycache__");
+        if pycache.is_dir() && fs::read_dir(&pycache)?.next().is_none() {
+            fs::remove_dir(&pycache)?;
+        }
+        if dir.is_dir() && fs::read_dir(&dir)?.next().is_none() {
+            fs::remove_dir(&dir)?;
+        }
+    }
+    Ok(())
+}
 
-    //    fs::write(scripts_file, existing_scripts).expect("Unable to write to the console_scripts file");
+/// Remove the `.pyc` files Python compiled from `py_file`, eg `__pycache__/six.cpython-38.pyc`.
+fn remove_bytecode(py_file: &Path) {
+    let (stem, dir) = match (py_file.file_stem(), py_file.parent()) {
+        (Some(s), Some(d)) => (s.to_string_lossy(), d.join("__pycache__")),
+        _ => return,
+    };
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let filename = entry.file_name().to_string_lossy().into_owned();
+            if filename.starts_with(&format!("{}.", stem)) && filename.ends_with(".pyc") {
+                let _ = fs::remove_file(entry.path());
+            }
+        }
+    }
 }
 
 /// Download and install a package. For wheels, we can just extract the contents into
-/// the lib folder.  For source dists, make a wheel first.
+/// the lib folder.  For source dists, make a wheel first. If `require_hash` is set, abort if
+/// the file doesn't match `expected_digest`, instead of asking whether to continue.
 #[allow(clippy::too_many_arguments)]
 pub fn download_and_install_package(
     name: &str,
@@ -174,40 +629,56 @@ pub fn download_and_install_package(
     paths: &util::Paths,
     package_type: PackageType,
     rename: &Option<(u32, String)>,
-) -> Result<(), reqwest::Error> {
+    require_hash: bool,
+) {
     if !paths.lib.exists() {
         fs::create_dir_all(&paths.lib).expect("Problem creating lib directory");
     }
     if !paths.cache.exists() {
         fs::create_dir_all(&paths.cache).expect("Problem creating cache directory");
     }
-    let archive_path = paths.cache.join(filename);
+    let archive_path = cached_archive_path(&paths.cache, filename, expected_digest);
 
-    // If the archive is already in the lib folder, don't re-download it. Note that this
-    // isn't the usual flow, but may have some uses.
-    if !archive_path.exists() {
-        // Save the file
-        let mut resp = reqwest::get(url)?; // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded package file");
-        // todo: DRY between here and py_versions.
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the package archive: {:?}", e));
-        }
-    }
+    let download = || match util::http::download_all(&[(url.to_owned(), archive_path.clone())]) {
+        Ok(hashes) => hashes[0].clone(),
+        Err(e) => util::abort(&format!("Problem downloading the package archive: {}", e)),
+    };
 
-    let file = util::open_archive(&archive_path);
+    // Reuse the archive if we've downloaded it before, eg for another project. Checking the
+    // cache hashes it; a new download's hashed as it's written.
+    let mut file_digest_str = if cache_hit(&archive_path, expected_digest) {
+        expected_digest.to_lowercase()
+    } else {
+        download()
+    };
 
-    // https://rust-lang-nursery.github.io/rust-cookbook/cryptography/hashing.html
-    let reader = io::BufReader::new(&file);
-    let file_digest = sha256_digest(reader).unwrap_or_else(|_| {
-        util::abort(&format!("Problem reading hash for {}", filename));
-    });
+    // Extracting a truncated or corrupted download could leave a half-installed package, so
+    // check it first, and download it again once if there's a problem.
+    let mut intact = check_archive(&archive_path);
+    if intact.is_err() || file_digest_str != expected_digest.to_lowercase() {
+        let problem = match &intact {
+            Err(e) => format!("is damaged ({})", e),
+            Ok(_) => "doesn't match its hash".to_owned(),
+        };
+        log::warn!("The download of {} {}; trying again", filename, problem);
+        let _ = fs::remove_file(&archive_path);
+        file_digest_str = download();
+        intact = check_archive(&archive_path);
+    }
+    if let Err(e) = intact {
+        let _ = fs::remove_file(&archive_path);
+        util::abort(&format!("The download of {} is damaged: {}", filename, e));
+    }
 
-    let file_digest_str = data_encoding::HEXUPPER.encode(file_digest.as_ref());
-    if file_digest_str.to_lowercase() != expected_digest.to_lowercase() {
+    if file_digest_str != expected_digest.to_lowercase() {
+        if require_hash {
+            util::abort(&format!(
+                "Hash failed for {}. Expected: {}, Actual: {}",
+                filename,
+                expected_digest.to_lowercase(),
+                file_digest_str.to_lowercase()
+            ));
+        }
         util::print_color(&format!("Hash failed for {}. Expected: {}, Actual: {}. Continue with installation anyway? (yes / no)", filename, expected_digest.to_lowercase(), file_digest_str.to_lowercase()), Color::Red);
 
         let mut input = String::new();
@@ -227,7 +698,6 @@ pub fn download_and_install_package(
         }
     }
 
-    // We must re-open the file after computing the hash.
     let archive_file = util::open_archive(&archive_path);
 
     let rename = rename
@@ -236,7 +706,13 @@ pub fn download_and_install_package(
 
     match package_type {
         PackageType::Wheel => {
-            util::extract_zip(&archive_file, &paths.lib, &rename, &None);
+            // Renamed packages have their files modified after extraction, so they get their
+            // own copy.
+            if rename.is_none() && is_pure_wheel(filename) {
+                install_pure_wheel(&archive_file, filename, &file_digest_str, paths);
+            } else {
+                util::extract_zip(&archive_file, &paths.lib, &rename, &None);
+            }
         }
         PackageType::Source => {
             // todo: Support .tar.bz2
@@ -316,9 +792,10 @@ pub fn download_and_install_package(
             }
             // Check if we have a zip file instead.
             if let Err(e) = archive_error {
-                println!(
+                log::debug!(
                     "Problem opening the tar.gz archive: {:?}: {:?}, checking if it's a zip...",
-                    &archive_file, e
+                    &archive_file,
+                    e
                 );
                 util::extract_zip(&archive_file, &paths.lib, &None, &Some((name, filename)));
             }
@@ -344,132 +821,25 @@ pub fn download_and_install_package(
 
             let extracted_parent = paths.lib.join(folder_name);
 
-            replace_distutils(&extracted_parent.join("setup.py"));
-
-            #[cfg(target_os = "windows")]
-            {
-                let output = Command::new(paths.bin.join("python"))
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
+            // Older sdists may use `distutils`, which can't build wheels.
+            let setup_path = extracted_parent.join("setup.py");
+            if setup_path.exists() {
+                replace_distutils(&setup_path);
             }
+
             // The Linux and Mac builds appear to be unable to build wheels due to
             // missing the ctypes library; revert to system python.
-            #[cfg(target_os = "linux")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-            #[cfg(target_os = "macos")]
-            {
-                let output = Command::new("python3")
-                    .current_dir(&extracted_parent)
-                    .args(&["setup.py", "bdist_wheel"])
-                    .output()
-                    .unwrap_or_else(|_| {
-                        panic!(
-                            "Problem running setup.py bdist_wheel in folder: {:?}. Py path: {:?}",
-                            &extracted_parent,
-                            paths.bin.join("python")
-                        )
-                    });
-                util::check_command_output_with(&output, |s| {
-                    panic!(
-                        "running setup.py bdist_wheel in folder {:?}. Py path: {:?}: {}",
-                        &extracted_parent,
-                        paths.bin.join("python"),
-                        s
-                    );
-                });
-            }
-
-            let dist_path = &extracted_parent.join("dist");
-            if !dist_path.exists() {
-                #[cfg(target_os = "windows")]
-                let error = &format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for Windows, and the system is missing dependencies required to compile it, \
-                 or if on WSL and installing to a mounted directory.",
-                    name
-                );
-
-                #[cfg(target_os = "linux")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.\
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-                #[cfg(target_os = "macos")]
-                let error = format!(
-                    "Problem building {} from source. \
-                 This may occur if a package that requires compiling has no wheels available \
-                 for this OS and this system is missing dependencies required to compile it.
-                 Try running `pip install --upgrade wheel`, then try again",
-                    name
-                );
-
-                util::abort(&error);
-            }
-
-            let built_wheel_filename = util::find_first_file(dist_path)
-                .file_name()
-                .expect("Unable to find built wheel filename")
-                .to_str()
-                .unwrap()
-                .to_owned();
-
-            let moved_path = paths.lib.join(&built_wheel_filename);
-
-            // todo: Again, try to move vice copy.
-            let options = fs_extra::file::CopyOptions::new();
-            fs_extra::file::move_file(dist_path.join(&built_wheel_filename), &moved_path, &options)
-                .expect("Problem copying wheel built from source");
-
-            let file_created = fs::File::open(&moved_path).expect("Can't find created wheel.");
+            #[cfg(target_os = "windows")]
+            let py_path = paths.bin.join("python");
+            #[cfg(not(target_os = "windows"))]
+            let py_path = PathBuf::from("python3");
+
+            // Build it with the backend its `pyproject.toml` declares, or setuptools.
+            let built_wheel =
+                build::build_wheel(&extracted_parent, &py_path, &extracted_parent.join("dist"));
+            let file_created = fs::File::open(&built_wheel).expect("Can't find created wheel.");
             util::extract_zip(&file_created, &paths.lib, &rename, &None);
 
-            // Remove the created and moved wheel
-            if fs::remove_file(moved_path).is_err() {
-                util::abort(&format!(
-                    "Problem removing this downloaded package: {:?}",
-                    &built_wheel_filename
-                ));
-            }
             // Remove the source directeory extracted from the tar.gz file.
             if fs::remove_dir_all(&extracted_parent).is_err() {
                 util::abort(&format!(
@@ -479,27 +849,40 @@ pub fn download_and_install_package(
             }
         }
     }
-    setup_scripts(name, version, &paths.lib, &paths.entry_pt);
-
-    Ok(())
+    install_data(name, version, paths);
+    setup_scripts(name, version, paths);
 }
 
 pub fn uninstall(name_ins: &str, vers_ins: &Version, lib_path: &Path) {
     #[cfg(target_os = "windows")]
-    println!(
+    log::info!(
         "Uninstalling {}: {}...",
         name_ins,
         vers_ins.to_string_color()
     );
     #[cfg(target_os = "linux")]
-    println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
+    log::info!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
     #[cfg(target_os = "macos")]
-    println!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
+    log::info!("🗑 Uninstalling {}: {}...", name_ins, vers_ins.to_string());
 
     // Uninstall the package
     // package folders appear to be lowercase, while metadata keeps the package title's casing.
 
     let dist_info_path = find_dist_info_path(name_ins, vers_ins, lib_path);
+
+    // Wheels list every file they install in `RECORD`, as do we for console scripts. Use it
+    // when we can, instead of guessing which files are the package's.
+    let record_file = dist_info_path.join("RECORD");
+    if record_file.exists() {
+        if let Err(e) = uninstall_record(&record_file, lib_path) {
+            print_color(
+                &format!("Problem uninstalling {} {}: {}", name_ins, vers_ins, e),
+                Color::Red, // Dark
+            );
+        }
+        return;
+    }
+
     let egg_info_path = lib_path.join(format!("{}-{}.egg-info", name_ins, vers_ins.to_string()));
 
     // todo: could top_level.txt be in egg-info too?
@@ -585,12 +968,15 @@ pub fn rename_package_files(top_path: &Path, old: &str, new: &str) {
         // Todo: Is this one too general? Supersedes the first. Needed for things like `add_newdoc('numpy.core.multiarray...`
         data = data.replace(&format!("{}.", old), &format!("{}.", new));
 
+        // Remove the file before writing, in case it's hardlinked to the wheel cache; we don't
+        // want to modify the copy other environments use.
+        fs::remove_file(&path).expect("Problem removing file while renaming");
         fs::write(path, data).expect("Problem writing file while renaming");
     }
 }
 
 /// Rename metadata files.
-pub fn rename_metadata(path: &Path, _old: &str, new: &str) {
+pub fn rename_metadata(path: &Path, old: &str, new: &str) {
     // todo: Handle multiple items in top_level. Figure out how to handle that.
     let top_file = path.join("top_level.txt");
     //    let mut top_data = fs::read_to_string(&top_file).expect("Problem opening top_level.txt");
@@ -599,54 +985,160 @@ pub fn rename_metadata(path: &Path, _old: &str, new: &str) {
 
     fs::write(top_file, top_data).expect("Problem writing file while renaming");
 
+    // Match the folder we renamed while extracting, so uninstalling finds its files.
+    let record_file = path.join("RECORD");
+    if let Ok(record) = fs::read_to_string(&record_file) {
+        let renamed: Vec<String> = record
+            .lines()
+            .map(|l| rename_record_line(l, old, new))
+            .collect();
+        fs::write(record_file, renamed.join("\n") + "\n")
+            .expect("Problem writing RECORD while renaming");
+    }
+
     // todo: Modify other files like entry_points.txt, perhaps.
 }
 
-/// Clone a git repo of a Python package, and build/install a wheel from it.
-/// Or do the same, but with a path instead of git.
+/// Rename the top-level folder in a `RECORD` line's path, eg `old/__init__.py,...`. Other paths,
+/// and the hash and size, are left alone.
+fn rename_record_line(line: &str, old: &str, new: &str) -> String {
+    let (quote, path) = match line.strip_prefix('"') {
+        Some(quoted) => ("\"", quoted),
+        None => ("", line),
+    };
+    match path.strip_prefix(old).filter(|rest| rest.starts_with('/')) {
+        Some(rest) => format!("{}{}{}", quote, new, rest),
+        None => line.to_owned(),
+    }
+}
+
+/// Clone a git repo of a Python package, check out `rev`, eg a commit hash or `origin/main`,
+/// and build/install a wheel from it. Returns the package's metadata, and the commit built.
+/// If `install` is false, only build it, eg to find its dependencies for the lock file.
 pub fn download_and_install_git(
     name: &str,
     url: &str,
+    rev: &str,
     git_path: &Path,
     paths: &util::Paths,
-) -> util::Metadata {
+    install: bool,
+) -> (util::Metadata, String) {
     if !git_path.exists() {
         fs::create_dir_all(git_path).expect("Problem creating git path");
     }
 
     let folder_name = util::standardize_name(name); // todo: Will this always work?
-                                                    //    match url {
-                                                    //        GitPath::Git(url) => {
-                                                    // Download the repo into the pyflow folder.
-                                                    // todo: Handle checking if it's current and correct; not just a matching folder
-                                                    // todo name.
-    if !&git_path.join(&folder_name).exists() && commands::download_git_repo(url, git_path).is_err()
-    {
+    let repo_path = git_path.join(&folder_name);
+    if commands::download_git_repo(url, &repo_path).is_err() {
         util::abort(&format!("Problem cloning this repo: {}", url));
-    } // todo to keep dl small while troubleshooting.
-      //        }
-      //        GitPath::Path(path) => {
-      //            let f = &git_path.join(&folder_name);
-      //            if !&f.exists() {
-      //                fs::create_dir(f).expect("Problem creating dir for a path dependency");
-      //                let options = fs_extra::dir::CopyOptions::new();
-      //                fs_extra::dir::copy(PathBuf::from(path), &git_path, &options)
-      //                    .expect("Problem copying path requirement to lib folder");
-      //            }
-      //        }
-      //}
-
-    // Build a wheel from the repo
-    let output = Command::new(paths.bin.join("python"))
-        // We assume that the module code is in the repo's immediate subfolder that has
-        // the package's name.
-        .current_dir(&git_path.join(&folder_name))
-        .args(&["setup.py", "bdist_wheel"])
+    }
+    let commit = match commands::git_checkout(&repo_path, rev) {
+        Ok(c) => c,
+        Err(e) => util::abort(&format!("Problem with the repo for {}: {}", name, e)),
+    };
+
+    let wheel_path = repo_path.join("dist");
+    let metadata = if install {
+        build_and_install(name, &repo_path, &wheel_path, paths)
+    } else {
+        build_metadata(name, &repo_path, &wheel_path, paths)
+    };
+    (metadata, commit)
+}
+
+/// Build a local project, eg a path dependency, and install it, as if it were from an index.
+/// `build_path` holds the wheel while we install it. Returns the package's metadata. If
+/// `install` is false, only build it.
+pub fn install_path(
+    name: &str,
+    project_path: &Path,
+    build_path: &Path,
+    paths: &util::Paths,
+    install: bool,
+) -> util::Metadata {
+    if !project_path.exists() {
+        util::abort(&format!(
+            "Can't find the path dependency {} at {:?}",
+            name, project_path
+        ));
+    }
+    let wheel_path = build_path.join(util::standardize_name(name));
+    if install {
+        build_and_install(name, project_path, &wheel_path, paths)
+    } else {
+        build_metadata(name, project_path, &wheel_path, paths)
+    }
+}
+
+/// Build a wheel from `project_path` into `wheel_path`, with its
+/// [PEP 517](https://www.python.org/dev/peps/pep-0517/) build backend, eg setuptools or flit.
+/// Returns the wheel's path.
+fn build_wheel(name: &str, project_path: &Path, wheel_path: &Path, paths: &util::Paths) -> PathBuf {
+    if wheel_path.exists() {
+        fs::remove_dir_all(wheel_path).expect("Problem clearing old builds");
+    }
+    let output = commands::python_command(paths.bin.join("python"))
+        .current_dir(project_path)
+        .args(["-m", "pip", "wheel", "--quiet", "--no-deps", "--wheel-dir"])
+        .arg(wheel_path)
+        .arg(".")
         .output()
-        .expect("Problem running setup.py bdist_wheel");
-    util::check_command_output(&output, "running setup.py bdist_wheel");
+        .expect("Problem running pip wheel");
+    util::check_command_output_with(&output, |e| {
+        util::abort(&format!("Problem building {}: {}", name, e));
+    });
+    util::find_first_file(wheel_path)
+}
+
+/// Build a wheel, and read its metadata without installing it.
+fn build_metadata(
+    name: &str,
+    project_path: &Path,
+    wheel_path: &Path,
+    paths: &util::Paths,
+) -> util::Metadata {
+    let archive_path = build_wheel(name, project_path, wheel_path, paths);
+    let metadata = wheel_metadata(name, &archive_path);
+    fs::remove_dir_all(wheel_path).expect("Problem removing the wheel we built");
+    metadata
+}
+
+/// Read the metadata from a wheel's top-level `dist-info` folder.
+fn wheel_metadata(name: &str, archive_path: &Path) -> util::Metadata {
+    let mut archive = zip::ZipArchive::new(util::open_archive(archive_path))
+        .unwrap_or_else(|_| util::abort(&format!("Problem reading the wheel built for {}", name)));
+
+    // Vendored packages may have their own `dist-info` folders.
+    let metadata_name = archive
+        .file_names()
+        .find(|f| f.ends_with(".dist-info/METADATA") && f.matches('/').count() == 1)
+        .map(str::to_owned)
+        .unwrap_or_else(|| util::abort(&format!("The wheel built for {} has no METADATA", name)));
+    let mut data = String::new();
+    archive
+        .by_name(&metadata_name)
+        .expect("Problem finding METADATA in the wheel")
+        .read_to_string(&mut data)
+        .expect("Problem reading METADATA from the wheel");
+    util::parse_metadata_str(&data)
+}
+
+/// Build a wheel, then install it, replacing any version already installed.
+fn build_and_install(
+    name: &str,
+    project_path: &Path,
+    wheel_path: &Path,
+    paths: &util::Paths,
+) -> util::Metadata {
+    let archive_path = build_wheel(name, project_path, wheel_path, paths);
+
+    // Otherwise, a changed version would leave the old one's files behind.
+    for (installed_name, version, _) in util::find_installed(&paths.lib) {
+        if util::compare_names(&installed_name, name) {
+            uninstall(&installed_name, &version, &paths.lib);
+        }
+    }
 
-    let archive_path = util::find_first_file(&git_path.join(folder_name).join("dist"));
     let filename = archive_path
         .file_name()
         .expect("Problem pulling filename from archive path");
@@ -677,14 +1169,400 @@ pub fn download_and_install_git(
 
     let metadata = util::parse_metadata(&paths.lib.join(dist_info).join("METADATA")); // todo temp!
 
-    setup_scripts(name, &metadata.version, &paths.lib, &paths.entry_pt);
+    install_data(name, &metadata.version, paths);
+    setup_scripts(name, &metadata.version, paths);
 
     // Remove the created and moved wheel
     if fs::remove_file(&archive_path).is_err() {
         util::abort(&format!(
-            "Problem removing this wheel built from a git repo: {:?}",
+            "Problem removing this wheel we built: {:?}",
             archive_path
         ));
     }
     metadata
 }
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn damaged_archives_caught() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let wheel = dir.join("pkg-1.0-py3-none-any.whl");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&wheel).unwrap());
+        zip.start_file("pkg.py", zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(&[b'x'; 4096]).unwrap();
+        zip.finish().unwrap();
+
+        let sdist = dir.join("pkg-1.0.tar.gz");
+        let gz = flate2::write::GzEncoder::new(
+            fs::File::create(&sdist).unwrap(),
+            flate2::Compression::default(),
+        );
+        let mut tar = tar::Builder::new(gz);
+        let data = vec![b'x'; 4096];
+        let mut header = tar::Header::new_gnu();
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        tar.append_data(&mut header, "pkg-1.0/setup.py", &data[..])
+            .unwrap();
+        tar.into_inner().unwrap().finish().unwrap();
+
+        let results: Vec<bool> = [&wheel, &sdist]
+            .iter()
+            .flat_map(|path| {
+                let intact = check_archive(path).is_ok();
+                // Cut the download short.
+                let bytes = fs::read(path).unwrap();
+                fs::write(path, &bytes[..bytes.len() / 2]).unwrap();
+                vec![intact, check_archive(path).is_ok()]
+            })
+            .collect();
+
+        assert_eq!(results, vec![true, false, true, false]);
+    }
+
+    #[test]
+    fn pure_wheels() {
+        assert!(is_pure_wheel("six-1.16.0-py2.py3-none-any.whl"));
+        assert!(is_pure_wheel("requests-2.28.1-py3-none-any.whl"));
+        assert!(!is_pure_wheel(
+            "numpy-1.24.0-cp311-cp311-manylinux_2_17_x86_64.whl"
+        ));
+        assert!(!is_pure_wheel("cffi-1.15.1-cp39-abi3-any.whl"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pure_wheel_hardlinked_across_envs() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let unpacked = root.join("cache/unpacked/0a1b2c");
+        fs::create_dir_all(unpacked.join("six-1.16.0.dist-info")).unwrap();
+        fs::write(unpacked.join("six.py"), "import sys").unwrap();
+        fs::write(unpacked.join("six-1.16.0.dist-info/RECORD"), "six.py").unwrap();
+
+        let lib_37 = root.join("__pypackages__/3.7/lib");
+        let lib_38 = root.join("__pypackages__/3.8/lib");
+        link_tree(&unpacked, &lib_37).unwrap();
+        link_tree(&unpacked, &lib_38).unwrap();
+
+        let inode = |p: &Path| fs::metadata(p).unwrap().ino();
+        assert_eq!(
+            inode(&lib_37.join("six.py")),
+            inode(&unpacked.join("six.py"))
+        );
+        assert_eq!(inode(&lib_37.join("six.py")), inode(&lib_38.join("six.py")));
+        assert_eq!(
+            inode(&lib_38.join("six-1.16.0.dist-info/RECORD")),
+            inode(&unpacked.join("six-1.16.0.dist-info/RECORD"))
+        );
+    }
+
+    #[test]
+    fn pure_wheel_cached_by_hash() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let paths = |env: &str| util::Paths {
+            bin: root.join(env).join("bin"),
+            lib: root.join(env).join("lib"),
+            entry_pt: root.join(env).join("bin"),
+            cache: root.join("cache"),
+        };
+
+        // Different files, eg from different indexes, with the same name.
+        let filename = "pkg-1.0-py3-none-any.whl";
+        let install = |contents: &[u8], sha256: &str, env: &str| {
+            let wheel = root.join(format!("{}-{}", sha256, filename));
+            let mut zip = zip::ZipWriter::new(fs::File::create(&wheel).unwrap());
+            zip.start_file("pkg.py", zip::write::FileOptions::default())
+                .unwrap();
+            zip.write_all(contents).unwrap();
+            zip.finish().unwrap();
+            install_pure_wheel(
+                &fs::File::open(&wheel).unwrap(),
+                filename,
+                sha256,
+                &paths(env),
+            );
+            fs::read(paths(env).lib.join("pkg.py")).unwrap()
+        };
+        let first = install(b"a = 1", "AAAA", "first");
+        let second = install(b"b = 2", "bbbb", "second");
+        let cached = root.join("cache/unpacked/aaaa/pkg.py").exists();
+
+        assert_eq!(first, b"a = 1");
+        assert_eq!(second, b"b = 2");
+        assert!(cached);
+    }
+
+    #[test]
+    fn console_scripts_parsed() {
+        let entry_points = "[gui_scripts]\nmygui = mypkg.gui:main\n\n\
+                            [console_scripts]\n\
+                            black = black:patched_main\n\
+                            blackd = blackd:patched_main [d]\n\
+                            \x20 mycli=mypkg.cli:app.run\n\n\
+                            [pytest11]\nmyplugin = mypkg.plugin\n";
+        assert_eq!(
+            parse_console_scripts(entry_points),
+            vec![
+                ("black".into(), "black".into(), "patched_main".into()),
+                ("blackd".into(), "blackd".into(), "patched_main".into()),
+                ("mycli".into(), "mypkg.cli".into(), "app.run".into()),
+            ]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn console_script_runnable() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        fs::create_dir_all(root.join("bin")).unwrap();
+        // Stands in for the interpreter, to show what the script runs.
+        let py = root.join("python");
+        fs::write(&py, "#!/bin/sh\necho \"$@\"\n").unwrap();
+        fs::set_permissions(&py, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let script = root.join("bin/mycli");
+        let created = make_script(
+            &script,
+            "mycli",
+            "mypkg.cli",
+            "app.run",
+            &root.join("lib"),
+            &py,
+        );
+        assert_eq!(created, vec![script.clone()]);
+
+        let contents = fs::read_to_string(&script).unwrap();
+        assert!(contents.contains("from mypkg.cli import app\n"));
+        assert!(contents.contains("sys.exit(app.run())"));
+
+        let output = Command::new(&script).arg("--check").output().unwrap();
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap(),
+            format!("{} --check\n", script.display())
+        );
+    }
+
+    #[test]
+    fn record_paths_renamed() {
+        assert_eq!(
+            rename_record_line("six/__init__.py,sha256=abc,10", "six", "six_renamed"),
+            "six_renamed/__init__.py,sha256=abc,10"
+        );
+        // Only the leading folder, not other paths that contain the name.
+        for line in &[
+            "six-1.16.0.dist-info/METADATA,sha256=abc,10",
+            "sixth/six/__init__.py,sha256=abc,10",
+            "../bin/six,,",
+        ] {
+            assert_eq!(rename_record_line(line, "six", "six_renamed"), *line);
+        }
+        assert_eq!(
+            rename_record_line("\"six/a,b.py\",sha256=abc,10", "six", "six_renamed"),
+            "\"six_renamed/a,b.py\",sha256=abc,10"
+        );
+        assert_eq!(
+            util::normalize_path(Path::new("/env/lib/./six/../../bin/six")),
+            Path::new("/env/bin/six")
+        );
+    }
+
+    #[test]
+    fn record_uninstall() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let lib = root.join("lib");
+        let bin = root.join("bin");
+        let dist_info = lib.join("mypkg-1.0.0.dist-info");
+        fs::create_dir_all(lib.join("mypkg/__pycache__")).unwrap();
+        fs::create_dir_all(lib.join("shared")).unwrap();
+        fs::create_dir_all(&dist_info).unwrap();
+
+        fs::write(lib.join("mypkg/__init__.py"), "").unwrap();
+        fs::write(lib.join("mypkg/__pycache__/__init__.cpython-38.pyc"), "").unwrap();
+        // A namespace package another package also installs into.
+        fs::write(lib.join("shared/mine.py"), "").unwrap();
+        fs::write(lib.join("shared/theirs.py"), "").unwrap();
+        fs::write(dist_info.join("METADATA"), "Name: mypkg").unwrap();
+        fs::write(
+            dist_info.join("entry_points.txt"),
+            "[console_scripts]\nmycli = mypkg:main\n",
+        )
+        .unwrap();
+        fs::write(
+            dist_info.join("RECORD"),
+            "mypkg/__init__.py,sha256=abc,0\n\
+             shared/mine.py,sha256=abc,0\n\
+             mypkg-1.0.0.dist-info/METADATA,sha256=abc,11\n\
+             mypkg-1.0.0.dist-info/entry_points.txt,sha256=abc,37\n\
+             mypkg-1.0.0.dist-info/RECORD,,",
+        )
+        .unwrap();
+
+        let version = Version::new(1, 0, 0);
+        let paths = util::Paths {
+            bin: root.join(".venv/bin"),
+            lib: lib.clone(),
+            entry_pt: bin.clone(),
+            cache: root.join("cache"),
+        };
+        setup_scripts("mypkg", &version, &paths);
+        assert!(bin.join("mycli").exists());
+        assert!(fs::read_to_string(dist_info.join("RECORD"))
+            .unwrap()
+            .ends_with("mypkg-1.0.0.dist-info/RECORD,,\n../bin/mycli,,\n"));
+
+        uninstall("mypkg", &version, &lib);
+        assert!(!lib.join("mypkg").exists());
+        assert!(!lib.join("shared/mine.py").exists());
+        assert!(lib.join("shared/theirs.py").exists());
+        assert!(!dist_info.exists());
+        assert!(!bin.join("mycli").exists());
+        // Only folders in `lib` are removed once empty.
+        assert!(bin.exists());
+    }
+
+    #[test]
+    fn shebangs_rewritten() {
+        let py = Path::new("/proj/__pypackages__/3.9/.venv/bin/python");
+        let expected = "#!/proj/__pypackages__/3.9/.venv/bin/python";
+        assert_eq!(new_shebang("#!python", py).unwrap(), expected);
+        assert_eq!(new_shebang("#!pythonw\r", py).unwrap(), expected);
+        assert_eq!(new_shebang("#!/usr/bin/python3.8", py).unwrap(), expected);
+        assert_eq!(new_shebang("#!/usr/bin/env python3", py).unwrap(), expected);
+        assert_eq!(
+            new_shebang("#!/home/build/venv/bin/python -E", py).unwrap(),
+            format!("{} -E", expected)
+        );
+        assert_eq!(new_shebang("#!/bin/sh", py), None);
+        assert_eq!(new_shebang("#!/usr/bin/env perl", py), None);
+        assert_eq!(new_shebang("import sys", py), None);
+    }
+
+    #[test]
+    fn wheel_data_installed() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let lib = root.join("lib");
+        let data = lib.join("mypkg-1.0.0.data");
+        let dist_info = lib.join("mypkg-1.0.0.dist-info");
+        fs::create_dir_all(data.join("scripts")).unwrap();
+        fs::create_dir_all(data.join("data/share/mypkg")).unwrap();
+        fs::create_dir_all(data.join("platlib/mypkg_ext")).unwrap();
+        fs::create_dir_all(&dist_info).unwrap();
+
+        fs::write(data.join("scripts/mytool"), "#!python -u\nprint('hi')\n").unwrap();
+        fs::write(data.join("scripts/mytool.sh"), "#!/bin/sh\necho hi\n").unwrap();
+        fs::write(data.join("data/share/mypkg/icon.svg"), "<svg/>").unwrap();
+        fs::write(data.join("platlib/mypkg_ext/__init__.py"), "").unwrap();
+        fs::write(
+            dist_info.join("RECORD"),
+            "mypkg-1.0.0.data/scripts/mytool,sha256=abc,24\n\
+             mypkg-1.0.0.data/scripts/mytool.sh,sha256=abc,20\n\
+             mypkg-1.0.0.data/data/share/mypkg/icon.svg,sha256=abc,6\n\
+             mypkg-1.0.0.data/platlib/mypkg_ext/__init__.py,sha256=abc,0\n\
+             mypkg-1.0.0.dist-info/RECORD,,\n",
+        )
+        .unwrap();
+
+        let paths = util::Paths {
+            bin: root.join(".venv/bin"),
+            lib: lib.clone(),
+            entry_pt: root.join("bin"),
+            cache: root.join("cache"),
+        };
+        install_data("mypkg", &Version::new(1, 0, 0), &paths);
+
+        assert!(!data.exists());
+        assert_eq!(
+            fs::read_to_string(root.join("bin/mytool")).unwrap(),
+            format!(
+                "#!{} -u\nprint('hi')\n",
+                root.join(".venv/bin/python").display()
+            )
+        );
+        assert_eq!(
+            fs::read_to_string(root.join("bin/mytool.sh")).unwrap(),
+            "#!/bin/sh\necho hi\n"
+        );
+        assert!(root.join("share/mypkg/icon.svg").exists());
+        assert!(lib.join("mypkg_ext/__init__.py").exists());
+        assert_eq!(
+            fs::read_to_string(dist_info.join("RECORD")).unwrap(),
+            "../bin/mytool,,\n\
+             ../bin/mytool.sh,sha256=abc,20\n\
+             ../share/mypkg/icon.svg,sha256=abc,6\n\
+             mypkg_ext/__init__.py,sha256=abc,0\n\
+             mypkg-1.0.0.dist-info/RECORD,,\n"
+        );
+    }
+
+    #[test]
+    fn built_wheel_metadata() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("mypkg-1.2.0-py3-none-any.whl");
+
+        let mut zip = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let options = zip::write::FileOptions::default();
+        zip.start_file("mypkg/_vendor/six-1.16.0.dist-info/METADATA", options)
+            .unwrap();
+        zip.write_all(b"Name: six\nVersion: 1.16.0\n").unwrap();
+        zip.start_file("mypkg-1.2.0.dist-info/METADATA", options)
+            .unwrap();
+        zip.write_all(b"Name: mypkg\nVersion: 1.2.0\nRequires-Dist: requests (>=2.0)\n")
+            .unwrap();
+        zip.finish().unwrap();
+
+        let metadata = wheel_metadata("mypkg", &path);
+        assert_eq!(metadata.version, Version::new(1, 2, 0));
+        assert_eq!(metadata.requires_dist.len(), 1);
+        assert_eq!(metadata.requires_dist[0].name, "requests");
+    }
+
+    #[test]
+    fn cache_checked() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path();
+        // The SHA256 of `hello`.
+        let sha = "2CF24DBA5FB0A30E26E83B2AC5B9E29E1B161E5C1FA7425E73043362938B9824";
+        let path = cached_archive_path(cache, "hello-1.0.tar.gz", sha);
+        assert_eq!(
+            path,
+            cache.join(sha.to_lowercase()).join("hello-1.0.tar.gz")
+        );
+        assert!(!cache_hit(&path, sha));
+
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "hello").unwrap();
+        assert!(cache_hit(&path, sha));
+
+        // Eg a download that was cut off.
+        fs::write(&path, "hel").unwrap();
+        let hit = cache_hit(&path, sha);
+        let removed = !path.exists();
+
+        assert!(!hit);
+        assert!(removed);
+    }
+}
@@ -3,10 +3,11 @@ use crate::cli_options::{ExternalCommand, ExternalSubcommands, Opt, SubCommand};
 use crate::dep_types::{Lock, Package, Req, Version};
 use crate::pyproject::{Config, CFG_FILENAME};
 use crate::util::abort;
-use crate::util::deps::sync;
+use crate::util::deps::{self, sync};
 
 use std::process;
 use std::{
+    env,
     path::PathBuf,
     sync::{Arc, RwLock},
 };
@@ -33,14 +34,50 @@ type PackToInstall = ((String, Version), Option<(u32, String)>); // ((Name, Vers
 /// Global multithreaded variables part
 ///////////////////////////////////////////////////////////////////////////////
 
+#[derive(Clone)]
 struct CliConfig {
     pub color_choice: ColorChoice,
+    /// A Simple-API index to use instead of PyPI.
+    pub index_url: Option<String>,
+    /// An HTTP proxy for all requests, instead of the one in the environment, if any.
+    pub proxy: Option<String>,
+    /// A CA bundle to trust, in addition to the system's.
+    pub cert: Option<PathBuf>,
+    pub no_verify_ssl: bool,
+    /// Run Python under this architecture, instead of the host's. (macOS only)
+    pub arch: Option<util::Arch>,
+    pub max_concurrent_downloads: usize,
+    /// In seconds.
+    pub timeout: u64,
+    pub retries: u32,
+    /// In bytes per second, shared across all downloads.
+    pub bandwidth_limit: Option<u64>,
+    pub offline: bool,
+    /// The Python alias or path to create environments with, skipping the search for one.
+    pub python: Option<String>,
+    /// Print the planned changes instead of installing, uninstalling, or writing the lock file.
+    pub dry_run: bool,
+    /// Print those changes as JSON.
+    pub json: bool,
 }
 
 impl Default for CliConfig {
     fn default() -> Self {
         Self {
             color_choice: ColorChoice::Auto,
+            index_url: None,
+            proxy: None,
+            cert: None,
+            no_verify_ssl: false,
+            arch: None,
+            max_concurrent_downloads: 4,
+            timeout: 30,
+            retries: 3,
+            bandwidth_limit: None,
+            offline: false,
+            python: None,
+            dry_run: false,
+            json: false,
         }
     }
 }
@@ -69,19 +106,47 @@ thread_local! {
 // TODO: Remove clippy::match_single_binding and clippy::single_match after full function refactoring
 fn main() {
     let (pyflow_path, dep_cache_path, script_env_path, git_path) = util::paths::get_paths();
-    let os = util::get_os();
+    let mut os = util::get_os();
 
     let opt = <Opt as structopt::StructOpt>::from_args();
-    #[cfg(debug_assertions)]
-    eprintln!("opts {:?}", opt);
+    util::logger::init(opt.verbose, opt.quiet);
+    log::trace!("opts {:?}", opt);
 
     CliConfig {
         color_choice: util::handle_color_option(
             opt.color.unwrap_or_else(|| String::from("auto")).as_str(),
         ),
+        index_url: opt.index_url,
+        proxy: opt.proxy,
+        cert: opt.cert,
+        no_verify_ssl: opt.no_verify_ssl,
+        arch: opt.arch,
+        max_concurrent_downloads: opt.max_concurrent_downloads,
+        timeout: opt.timeout,
+        retries: opt.retries,
+        bandwidth_limit: opt.bandwidth_limit,
+        offline: opt.offline,
+        python: opt
+            .python
+            .or_else(|| env::var("PYFLOW_PYTHON").ok())
+            .filter(|p| !p.is_empty()),
+        dry_run: opt.dry_run,
+        json: opt.json,
     }
     .make_current();
 
+    if opt.arch.is_some() && !cfg!(target_os = "macos") {
+        abort("`--arch` is only supported on macOS");
+    }
+
+    if CliConfig::current().no_verify_ssl {
+        util::print_color(
+            "Warning: TLS certificate verification is disabled. Packages could be tampered with \
+             in transit; consider using `--cert` instead.",
+            Color::Yellow,
+        );
+    }
+
     // Handle commands that don't involve operating out of a project before one that do, with setup
     // code in-between.
     let subcmd = opt.subcmds;
@@ -96,8 +161,12 @@ fn main() {
         // Actions requires nothing to know about the project
         SubCommand::New { name } => actions::new(name),
         SubCommand::Init => actions::init(CFG_FILENAME),
-        SubCommand::Reset {} => actions::reset(),
+        SubCommand::Migrate { from, archive } => {
+            actions::migrate(&PathBuf::from("."), from, *archive)
+        }
+        SubCommand::Reset { keep_lock } => actions::reset(*keep_lock),
         SubCommand::Clear {} => actions::clear(&pyflow_path, &dep_cache_path, &script_env_path),
+        SubCommand::Clean => actions::clean(&dep_cache_path),
         SubCommand::Switch { version } => actions::switch(version),
         SubCommand::External(ref x) => match ExternalCommand::from_opt(x.to_owned()) {
             ExternalCommand { cmd, args } => match cmd {
@@ -114,8 +183,31 @@ fn main() {
     }
 
     let pcfg = pyproject::current::get_config().unwrap_or_else(|| process::exit(1));
+
+    // An index passed on the command line takes precedence over the config.
+    if CliConfig::current().index_url.is_none() && pcfg.config.index_url.is_some() {
+        CliConfig {
+            index_url: pcfg.config.index_url.clone(),
+            ..(*CliConfig::current()).clone()
+        }
+        .make_current();
+    }
+    // Likewise a proxy.
+    if CliConfig::current().proxy.is_none() && pcfg.config.proxy.is_some() {
+        CliConfig {
+            proxy: pcfg.config.proxy.clone(),
+            ..(*CliConfig::current()).clone()
+        }
+        .make_current();
+    }
     let cfg_vers = if let Some(v) = pcfg.config.py_version.clone() {
         v
+    } else if !pcfg.config.py_version_range.is_empty() {
+        py_versions::resolve_py_range(
+            &pcfg.config.py_version_range,
+            &pcfg.pypackages_path,
+            &pyflow_path,
+        )
     } else {
         let specified = util::prompts::py_vers();
 
@@ -127,28 +219,70 @@ fn main() {
         specified
     };
 
+    // Offline, `lock --check` only compares the lock with `pyproject.toml`, which doesn't need an
+    // environment.
+    if let SubCommand::Lock { check: true } = subcmd {
+        if CliConfig::current().offline {
+            let lock = util::read_lock(&pcfg.lock_path).unwrap_or_default();
+            actions::check_lock(&pcfg.config, &lock.packages(), os, &cfg_vers);
+            return;
+        }
+    }
+
     // Check for environments. Create one if none exist. Set `vers_path`.
     let (vers_path, py_vers) = util::find_or_create_venv(
         &cfg_vers,
         &pcfg.pypackages_path,
         &pyflow_path,
         &dep_cache_path,
+        pcfg.config.use_pep582,
     );
 
-    let paths = util::Paths {
+    // An environment keeps the architecture it was created with, so we don't need to pass
+    // `--arch` each time. Ones created without it use the host's.
+    let env_arch = util::Arch::from_env(&vers_path);
+    if let Some(arch) = CliConfig::current().arch {
+        let existing = env_arch.unwrap_or_else(util::Arch::host);
+        if arch != existing {
+            abort(&format!(
+                "This environment was created for {}. Run `pyflow reset` to recreate it for {}",
+                existing, arch
+            ));
+        }
+    }
+    if let Some(arch) = env_arch {
+        CliConfig {
+            arch: Some(arch),
+            ..(*CliConfig::current()).clone()
+        }
+        .make_current();
+        os = os.with_arch(arch);
+    }
+
+    let mut paths = util::Paths {
         bin: util::find_bin_path(&vers_path),
         lib: vers_path.join("lib"),
         entry_pt: vers_path.join("bin"),
         cache: dep_cache_path,
     };
 
-    // Add all path reqs to the PYTHONPATH; this is the way we make these packages accessible when
-    // running `pyflow`.
+    // Add editable path reqs to the PYTHONPATH; this is the way we make these packages accessible
+    // when running `pyflow`. Other path reqs are installed in `lib`.
     let mut pythonpath = vec![paths.lib.clone()];
-    for r in pcfg.config.reqs.iter().filter(|r| r.path.is_some()) {
+    for r in pcfg
+        .config
+        .reqs
+        .iter()
+        .filter(|r| r.path.is_some() && r.develop)
+    {
         pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
     }
-    for r in pcfg.config.dev_reqs.iter().filter(|r| r.path.is_some()) {
+    for r in pcfg
+        .config
+        .dev_reqs
+        .iter()
+        .filter(|r| r.path.is_some() && r.develop)
+    {
         pythonpath.push(PathBuf::from(r.path.clone().unwrap()));
     }
 
@@ -161,18 +295,63 @@ fn main() {
         Err(_) => Lock::default(),
     };
 
-    let lockpacks = lock.package.unwrap_or_else(Vec::new);
-
-    sync(
-        &paths,
-        &lockpacks,
-        &pcfg.config.reqs,
-        &pcfg.config.dev_reqs,
-        &util::find_dont_uninstall(&pcfg.config.reqs, &pcfg.config.dev_reqs),
-        os,
-        &py_vers,
-        &pcfg.lock_path,
-    );
+    let lockpacks = lock.packages();
+
+    if let SubCommand::Reset { keep_lock: true } = subcmd {
+        let resolved = deps::resolve_lock(
+            &paths,
+            &lockpacks,
+            &pcfg.config.reqs,
+            &pcfg.config.dev_reqs,
+            &pcfg.config.overrides,
+            &[],
+            os,
+            &py_vers,
+        );
+        actions::reset_keeping_lock(&lockpacks, &resolved, &pcfg.pypackages_path);
+        // Recreated as `pyproject.toml` specifies now, eg with or without a venv.
+        let (vers_path, _) = util::find_or_create_venv(
+            &cfg_vers,
+            &pcfg.pypackages_path,
+            &pyflow_path,
+            &paths.cache,
+            pcfg.config.use_pep582,
+        );
+        paths.bin = util::find_bin_path(&vers_path);
+    }
+
+    // A dry run mustn't touch the lock file or installed packages. Upgrades are handled when
+    // installing.
+    let skip_sync = match &subcmd {
+        SubCommand::Install {
+            dry_run,
+            upgrade_package,
+            ..
+        } => *dry_run || !upgrade_package.is_empty() || CliConfig::current().dry_run,
+        SubCommand::Uninstall { .. } | SubCommand::Add { .. } => CliConfig::current().dry_run,
+        SubCommand::Update { .. }
+        | SubCommand::Lock { .. }
+        | SubCommand::Export { .. }
+        | SubCommand::Check => true,
+        _ => false,
+    };
+    if !skip_sync {
+        sync(
+            &paths,
+            &lockpacks,
+            &pcfg.config.reqs,
+            &pcfg.config.dev_reqs,
+            &pcfg.config.overrides,
+            &util::find_dont_uninstall(&pcfg.config.reqs, &pcfg.config.dev_reqs),
+            &[],
+            false,
+            os,
+            &py_vers,
+            &pcfg.lock_path,
+            &pcfg.config.hashes,
+            pcfg.config.post_resolve_hook.as_deref(),
+        );
+    }
 
     // Now handle subcommands that require info about the environment
     match subcmd {
@@ -181,21 +360,73 @@ fn main() {
         // We use data from three sources: `pyproject.toml`, `pyflow.lock`, and
         // the currently-installed packages, found by crawling metadata in the `lib` path.
         // See the readme section `How installation and locking work` for details.
-        SubCommand::Install { packages, dev } | SubCommand::Add { packages, dev } => {
-            actions::install(
-                &pcfg.config_path,
-                &pcfg.config,
-                &git_path,
-                &paths,
-                found_lock,
-                &packages,
-                dev,
-                &lockpacks,
-                &os,
-                &py_vers,
-                &pcfg.lock_path,
-            )
-        }
+        SubCommand::Install {
+            packages,
+            dev,
+            require_license,
+            dry_run,
+            json,
+            upgrade_package,
+        } => actions::install(
+            &pcfg.config_path,
+            &pcfg.config,
+            &git_path,
+            &paths,
+            found_lock,
+            &packages,
+            dev,
+            &lockpacks,
+            &os,
+            &py_vers,
+            &pcfg.lock_path,
+            require_license,
+            dry_run || CliConfig::current().dry_run,
+            json || CliConfig::current().json,
+            &upgrade_package,
+        ),
+        SubCommand::Add {
+            packages,
+            dev,
+            require_license,
+        } => actions::install(
+            &pcfg.config_path,
+            &pcfg.config,
+            &git_path,
+            &paths,
+            found_lock,
+            &packages,
+            dev,
+            &lockpacks,
+            &os,
+            &py_vers,
+            &pcfg.lock_path,
+            require_license,
+            CliConfig::current().dry_run,
+            CliConfig::current().json,
+            &[],
+        ),
+
+        SubCommand::Update { packages } => actions::update(
+            &pcfg.config,
+            &git_path,
+            &paths,
+            &lockpacks,
+            &packages,
+            os,
+            &py_vers,
+            &pcfg.lock_path,
+        ),
+
+        SubCommand::Lock { check } => actions::lock(
+            &pcfg.config,
+            &git_path,
+            &paths,
+            &lockpacks,
+            os,
+            &py_vers,
+            &pcfg.lock_path,
+            check,
+        ),
 
         SubCommand::Uninstall { packages } => {
             // todo: uninstall dev?
@@ -210,7 +441,10 @@ fn main() {
                 })
                 .collect();
 
-            files::remove_reqs_from_cfg(&pcfg.config_path, &removed_reqs);
+            let dry_run = CliConfig::current().dry_run;
+            if !dry_run {
+                files::remove_reqs_from_cfg(&pcfg.config_path, &removed_reqs);
+            }
 
             // Filter reqs here instead of re-reading the config from file.
             let updated_reqs: Vec<Req> = pcfg
@@ -226,15 +460,22 @@ fn main() {
                 &lockpacks,
                 &updated_reqs,
                 &pcfg.config.dev_reqs,
+                &pcfg.config.overrides,
+                &[],
                 &[],
+                false,
                 os,
                 &py_vers,
                 &pcfg.lock_path,
+                &pcfg.config.hashes,
+                pcfg.config.post_resolve_hook.as_deref(),
             );
-            util::print_color("Uninstall complete", Color::Green);
+            if !dry_run {
+                util::print_color("Uninstall complete", Color::Green);
+            }
         }
 
-        SubCommand::Package { extras } => actions::package(
+        SubCommand::Package { extras, check } => actions::package(
             &paths,
             &lockpacks,
             os,
@@ -242,16 +483,49 @@ fn main() {
             &pcfg.lock_path,
             &pcfg.config,
             &extras,
+            check,
+        ),
+        SubCommand::Check => build::check(&pcfg.config),
+        SubCommand::Publish { repository } => build::publish(&pcfg.config, repository.as_deref()),
+        SubCommand::List { outdated: true, .. } => actions::outdated(
+            &paths.lib,
+            &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &py_vers,
         ),
-        SubCommand::Publish {} => build::publish(&paths.bin, &pcfg.config),
-        SubCommand::List {} => actions::list(
+        SubCommand::List { json, .. } => actions::list(
             &paths.lib,
             &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()]
                 .concat()
                 .into_iter()
-                .filter(|r| r.path.is_some())
+                .filter(|r| r.path.is_some() && r.develop)
                 .collect::<Vec<Req>>(),
+            json,
         ),
+        SubCommand::Outdated => actions::outdated(
+            &paths.lib,
+            &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &py_vers,
+        ),
+        SubCommand::Tree { package } => actions::tree(
+            &lockpacks,
+            &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            package.as_deref(),
+        ),
+        SubCommand::Why { package } => actions::why(
+            &lockpacks,
+            &[pcfg.config.reqs.as_slice(), pcfg.config.dev_reqs.as_slice()].concat(),
+            &package,
+        ),
+        SubCommand::Export {
+            dev,
+            hashes,
+            output,
+        } => actions::export(&lockpacks, dev, hashes, output.as_deref()),
+        SubCommand::Env { json } => actions::env(&paths, &vers_path, &py_vers, os, json),
+        SubCommand::Shell => actions::shell(&paths, &vers_path, &pythonpath),
+        SubCommand::Reset { .. } => {
+            util::print_color("Reinstalled the packages in `pyflow.lock`", Color::Green)
+        }
         _ => (),
     }
 
@@ -276,4 +550,64 @@ fn main() {
 }
 
 #[cfg(test)]
-pub mod tests {}
+pub mod tests {
+    use std::{
+        env,
+        path::{Path, PathBuf},
+        process,
+    };
+
+    use tempfile::TempDir;
+
+    use crate::dep_types::LockPackage;
+
+    const CHILD_VAR: &str = "PYFLOW_TEST_CHILD";
+    const DIR_VAR: &str = "PYFLOW_TEST_DIR";
+
+    /// For testing code that exits the process, eg by aborting: run the test `name` again in a
+    /// child process, and return its output. In the child, this returns `None`, for the test to
+    /// run that code. The child uses the same `dir`, from `child_test_dir`.
+    pub fn in_child_process(name: &str, dir: &Path) -> Option<process::Output> {
+        if env::var_os(CHILD_VAR).is_some() {
+            return None;
+        }
+        let output = process::Command::new(env::current_exe().unwrap())
+            .args([name, "--exact", "--nocapture"])
+            .env(CHILD_VAR, "1")
+            .env(DIR_VAR, dir)
+            .output()
+            .unwrap();
+        Some(output)
+    }
+
+    /// A temporary folder for a test that uses `in_child_process`. The test creates it, and keeps
+    /// the `TempDir` to remove it afterwards; its child finds the same folder.
+    pub fn child_test_dir() -> (PathBuf, Option<TempDir>) {
+        match env::var_os(DIR_VAR) {
+            Some(dir) if env::var_os(CHILD_VAR).is_some() => (dir.into(), None),
+            _ => {
+                let tmp = tempfile::tempdir().unwrap();
+                (tmp.path().to_owned(), Some(tmp))
+            }
+        }
+    }
+
+    /// A package locked from PyPI, with dependencies formatted as in the lock file, eg
+    /// `idna 2.10 pypi+x`.
+    pub fn lock_pack(name: &str, version: &str, deps: &[&str]) -> LockPackage {
+        LockPackage {
+            id: 1,
+            name: name.into(),
+            version: version.into(),
+            source: Some(format!(
+                "pypi+https://pypi.org/pypi/{}/{}/json",
+                name, version
+            )),
+            dependencies: Some(deps.iter().map(|d| (*d).to_owned()).collect()),
+            rename: None,
+            extras: None,
+            hash: None,
+            dev: false,
+        }
+    }
+}
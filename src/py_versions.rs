@@ -1,11 +1,11 @@
 //! Manages Python installations
 
 use crate::commands;
-use crate::dep_types::Version;
+use crate::dep_types::{Constraint, Version};
 use crate::{install, util};
 use std::error::Error;
 #[allow(unused_imports)]
-use std::{fmt, fs, io, path::Path, path::PathBuf};
+use std::{env, fmt, fs, io, io::IsTerminal, path::Path, path::PathBuf, time};
 use termcolor::Color;
 
 /// Only versions we've built and hosted
@@ -248,13 +248,10 @@ fn download(py_install_path: &Path, version: &Version) {
             &format!("Downloading Python {}...", vers_to_dl),
             Color::Cyan,
         );
-        let mut resp = reqwest::get(&url).expect("Problem downloading Python"); // Download the file
-        let mut out =
-            fs::File::create(&archive_path).expect("Failed to save downloaded Python archive");
-        if let Err(e) = io::copy(&mut resp, &mut out) {
-            // Clean up the downloaded file, or we'll get an error next time.
-            fs::remove_file(&archive_path).expect("Problem removing the broken file");
-            util::abort(&format!("Problem downloading the Python archive: {:?}", e));
+        // This is written to a temporary file first, so an interrupted download doesn't look
+        // complete next time.
+        if let Err(e) = util::http::download_all(&[(url.clone(), archive_path.clone())]) {
+            util::abort(&format!("Problem downloading the Python archive: {}", e));
         }
     }
     util::print_color(&format!("Installing Python {}...", vers_to_dl), Color::Cyan);
@@ -288,10 +285,135 @@ impl fmt::Display for AliasError {
     }
 }
 
+/// Check if a Python we found is the one requested. A full version like `3.9.7` must match
+/// exactly; `3.9` matches any patch version.
+pub fn matches_requested(requested: &Version, found: &Version) -> bool {
+    found.major == requested.major
+        && found.minor == requested.minor
+        && requested.patch.is_none_or(|p| found.patch == Some(p))
+}
+
+fn in_range(range: &[Constraint], version: &Version) -> bool {
+    range.iter().all(|c| c.is_compatible(version))
+}
+
+/// Pick the minor version to use for a `py_version` range: one we've already created an
+/// environment for, or else the newest Python `found`.
+fn pick_from_range(
+    range: &[Constraint],
+    venvs: &[(u32, u32)],
+    found: &[Version],
+) -> Option<Version> {
+    let existing = venvs
+        .iter()
+        .filter(|(ma, mi)| in_range(range, &Version::new_short(*ma, *mi)))
+        .max();
+    if let Some((ma, mi)) = existing {
+        return Some(Version::new_short(*ma, *mi));
+    }
+    found
+        .iter()
+        .filter(|v| in_range(range, v))
+        .max()
+        .map(|v| Version::new_opt(v.major, v.minor, None))
+}
+
+/// Resolve a `py_version` range, eg `>=3.8,<3.12`, to the minor version whose environment we
+/// use, from the environments and interpreters available. Unlike a single version, we don't
+/// install a Python for a range.
+pub fn resolve_py_range(range: &[Constraint], pypackages_dir: &Path, pyflow_dir: &Path) -> Version {
+    let found: Vec<Version> = match crate::CliConfig::current().python.clone() {
+        // Only consider the Python passed with `--python` or `PYFLOW_PYTHON`, if there is one.
+        Some(specified) => commands::find_py_version(&specified).into_iter().collect(),
+        None => find_installed_versions(pyflow_dir)
+            .into_iter()
+            .chain(
+                find_py_aliases_matching(|v| in_range(range, v))
+                    .into_iter()
+                    .map(|(_, v)| v),
+            )
+            .collect(),
+    };
+
+    match pick_from_range(range, &util::find_venvs(pypackages_dir), &found) {
+        Some(v) => v,
+        None => {
+            let range: Vec<String> = range.iter().map(|c| c.to_string()).collect();
+            let mut found: Vec<String> = found.iter().map(Version::to_string).collect();
+            found.sort();
+            found.dedup();
+            util::abort(&format!(
+                "No installed Python matches `py_version` ({}). Found: {}. Install one in the \
+                 range, or set `py_version` to a version, eg `3.10`, for pyflow to install",
+                range.join(","),
+                if found.is_empty() {
+                    "none".to_owned()
+                } else {
+                    found.join(", ")
+                }
+            ))
+        }
+    }
+}
+
+/// Abort, listing the full versions available for a requested `major.minor.patch` Python.
+fn abort_no_exact_match(requested: &Version, available: &[Version]) -> ! {
+    let mut available: Vec<String> = available.iter().map(Version::to_string).collect();
+    available.sort();
+    available.dedup();
+
+    util::abort(&format!(
+        "Can't find Python {}. Available {}.{} versions: {}",
+        requested,
+        requested.major.unwrap_or(0),
+        requested.minor.unwrap_or(0),
+        if available.is_empty() {
+            "none".to_owned()
+        } else {
+            available.join(", ")
+        }
+    ))
+}
+
+/// Check that the Python passed with `--python` or `PYFLOW_PYTHON` runs, and is the version the
+/// project needs. `found` is the version it reports. Returns that version.
+fn check_specified_py(specified: &str, cfg_v: &Version, found: Option<Version>) -> Version {
+    match found {
+        Some(v) if matches_requested(cfg_v, &v) => v,
+        Some(v) => util::abort(&format!(
+            "The Python specified, {}, is version {}, but this project uses {}",
+            specified, v, cfg_v
+        )),
+        None => util::abort(&format!(
+            "Can't run the Python specified, {}. Is it on the PATH?",
+            specified
+        )),
+    }
+}
+
+/// What to tell the user if there are several Pythons to choose from, and we can't ask them.
+fn multiple_aliases_msg(aliases: &[(String, Version)]) -> String {
+    let found: Vec<String> = aliases
+        .iter()
+        .map(|(alias, v)| format!("{} ({})", alias, v))
+        .collect();
+    format!(
+        "Found multiple compatible Python versions: {}. Pass `--python` or set `PYFLOW_PYTHON` \
+         to choose one, eg `--python {}`",
+        found.join(", "),
+        aliases[0].0
+    )
+}
+
 /// Make an educated guess at the command needed to execute python the
 /// current system.  An alternative approach is trying to find python
 /// installations.
 pub fn find_py_aliases(version: &Version) -> Vec<(String, Version)> {
+    find_py_aliases_matching(|v| matches_requested(version, v))
+}
+
+/// Like `find_py_aliases`, for any Python `matches` accepts.
+fn find_py_aliases_matching(matches: impl Fn(&Version) -> bool) -> Vec<(String, Version)> {
     let possible_aliases = &[
         "python3.19",
         "python3.18",
@@ -325,15 +447,135 @@ pub fn find_py_aliases(version: &Version) -> Vec<(String, Version)> {
         // this command is associated with Python.
         let dets = commands::find_py_dets(alias);
         if let Some(v) = commands::find_py_version(alias) {
-            if v.major == version.major && v.minor == version.minor && !found_dets.contains(&dets) {
+            if matches(&v) && !found_dets.contains(&dets) {
                 result.push((alias.to_string(), v));
                 found_dets.push(dets);
             }
         }
     }
+
+    // pyenv versions that aren't shimmed onto the PATH.
+    if let Some(root) = pyenv_root() {
+        for (_, path) in pyenv_pythons(&root) {
+            let path = path.to_string_lossy().into_owned();
+            let dets = commands::find_py_dets(&path);
+            if let Some(v) = commands::find_py_version(&path) {
+                if matches(&v) && !found_dets.contains(&dets) {
+                    result.push((path, v));
+                    found_dets.push(dets);
+                }
+            }
+        }
+    }
+
+    // Stock Windows installs often aren't on the PATH under a `python3.x` name; the `py`
+    // launcher knows where they are.
+    #[cfg(target_os = "windows")]
+    for path in find_py_launcher_paths() {
+        let dets = commands::find_py_dets(&path);
+        if let Some(v) = commands::find_py_version(&path) {
+            if matches(&v) && !found_dets.contains(&dets) {
+                result.push((path, v));
+                found_dets.push(dets);
+            }
+        }
+    }
+    result
+}
+
+/// List the interpreters the Windows `py` launcher knows about, with `py -0p`.
+#[cfg(target_os = "windows")]
+fn find_py_launcher_paths() -> Vec<String> {
+    match std::process::Command::new("py").arg("-0p").output() {
+        Ok(output) => parse_py_launcher_list(&String::from_utf8_lossy(&output.stdout)),
+        Err(_) => vec![],
+    }
+}
+
+/// Parse the paths from `py -0p`'s output. Newer launchers list eg
+/// ` -V:3.12 *        C:\Python312\python.exe`; older ones ` -3.8-64  C:\...\python.exe *`,
+/// under a header line. The `*` marks the default.
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+fn parse_py_launcher_list(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(str::trim)
+        .filter(|line| line.starts_with('-'))
+        .filter_map(|line| {
+            let (_tag, rest) = line.split_once(char::is_whitespace)?;
+            let path = rest.trim().trim_matches('*').trim();
+            if path.is_empty() {
+                None
+            } else {
+                Some(path.to_owned())
+            }
+        })
+        .collect()
+}
+
+/// Where pyenv keeps its interpreters: `$PYENV_ROOT`, or `~/.pyenv`.
+fn pyenv_root() -> Option<PathBuf> {
+    if let Some(root) = env::var_os("PYENV_ROOT") {
+        return Some(PathBuf::from(root));
+    }
+    directories::BaseDirs::new().map(|dirs| dirs.home_dir().join(".pyenv"))
+}
+
+/// Each version installed under pyenv's `versions` directory, by name, with its interpreter.
+fn pyenv_pythons(pyenv_root: &Path) -> Vec<(String, PathBuf)> {
+    #[cfg(target_os = "windows")]
+    let py_name = "python.exe";
+    #[cfg(target_os = "linux")]
+    let py_name = "bin/python";
+    #[cfg(target_os = "macos")]
+    let py_name = "bin/python";
+
+    let entries = match pyenv_root.join("versions").read_dir() {
+        Ok(e) => e,
+        Err(_) => return vec![],
+    };
+    let mut result: Vec<(String, PathBuf)> = entries
+        .flatten()
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            (name, entry.path().join(py_name))
+        })
+        .filter(|(_, path)| path.exists())
+        .collect();
+    result.sort();
     result
 }
 
+/// Parse the version names from a `.python-version` file, one per line. `system` means whatever's
+/// on the PATH, so isn't a pyenv version.
+fn parse_python_version_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && *line != "system")
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Find the pyenv interpreter selected by a `.python-version` file in the project, if it's
+/// compatible with the version requested.
+fn find_pyenv_selected(project_path: &Path, version: &Version) -> Option<(String, Version)> {
+    let contents = fs::read_to_string(project_path.join(".python-version")).ok()?;
+    let installed = pyenv_pythons(&pyenv_root()?);
+
+    for name in parse_python_version_file(&contents) {
+        if let Some((_, path)) = installed.iter().find(|(n, _)| *n == name) {
+            let path = path.to_string_lossy().into_owned();
+            if let Some(v) = commands::find_py_version(&path) {
+                if matches_requested(version, &v) {
+                    return Some((path, v));
+                }
+            }
+        }
+    }
+    None
+}
+
 // Find versions installed with this tool.
 fn find_installed_versions(pyflow_dir: &Path) -> Vec<Version> {
     #[cfg(target_os = "windows")]
@@ -370,6 +612,7 @@ pub fn create_venv(
     pypackages_dir: &Path,
     pyflow_dir: &Path,
     dep_cache_path: &Path,
+    pep582: bool,
 ) -> Version {
     let os;
     let python_name;
@@ -398,11 +641,27 @@ pub fn create_venv(
     let mut alias_path = None;
     let mut py_ver = None;
 
+    // A Python passed with `--python` or `PYFLOW_PYTHON` takes precedence over any we find.
+    if let Some(specified) = crate::CliConfig::current().python.clone() {
+        let v = check_specified_py(&specified, cfg_v, commands::find_py_version(&specified));
+        alias = Some(specified);
+        py_ver = Some(v);
+    }
+
+    // Then one pyenv's `.python-version` selects for the project.
+    if py_ver.is_none() {
+        let project_path = pypackages_dir.parent().unwrap_or(pypackages_dir);
+        if let Some((path, v)) = find_pyenv_selected(project_path, cfg_v) {
+            alias = Some(path);
+            py_ver = Some(v);
+        }
+    }
+
     // If we find both a system alias, and internal version installed, go with the internal.
     // One's this tool installed
     let installed_versions = find_installed_versions(pyflow_dir);
     for iv in &installed_versions {
-        if iv.major == cfg_v.major && iv.minor == cfg_v.minor {
+        if py_ver.is_none() && matches_requested(cfg_v, iv) {
             let folder_name = format!("python-{}", iv.to_string());
             alias_path = Some(pyflow_dir.join(folder_name).join(&py_name));
             py_ver = Some(iv.clone());
@@ -425,7 +684,10 @@ pub fn create_venv(
                 py_ver = Some(r.1);
             }
             _ => {
-                //                let r = prompt_alias(&aliases);
+                // Don't wait forever for an answer that can't come, eg in CI.
+                if !io::stdin().is_terminal() {
+                    util::abort(&multiple_aliases_msg(&aliases));
+                }
                 let r = util::prompts::list(
                     "Found multiple compatible Python versions. Please enter the number associated with the one you'd like to use:",
                     "Python alias",
@@ -438,6 +700,22 @@ pub fn create_venv(
         };
     }
 
+    // We only download the one build we host for each minor version, so if an exact version's
+    // requested that isn't it, there's nothing more to try.
+    if py_ver.is_none() && cfg_v.patch.is_some() {
+        let hosted = PyVers::from((cfg_v.clone(), os)).to_vers();
+        if !matches_requested(cfg_v, &hosted) {
+            let minor_only = Version::new_opt(cfg_v.major, cfg_v.minor, None);
+            let mut available: Vec<Version> = installed_versions
+                .into_iter()
+                .filter(|v| matches_requested(&minor_only, v))
+                .collect();
+            available.extend(find_py_aliases(&minor_only).into_iter().map(|(_, v)| v));
+            available.push(hosted);
+            abort_no_exact_match(cfg_v, &available);
+        }
+    }
+
     if py_ver.is_none() {
         // Download and install the appropriate Python binary, if we can't find either a
         // custom install, or on the Path.
@@ -480,14 +758,24 @@ pub fn create_venv(
     }
 
     #[cfg(target_os = "windows")]
-    println!("Setting up Python...");
+    log::info!("Setting up Python...");
     #[cfg(target_os = "linux")]
-    println!("🐍 Setting up Python..."); // Beware! Snake may be invisible.
+    log::info!("🐍 Setting up Python..."); // Beware! Snake may be invisible.
     #[cfg(target_os = "macos")]
-    println!("🐍 Setting up Python...");
-
+    log::info!("🐍 Setting up Python...");
+
+    if pep582 {
+        // Per PEP 582, there's no venv; we run the interpreter itself, with `lib` on the
+        // `PYTHONPATH`.
+        let interpreter = alias
+            .or_else(|| alias_path.map(|p| p.to_string_lossy().into_owned()))
+            .and_then(|py| commands::find_py_executable(&py))
+            .unwrap_or_else(|| util::abort("Problem finding the Python interpreter"));
+        if let Err(e) = util::link_python(&interpreter, &vers_path) {
+            util::abort(&format!("Problem setting up Python: {}", e));
+        }
     // For an alias on the PATH
-    if let Some(alias) = alias {
+    } else if let Some(alias) = alias {
         if commands::create_venv(&alias, &lib_path, ".venv").is_err() {
             util::abort("Problem creating virtual environment");
         }
@@ -498,10 +786,25 @@ pub fn create_venv(
         }
     }
 
+    if let Some(arch) = crate::CliConfig::current().arch {
+        arch.write_env(&vers_path);
+    }
+
     let bin_path = util::find_bin_path(&vers_path);
 
-    util::wait_for_dirs(&[bin_path.join(python_name)])
-        .expect("Timed out waiting for venv to be created.");
+    if let Err(e) =
+        util::wait_for_python(&bin_path.join(python_name), time::Duration::from_secs(30))
+    {
+        util::abort(&format!(
+            "Problem creating virtual environment: {}",
+            e.details
+        ));
+    }
+
+    // The rest sets up the venv.
+    if pep582 {
+        return py_ver;
+    }
 
     // Try 64 first; if not, use 32.
     #[allow(unused_variables)]
@@ -542,8 +845,125 @@ pub fn create_venv(
         &paths,
         install::PackageType::Wheel,
         &None,
-    )
-    .expect("Problem installing `wheel`");
+        false,
+    );
 
     py_ver
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_version_required() {
+        let requested = Version::new(3, 9, 7);
+
+        assert!(matches_requested(&requested, &Version::new(3, 9, 7)));
+        assert!(!matches_requested(&requested, &Version::new(3, 9, 2)));
+        assert!(!matches_requested(&requested, &Version::new(3, 8, 7)));
+
+        // Without a patch version, any 3.9 will do.
+        let requested = Version::new_short(3, 9);
+        assert!(matches_requested(&requested, &Version::new(3, 9, 2)));
+        assert!(!matches_requested(&requested, &Version::new(3, 10, 0)));
+    }
+
+    #[test]
+    fn newest_in_range_picked() {
+        let range = Constraint::from_str_multiple(">=3.8,<3.12").unwrap();
+        let found = vec![
+            Version::new(3, 7, 9),
+            Version::new(3, 9, 7),
+            Version::new(3, 11, 2),
+            Version::new(3, 12, 0),
+        ];
+        assert_eq!(
+            pick_from_range(&range, &[], &found),
+            Some(Version::new_short(3, 11))
+        );
+
+        // An existing environment in the range is reused, even if there's a newer Python.
+        assert_eq!(
+            pick_from_range(&range, &[(3, 7), (3, 9)], &found),
+            Some(Version::new_short(3, 9))
+        );
+        assert_eq!(pick_from_range(&range, &[(3, 12)], &found[..1]), None);
+    }
+
+    #[test]
+    fn python_chosen_without_prompt() {
+        let requested = Version::new_short(3, 9);
+        assert_eq!(
+            check_specified_py(
+                "/opt/py39/bin/python",
+                &requested,
+                Some(Version::new(3, 9, 7))
+            ),
+            Version::new(3, 9, 7)
+        );
+
+        let aliases = vec![
+            ("python3.9".to_owned(), Version::new(3, 9, 7)),
+            ("python3".to_owned(), Version::new(3, 9, 2)),
+        ];
+        assert_eq!(
+            multiple_aliases_msg(&aliases),
+            "Found multiple compatible Python versions: python3.9 (3.9.7), python3 (3.9.2). \
+             Pass `--python` or set `PYFLOW_PYTHON` to choose one, eg `--python python3.9`"
+        );
+    }
+
+    #[test]
+    fn py_launcher_list_parsed() {
+        let output = " -V:3.12 *        C:\\Python312\\python.exe\r
+ -V:3.8           C:\\Program Files\\Python38\\python.exe\r
+";
+        assert_eq!(
+            parse_py_launcher_list(output),
+            vec![
+                "C:\\Python312\\python.exe",
+                "C:\\Program Files\\Python38\\python.exe"
+            ]
+        );
+
+        let output = "Installed Pythons found by py Launcher for Windows
+ -3.9-64        C:\\Users\\me\\AppData\\Local\\Programs\\Python\\Python39\\python.exe *
+ -2.7-32        C:\\Python27\\python.exe
+";
+        assert_eq!(
+            parse_py_launcher_list(output),
+            vec![
+                "C:\\Users\\me\\AppData\\Local\\Programs\\Python\\Python39\\python.exe",
+                "C:\\Python27\\python.exe"
+            ]
+        );
+        assert!(parse_py_launcher_list("No Installed Pythons Found!").is_empty());
+    }
+
+    #[test]
+    fn pyenv_versions_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        #[cfg(target_os = "windows")]
+        let py_name = "python.exe";
+        #[cfg(not(target_os = "windows"))]
+        let py_name = "bin/python";
+        for name in &["3.9.7", "3.10.1"] {
+            let py_path = root.join("versions").join(name).join(py_name);
+            fs::create_dir_all(py_path.parent().unwrap()).unwrap();
+            fs::write(py_path, "").unwrap();
+        }
+        // An interrupted install, without an interpreter.
+        fs::create_dir_all(root.join("versions/3.8.12")).unwrap();
+
+        let found: Vec<String> = pyenv_pythons(root).into_iter().map(|(n, _)| n).collect();
+        assert_eq!(found, vec!["3.10.1", "3.9.7"]);
+        assert!(pyenv_pythons(&root.join("missing")).is_empty());
+
+        assert_eq!(
+            parse_python_version_file("3.9.7\n\n# fallback\nsystem\n3.8.12\n"),
+            vec!["3.9.7", "3.8.12"]
+        );
+    }
+}
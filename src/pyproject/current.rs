@@ -44,7 +44,10 @@ pub fn get_config() -> Option<PresentConfig> {
     let pypackages_path = project_path.join("__pypackages__");
     let lock_path = project_path.join(LOCK_FILENAME);
 
-    let mut config = Config::from_file(&config_path).unwrap_or_default();
+    let mut config = match Config::from_file(&config_path) {
+        Ok(c) => c.unwrap_or_default(),
+        Err(e) => util::abort(&format!("Problem parsing `pyproject.toml`: {}", e)),
+    };
     config.populate_path_subreqs();
     Some(PresentConfig {
         config,
@@ -2,16 +2,18 @@ pub mod current;
 
 use std::{
     collections::HashMap,
-    fs,
+    error::Error,
+    fmt, fs,
     path::{Path, PathBuf},
     str::FromStr,
 };
 
 use regex::Regex;
 use serde::Deserialize;
+use termcolor::Color;
 
 use crate::{
-    dep_types::{Constraint, Req, Version},
+    dep_types::{Constraint, Marker, Req, ReqType, Version},
     files,
     util::{self, abort},
 };
@@ -28,12 +30,79 @@ pub struct PresentConfig {
     pub config: Config,
 }
 
+/// A problem reading `pyproject.toml`.
+#[derive(Debug, PartialEq)]
+pub enum ConfigError {
+    /// The file isn't valid TOML.
+    Toml(String),
+    /// A value, like a dependency's constraints, couldn't be parsed. `line` is 1-based.
+    Value {
+        line: Option<usize>,
+        content: String,
+    },
+}
+
+impl ConfigError {
+    /// Find the line that sets `key` to `value`, so we can point the user to it.
+    fn value(toml_str: &str, key: &str, value: &str) -> Self {
+        let quotes: &[char] = &['"', '\''];
+        let sets_key = |l: &str| {
+            l.trim_start()
+                .trim_start_matches(quotes)
+                .strip_prefix(key)
+                .is_some_and(|rest| {
+                    rest.trim_start_matches(quotes)
+                        .trim_start()
+                        .starts_with('=')
+                })
+        };
+
+        let mut lines = toml_str.lines().enumerate();
+        let found = lines
+            .clone()
+            .find(|(_, l)| sets_key(l) && l.contains(value))
+            .or_else(|| lines.find(|(_, l)| l.contains(value)));
+
+        match found {
+            Some((i, l)) => Self::Value {
+                line: Some(i + 1),
+                content: l.trim().to_owned(),
+            },
+            None => Self::Value {
+                line: None,
+                content: format!("{} = {}", key, value),
+            },
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Toml(details) => write!(f, "{}", details),
+            Self::Value {
+                line: Some(line),
+                content,
+            } => write!(f, "line {}: couldn't parse '{}'", line, content),
+            Self::Value {
+                line: None,
+                content,
+            } => write!(f, "couldn't parse '{}'", content),
+        }
+    }
+}
+
 /// A config, parsed from pyproject.toml
-#[derive(Clone, Debug, Default, Deserialize)]
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
 // todo: Auto-desr some of these
 pub struct Config {
     pub name: Option<String>,
     pub py_version: Option<Version>,
+    /// A range of Python versions, eg `>=3.8,<3.12`, given as `py_version` instead of a version.
+    /// We use the newest installed Python in it.
+    pub py_version_range: Vec<Constraint>,
     pub reqs: Vec<Req>,
     pub dev_reqs: Vec<Req>,
     pub version: Option<Version>,
@@ -53,79 +122,264 @@ pub struct Config {
     pub scripts: HashMap<String, String>, //todo: put under [tool.pyflow.scripts] ?
     //    console_scripts: Vec<String>, // We don't parse these; pass them to `setup.py` as-entered.
     pub python_requires: Option<String>,
+    pub index_url: Option<String>,
+    /// An HTTP proxy for all requests, eg `http://proxy.example.com:3128`.
+    pub proxy: Option<String>,
+    /// Install into `__pypackages__` without a venv, per PEP 582, running Python with its `lib`
+    /// on the `PYTHONPATH`.
+    pub use_pep582: bool,
+    /// A command run with the resolved packages as JSON on stdin, before installing. If it
+    /// exits with an error, the install is aborted.
+    pub post_resolve_hook: Option<String>,
+    /// Allowed SHA256 hashes for each package. If any are specified, every package
+    /// installed must have one. (Hash-checking mode)
+    pub hashes: HashMap<String, Vec<String>>,
+    /// Constraints applied to these packages wherever they appear in the dependency graph,
+    /// eg to hold back a sub-dependency.
+    pub overrides: Vec<Req>,
+    /// `[build-system]`, used by `pyflow package`.
+    pub build_system: Option<files::BuildSystem>,
 }
 
+/// Requirements, optional requirements, and the Python version, from Poetry's dependencies.
+type PoetryDeps = (Vec<Req>, Vec<Req>, Option<Version>);
+
 impl Config {
-    /// Helper fn to prevent repetition
-    pub fn parse_deps(deps: HashMap<String, files::DepComponentWrapper>) -> Vec<Req> {
+    /// Helper fn to prevent repetition. `toml_str` is the file's contents, used to locate
+    /// any values we can't parse.
+    pub fn parse_deps(
+        deps: HashMap<String, files::DepComponentWrapper>,
+        toml_str: &str,
+    ) -> Result<Vec<Req>, ConfigError> {
         let mut result = Vec::new();
         for (name, data) in deps {
-            let constraints;
-            let mut extras = None;
-            let mut git = None;
-            let mut path = None;
-            let mut python_version = None;
-            match data {
+            let specs = match data {
                 files::DepComponentWrapper::A(constrs) => {
-                    constraints = if let Ok(c) = Constraint::from_str_multiple(&constrs) {
-                        c
-                    } else {
-                        abort(&format!(
-                            "Problem parsing constraints in `pyproject.toml`: {}",
-                            &constrs
-                        ))
-                    };
+                    let constraints = Constraint::from_str_multiple(&constrs)
+                        .map_err(|_| ConfigError::value(toml_str, &name, &constrs))?;
+                    result.push(Req::new(name, constraints));
+                    continue;
                 }
-                files::DepComponentWrapper::B(subdata) => {
-                    constraints = match subdata.constrs {
-                        Some(constrs) => {
-                            if let Ok(c) = Constraint::from_str_multiple(&constrs) {
-                                c
-                            } else {
-                                abort(&format!(
-                                    "Problem parsing constraints in `pyproject.toml`: {}",
-                                    &constrs
-                                ))
-                            }
-                        }
-                        None => vec![],
-                    };
-
-                    if let Some(ex) = subdata.extras {
-                        extras = Some(ex);
-                    }
-                    if let Some(p) = subdata.path {
-                        path = Some(p);
+                files::DepComponentWrapper::B(subdata) => vec![subdata],
+                files::DepComponentWrapper::C(alternatives) => alternatives,
+            };
+
+            for subdata in specs {
+                let constraints = match subdata.constrs {
+                    Some(constrs) => Constraint::from_str_multiple(&constrs)
+                        .map_err(|_| ConfigError::value(toml_str, &name, &constrs))?,
+                    None => vec![],
+                };
+
+                let mut git = None;
+                let mut branch = None;
+                let mut develop = false;
+                let mut url = None;
+                let mut path = None;
+                if let Some(p) = subdata.path {
+                    path = Some(p);
+                    develop = subdata.develop.unwrap_or_default();
+                }
+                if let Some(u) = subdata.url {
+                    if util::artifact_from_url(&u).is_none() {
+                        return Err(ConfigError::value(toml_str, &name, &u));
                     }
-                    if let Some(repo) = subdata.git {
-                        git = Some(repo);
+                    url = Some(u);
+                }
+                if let Some(repo) = subdata.git {
+                    git = Some(repo);
+                    branch = subdata.branch;
+                }
+                let python_version = match subdata.python {
+                    Some(v) => Some(
+                        Constraint::from_str_multiple(&v)
+                            .map_err(|_| ConfigError::value(toml_str, &name, &v))?,
+                    ),
+                    None => None,
+                };
+                let markers = match subdata.markers {
+                    Some(m) => Some(parse_markers(&m, &name, toml_str)?),
+                    None => None,
+                };
+
+                result.push(Req {
+                    name: name.clone(),
+                    constraints,
+                    extra: None,
+                    sys_platform: None,
+                    python_version,
+                    install_with_extras: subdata.extras,
+                    path,
+                    git,
+                    branch,
+                    develop,
+                    url,
+                    markers,
+                });
+            }
+        }
+        Ok(result)
+    }
+
+    /// Helper fn to prevent repetition. Poetry specifies the Python version as a dependency;
+    /// return it separately, along with the optional dependencies, which only its extras install.
+    fn parse_poetry_deps(
+        deps: HashMap<String, files::DepComponentWrapperPoetry>,
+        toml_str: &str,
+    ) -> Result<PoetryDeps, ConfigError> {
+        let mut reqs = vec![];
+        let mut optional_reqs = vec![];
+        let mut py_version = None;
+
+        for (name, data) in deps {
+            let specs = match data {
+                files::DepComponentWrapperPoetry::A(constrs) => {
+                    let constraints = Constraint::from_str_multiple(&constrs)
+                        .map_err(|_| ConfigError::value(toml_str, &name, &constrs))?;
+                    if name.to_lowercase() == "python" {
+                        py_version = constraints.first().map(|c| c.version.clone());
+                    } else {
+                        reqs.push(Req::new(name, constraints));
                     }
-                    if let Some(v) = subdata.python {
-                        let pv = Constraint::from_str(&v)
-                            .expect("Problem parsing python version in dependency");
-                        python_version = Some(vec![pv]);
+                    continue;
+                }
+                files::DepComponentWrapperPoetry::B(subdata) => vec![*subdata],
+                // Each alternative is its own requirement, which only applies where its
+                // `python` and `markers` do.
+                files::DepComponentWrapperPoetry::C(alternatives) => alternatives,
+            };
+
+            for subdata in specs {
+                let constraints = match &subdata.constrs {
+                    Some(constrs) => Constraint::from_str_multiple(constrs)
+                        .map_err(|_| ConfigError::value(toml_str, &name, constrs))?,
+                    None => vec![],
+                };
+                if name.to_lowercase() == "python" {
+                    py_version = constraints.first().map(|c| c.version.clone());
+                    continue;
+                }
+                let python_version = match &subdata.python {
+                    Some(v) => Some(
+                        Constraint::from_str_multiple(v)
+                            .map_err(|_| ConfigError::value(toml_str, &name, v))?,
+                    ),
+                    None => None,
+                };
+                let markers = match &subdata.markers {
+                    Some(m) => Some(parse_markers(m, &name, toml_str)?),
+                    None => None,
+                };
+                if let Some(u) = &subdata.url {
+                    if util::artifact_from_url(u).is_none() {
+                        return Err(ConfigError::value(toml_str, &name, u));
                     }
                 }
+
+                let req = Req {
+                    name: name.clone(),
+                    constraints,
+                    extra: None,
+                    sys_platform: None,
+                    python_version,
+                    install_with_extras: subdata.extras,
+                    develop: subdata.path.is_some() && subdata.develop.unwrap_or_default(),
+                    path: subdata.path,
+                    branch: subdata
+                        .git
+                        .as_ref()
+                        .and(subdata.branch.or(subdata.rev).or(subdata.tag)),
+                    git: subdata.git,
+                    url: subdata.url,
+                    markers,
+                };
+                if subdata.optional.unwrap_or_default() {
+                    optional_reqs.push(req);
+                } else {
+                    reqs.push(req);
+                }
+            }
+        }
+        Ok((reqs, optional_reqs, py_version))
+    }
+
+    /// Helper fn to prevent repetition. `default_index` is the name of the Pipfile's first
+    /// source, which is the only one we install from.
+    fn parse_pipfile_deps(
+        deps: HashMap<String, files::DepComponentWrapperPipfile>,
+        toml_str: &str,
+        default_index: Option<&str>,
+    ) -> Result<Vec<Req>, ConfigError> {
+        let mut result = vec![];
+        for (name, data) in deps {
+            let subdata = match data {
+                files::DepComponentWrapperPipfile::A(constrs) => {
+                    let constraints = Constraint::from_str_multiple(&constrs)
+                        .map_err(|_| ConfigError::value(toml_str, &name, &constrs))?;
+                    result.push(Req::new(name, constraints));
+                    continue;
+                }
+                files::DepComponentWrapperPipfile::B(subdata) => *subdata,
+            };
+
+            if let (Some(index), Some(default)) = (&subdata.index, default_index) {
+                if index != default {
+                    util::print_color(
+                        &format!(
+                            "{} is installed from the `{}` source in `Pipfile`; pyflow only \
+                             uses one index, so it'll be installed from `{}`",
+                            name, index, default
+                        ),
+                        Color::Yellow,
+                    );
+                }
+            }
+            // Eg `myproject = { path = ".", editable = true }`; this is the project itself.
+            if subdata
+                .path
+                .as_deref()
+                .is_some_and(|p| p == "." || p == "./")
+            {
+                continue;
             }
 
-            result.push(Req {
-                name,
-                constraints,
-                extra: None,
-                sys_platform: None,
-                python_version,
-                install_with_extras: extras,
-                path,
-                git,
-            });
+            let constraints = match &subdata.version {
+                Some(constrs) => Constraint::from_str_multiple(constrs)
+                    .map_err(|_| ConfigError::value(toml_str, &name, constrs))?,
+                None => vec![],
+            };
+            let markers = match subdata.combined_markers() {
+                Some(m) => Some(parse_markers(&m, &name, toml_str)?),
+                None => None,
+            };
+
+            let mut req = Req::new(name, constraints);
+            req.install_with_extras = subdata.extras;
+            req.markers = markers;
+            if let Some(git) = subdata.git {
+                req.git = Some(git.trim_start_matches("git+").to_owned());
+                req.branch = subdata.ref_;
+            } else if let Some(path) = subdata.path {
+                req.path = Some(path);
+                req.develop = subdata.editable.unwrap_or_default();
+            } else if let Some(file) = subdata.file {
+                // A download link, or a local file or folder, possibly as a `file://` URL.
+                if file.starts_with("http://") || file.starts_with("https://") {
+                    if util::artifact_from_url(&file).is_none() {
+                        return Err(ConfigError::value(toml_str, &req.name, &file));
+                    }
+                    req.url = Some(file);
+                } else {
+                    req.path = Some(file.trim_start_matches("file://").to_owned());
+                }
+            }
+            result.push(req);
         }
-        result
+        Ok(result)
     }
 
     // todo: DRY at the top from `from_file`.
     pub fn from_pipfile(path: &Path) -> Option<Self> {
-        // todo: Lots of tweaks and QC could be done re what fields to parse, and how best to
-        // todo parse and store them.
         let toml_str = match fs::read_to_string(path).ok() {
             Some(d) => d,
             None => return None,
@@ -138,32 +392,179 @@ impl Config {
         };
         let mut result = Self::default();
 
+        // The first source is the default index. We don't need to set PyPI, since it's ours too.
+        let sources = decoded.source.unwrap_or_default();
+        let default_index = sources.first().and_then(|s| s.name.clone());
+        if let Some(source) = sources.first() {
+            if !source.url.contains("pypi.org") {
+                result.index_url = Some(source.url.clone());
+            }
+        }
+
+        let parse_deps = |deps| {
+            Self::parse_pipfile_deps(deps, &toml_str, default_index.as_deref())
+                .unwrap_or_else(|e| abort(&format!("Problem parsing `Pipfile`: {}", e)))
+        };
         if let Some(pipfile_deps) = decoded.packages {
-            result.reqs = Self::parse_deps(pipfile_deps);
+            result.reqs = parse_deps(pipfile_deps);
         }
         if let Some(pipfile_dev_deps) = decoded.dev_packages {
-            result.dev_reqs = Self::parse_deps(pipfile_dev_deps);
+            result.dev_reqs = parse_deps(pipfile_dev_deps);
+        }
+        if let Some(requires) = decoded.requires {
+            if let Some(v) = requires.python_full_version.or(requires.python_version) {
+                result.py_version = Some(
+                    Version::from_str(&v).expect("Problem parsing python version in `Pipfile`"),
+                );
+            }
         }
 
         Some(result)
     }
 
+    /// Fill in fields from the standard `[project]` table.
+    fn parse_project(&mut self, pr: files::Project, toml_str: &str) -> Result<(), ConfigError> {
+        if let Some(v) = pr.name {
+            self.name = Some(v);
+        }
+        if let Some(v) = pr.version {
+            self.version = Some(
+                Version::from_str(&v).map_err(|_| ConfigError::value(toml_str, "version", &v))?,
+            );
+        }
+        if let Some(v) = pr.description {
+            self.description = Some(v);
+        }
+        if let Some(files::ProjectFile::A(path))
+        | Some(files::ProjectFile::B {
+            file: Some(path), ..
+        }) = pr.readme
+        {
+            self.readme = Some(path);
+        }
+        if let Some(files::ProjectFile::A(name))
+        | Some(files::ProjectFile::B {
+            text: Some(name), ..
+        }) = pr.license
+        {
+            self.license = Some(name);
+        }
+        if let Some(v) = pr.authors {
+            self.authors = v
+                .into_iter()
+                .filter_map(|a| match (a.name, a.email) {
+                    (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+                    (Some(name), None) => Some(name),
+                    (None, Some(email)) => Some(format!("<{}>", email)),
+                    (None, None) => None,
+                })
+                .collect();
+        }
+        if let Some(v) = pr.keywords {
+            self.keywords = v;
+        }
+        if let Some(v) = pr.classifiers {
+            self.classifiers = v;
+        }
+        for (label, url) in pr.urls.unwrap_or_default() {
+            match label.to_lowercase().as_ref() {
+                "homepage" => self.homepage = Some(url),
+                "repository" | "source" => self.repository = Some(url),
+                _ => (),
+            }
+        }
+        if let Some(v) = pr.scripts {
+            self.scripts = v;
+        }
+
+        // Use the oldest Python the project supports, eg 3.8 for `>=3.8`.
+        if let Some(v) = pr.requires_python {
+            let constraints = Constraint::from_str_multiple(&v)
+                .map_err(|_| ConfigError::value(toml_str, "requires-python", &v))?;
+            self.py_version = constraints
+                .iter()
+                .find(|c| {
+                    matches!(
+                        c.type_,
+                        ReqType::Gte | ReqType::Exact | ReqType::Caret | ReqType::TildeEq
+                    )
+                })
+                .and_then(|c| Some(Version::new_short(c.version.major?, c.version.minor?)));
+            self.python_requires = Some(v);
+        }
+
+        let parse_reqs = |deps: Vec<String>| -> Result<Vec<Req>, ConfigError> {
+            deps.iter()
+                .map(|d| {
+                    Req::from_pip_str(d)
+                        .ok_or_else(|| ConfigError::value(toml_str, "dependencies", d))
+                })
+                .collect()
+        };
+        if let Some(deps) = pr.dependencies {
+            self.reqs = parse_reqs(deps)?;
+        }
+        for (extra, deps) in pr.optional_dependencies.unwrap_or_default() {
+            // Check they're valid, though we store extras as written.
+            parse_reqs(deps.clone())?;
+            self.extras.insert(extra, deps.join(", "));
+        }
+        Ok(())
+    }
+
+    /// Fields set in both `[project]` and `[tool.pyflow]`.
+    fn duplicated_fields(pr: &files::Project, pf: &files::Pyflow) -> Vec<&'static str> {
+        let fields = [
+            ("name", pr.name.is_some() && pf.name.is_some()),
+            ("version", pr.version.is_some() && pf.version.is_some()),
+            (
+                "description",
+                pr.description.is_some() && pf.description.is_some(),
+            ),
+            ("readme", pr.readme.is_some() && pf.readme.is_some()),
+            ("license", pr.license.is_some() && pf.license.is_some()),
+            (
+                "authors",
+                pr.authors.is_some() && (pf.authors.is_some() || pf.author.is_some()),
+            ),
+            (
+                "requires-python",
+                pr.requires_python.is_some()
+                    && (pf.py_version.is_some() || pf.python_requires.is_some()),
+            ),
+            (
+                "dependencies",
+                pr.dependencies.is_some() && pf.dependencies.is_some(),
+            ),
+            (
+                "optional-dependencies",
+                pr.optional_dependencies.is_some() && pf.extras.is_some(),
+            ),
+            ("scripts", pr.scripts.is_some() && pf.scripts.is_some()),
+        ];
+        fields
+            .iter()
+            .filter(|(_, dup)| *dup)
+            .map(|(field, _)| *field)
+            .collect()
+    }
+
     /// Pull config data from `pyproject.toml`. We use this to deserialize things like Versions
-    /// and requirements.
-    pub fn from_file(path: &Path) -> Option<Self> {
+    /// and requirements. Returns `None` if the file can't be read.
+    pub fn from_file(path: &Path) -> Result<Option<Self>, ConfigError> {
         // todo: Lots of tweaks and QC could be done re what fields to parse, and how best to
         // todo parse and store them.
         let toml_str = match fs::read_to_string(path) {
             Ok(d) => d,
-            Err(_) => return None,
+            Err(_) => return Ok(None),
         };
 
-        let decoded: files::Pyproject = if let Ok(d) = toml::from_str(&toml_str) {
-            d
-        } else {
-            abort("Problem parsing `pyproject.toml`");
+        let decoded: files::Pyproject =
+            toml::from_str(&toml_str).map_err(|e| ConfigError::Toml(e.to_string()))?;
+        let mut result = Self {
+            build_system: decoded.build_system,
+            ..Self::default()
         };
-        let mut result = Self::default();
 
         // Parse Poetry first, since we'll use pyflow if there's a conflict.
         if let Some(po) = decoded.tool.poetry {
@@ -206,61 +607,67 @@ impl Config {
             //            if let Some(v) = po.scripts {
             //                result.console_scripts = v;
             //            }
-            if let Some(v) = po.extras {
-                result.extras = v;
-            }
 
             if let Some(v) = po.version {
                 result.version = Some(
-                    Version::from_str(&v).expect("Problem parsing version in `pyproject.toml`"),
+                    Version::from_str(&v)
+                        .map_err(|_| ConfigError::value(&toml_str, "version", &v))?,
                 )
             }
 
-            // todo: DRY (c+p) from pyflow dependency parsing, other than parsing python version here,
-            // todo which only poetry does.
-            // todo: Parse poetry dev deps
             if let Some(deps) = po.dependencies {
-                for (name, data) in deps {
-                    let constraints;
-                    let mut extras = None;
-                    let mut python_version = None;
-                    match data {
-                        files::DepComponentWrapperPoetry::A(constrs) => {
-                            constraints = Constraint::from_str_multiple(&constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                        }
-                        files::DepComponentWrapperPoetry::B(subdata) => {
-                            constraints = Constraint::from_str_multiple(&subdata.constrs)
-                                .expect("Problem parsing constraints in `pyproject.toml`.");
-                            if let Some(ex) = subdata.extras {
-                                extras = Some(ex);
-                            }
-                            if let Some(v) = subdata.python {
-                                let pv = Constraint::from_str(&v)
-                                    .expect("Problem parsing python version in dependency");
-                                python_version = Some(vec![pv]);
-                            }
-                            // todo repository etc
-                        }
-                    }
-                    if &name.to_lowercase() == "python" {
-                        if let Some(constr) = constraints.get(0) {
-                            result.py_version = Some(constr.version.clone())
-                        }
-                    } else {
-                        result.reqs.push(Req {
-                            name,
-                            constraints,
-                            extra: None,
-                            sys_platform: None,
-                            python_version,
-                            install_with_extras: extras,
-                            path: None,
-                            git: None,
-                        });
-                    }
+                let (reqs, optional_reqs, py_version) = Self::parse_poetry_deps(deps, &toml_str)?;
+                result.reqs = reqs;
+                if py_version.is_some() {
+                    result.py_version = py_version;
+                }
+                // We store extras as requirements, like `[project.optional-dependencies]`.
+                for (extra, names) in po.extras.unwrap_or_default() {
+                    let deps: Vec<String> = names
+                        .iter()
+                        .map(|n| {
+                            optional_reqs
+                                .iter()
+                                .find(|r| util::compare_names(&r.name, n))
+                                .map(|r| r.to_setup_py_string())
+                                .unwrap_or_else(|| n.clone())
+                        })
+                        .collect();
+                    result.extras.insert(extra, deps.join(", "));
+                }
+            }
+            if let Some(deps) = po.dev_dependencies {
+                result.dev_reqs = Self::parse_poetry_deps(deps, &toml_str)?.0;
+            }
+            // Pyflow doesn't have dependency groups; treat them as dev dependencies.
+            for group in po.group.unwrap_or_default().into_values() {
+                if let Some(deps) = group.dependencies {
+                    result
+                        .dev_reqs
+                        .append(&mut Self::parse_poetry_deps(deps, &toml_str)?.0);
+                }
+            }
+            if let Some(v) = po.scripts {
+                result.scripts = v;
+            }
+        }
+
+        // `[tool.pyflow]` takes precedence over `[project]`.
+        if let Some(pr) = decoded.project {
+            if let Some(pf) = &decoded.tool.pyflow {
+                let duplicated = Self::duplicated_fields(&pr, pf);
+                if !duplicated.is_empty() {
+                    util::print_color(
+                        &format!(
+                            "`pyproject.toml` specifies {} in both `[project]` and \
+                             `[tool.pyflow]`; using `[tool.pyflow]`",
+                            duplicated.join(", ")
+                        ),
+                        Color::Yellow,
+                    );
                 }
             }
+            result.parse_project(pr, &toml_str)?;
         }
 
         if let Some(pf) = decoded.tool.pyflow {
@@ -275,6 +682,18 @@ impl Config {
                     v
                 };
             }
+            // Only used if `authors` isn't specified.
+            let author = match (pf.author, pf.author_email) {
+                (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+                (Some(name), None) => Some(name),
+                (None, Some(email)) => Some(format!("<{}>", email)),
+                (None, None) => None,
+            };
+            if let Some(v) = author {
+                if result.authors.is_empty() {
+                    result.authors = vec![v];
+                }
+            }
             if let Some(v) = pf.license {
                 result.license = Some(v);
             }
@@ -287,6 +706,9 @@ impl Config {
             if let Some(v) = pf.repository {
                 result.repository = Some(v);
             }
+            if let Some(v) = pf.repo_url {
+                result.repo_url = Some(v);
+            }
 
             // todo: Process entry pts, classifiers etc?
             if let Some(v) = pf.classifiers {
@@ -316,28 +738,67 @@ impl Config {
                 result.package_url = Some(v);
             }
 
+            if let Some(v) = pf.index_url {
+                result.index_url = Some(v);
+            }
+
+            if let Some(v) = pf.proxy {
+                result.proxy = Some(v);
+            }
+
+            if let Some(v) = pf.use_pep582 {
+                result.use_pep582 = v;
+            }
+
+            if let Some(v) = pf.post_resolve_hook {
+                result.post_resolve_hook = Some(v);
+            }
+
+            if let Some(v) = pf.extras {
+                result.extras = v;
+            }
+
+            if let Some(v) = pf.hashes {
+                result.hashes = v;
+            }
+
+            if let Some(v) = pf.overrides {
+                let mut overrides = vec![];
+                for (name, constrs) in v {
+                    let constraints = Constraint::from_str_multiple(&constrs)
+                        .map_err(|_| ConfigError::value(&toml_str, &name, &constrs))?;
+                    overrides.push(Req::new(name, constraints));
+                }
+                overrides.sort_by(|a, b| a.name.cmp(&b.name));
+                result.overrides = overrides;
+            }
+
             if let Some(v) = pf.version {
                 result.version = Some(
-                    Version::from_str(&v).expect("Problem parsing version in `pyproject.toml`"),
+                    Version::from_str(&v)
+                        .map_err(|_| ConfigError::value(&toml_str, "version", &v))?,
                 )
             }
 
             if let Some(v) = pf.py_version {
-                result.py_version = Some(
-                    Version::from_str(&v)
-                        .expect("Problem parsing python version in `pyproject.toml`"),
-                );
+                match Version::from_str(&v) {
+                    Ok(vers) => result.py_version = Some(vers),
+                    Err(_) => {
+                        result.py_version_range = Constraint::from_str_multiple(&v)
+                            .map_err(|_| ConfigError::value(&toml_str, "py_version", &v))?
+                    }
+                }
             }
 
             if let Some(deps) = pf.dependencies {
-                result.reqs = Self::parse_deps(deps);
+                result.reqs = Self::parse_deps(deps, &toml_str)?;
             }
             if let Some(deps) = pf.dev_dependencies {
-                result.dev_reqs = Self::parse_deps(deps);
+                result.dev_reqs = Self::parse_deps(deps, &toml_str)?;
             }
         }
 
-        Some(result)
+        Ok(Some(result))
     }
 
     /// For reqs of `path` type, add their sub-reqs by parsing `setup.py` or `pyproject.toml`.
@@ -354,76 +815,160 @@ impl Config {
             abort("`pyproject.toml` already exists")
         }
 
+        if fs::write(file, self.to_toml_string()).is_err() {
+            abort("Problem writing `pyproject.toml`")
+        }
+    }
+
+    /// Format the config as `pyproject.toml` contents.
+    pub fn to_toml_string(&self) -> String {
         let mut result = String::new();
 
         result.push_str("\n[tool.pyflow]\n");
-        if let Some(name) = &self.name {
-            result.push_str(&("name = \"".to_owned() + name + "\"\n"));
-        } else {
-            // Give name, and a few other fields default values.
-            result.push_str(&("name = \"\"".to_owned() + "\n"));
-        }
+        // Give name, and a few other fields default values.
+        push_toml_str(&mut result, "name", self.name.as_deref().unwrap_or(""));
         if let Some(py_v) = &self.py_version {
-            result.push_str(&("py_version = \"".to_owned() + &py_v.to_string_no_patch() + "\"\n"));
+            push_toml_str(&mut result, "py_version", &py_v.to_string());
+        } else if !self.py_version_range.is_empty() {
+            let range: Vec<String> = self
+                .py_version_range
+                .iter()
+                .map(|c| c.to_string())
+                .collect();
+            push_toml_str(&mut result, "py_version", &range.join(","));
         } else {
-            result.push_str(&("py_version = \"3.8\"".to_owned() + "\n"));
+            push_toml_str(&mut result, "py_version", "3.8");
         }
-        if let Some(vers) = self.version.clone() {
-            result.push_str(&(format!("version = \"{}\"", vers.to_string() + "\n")));
+        if let Some(vers) = &self.version {
+            push_toml_str(&mut result, "version", &vers.to_string());
         } else {
-            result.push_str("version = \"0.1.0\"");
-            result.push('\n');
-        }
-        if !self.authors.is_empty() {
-            result.push_str("authors = [\"");
-            for (i, author) in self.authors.iter().enumerate() {
-                if i != 0 {
-                    result.push_str(", ");
-                }
-                result.push_str(author);
-            }
-            result.push_str("\"]\n");
+            push_toml_str(&mut result, "version", "0.1.0");
         }
+        push_toml_list(&mut result, "authors", &self.authors);
 
-        if let Some(v) = &self.description {
-            result.push_str(&(format!("description = \"{}\"", v) + "\n"));
+        let optional = [
+            ("description", &self.description),
+            ("license", &self.license),
+            ("homepage", &self.homepage),
+            ("repository", &self.repository),
+            ("repo_url", &self.repo_url),
+            ("readme", &self.readme),
+            ("build", &self.build),
+            ("python_requires", &self.python_requires),
+            ("package_url", &self.package_url),
+            ("index_url", &self.index_url),
+            ("proxy", &self.proxy),
+            ("post_resolve_hook", &self.post_resolve_hook),
+        ];
+        for (key, val) in optional.iter() {
+            if let Some(v) = val {
+                push_toml_str(&mut result, key, v);
+            }
         }
-        if let Some(v) = &self.homepage {
-            result.push_str(&(format!("homepage = \"{}\"", v) + "\n"));
+        if self.use_pep582 {
+            result.push_str("use_pep582 = true\n");
         }
-
-        // todo: More fields
+        push_toml_list(&mut result, "keywords", &self.keywords);
+        push_toml_list(&mut result, "classifiers", &self.classifiers);
 
         result.push('\n');
         result.push_str("[tool.pyflow.scripts]\n");
-        for (name, mod_fn) in &self.scripts {
-            result.push_str(&(format!("{} = \"{}\"", name, mod_fn) + "\n"));
+        push_toml_table(&mut result, &self.scripts);
+
+        if !self.extras.is_empty() {
+            result.push('\n');
+            result.push_str("[tool.pyflow.extras]\n");
+            push_toml_table(&mut result, &self.extras);
+        }
+
+        if !self.hashes.is_empty() {
+            result.push('\n');
+            result.push_str("[tool.pyflow.hashes]\n");
+            let mut names: Vec<&String> = self.hashes.keys().collect();
+            names.sort();
+            for name in names {
+                let key = toml_edit::Key::new(name.as_str()).to_string();
+                push_toml_list(&mut result, &key, &self.hashes[name]);
+            }
+        }
+
+        if !self.overrides.is_empty() {
+            result.push('\n');
+            result.push_str("[tool.pyflow.overrides]\n");
+            for over in &self.overrides {
+                let key = toml_edit::Key::new(over.name.as_str()).to_string();
+                let constraints: Vec<String> = over
+                    .constraints
+                    .iter()
+                    .map(|c| c.to_string2(true, false))
+                    .collect();
+                push_toml_str(&mut result, &key, &constraints.join(", "));
+            }
         }
 
         result.push('\n');
         result.push_str("[tool.pyflow.dependencies]\n");
-        for dep in &self.reqs {
-            result.push_str(&(dep.to_cfg_string() + "\n"));
-        }
+        push_reqs(&mut result, &self.reqs);
 
         result.push('\n');
         result.push_str("[tool.pyflow.dev-dependencies]\n");
-        for dep in &self.dev_reqs {
-            result.push_str(&(dep.to_cfg_string() + "\n"));
-        }
+        push_reqs(&mut result, &self.dev_reqs);
 
         result.push('\n'); // trailing newline
+        result
+    }
+}
 
-        if fs::write(file, result).is_err() {
-            abort("Problem writing `pyproject.toml`")
+fn parse_markers(markers: &str, name: &str, toml_str: &str) -> Result<Marker, ConfigError> {
+    Marker::from_str(markers).map_err(|_| ConfigError::value(toml_str, name, markers))
+}
+
+/// Add a line for each requirement. Requirements sharing a name, eg for different Python
+/// versions, go on one line as a list of alternatives.
+fn push_reqs(result: &mut String, reqs: &[Req]) {
+    for (i, req) in reqs.iter().enumerate() {
+        if reqs[..i].iter().any(|r| r.name == req.name) {
+            continue;
+        }
+        let alternatives: Vec<&Req> = reqs.iter().filter(|r| r.name == req.name).collect();
+        if alternatives.len() == 1 {
+            result.push_str(&(req.to_cfg_string() + "\n"));
+            continue;
         }
+        let entries: Vec<(String, String)> =
+            alternatives.iter().map(|r| r.cfg_entry(true)).collect();
+        let values: Vec<&str> = entries.iter().map(|(_, v)| v.as_str()).collect();
+        result.push_str(&format!("{} = [{}]\n", entries[0].0, values.join(", ")));
+    }
+}
+
+/// Add a `key = "value"` line, escaping the value as required.
+fn push_toml_str(result: &mut String, key: &str, val: &str) {
+    result.push_str(&format!("{} = {}\n", key, toml::Value::from(val)));
+}
+
+/// Add a `key = ["a", "b"]` line, if there are any items.
+fn push_toml_list(result: &mut String, key: &str, vals: &[String]) {
+    if !vals.is_empty() {
+        result.push_str(&format!("{} = {}\n", key, toml::Value::from(vals.to_vec())));
+    }
+}
+
+/// Add the contents of a table of strings, sorted so the output is stable.
+fn push_toml_table(result: &mut String, table: &HashMap<String, String>) {
+    let mut keys: Vec<&String> = table.keys().collect();
+    keys.sort();
+    for key in keys {
+        let key_repr = toml_edit::Key::new(key.as_str()).to_string();
+        push_toml_str(result, &key_repr, &table[key]);
     }
 }
 
 /// Reduce repetition between reqs and dev reqs when populating reqs of path reqs.
 fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
     let mut result = vec![];
-    for req in reqs.iter().filter(|r| r.path.is_some()) {
+    // We read the sub-reqs of other path reqs from the metadata of the wheel we build.
+    for req in reqs.iter().filter(|r| r.path.is_some() && r.develop) {
         let req_path = PathBuf::from(req.path.clone().unwrap());
         let pyproj = req_path.join("pyproject.toml");
         let req_txt = req_path.join("requirements.txt");
@@ -448,8 +993,11 @@ fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
         // We don't parse `setup.py`, since it involves running arbitrary Python code.
 
         if pyproj.exists() {
-            let mut req_cfg = Config::from_file(&PathBuf::from(&pyproj))
-                .unwrap_or_else(|| panic!("Problem parsing`pyproject.toml`: {:?}", &pyproj));
+            let mut req_cfg = match Config::from_file(&PathBuf::from(&pyproj)) {
+                Ok(Some(c)) => c,
+                Ok(None) => abort(&format!("Problem reading {:?}", &pyproj)),
+                Err(e) => abort(&format!("Problem parsing {:?}: {}", &pyproj, e)),
+            };
             result.append(&mut req_cfg.reqs)
         }
 
@@ -467,3 +1015,535 @@ fn pop_reqs_helper(reqs: &[Req], dev: bool) -> Vec<Req> {
     }
     result
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{dep_resolution::req_applies, dep_types::ReqType, util::Os};
+
+    /// Write `contents` to `pyproject.toml` in a temporary folder, and parse it.
+    fn parse_cfg(contents: &str) -> Config {
+        try_parse_cfg(contents).unwrap().unwrap()
+    }
+
+    fn try_parse_cfg(contents: &str) -> Result<Option<Config>, ConfigError> {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join(CFG_FILENAME);
+        fs::write(&path, contents).unwrap();
+        Config::from_file(&path)
+    }
+
+    #[test]
+    fn malformed_dep_reported() {
+        let err = try_parse_cfg(
+            r#"
+[tool.pyflow]
+name = "typo"
+
+[tool.pyflow.dependencies]
+numpy = "^1.20"
+reqeusts = "^two"
+"#,
+        )
+        .unwrap_err();
+
+        assert_eq!(
+            err,
+            ConfigError::Value {
+                line: Some(7),
+                content: r#"reqeusts = "^two""#.into(),
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            r#"line 7: couldn't parse 'reqeusts = "^two"'"#
+        );
+
+        let err = try_parse_cfg("[tool.pyflow\n").unwrap_err();
+        assert!(matches!(err, ConfigError::Toml(_)));
+    }
+
+    #[test]
+    fn parse_toml_features() {
+        let cfg = parse_cfg(
+            r#"
+[tool.pyflow]
+name = "everythingkiller"
+description = "Solves a = b, and more"
+classifiers = [
+    "Topic :: System :: Hardware",
+    "Topic :: Scientific/Engineering :: Human Machine Interfaces",
+]
+
+[tool.pyflow.extras]
+test = "pytest"
+
+[tool.pyflow.dependencies]
+ipython = {version = "^7.7.0", extras = ["qtconsole"]}
+
+[build-system]
+requires = ["flit_core >=3.2,<4"]
+build-backend = "flit_core.buildapi"
+"#,
+        );
+
+        assert_eq!(cfg.description, Some("Solves a = b, and more".into()));
+        assert_eq!(
+            cfg.build_system,
+            Some(files::BuildSystem {
+                requires: vec!["flit_core >=3.2,<4".into()],
+                build_backend: Some("flit_core.buildapi".into()),
+                backend_path: None,
+            })
+        );
+        assert_eq!(cfg.classifiers.len(), 2);
+        assert_eq!(cfg.extras.get("test"), Some(&"pytest".to_owned()));
+
+        assert_eq!(cfg.reqs.len(), 1);
+        assert_eq!(cfg.reqs[0].name, "ipython");
+        assert_eq!(cfg.reqs[0].constraints[0].type_, ReqType::Caret);
+        assert_eq!(
+            cfg.reqs[0].install_with_extras,
+            Some(vec!["qtconsole".to_owned()])
+        );
+    }
+
+    #[test]
+    fn source_deps_parsed() {
+        let cfg = parse_cfg(
+            r#"
+[tool.pyflow]
+name = "everythingkiller"
+
+[tool.pyflow.dependencies]
+libfoo = { path = "../libfoo" }
+libbar = { path = "../libbar", develop = true }
+saturn = { git = "https://github.com/david-oconnor/saturn.git", branch = "dev" }
+internal = { url = "https://example.com/internal-1.2.0-py3-none-any.whl" }
+"#,
+        );
+
+        let req = |name: &str| cfg.reqs.iter().find(|r| r.name == name).unwrap();
+        assert_eq!(req("libfoo").path, Some("../libfoo".to_owned()));
+        assert!(!req("libfoo").develop);
+        assert!(req("libbar").develop);
+        assert_eq!(
+            req("saturn").git,
+            Some("https://github.com/david-oconnor/saturn.git".to_owned())
+        );
+        assert_eq!(req("saturn").branch, Some("dev".to_owned()));
+        assert_eq!(
+            req("internal").url,
+            Some("https://example.com/internal-1.2.0-py3-none-any.whl".to_owned())
+        );
+
+        let err = try_parse_cfg(
+            r#"
+[tool.pyflow.dependencies]
+internal = { url = "https://example.com/internal-1.2.0.zip" }
+"#,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err,
+            ConfigError::Value {
+                line: Some(3),
+                content: r#"internal = { url = "https://example.com/internal-1.2.0.zip" }"#.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn pep_621_project() {
+        let cfg = parse_cfg(
+            r#"
+[project]
+name = "everythingkiller"
+version = "0.3.1"
+description = "Small, but packs a punch!"
+readme = {file = "README.rst", content-type = "text/x-rst"}
+requires-python = ">=3.8"
+license = {text = "MIT"}
+authors = [{name = "Fraa Erasmas", email = "raz@edhar.math"}, {name = "Jad"}]
+dependencies = [
+    "numpy >=1.20",
+    "requests[security]",
+    "colorama>=0.4 ; os_name == 'nt'",
+]
+
+[project.optional-dependencies]
+test = ["pytest>=6", "coverage"]
+
+[project.urls]
+Homepage = "https://everything.math"
+Source = "https://github.com/raz/everythingkiller"
+
+[project.scripts]
+activate = "everythingkiller:activate"
+"#,
+        );
+
+        assert_eq!(cfg.name, Some("everythingkiller".into()));
+        assert_eq!(cfg.version, Some(Version::new(0, 3, 1)));
+        assert_eq!(cfg.readme, Some("README.rst".into()));
+        assert_eq!(cfg.license, Some("MIT".into()));
+        assert_eq!(cfg.py_version, Some(Version::new_short(3, 8)));
+        assert_eq!(cfg.python_requires, Some(">=3.8".into()));
+        assert_eq!(
+            cfg.authors,
+            vec!["Fraa Erasmas <raz@edhar.math>".to_owned(), "Jad".to_owned()]
+        );
+        assert_eq!(cfg.homepage, Some("https://everything.math".into()));
+        assert_eq!(
+            cfg.repository,
+            Some("https://github.com/raz/everythingkiller".into())
+        );
+        assert_eq!(
+            cfg.scripts.get("activate"),
+            Some(&"everythingkiller:activate".to_owned())
+        );
+        assert_eq!(
+            cfg.extras.get("test"),
+            Some(&"pytest>=6, coverage".to_owned())
+        );
+
+        let names: Vec<&str> = cfg.reqs.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(names, vec!["numpy", "requests", "colorama"]);
+        assert_eq!(
+            cfg.reqs[0].constraints,
+            vec![Constraint::new(ReqType::Gte, Version::new(1, 20, 0))]
+        );
+        assert_eq!(
+            cfg.reqs[1].install_with_extras,
+            Some(vec!["security".to_owned()])
+        );
+        assert!(cfg.reqs[2].markers.is_some());
+
+        // `[tool.pyflow]` wins where both set something.
+        let cfg = parse_cfg(
+            r#"
+[project]
+name = "everythingkiller"
+description = "Small, but packs a punch!"
+dependencies = ["numpy >=1.20"]
+
+[tool.pyflow]
+name = "everythingkiller2"
+
+[tool.pyflow.dependencies]
+numpy = "^1.21"
+"#,
+        );
+        assert_eq!(cfg.name, Some("everythingkiller2".into()));
+        assert_eq!(cfg.description, Some("Small, but packs a punch!".into()));
+        assert_eq!(cfg.reqs.len(), 1);
+        assert_eq!(cfg.reqs[0].constraints[0].type_, ReqType::Caret);
+
+        let err =
+            try_parse_cfg("[project]\ndependencies = [\n    \"numpy >=one\",\n]\n").unwrap_err();
+        assert_eq!(
+            err,
+            ConfigError::Value {
+                line: Some(3),
+                content: r#""numpy >=one","#.into(),
+            }
+        );
+    }
+
+    #[test]
+    fn poetry_full_specs() {
+        let cfg = parse_cfg(
+            r#"
+[tool.poetry]
+name = "everythingkiller"
+
+[tool.poetry.dependencies]
+python = "^3.8"
+ipython = { version = "^7.7", extras = ["qtconsole"], markers = "python_version < '3.10'" }
+saturn = { git = "https://github.com/david-oconnor/saturn.git", tag = "v0.3.1" }
+libbar = { path = "../libbar", develop = true }
+psycopg2 = { version = "^2.8", optional = true }
+numpy = [
+    { version = "~1.19", python = "<3.9" },
+    { version = "^1.21", python = ">=3.9" },
+]
+
+[tool.poetry.extras]
+postgres = ["psycopg2"]
+
+[tool.poetry.group.test.dependencies]
+pytest = "^6.0"
+"#,
+        );
+        let req = |name: &str| cfg.reqs.iter().find(|r| r.name == name).unwrap();
+
+        assert_eq!(cfg.py_version, Some(Version::new_short(3, 8)));
+        assert_eq!(cfg.reqs.len(), 5);
+        assert_eq!(
+            req("ipython").install_with_extras,
+            Some(vec!["qtconsole".to_owned()])
+        );
+        assert!(req("ipython").markers.is_some());
+        assert_eq!(
+            req("saturn").git,
+            Some("https://github.com/david-oconnor/saturn.git".to_owned())
+        );
+        assert_eq!(req("saturn").branch, Some("v0.3.1".to_owned()));
+        assert_eq!(req("libbar").path, Some("../libbar".to_owned()));
+        assert!(req("libbar").develop);
+        // Each alternative applies to its own Python versions.
+        let numpy: Vec<&Req> = cfg.reqs.iter().filter(|r| r.name == "numpy").collect();
+        assert_eq!(numpy.len(), 2);
+        let applies = |py_vers: Version| -> Vec<Constraint> {
+            numpy
+                .iter()
+                .filter(|r| req_applies(r, &[], Os::Linux, &py_vers))
+                .flat_map(|r| r.constraints.clone())
+                .collect()
+        };
+        assert_eq!(
+            applies(Version::new_short(3, 8)),
+            vec![Constraint::new(ReqType::Tilde, Version::new_short(1, 19))]
+        );
+        assert_eq!(
+            applies(Version::new_short(3, 10)),
+            vec![Constraint::new(ReqType::Caret, Version::new_short(1, 21))]
+        );
+
+        // Written as a list in pyflow's own section, which reads back the same.
+        let toml = cfg.to_toml_string();
+        assert!(toml.contains(
+            r#"numpy = [{ version = "~1.19", python = "<3.9" }, { version = "^1.21", python = ">=3.9" }]"#
+        ));
+        let reread = parse_cfg(&toml);
+        assert_eq!(reread.reqs.len(), 5);
+        assert_eq!(
+            reread
+                .reqs
+                .iter()
+                .filter(|r| r.name == "numpy")
+                .map(|r| r.python_version.clone())
+                .collect::<Vec<_>>(),
+            numpy
+                .iter()
+                .map(|r| r.python_version.clone())
+                .collect::<Vec<_>>()
+        );
+
+        // Optional dependencies are only installed through their extras.
+        assert_eq!(
+            cfg.extras.get("postgres"),
+            Some(&"psycopg2>=2.8".to_owned())
+        );
+        assert_eq!(cfg.dev_reqs[0].name, "pytest");
+    }
+
+    #[test]
+    fn pipfile_full() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join("Pipfile");
+        fs::write(
+            &path,
+            r#"[[source]]
+name = "internal"
+url = "https://pypi.example.com/simple/"
+verify_ssl = true
+
+[[source]]
+name = "pypi"
+url = "https://pypi.org/simple"
+verify_ssl = true
+
+[packages]
+requests = { version = ">=2.20", extras = ["security"], index = "pypi" }
+pywin32 = { version = "*", os_name = "== 'nt'", markers = "python_version >= '3.6'" }
+saturn = { git = "https://github.com/david-oconnor/saturn.git", ref = "dev" }
+libbar = { path = "../libbar", editable = true }
+everythingkiller = { path = ".", editable = true }
+internal = { file = "https://example.com/internal-1.2.0-py3-none-any.whl" }
+vendored = { file = "vendor/vendored-1.0.tar.gz" }
+
+[dev-packages]
+black = "*"
+
+[requires]
+python_full_version = "3.8.6"
+"#,
+        )
+        .unwrap();
+        let cfg = Config::from_pipfile(&path).unwrap();
+        let req = |name: &str| cfg.reqs.iter().find(|r| r.name == name).unwrap();
+
+        assert_eq!(
+            cfg.index_url,
+            Some("https://pypi.example.com/simple/".to_owned())
+        );
+        assert_eq!(cfg.py_version, Some(Version::new(3, 8, 6)));
+        assert_eq!(cfg.reqs.len(), 6);
+        assert_eq!(
+            req("requests").install_with_extras,
+            Some(vec!["security".to_owned()])
+        );
+        assert_eq!(
+            req("pywin32").markers,
+            Some(Marker::from_str("(python_version >= '3.6') and (os_name == 'nt')").unwrap())
+        );
+        assert_eq!(req("saturn").branch, Some("dev".to_owned()));
+        assert_eq!(req("libbar").path, Some("../libbar".to_owned()));
+        assert!(req("libbar").develop);
+        // Whether a `file` is downloaded depends on its scheme, not its extension.
+        assert_eq!(
+            req("internal").url,
+            Some("https://example.com/internal-1.2.0-py3-none-any.whl".to_owned())
+        );
+        assert_eq!(req("vendored").url, None);
+        assert_eq!(
+            req("vendored").path,
+            Some("vendor/vendored-1.0.tar.gz".to_owned())
+        );
+        assert_eq!(cfg.dev_reqs[0].name, "black");
+    }
+
+    #[test]
+    fn parse_metadata() {
+        let cfg = parse_cfg(
+            r#"
+[tool.pyflow]
+name = "everythingkiller"
+author = "Fraa Erasmas"
+author_email = "raz@edhar.math"
+license = "MIT"
+homepage = "https://everything.math"
+repo_url = "https://github.com/raz/everythingkiller"
+keywords = ["nanotech", "weapons"]
+classifiers = ["Topic :: System :: Hardware"]
+python_requires = ">=3.6"
+"#,
+        );
+
+        assert_eq!(
+            cfg.authors,
+            vec!["Fraa Erasmas <raz@edhar.math>".to_owned()]
+        );
+        assert_eq!(cfg.license, Some("MIT".into()));
+        assert_eq!(cfg.homepage, Some("https://everything.math".into()));
+        assert_eq!(
+            cfg.repo_url,
+            Some("https://github.com/raz/everythingkiller".into())
+        );
+        assert_eq!(
+            cfg.keywords,
+            vec!["nanotech".to_owned(), "weapons".to_owned()]
+        );
+        assert_eq!(
+            cfg.classifiers,
+            vec!["Topic :: System :: Hardware".to_owned()]
+        );
+        assert_eq!(cfg.python_requires, Some(">=3.6".into()));
+    }
+
+    #[test]
+    fn write_and_reparse() {
+        let mut scripts = HashMap::new();
+        scripts.insert("activate".into(), "jeejah:activate".into());
+        let mut extras = HashMap::new();
+        extras.insert("test".into(), "pytest".into());
+
+        let cfg = Config {
+            name: Some("everythingkiller".into()),
+            py_version: Some(Version::new_short(3, 7)),
+            py_version_range: vec![],
+            version: Some(Version::new(0, 3, 1)),
+            authors: vec!["Fraa Erasmas <raz@edhar.math>".into(), "Jad".into()],
+            license: Some("MIT".into()),
+            description: Some("Small, but packs a \"punch\"!".into()),
+            keywords: vec!["nanotech".into(), "weapons".into()],
+            classifiers: vec!["Topic :: System :: Hardware".into()],
+            homepage: Some("https://everything.math".into()),
+            repository: Some("https://github.com/raz/everythingkiller".into()),
+            repo_url: Some("https://github.com/raz/everythingkiller.git".into()),
+            package_url: Some("https://upload.pypi.org/legacy/".into()),
+            readme: Some("README.rst".into()),
+            build: Some("build.py".into()),
+            python_requires: Some(">=3.6".into()),
+            index_url: Some("https://pypi.example.com/simple/".into()),
+            proxy: Some("http://proxy.example.com:3128".into()),
+            use_pep582: true,
+            post_resolve_hook: Some("python check_licenses.py".into()),
+            hashes: vec![("numpy".to_owned(), vec!["sha256:0123abcd".to_owned()])]
+                .into_iter()
+                .collect(),
+            overrides: vec![Req::new(
+                "urllib3".into(),
+                vec![Constraint::new(ReqType::Lt, Version::new_short(2, 0))],
+            )],
+            scripts,
+            extras,
+            build_system: None,
+            reqs: vec![Req::new(
+                "numpy".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(1, 16, 4))],
+            )],
+            dev_reqs: vec![Req::new(
+                "black".into(),
+                vec![Constraint::new(ReqType::Caret, Version::new(18, 0, 0))],
+            )],
+        };
+
+        let toml = cfg.to_toml_string();
+        assert!(toml.contains("py_version = \"3.7\"\n"));
+        assert!(toml.contains("version = \"0.3.1\"\n"));
+
+        assert_eq!(parse_cfg(&toml), cfg);
+    }
+
+    #[test]
+    fn py_version_range_parsed() {
+        let cfg = parse_cfg(
+            r#"
+[tool.pyflow]
+name = "range"
+py_version = ">=3.8,<3.12"
+"#,
+        );
+        assert_eq!(cfg.py_version, None);
+        assert_eq!(
+            cfg.py_version_range,
+            Constraint::from_str_multiple(">=3.8,<3.12").unwrap()
+        );
+        assert!(cfg
+            .to_toml_string()
+            .contains("py_version = \">=3.8,<3.12\"\n"));
+    }
+
+    #[test]
+    fn py_version_and_version_kept_separate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let path = dir.join(CFG_FILENAME);
+
+        let cfg = Config {
+            py_version: Some(Version::new_short(3, 7)),
+            version: Some(Version::new(1, 2, 3)),
+            ..Default::default()
+        };
+        cfg.write_file(&path);
+        let written = fs::read_to_string(&path).unwrap();
+        let reparsed = Config::from_file(&path).unwrap().unwrap();
+
+        assert!(written.contains("\npy_version = \"3.7\"\n"));
+        assert!(written.contains("\nversion = \"1.2.3\"\n"));
+        assert_eq!(reparsed.py_version, Some(Version::new_short(3, 7)));
+        assert_eq!(reparsed.version, Some(Version::new(1, 2, 3)));
+
+        // A pinned patch version is kept.
+        let pinned = Config {
+            py_version: Some(Version::new(3, 9, 7)),
+            ..Default::default()
+        };
+        assert!(pinned
+            .to_toml_string()
+            .contains("\npy_version = \"3.9.7\"\n"));
+    }
+}
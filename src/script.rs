@@ -2,6 +2,7 @@ use crate::dep_resolution::res;
 use crate::dep_types::{Constraint, Extras, Lock, Req, ReqType, Version};
 use crate::util;
 use regex::Regex;
+use std::collections::HashMap;
 use std::fs;
 use std::path::Path;
 
@@ -19,8 +20,7 @@ pub fn run_script(
     args: &[String],
     pyflow_dir: &Path,
 ) {
-    #[cfg(debug_assertions)]
-    eprintln!("Run script args: {:?}", args);
+    log::debug!("Run script args: {:?}", args);
 
     // todo: DRY with run_cli_tool and subcommand::Install
     let filename = if let Some(arg) = args.get(0) {
@@ -63,8 +63,13 @@ pub fn run_script(
 
     // todo DRY
     let pypackages_dir = env_path.join("__pypackages__");
-    let (vers_path, py_vers) =
-        util::find_or_create_venv(&cfg_vers, &pypackages_dir, pyflow_dir, dep_cache_path);
+    let (vers_path, py_vers) = util::find_or_create_venv(
+        &cfg_vers,
+        &pypackages_dir,
+        pyflow_dir,
+        dep_cache_path,
+        false,
+    );
 
     let bin_path = util::find_bin_path(&vers_path);
     let lib_path = vers_path.join("lib");
@@ -85,7 +90,7 @@ pub fn run_script(
         Err(_) => Lock::default(),
     };
 
-    let lockpacks = lock.package.unwrap_or_else(Vec::new);
+    let lockpacks = lock.packages();
 
     let reqs: Vec<Req> = deps
         .iter()
@@ -121,9 +126,14 @@ pub fn run_script(
         &reqs,
         &[],
         &[],
+        &[],
+        &[],
+        false,
         os,
         &py_vers,
         &lock_path,
+        &HashMap::new(),
+        None,
     );
 
     if commands::run_python(&paths.bin, &[paths.lib], args).is_err() {
@@ -234,8 +244,13 @@ mod tests {
             minor: Some(9),
             patch: Some(1),
             extra_num: None,
+            more_nums: vec![],
             modifier: None,
             star: false,
+            dev: None,
+            epoch: None,
+            post: None,
+            local: None,
         });
 
         let expected = version;
@@ -1,58 +1,285 @@
-use std::{collections::HashMap, path::Path, str::FromStr};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use regex::Regex;
+use serde::Serialize;
 use termcolor::Color;
 
 use crate::{
-    dep_resolution::res,
+    build, commands,
+    dep_resolution::{req_applies, res, WarehouseDigests, WarehouseRelease},
     dep_types::{Constraint, Lock, LockPackage, Package, Rename, Req, ReqType, Version},
-    install,
-    util::{self, abort},
-    PackToInstall,
+    install::{self, PackageType},
+    util::{self, abort, rollback},
+    CliConfig, PackToInstall,
 };
 
+/// Where the environment's snapshotted while syncing, in `__pypackages__/3.x/`.
+const SYNC_BACKUP: &str = ".sync-backup";
+
+/// A change to the installed packages, as planned by `install --dry-run`. Serializes
+/// with an `action` field naming the kind of change.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+pub enum PlannedOp {
+    Install {
+        name: String,
+        version: String,
+        source: Option<String>,
+    },
+    Upgrade {
+        name: String,
+        from: String,
+        to: String,
+        source: Option<String>,
+    },
+    Downgrade {
+        name: String,
+        from: String,
+        to: String,
+        source: Option<String>,
+    },
+    Remove {
+        name: String,
+        version: String,
+    },
+}
+
+impl fmt::Display for PlannedOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Install { name, version, .. } => write!(f, "Install {} {}", name, version),
+            Self::Upgrade { name, from, to, .. } => {
+                write!(f, "Upgrade {} {} -> {}", name, from, to)
+            }
+            Self::Downgrade { name, from, to, .. } => {
+                write!(f, "Downgrade {} {} -> {}", name, from, to)
+            }
+            Self::Remove { name, version } => write!(f, "Remove {} {}", name, version),
+        }
+    }
+}
+
 /// Function used by `Install` and `Uninstall` subcommands to syn dependencies with
-/// the config and lock files.
+/// the config and lock files. If `hashes` isn't empty, every package installed must
+/// have one of its listed hashes. (Hash-checking mode) If a post-resolve hook is set, it
+/// can stop the install before the lock file's written. Packages named in `upgrade` are
+/// re-resolved to their newest compatible versions; others stay at their locked versions.
+/// Packages only dev dependencies need are always locked, but only installed with `dev`; without
+/// it, ones already installed are kept, and synced with the lock.
+/// `overrides` constrain packages wherever they appear in the graph.
 #[allow(clippy::too_many_arguments)]
 pub fn sync(
     paths: &util::Paths,
     lockpacks: &[LockPackage],
     reqs: &[Req],
     dev_reqs: &[Req],
+    overrides: &[Req],
     dont_uninstall: &[String],
+    upgrade: &[String],
+    dev: bool,
     os: util::Os,
     py_vers: &Version,
     lock_path: &Path,
+    hashes: &HashMap<String, Vec<String>>,
+    post_resolve_hook: Option<&str>,
 ) {
     let installed = util::find_installed(&paths.lib);
-    // We control the lock format, so this regex will always match
-    let dep_re = Regex::new(r"^(.*?)\s(.*)\s.*$").unwrap();
+    let mut updated_lock_packs = resolve_lock(
+        paths, lockpacks, reqs, dev_reqs, overrides, upgrade, os, py_vers,
+    );
 
-    // We don't need to resolve reqs that are already locked.
-    let locked: Vec<Package> = lockpacks
-        .iter()
-        .map(|lp| {
-            let mut deps = vec![];
-            for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
-                let caps = dep_re
-                    .captures(dep)
-                    .expect("Problem reading lock file dependencies");
-                let name = caps.get(1).unwrap().as_str().to_owned();
-                let vers = Version::from_str(caps.get(2).unwrap().as_str())
-                    .expect("Problem parsing version from lock");
-                deps.push((999, name, vers)); // dummy id
-            }
+    // With `--dry-run`, show what would change, but leave the lock file and packages alone.
+    if CliConfig::current().dry_run {
+        let ops = plan_changes(
+            &without_dev(&updated_lock_packs, dev, &installed),
+            dont_uninstall,
+            &installed,
+        );
+        return print_plan(&ops, CliConfig::current().json);
+    }
 
-            Package {
-                id: lp.id, // todo
-                parent: 0, // todo
-                name: lp.name.clone(),
-                version: Version::from_str(&lp.version).expect("Problem parsing lock version"),
-                deps,
-                rename: Rename::No, // todo
+    if let Some(hook) = post_resolve_hook {
+        match run_post_resolve_hook(hook, &updated_lock_packs) {
+            Ok(notes) => {
+                if !notes.is_empty() {
+                    println!("{}", notes);
+                }
             }
+            Err(msg) => abort(&format!(
+                "The post-resolve hook stopped the install: {}",
+                msg
+            )),
+        }
+    }
+
+    // Sync installed dependencies with the resolved packages. If this fails, the environment's
+    // restored, and the lock file's left alone, so they still match.
+    let installed_hashes = sync_deps(
+        paths,
+        &without_dev(&updated_lock_packs, dev, &installed),
+        dont_uninstall,
+        &installed,
+        os,
+        py_vers,
+        hashes,
+    );
+
+    // Record what we installed, so later installs can check they get the same files.
+    for lp in updated_lock_packs.iter_mut().filter(|lp| lp.hash.is_none()) {
+        if let Some(hash) = installed_hashes.get(&util::normalize_name(&lp.name)) {
+            lp.hash = Some(hash.clone());
+        }
+    }
+    write_lock_packs(lock_path, &updated_lock_packs);
+}
+
+pub fn write_lock_packs(lock_path: &Path, lock_packs: &[LockPackage]) {
+    if util::write_lock(lock_path, &Lock::new(lock_packs)).is_err() {
+        abort("Problem writing lock file");
+    }
+}
+
+/// Pass the resolved packages to a post-resolve hook as JSON, on its stdin. Returns anything
+/// the hook printed, or its message if it rejected the packages by exiting with an error.
+fn run_post_resolve_hook(hook: &str, lock_packs: &[LockPackage]) -> Result<String, String> {
+    let graph =
+        serde_json::to_string(lock_packs).expect("Problem serializing the resolved packages");
+
+    let output = commands::run_hook(hook, &graph)
+        .map_err(|e| format!("Problem running `{}`: {}", hook, e))?;
+
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_owned();
+    if output.status.success() {
+        return Ok(stdout);
+    }
+
+    let stderr = String::from_utf8_lossy(&output.stderr).trim().to_owned();
+    Err(if !stderr.is_empty() {
+        stderr
+    } else if !stdout.is_empty() {
+        stdout
+    } else {
+        format!("`{}` exited with {}", hook, output.status)
+    })
+}
+
+/// Work out what `sync` would change, without touching the lock file or installed
+/// packages.
+#[allow(clippy::too_many_arguments)]
+pub fn plan(
+    paths: &util::Paths,
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    overrides: &[Req],
+    dont_uninstall: &[String],
+    upgrade: &[String],
+    dev: bool,
+    os: util::Os,
+    py_vers: &Version,
+) -> Vec<PlannedOp> {
+    let installed = util::find_installed(&paths.lib);
+    let updated_lock_packs = resolve_lock(
+        paths, lockpacks, reqs, dev_reqs, overrides, upgrade, os, py_vers,
+    );
+
+    plan_changes(
+        &without_dev(&updated_lock_packs, dev, &installed),
+        dont_uninstall,
+        &installed,
+    )
+}
+
+/// The requirements a dry run can resolve: not git or path ones, since we'd need to install them
+/// to find their dependencies.
+pub fn registry_only(reqs: &[Req]) -> Vec<Req> {
+    reqs.iter()
+        .filter(|r| r.git.is_none() && r.path.is_none())
+        .cloned()
+        .collect()
+}
+
+/// Print the changes a dry run would make, one per line, or as JSON.
+pub fn print_plan(ops: &[PlannedOp], json: bool) {
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(ops).expect("Problem serializing the install plan")
+        );
+    } else if ops.is_empty() {
+        util::print_color("Nothing to install, upgrade, or remove", Color::Green);
+    } else {
+        for op in ops {
+            println!("{}", op);
+        }
+    }
+}
+
+/// Abort if any of `names` isn't in the lock file, eg because of a typo. `action` describes
+/// what we're doing with them, eg "upgrade".
+pub fn check_locked(lockpacks: &[LockPackage], names: &[String], action: &str) {
+    for name in names {
+        if !lockpacks
+            .iter()
+            .any(|lp| util::compare_names(&lp.name, name))
+        {
+            abort(&format!(
+                "Can't {} `{}`, since it isn't in the lock file",
+                action, name
+            ));
+        }
+    }
+}
+
+/// Leave out the packages only dev dependencies need, unless `dev` is set, or they're already
+/// installed.
+fn without_dev(
+    lock_packs: &[LockPackage],
+    dev: bool,
+    installed: &[(String, Version, Vec<String>)],
+) -> Vec<LockPackage> {
+    lock_packs
+        .iter()
+        .filter(|lp| {
+            dev || !lp.dev
+                || installed
+                    .iter()
+                    .any(|(name, _, _)| util::compare_names(name, &lp.name))
         })
-        .collect();
+        .cloned()
+        .collect()
+}
+
+/// Resolve the requirements, reusing lock entries that still satisfy them, and return
+/// the packages the lock file should contain.
+#[allow(clippy::too_many_arguments)]
+pub fn resolve_lock(
+    paths: &util::Paths,
+    lockpacks: &[LockPackage],
+    reqs: &[Req],
+    dev_reqs: &[Req],
+    overrides: &[Req],
+    upgrade: &[String],
+    os: util::Os,
+    py_vers: &Version,
+) -> Vec<LockPackage> {
+    // Without a lock file, reuse what's already installed instead of querying it all again.
+    let installed_packs;
+    let lockpacks = if lockpacks.is_empty() {
+        installed_packs = installed_lock_packs(&paths.lib, os, py_vers);
+        &installed_packs[..]
+    } else {
+        lockpacks
+    };
+
+    // We don't need to resolve reqs that are already locked.
+    let locked = locked_packages(lockpacks, upgrade);
 
     // todo: Only show this when needed.
     // todo: Temporarily? Removed.
@@ -71,17 +298,74 @@ pub fn sync(
         combined_reqs.push(dev_req);
     }
 
-    let resolved = if let Ok(r) = res::resolve(&combined_reqs, &locked, os, py_vers) {
-        r
-    } else {
-        abort("Problem resolving dependencies")
+    // Skip requirements whose environment markers or `python` exclude this platform or Python
+    // version, eg `pywin32; sys_platform == 'win32'` on Linux.
+    combined_reqs.retain(|r| req_applies(r, &[], os, py_vers));
+
+    // Git requirements were already built and pinned by `process_reqs`, and URL requirements
+    // name the exact file to install; lock them as-is.
+    let (git_reqs, combined_reqs): (Vec<Req>, Vec<Req>) =
+        combined_reqs.into_iter().partition(|r| r.git.is_some());
+    let (url_reqs, mut combined_reqs): (Vec<Req>, Vec<Req>) =
+        combined_reqs.into_iter().partition(|r| r.url.is_some());
+
+    // We can't resolve anything offline, so use the lock as-is if it covers everything.
+    if CliConfig::current().offline {
+        let unsatisfied = unsatisfied_reqs(&combined_reqs, lockpacks);
+        if !unsatisfied.is_empty() {
+            abort(&format!(
+                "Can't resolve dependencies offline, and the lock file doesn't satisfy: {}",
+                unsatisfied.join(", ")
+            ));
+        }
+        let mut lock_packs = lockpacks.to_vec();
+        mark_dev_only(&mut lock_packs, reqs);
+        return lock_packs;
+    }
+
+    // Files at URLs list their dependencies in their metadata; resolve those with the rest.
+    let url_deps: Vec<Vec<Req>> = url_reqs
+        .iter()
+        .map(|req| {
+            let url = req.url.as_ref().unwrap();
+            let source = util::url_source(url);
+            let locked_hash = lockpacks
+                .iter()
+                .find(|lp| lp.source.as_ref() == Some(&source))
+                .and_then(|lp| lp.hash.as_deref());
+            let deps = match url_requires_dist(url, locked_hash, &paths.cache) {
+                Ok(d) => d,
+                Err(e) => abort(&format!("Problem reading the metadata of {}: {}", url, e)),
+            };
+            let extras = req.install_with_extras.clone().unwrap_or_default();
+            deps.into_iter()
+                .filter(|d| req_applies(d, &extras, os, py_vers))
+                .collect()
+        })
+        .collect();
+    combined_reqs.extend(url_deps.iter().flatten().cloned());
+
+    let resolved = match res::resolve(&combined_reqs, &locked, overrides, os, py_vers) {
+        Ok(r) => r,
+        Err(e) => abort(&e.to_string()),
     };
 
     // Now merge the existing lock packages with new ones from resolved packages.
     // We have a collection of requirements; attempt to merge them with the already-locked ones.
     let mut updated_lock_packs = vec![];
+    let index_url = CliConfig::current().index_url.clone();
+    let source =
+        |name: &str, version: &str| util::index_source(index_url.as_deref(), name, version);
 
     for package in &resolved {
+        let mut extras = package.extras.clone();
+        extras.sort();
+        let extras = if extras.is_empty() {
+            None
+        } else {
+            Some(extras)
+        };
+
         let dummy_constraints = vec![Constraint::new(ReqType::Exact, package.version.clone())];
         if already_locked(&locked, &package.name, &dummy_constraints) {
             let existing: Vec<&LockPackage> = lockpacks
@@ -90,18 +374,19 @@ pub fn sync(
                 .collect();
             let existing2 = existing[0];
 
-            updated_lock_packs.push(existing2.clone());
-            continue;
+            // If the extras changed, the locked dependencies are stale.
+            if existing2.extras == extras {
+                updated_lock_packs.push(existing2.clone());
+                continue;
+            }
         }
 
         let deps = package
             .deps
             .iter()
             .map(|(_, name, version)| {
-                format!(
-                    "{} {} pypi+https://pypi.org/pypi/{}/{}/json",
-                    name, version, name, version,
-                )
+                let version = version.to_string();
+                format!("{} {} {}", name, version, source(name, &version))
             })
             .collect();
 
@@ -109,40 +394,238 @@ pub fn sync(
             id: package.id,
             name: package.name.clone(),
             version: package.version.to_string(),
-            source: Some(format!(
-                "pypi+https://pypi.org/pypi/{}/{}/json",
-                package.name,
-                package.version.to_string()
-            )),
+            source: Some(source(&package.name, &package.version.to_string())),
             dependencies: Some(deps),
             rename: match &package.rename {
                 Rename::Yes(parent_id, _, name) => Some(format!("{} {}", parent_id, name)),
                 Rename::No => None,
             },
+            extras,
+            hash: None, // Filled in once installed.
+            dev: false,
         });
     }
 
-    let updated_lock = Lock {
-        //        metadata: Some(lock_metadata),
-        metadata: HashMap::new(), // todo: Problem with toml conversion.
-        package: Some(updated_lock_packs.clone()),
+    for req in &git_reqs {
+        updated_lock_packs.push(git_lock_pack(
+            req,
+            updated_lock_packs.iter().map(|lp| lp.id).max().unwrap_or(0) + 1,
+        ));
+    }
+    for (req, deps) in url_reqs.iter().zip(&url_deps) {
+        let mut lock_pack = url_lock_pack(
+            req,
+            lockpacks,
+            updated_lock_packs.iter().map(|lp| lp.id).max().unwrap_or(0) + 1,
+        );
+        lock_pack.dependencies = Some(
+            deps.iter()
+                .filter_map(|d| {
+                    updated_lock_packs
+                        .iter()
+                        .find(|lp| util::compare_names(&lp.name, &d.name))
+                })
+                .map(|lp| {
+                    format!(
+                        "{} {} {}",
+                        lp.name,
+                        lp.version,
+                        lp.source.as_deref().unwrap_or_default()
+                    )
+                })
+                .collect(),
+        );
+        updated_lock_packs.push(lock_pack);
+    }
+
+    mark_dev_only(&mut updated_lock_packs, reqs);
+    order_like_lock(&mut updated_lock_packs, lockpacks);
+    updated_lock_packs
+}
+
+/// Keep packages that were already locked where they were in the lock file, and add new ones
+/// after them, by name; the resolver returns packages in no particular order, and we don't want
+/// the lock file to churn.
+fn order_like_lock(lock_packs: &mut [LockPackage], existing: &[LockPackage]) {
+    lock_packs.sort_by_cached_key(|lp| {
+        let position = existing
+            .iter()
+            .position(|e| util::compare_names(&e.name, &lp.name) && e.version == lp.version)
+            .unwrap_or(existing.len());
+        (position, util::normalize_name(&lp.name))
+    });
+}
+
+/// Lock a requirement `process_reqs` built from git, at the version and commit it built.
+fn git_lock_pack(req: &Req, id: u32) -> LockPackage {
+    LockPackage {
+        id,
+        name: req.name.clone(),
+        version: req.constraints[0].version.to_string(),
+        source: Some(util::git_source(
+            req.git.as_ref().unwrap(),
+            req.branch.as_deref().unwrap_or_default(),
+        )),
+        dependencies: None,
+        rename: None,
+        extras: req.install_with_extras.clone(),
+        hash: None,
+        dev: false,
+    }
+}
+
+/// Lock a requirement for a file at a URL. Keep the hash we recorded when we installed it, if
+/// it's the same URL.
+fn url_lock_pack(req: &Req, lockpacks: &[LockPackage], id: u32) -> LockPackage {
+    let url = req.url.as_ref().unwrap();
+    let source = util::url_source(url);
+    let version = match util::artifact_from_url(url) {
+        Some((_, v)) => v,
+        None => abort(&format!(
+            "Can't find the version of {} from {}",
+            req.name, url
+        )),
     };
-    if util::write_lock(lock_path, &updated_lock).is_err() {
-        abort("Problem writing lock file");
+
+    LockPackage {
+        id,
+        name: req.name.clone(),
+        version: version.to_string(),
+        hash: lockpacks
+            .iter()
+            .find(|lp| lp.source.as_ref() == Some(&source))
+            .and_then(|lp| lp.hash.clone()),
+        source: Some(source),
+        dependencies: None,
+        rename: None,
+        extras: req.install_with_extras.clone(),
+        dev: false,
     }
+}
 
-    // Now that we've confirmed or modified the lock file, we're ready to sync installed
-    // dependencies with it.
-    sync_deps(
-        paths,
-        &updated_lock_packs,
-        dont_uninstall,
-        &installed,
-        os,
-        py_vers,
-    );
+/// Packages built from git are installed by `process_reqs`, not from an index.
+fn is_git(lp: &LockPackage) -> bool {
+    lp.source.as_ref().is_some_and(|s| s.starts_with("git+"))
 }
-/// Install/uninstall deps as required from the passed list, and re-write the lock file.
+
+/// Flag the packages that aren't needed by `reqs`, directly or through other packages; ie
+/// ones only dev dependencies need.
+fn mark_dev_only(lock_packs: &mut [LockPackage], reqs: &[Req]) {
+    let mut needed: Vec<String> = reqs.iter().map(|r| r.name.clone()).collect();
+    let mut i = 0;
+    while i < needed.len() {
+        let deps: Vec<String> = lock_packs
+            .iter()
+            .filter(|lp| util::compare_names(&lp.name, &needed[i]))
+            .flat_map(|lp| lp.dependencies.iter().flatten())
+            .filter_map(|dep| dep.split_whitespace().next().map(str::to_owned))
+            .collect();
+        for dep in deps {
+            if !needed.iter().any(|n| util::compare_names(n, &dep)) {
+                needed.push(dep);
+            }
+        }
+        i += 1;
+    }
+
+    for lp in lock_packs.iter_mut() {
+        lp.dev = !needed.iter().any(|n| util::compare_names(n, &lp.name));
+    }
+}
+
+/// Reconstruct lock entries for installed packages, with dependencies read from their
+/// `.dist-info` metadata. Packages whose dependencies aren't all installed at compatible
+/// versions are left out, so they're resolved as usual.
+fn installed_lock_packs(lib_path: &Path, os: util::Os, py_vers: &Version) -> Vec<LockPackage> {
+    if !lib_path.exists() {
+        return vec![];
+    }
+    let index_url = CliConfig::current().index_url.clone();
+    let source =
+        |name: &str, version: &str| util::index_source(index_url.as_deref(), name, version);
+
+    let mut installed = vec![];
+    for folder_name in util::find_folders(lib_path) {
+        if !folder_name.ends_with(".dist-info") {
+            continue;
+        }
+        let dist_info_path = lib_path.join(&folder_name);
+        if let Some((name, version)) = util::read_dist_info(&dist_info_path) {
+            let metadata_path = dist_info_path.join("METADATA");
+            let reqs = if metadata_path.exists() {
+                util::parse_metadata(&metadata_path).requires_dist
+            } else {
+                vec![]
+            };
+            installed.push((name, version.to_string(), version, reqs));
+        }
+    }
+    installed.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut result = vec![];
+    'packages: for (name, vers_str, _, reqs) in &installed {
+        let mut deps = vec![];
+        for req in reqs.iter().filter(|r| req_applies(r, &[], os, py_vers)) {
+            let dep = installed.iter().find(|(n, _, v, _)| {
+                util::compare_names(n, &req.name) && res::is_compat(&req.constraints, v)
+            });
+            match dep {
+                Some((n, v, _, _)) => deps.push(format!("{} {} {}", n, v, source(n, v))),
+                None => continue 'packages,
+            }
+        }
+
+        result.push(LockPackage {
+            id: result.len() as u32 + 1,
+            name: name.clone(),
+            version: vers_str.clone(),
+            source: Some(source(name, vers_str)),
+            dependencies: Some(deps),
+            rename: None,
+            extras: None,
+            hash: None,
+            dev: false,
+        });
+    }
+    result
+}
+
+/// Convert lock entries to packages the resolver can reuse without querying. Packages being
+/// upgraded are left out, so the resolver picks their newest compatible versions instead.
+pub(crate) fn locked_packages(lockpacks: &[LockPackage], upgrade: &[String]) -> Vec<Package> {
+    // We control the lock format, so this regex will always match
+    let dep_re = Regex::new(r"^(.*?)\s(.*)\s.*$").unwrap();
+
+    lockpacks
+        .iter()
+        .filter(|lp| !upgrade.iter().any(|u| util::compare_names(u, &lp.name)))
+        .map(|lp| {
+            let mut deps = vec![];
+            for dep in lp.dependencies.as_ref().unwrap_or(&vec![]) {
+                let caps = dep_re
+                    .captures(dep)
+                    .expect("Problem reading lock file dependencies");
+                let name = caps.get(1).unwrap().as_str().to_owned();
+                let vers = Version::from_str(caps.get(2).unwrap().as_str())
+                    .expect("Problem parsing version from lock");
+                deps.push((999, name, vers)); // dummy id
+            }
+
+            Package {
+                id: lp.id, // todo
+                parent: 0, // todo
+                name: lp.name.clone(),
+                version: Version::from_str(&lp.version).expect("Problem parsing lock version"),
+                deps,
+                rename: Rename::No, // todo
+                extras: lp.extras.clone().unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Install/uninstall deps as required from the passed list. Returns the hash of each file
+/// installed, by normalized package name.
 fn sync_deps(
     paths: &util::Paths,
     lock_packs: &[LockPackage],
@@ -150,8 +633,309 @@ fn sync_deps(
     installed: &[(String, Version, Vec<String>)],
     os: util::Os,
     python_vers: &Version,
-) {
-    let packages: Vec<PackToInstall> = lock_packs
+    hashes: &HashMap<String, Vec<String>>,
+) -> HashMap<String, String> {
+    let lock_packs: Vec<LockPackage> = lock_packs
+        .iter()
+        .filter(|lp| !is_git(lp))
+        .cloned()
+        .collect();
+    let lock_packs = &lock_packs[..];
+    let packages = lock_packages(lock_packs);
+    let (to_install, to_uninstall) = find_changes(&packages, dont_uninstall, installed);
+
+    // In hash-checking mode, refuse to install anything before we've confirmed every package,
+    // including transitive dependencies, is pinned.
+    if !hashes.is_empty() {
+        let packages_only: Vec<&(String, Version)> = packages.iter().map(|(p, _)| p).collect();
+        let unpinned = find_unpinned(&packages_only, hashes);
+        if !unpinned.is_empty() {
+            abort(&format!(
+                "Hash-checking mode is on, since `[tool.pyflow.hashes]` isn't empty, but these \
+                 packages don't have hashes listed: {}",
+                unpinned.join(", ")
+            ));
+        }
+    }
+
+    if to_install.is_empty() && to_uninstall.is_empty() {
+        return HashMap::new();
+    }
+
+    // Stage the changes: if any fail, put the environment back as it was, instead of leaving it
+    // partly synced.
+    let backup_path = paths.lib.with_file_name(SYNC_BACKUP);
+    if let Err(e) = rollback::begin(&[&paths.lib, &paths.entry_pt], &backup_path) {
+        abort(&format!(
+            "Problem preparing to change the environment: {}",
+            e
+        ));
+    }
+    let applied = panic::catch_unwind(AssertUnwindSafe(|| {
+        apply_changes(
+            paths,
+            lock_packs,
+            &to_install,
+            &to_uninstall,
+            os,
+            python_vers,
+            hashes,
+        )
+    }));
+    match applied {
+        Ok(installed_hashes) => {
+            rollback::commit(&backup_path);
+            installed_hashes
+        }
+        Err(e) => {
+            rollback::restore();
+            panic::resume_unwind(e)
+        }
+    }
+}
+
+/// Uninstall and install packages, as `sync_deps` found we need to. Returns the hash of each file
+/// installed, by normalized package name.
+fn apply_changes(
+    paths: &util::Paths,
+    lock_packs: &[LockPackage],
+    to_install: &[&PackToInstall],
+    to_uninstall: &[(String, Version)],
+    os: util::Os,
+    python_vers: &Version,
+    hashes: &HashMap<String, Vec<String>>,
+) -> HashMap<String, String> {
+    for (name, version) in to_uninstall {
+        // todo: Deal with renamed. Currently won't work correctly with them.
+        install::uninstall(name, version, &paths.lib)
+    }
+
+    // Only check these once; they're the same for every package.
+    let glibc_vers = util::get_glibc_version();
+    let macos_vers = util::get_macos_version();
+
+    // Pick a file for each package first, so we can download them all at once.
+    let mut selected = vec![];
+    let offline = CliConfig::current().offline;
+    let mut not_cached = vec![];
+
+    // Each lookup is a round trip to the index, so make several at once. The results line up
+    // with `to_install`; `None` for packages whose file we know without one.
+    let looked_up = util::http::parallel_map(
+        to_install,
+        CliConfig::current().max_concurrent_downloads,
+        |((name, version), _)| {
+            if offline || locked_url(lock_packs, name, version).is_some() {
+                None
+            } else {
+                Some(res::get_warehouse_release(name, version))
+            }
+        },
+    );
+
+    for (((name, version), rename), looked_up) in to_install.iter().zip(looked_up) {
+        let locked_hash = lock_packs
+            .iter()
+            .find(|lp| {
+                util::compare_names(&lp.name, name)
+                    && Version::from_str(&lp.version).ok().as_ref() == Some(version)
+            })
+            .and_then(|lp| lp.hash.clone());
+
+        // Offline, we can't look up the package's files, so use the one the lock recorded,
+        // if it's cached.
+        if offline {
+            let cached = locked_hash
+                .as_ref()
+                .and_then(|h| cached_release(&paths.cache, h));
+            match cached {
+                Some((release, package_type)) => {
+                    if !hashes.is_empty()
+                        && !allowed_hashes(name, hashes)
+                            .unwrap_or(&[])
+                            .iter()
+                            .any(|h| hash_matches(h, &release.digests.sha256))
+                    {
+                        abort(&format!(
+                            "The cached file for {} {} doesn't match the hashes listed in \
+                             `pyproject.toml`",
+                            name, version
+                        ));
+                    }
+                    selected.push((name, version, rename, release, package_type, true));
+                }
+                None => not_cached.push(format!("{} {}", name, version)),
+            }
+            continue;
+        }
+
+        if let Some(url) = locked_url(lock_packs, name, version) {
+            let (release, package_type) =
+                match url_release(url, locked_hash.as_deref(), &paths.cache) {
+                    Ok(r) => r,
+                    Err(e) => abort(&format!("Problem downloading {}: {}", url, e)),
+                };
+            if !hashes.is_empty()
+                && !allowed_hashes(name, hashes)
+                    .unwrap_or(&[])
+                    .iter()
+                    .any(|h| hash_matches(h, &release.digests.sha256))
+            {
+                abort(&format!(
+                    "The file at {} doesn't match the hashes listed in `pyproject.toml`",
+                    url
+                ));
+            }
+            let require_hash = locked_hash.is_some();
+            selected.push((name, version, rename, release, package_type, require_hash));
+            continue;
+        }
+
+        let mut data = looked_up
+            .expect("Missing the package's lookup")
+            .expect("Problem getting warehouse data");
+
+        if let Some(locked) = &locked_hash {
+            if let Err(msg) = check_locked_hash(name, version, locked, &data) {
+                abort(&msg);
+            }
+        }
+
+        if !hashes.is_empty() {
+            data = filter_by_hashes(data, allowed_hashes(name, hashes).unwrap_or(&[]));
+            if data.is_empty() {
+                abort(&format!(
+                    "None of the files for {} {} match the hashes listed in `pyproject.toml`",
+                    name, version
+                ));
+            }
+        }
+
+        let (best_release, package_type) = util::find_best_release(
+            &data,
+            name,
+            version,
+            os,
+            python_vers,
+            glibc_vers,
+            macos_vers,
+        );
+
+        // Don't offer to install anyway if the download doesn't match a hash we've committed to.
+        let require_hash = !hashes.is_empty()
+            || locked_hash
+                .as_ref()
+                .is_some_and(|h| hash_matches(h, &best_release.digests.sha256));
+
+        selected.push((
+            name,
+            version,
+            rename,
+            best_release,
+            package_type,
+            require_hash,
+        ));
+    }
+
+    if !not_cached.is_empty() {
+        abort(&format!(
+            "These packages aren't in the cache, so can't be installed offline: {}. Install \
+             them once with a connection to cache them.",
+            not_cached.join(", ")
+        ));
+    }
+
+    // Skip archives we've already downloaded, eg for another project.
+    let downloads: Vec<(String, PathBuf)> = selected
+        .iter()
+        .filter_map(|(_, _, _, release, _, _)| {
+            let sha256 = &release.digests.sha256;
+            let archive_path =
+                install::cached_archive_path(&paths.cache, &release.filename, sha256);
+            if install::cache_hit(&archive_path, sha256) {
+                None
+            } else {
+                Some((release.url.clone(), archive_path))
+            }
+        })
+        .collect();
+    if let Err(e) = util::http::download_all(&downloads) {
+        abort(&format!("Problem downloading packages:\n{}", e));
+    }
+
+    // The hashes of the files we install, to record in the lock.
+    let mut installed_hashes = HashMap::new();
+
+    for (name, version, rename, best_release, package_type, require_hash) in selected {
+        // Powershell  doesn't like emojis
+        // todo format literal issues, so repeating this whole statement.
+        // This is progress, so `--quiet` leaves it out.
+        if log::log_enabled!(log::Level::Info) {
+            #[cfg(target_os = "windows")]
+            util::print_color_(&format!("Installing {}", &name), Color::Cyan);
+            #[cfg(target_os = "linux")]
+            util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
+            #[cfg(target_os = "macos")]
+            util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
+            println!(" {} ...", &version.to_string_color());
+        }
+
+        install::download_and_install_package(
+            name,
+            version,
+            &best_release.url,
+            &best_release.filename,
+            &best_release.digests.sha256,
+            paths,
+            package_type,
+            rename,
+            require_hash,
+        );
+
+        installed_hashes.insert(
+            util::normalize_name(name),
+            format!("sha256:{}", best_release.digests.sha256.to_lowercase()),
+        );
+    }
+    // Perform renames after all packages are installed, or we may attempt to rename a package
+    // we haven't yet installed.
+    for ((name, version), rename) in to_install {
+        if let Some((id, new)) = rename {
+            // Rename in the renamed package
+
+            let renamed_path = &paths.lib.join(util::standardize_name(new));
+
+            util::wait_for_dirs(&[renamed_path.clone()]).expect("Problem creating renamed path");
+            install::rename_package_files(renamed_path, name, new);
+
+            // Rename in the parent calling the renamed package. // todo: Multiple parents?
+            let parent = lock_packs
+                .iter()
+                .find(|lp| lp.id == *id)
+                .expect("Can't find parent calling renamed package");
+            install::rename_package_files(
+                &paths.lib.join(util::standardize_name(&parent.name)),
+                name,
+                new,
+            );
+
+            // todo: Handle this more generally, in case we don't have proper semver dist-info paths.
+            install::rename_metadata(
+                &paths
+                    .lib
+                    .join(&format!("{}-{}.dist-info", name, version.to_string())),
+                name,
+                new,
+            );
+        }
+    }
+
+    installed_hashes
+}
+
+/// The packages in the lock, with their renames.
+fn lock_packages(lock_packs: &[LockPackage]) -> Vec<PackToInstall> {
+    lock_packs
         .iter()
         .map(|lp| {
             (
@@ -162,8 +946,16 @@ fn sync_deps(
                 lp.rename.as_ref().map(|rn| parse_lockpack_rename(rn)),
             )
         })
-        .collect();
+        .collect()
+}
 
+/// Compare the locked packages with the installed ones, returning the packages we need
+/// to install, and the installed ones we need to remove.
+fn find_changes<'a>(
+    packages: &'a [PackToInstall],
+    dont_uninstall: &[String],
+    installed: &[(String, Version, Vec<String>)],
+) -> (Vec<&'a PackToInstall>, Vec<(String, Version)>) {
     // todo shim. Use top-level A/R. We discard it temporarily while working other issues.
     let installed: Vec<(String, Version)> = installed
         .iter()
@@ -190,8 +982,7 @@ fn sync_deps(
         .collect();
 
     // todo: Once you include rename info in installed, you won't need to use the map logic here.
-    let packages_only: Vec<&(String, Version)> = packages.iter().map(|(p, _)| p).collect();
-    let to_uninstall: Vec<&(String, Version)> = installed
+    let to_uninstall: Vec<(String, Version)> = installed
         .iter()
         .filter(|inst| {
             // Don't standardize the name here; we need original capitalization to uninstall
@@ -199,7 +990,7 @@ fn sync_deps(
             let inst = (inst.0.clone(), inst.1.clone());
             let mut contains = false;
             // We can't just use the contains method, due to needing compare_names().
-            for pack in &packages_only {
+            for (pack, _) in packages {
                 if util::compare_names(&pack.0, &inst.0) && pack.1 == inst.1 {
                     contains = true;
                     break;
@@ -215,76 +1006,264 @@ fn sync_deps(
 
             !contains
         })
+        .cloned()
         .collect();
 
-    for (name, version) in &to_uninstall {
-        // todo: Deal with renamed. Currently won't work correctly with them.
-        install::uninstall(name, version, &paths.lib)
-    }
+    (to_install, to_uninstall)
+}
 
-    for ((name, version), rename) in &to_install {
-        let data =
-            res::get_warehouse_release(name, version).expect("Problem getting warehouse data");
+/// Describe the changes needed to bring the installed packages in line with the lock.
+/// A package that's installed at a different version is an upgrade or downgrade, rather
+/// than a removal and an install.
+fn plan_changes(
+    lock_packs: &[LockPackage],
+    dont_uninstall: &[String],
+    installed: &[(String, Version, Vec<String>)],
+) -> Vec<PlannedOp> {
+    let packages = lock_packages(lock_packs);
+    let (to_install, mut to_uninstall) = find_changes(&packages, dont_uninstall, installed);
 
-        let (best_release, package_type) =
-            util::find_best_release(&data, name, version, os, python_vers);
+    let mut result = vec![];
+    for ((name, version), _) in to_install {
+        let source = lock_packs
+            .iter()
+            .find(|lp| util::compare_names(&lp.name, name))
+            .and_then(|lp| lp.source.clone());
 
-        // Powershell  doesn't like emojis
-        // todo format literal issues, so repeating this whole statement.
-        #[cfg(target_os = "windows")]
-        util::print_color_(&format!("Installing {}", &name), Color::Cyan);
-        #[cfg(target_os = "linux")]
-        util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
-        #[cfg(target_os = "macos")]
-        util::print_color_(&format!("⬇ Installing {}", &name), Color::Cyan);
-        println!(" {} ...", &version.to_string_color());
-
-        if install::download_and_install_package(
-            name,
-            version,
-            &best_release.url,
-            &best_release.filename,
-            &best_release.digests.sha256,
-            paths,
-            package_type,
-            rename,
-        )
-        .is_err()
+        match to_uninstall
+            .iter()
+            .position(|(inst, _)| util::compare_names(inst, name))
         {
-            abort("Problem downloading packages");
+            Some(i) => {
+                let (_, old) = to_uninstall.remove(i);
+                let (from, to) = (old.to_string(), version.to_string());
+                result.push(if *version > old {
+                    PlannedOp::Upgrade {
+                        name: name.clone(),
+                        from,
+                        to,
+                        source,
+                    }
+                } else {
+                    PlannedOp::Downgrade {
+                        name: name.clone(),
+                        from,
+                        to,
+                        source,
+                    }
+                });
+            }
+            None => result.push(PlannedOp::Install {
+                name: name.clone(),
+                version: version.to_string(),
+                source,
+            }),
         }
     }
-    // Perform renames after all packages are installed, or we may attempt to rename a package
-    // we haven't yet installed.
-    for ((name, version), rename) in &to_install {
-        if let Some((id, new)) = rename {
-            // Rename in the renamed package
 
-            let renamed_path = &paths.lib.join(util::standardize_name(new));
+    for (name, version) in to_uninstall {
+        result.push(PlannedOp::Remove {
+            name,
+            version: version.to_string(),
+        });
+    }
+    result
+}
 
-            util::wait_for_dirs(&[renamed_path.clone()]).expect("Problem creating renamed path");
-            install::rename_package_files(renamed_path, name, new);
+/// Find the allowed hashes for a package, if it has any.
+fn allowed_hashes<'a>(
+    name: &str,
+    hashes: &'a HashMap<String, Vec<String>>,
+) -> Option<&'a [String]> {
+    hashes
+        .iter()
+        .find(|(n, _)| util::compare_names(n, name))
+        .map(|(_, h)| h.as_slice())
+}
 
-            // Rename in the parent calling the renamed package. // todo: Multiple parents?
-            let parent = lock_packs
-                .iter()
-                .find(|lp| lp.id == *id)
-                .expect("Can't find parent calling renamed package");
-            install::rename_package_files(
-                &paths.lib.join(util::standardize_name(&parent.name)),
-                name,
-                new,
-            );
+/// Find the packages that don't have any allowed hashes.
+fn find_unpinned(
+    packages: &[&(String, Version)],
+    hashes: &HashMap<String, Vec<String>>,
+) -> Vec<String> {
+    packages
+        .iter()
+        .filter(|(name, _)| allowed_hashes(name, hashes).is_none_or(|h| h.is_empty()))
+        .map(|(name, version)| format!("{} {}", name, version))
+        .collect()
+}
 
-            // todo: Handle this more generally, in case we don't have proper semver dist-info paths.
-            install::rename_metadata(
-                &paths
-                    .lib
-                    .join(&format!("{}-{}.dist-info", name, version.to_string())),
-                name,
-                new,
-            );
-        }
+/// Only keep releases whose SHA256 digest is allowed. Hashes may be prefixed with `sha256:`,
+/// as in `requirements.txt`.
+fn filter_by_hashes(releases: Vec<WarehouseRelease>, allowed: &[String]) -> Vec<WarehouseRelease> {
+    releases
+        .into_iter()
+        .filter(|r| allowed.iter().any(|h| hash_matches(h, &r.digests.sha256)))
+        .collect()
+}
+
+/// Compare a hash as listed in a config or lock file, eg `sha256:ab12...`, with a SHA256 digest.
+fn hash_matches(listed: &str, sha256: &str) -> bool {
+    listed
+        .strip_prefix("sha256:")
+        .unwrap_or(listed)
+        .eq_ignore_ascii_case(sha256)
+}
+
+/// Find a package's archive in the cache, from the hash recorded in the lock. Used offline,
+/// where we can't ask the index which file to install.
+fn cached_release(cache_path: &Path, locked_hash: &str) -> Option<(WarehouseRelease, PackageType)> {
+    let sha256 = locked_hash
+        .strip_prefix("sha256:")
+        .unwrap_or(locked_hash)
+        .to_lowercase();
+    let archive_path = cache_path
+        .join(&sha256)
+        .read_dir()
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .find(|path| path.extension().is_some_and(|ext| ext != "part"))?;
+    if !install::cache_hit(&archive_path, &sha256) {
+        return None;
+    }
+
+    let filename = archive_path.file_name()?.to_str()?.to_owned();
+    Some(direct_release(filename, sha256, String::new()))
+}
+
+/// Describe a file we didn't find on an index, eg one in the cache, or at a URL.
+fn direct_release(
+    filename: String,
+    sha256: String,
+    url: String,
+) -> (WarehouseRelease, PackageType) {
+    let (packagetype, package_type) = if filename.ends_with(".whl") {
+        ("bdist_wheel", PackageType::Wheel)
+    } else {
+        ("sdist", PackageType::Source)
+    };
+    let release = WarehouseRelease {
+        filename,
+        has_sig: false,
+        digests: WarehouseDigests {
+            md5: String::new(),
+            sha256,
+        },
+        packagetype: packagetype.to_owned(),
+        python_version: String::new(),
+        requires_python: None,
+        url,
+        dependencies: None,
+        yanked: false,
+        core_metadata: false,
+    };
+    (release, package_type)
+}
+
+/// The URL a package is locked to download from directly, if any.
+fn locked_url<'a>(lock_packs: &'a [LockPackage], name: &str, version: &Version) -> Option<&'a str> {
+    lock_packs
+        .iter()
+        .find(|lp| {
+            util::compare_names(&lp.name, name)
+                && Version::from_str(&lp.version).ok().as_ref() == Some(version)
+        })?
+        .source
+        .as_ref()?
+        .strip_prefix("url+")
+}
+
+/// Find the file for a package locked to a URL. Without a hash from the lock, we download it
+/// now to find one; we need it to cache the file.
+fn url_release(
+    url: &str,
+    locked_hash: Option<&str>,
+    cache_path: &Path,
+) -> Result<(WarehouseRelease, PackageType), String> {
+    let (filename, _) =
+        util::artifact_from_url(url).ok_or_else(|| "it isn't a .whl or .tar.gz file".to_owned())?;
+
+    let sha256 = match locked_hash {
+        Some(h) => h.strip_prefix("sha256:").unwrap_or(h).to_lowercase(),
+        None => download_to_cache(url, &filename, cache_path)?,
+    };
+    Ok(direct_release(filename, sha256, url.to_owned()))
+}
+
+/// Download the file at a URL into the cache, and return its SHA256 digest.
+fn download_to_cache(url: &str, filename: &str, cache_path: &Path) -> Result<String, String> {
+    let download_path = cache_path.join("url").join(filename);
+    let sha256 = util::http::download_all(&[(url.to_owned(), download_path.clone())])?.remove(0);
+
+    let archive_path = install::cached_archive_path(cache_path, filename, &sha256);
+    fs::create_dir_all(archive_path.parent().unwrap()).map_err(|e| e.to_string())?;
+    fs::rename(&download_path, &archive_path).map_err(|e| e.to_string())?;
+    Ok(sha256)
+}
+
+/// Find the requirements of the file at a URL, from a wheel's `METADATA`, or an sdist's
+/// `PKG-INFO` if it lists them. It's downloaded into the cache, unless it's there already.
+fn url_requires_dist(
+    url: &str,
+    locked_hash: Option<&str>,
+    cache_path: &Path,
+) -> Result<Vec<Req>, String> {
+    let (filename, _) =
+        util::artifact_from_url(url).ok_or_else(|| "it isn't a .whl or .tar.gz file".to_owned())?;
+
+    let cached = locked_hash
+        .map(|h| h.strip_prefix("sha256:").unwrap_or(h).to_lowercase())
+        .filter(|h| install::cache_hit(&install::cached_archive_path(cache_path, &filename, h), h));
+    let sha256 = match cached {
+        Some(h) => h,
+        None => download_to_cache(url, &filename, cache_path)?,
+    };
+
+    let filetype = if filename.ends_with(".whl") {
+        "bdist_wheel"
+    } else {
+        "sdist"
+    };
+    let archive_path = install::cached_archive_path(cache_path, &filename, &sha256);
+    let metadata = build::read_dist_metadata(&archive_path, filetype)?;
+    Ok(util::parse_metadata_str(&metadata).requires_dist)
+}
+
+/// Find the requirements that no locked package satisfies.
+fn unsatisfied_reqs(reqs: &[Req], lock_packs: &[LockPackage]) -> Vec<String> {
+    reqs.iter()
+        .filter(|req| {
+            !lock_packs.iter().any(|lp| {
+                util::compare_names(&lp.name, &req.name)
+                    && Version::from_str(&lp.version)
+                        .is_ok_and(|v| res::is_compat(&req.constraints, &v))
+            })
+        })
+        .map(|req| req.to_string())
+        .collect()
+}
+
+/// Check a package's files on the index against the hash recorded in the lock. The lock records
+/// the file installed where it was written, so on other platforms, we may install a different
+/// file of the same release. If no file matches though, the release was changed after locking.
+fn check_locked_hash(
+    name: &str,
+    version: &Version,
+    locked: &str,
+    releases: &[WarehouseRelease],
+) -> Result<(), String> {
+    if releases
+        .iter()
+        .any(|r| hash_matches(locked, &r.digests.sha256))
+    {
+        Ok(())
+    } else {
+        Err(format!(
+            "None of the files for {} {} match its hash in `pyflow.lock` ({}). They may have \
+             been replaced since it was locked.",
+            name, version, locked
+        ))
     }
 }
 
@@ -313,3 +1292,557 @@ fn parse_lockpack_rename(rename: &str) -> (u32, String) {
 
     (id, name)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::lock_pack;
+
+    fn release(filename: &str, sha256: &str) -> WarehouseRelease {
+        WarehouseRelease {
+            filename: filename.into(),
+            has_sig: false,
+            digests: WarehouseDigests {
+                md5: "".into(),
+                sha256: sha256.into(),
+            },
+            packagetype: "bdist_wheel".into(),
+            python_version: "py3".into(),
+            requires_python: None,
+            url: "".into(),
+            dependencies: None,
+            yanked: false,
+            core_metadata: false,
+        }
+    }
+
+    #[test]
+    fn unpinned_transitive_dep() {
+        let mut hashes = HashMap::new();
+        hashes.insert("Requests".to_owned(), vec!["sha256:abcd".to_owned()]);
+
+        // `urllib3` is pulled in by `requests`, but isn't listed in the hashes.
+        let requests = ("requests".to_owned(), Version::new(2, 25, 1));
+        let urllib3 = ("urllib3".to_owned(), Version::new(1, 26, 4));
+
+        assert_eq!(
+            find_unpinned(&[&requests, &urllib3], &hashes),
+            vec!["urllib3 1.26.4".to_owned()]
+        );
+
+        hashes.insert("urllib3".to_owned(), vec!["sha256:ef01".to_owned()]);
+        assert!(find_unpinned(&[&requests, &urllib3], &hashes).is_empty());
+    }
+
+    fn test_paths(dir: &Path) -> util::Paths {
+        util::Paths {
+            bin: dir.join("bin"),
+            lib: dir.join("lib"),
+            entry_pt: dir.join("bin"),
+            cache: dir.join("cache"),
+        }
+    }
+
+    #[test]
+    fn unpinned_transitive_dep_aborts() {
+        let (dir, _tmp) = crate::tests::child_test_dir();
+        let child = || {
+            let mut requests = lock_pack("requests", "2.25.1", &[]);
+            requests.dependencies = Some(vec!["urllib3 1.26.4 pypi+x".into()]);
+            let lockpacks = vec![requests, lock_pack("urllib3", "1.26.4", &[])];
+            let mut hashes = HashMap::new();
+            hashes.insert("requests".to_owned(), vec!["sha256:abcd".to_owned()]);
+
+            sync_deps(
+                &test_paths(&dir),
+                &lockpacks,
+                &[],
+                &[],
+                util::Os::Linux,
+                &Version::new(3, 9, 0),
+                &hashes,
+            );
+        };
+
+        // Aborting exits the process, so sync in a child.
+        let name = "util::deps::tests::unpinned_transitive_dep_aborts";
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let output = match crate::tests::in_child_process(name, &dir) {
+            Some(output) => output,
+            None => return child(),
+        };
+        let installed = fs::read_dir(dir.join("lib")).unwrap().count();
+
+        assert_eq!(output.status.code(), Some(1));
+        assert!(String::from_utf8_lossy(&output.stdout)
+            .contains("packages don't have hashes listed: urllib3 1.26.4"));
+        assert_eq!(installed, 0);
+    }
+
+    #[test]
+    fn releases_filtered_by_hash() {
+        let releases = vec![
+            release("requests-2.25.1-py2.py3-none-any.whl", "abcd"),
+            release("requests-2.25.1.tar.gz", "ef01"),
+        ];
+
+        let allowed = filter_by_hashes(releases.clone(), &["sha256:EF01".to_owned()]);
+        assert_eq!(allowed.len(), 1);
+        assert_eq!(allowed[0].filename, "requests-2.25.1.tar.gz");
+
+        assert!(filter_by_hashes(releases, &["sha256:9999".to_owned()]).is_empty());
+    }
+
+    #[test]
+    fn lock_order_kept() {
+        let existing = vec![
+            lock_pack("requests", "2.25.1", &[]),
+            lock_pack("urllib3", "1.26.4", &[]),
+            lock_pack("idna", "2.10.0", &[]),
+        ];
+        let mut resolved = vec![
+            lock_pack("idna", "2.10.0", &[]),
+            lock_pack("Requests", "2.25.1", &[]),
+            lock_pack("rich", "10.2.2", &[]),
+            lock_pack("colorama", "0.4.4", &[]),
+            lock_pack("urllib3", "1.26.5", &[]),
+        ];
+
+        order_like_lock(&mut resolved, &existing);
+        let order: Vec<(&str, &str)> = resolved
+            .iter()
+            .map(|lp| (lp.name.as_str(), lp.version.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                ("Requests", "2.25.1"),
+                ("idna", "2.10.0"),
+                // The new version of `urllib3` is a new entry.
+                ("colorama", "0.4.4"),
+                ("rich", "10.2.2"),
+                ("urllib3", "1.26.5"),
+            ]
+        );
+    }
+
+    #[test]
+    fn dry_run_planned_as_json() {
+        let (dir, _tmp) = crate::tests::child_test_dir();
+        let lock_path = dir.join("pyflow.lock");
+        let name = "util::deps::tests::dry_run_planned_as_json";
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        let output = match crate::tests::in_child_process(name, &dir) {
+            Some(output) => output,
+            None => {
+                CliConfig {
+                    dry_run: true,
+                    json: true,
+                    offline: true,
+                    ..Default::default()
+                }
+                .make_current();
+                return sync(
+                    &test_paths(&dir),
+                    &[lock_pack("six", "1.16.0", &[])],
+                    &[Req::from_str("six>=1.16", true).unwrap()],
+                    &[],
+                    &[],
+                    &[],
+                    &[],
+                    false,
+                    util::Os::Linux,
+                    &Version::new(3, 9, 0),
+                    &lock_path,
+                    &HashMap::new(),
+                    None,
+                );
+            }
+        };
+        let installed = fs::read_dir(dir.join("lib")).unwrap().count();
+        let locked = lock_path.exists();
+
+        assert!(output.status.success());
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        assert!(stdout.contains(r#""action": "install""#));
+        assert!(stdout.contains(r#""name": "six""#));
+        assert!(!locked);
+        assert_eq!(installed, 0);
+    }
+
+    #[test]
+    fn url_wheel_deps_locked() {
+        use std::io::Write;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let paths = test_paths(dir);
+        let filename = "internal-1.2.0-py3-none-any.whl";
+        let url = format!("https://example.com/dist/{}", filename);
+
+        // The wheel's cached from a previous install, so we don't download it.
+        let wheel = dir.join(filename);
+        let mut zip = zip::ZipWriter::new(fs::File::create(&wheel).unwrap());
+        zip.start_file("internal-1.2.0.dist-info/METADATA", Default::default())
+            .unwrap();
+        zip.write_all(
+            b"Name: internal\nVersion: 1.2.0\nRequires-Dist: six (>=1.15)\n\
+              Requires-Dist: pywin32 ; sys_platform == \"win32\"\n",
+        )
+        .unwrap();
+        zip.finish().unwrap();
+        let sha256 = install::file_sha256(&wheel).unwrap();
+        let archive_path = install::cached_archive_path(&paths.cache, filename, &sha256);
+        fs::create_dir_all(archive_path.parent().unwrap()).unwrap();
+        fs::rename(&wheel, &archive_path).unwrap();
+
+        let mut internal = lock_pack("internal", "1.2.0", &[]);
+        internal.source = Some(util::url_source(&url));
+        internal.hash = Some(format!("sha256:{}", sha256));
+        let mut six = lock_pack("six", "1.16.0", &[]);
+        six.dependencies = Some(vec![]);
+        let mut req = Req::new("internal".into(), vec![]);
+        req.url = Some(url);
+
+        let resolved = resolve_lock(
+            &paths,
+            &[internal, six],
+            &[req],
+            &[],
+            &[],
+            &[],
+            util::Os::Linux,
+            &Version::new(3, 9, 0),
+        );
+
+        let internal = resolved.iter().find(|lp| lp.name == "internal").unwrap();
+        assert_eq!(
+            internal.dependencies,
+            Some(vec![
+                "six 1.16.0 pypi+https://pypi.org/pypi/six/1.16.0/json".to_owned()
+            ])
+        );
+        assert!(resolved.iter().any(|lp| lp.name == "six" && !lp.dev));
+    }
+
+    #[test]
+    fn dry_run_json_plan() {
+        let lock_packs = vec![
+            lock_pack("requests", "2.25.1", &[]),
+            lock_pack("urllib3", "1.26.4", &[]),
+            lock_pack("idna", "2.10.0", &[]),
+            lock_pack("certifi", "2020.12.5", &[]),
+        ];
+        let installed = vec![
+            ("urllib3".to_owned(), Version::new(1, 25, 0), vec![]),
+            ("idna".to_owned(), Version::new(3, 1, 0), vec![]),
+            ("certifi".to_owned(), Version::new(2020, 12, 5), vec![]),
+            ("chardet".to_owned(), Version::new(4, 0, 0), vec![]),
+            ("mypkg".to_owned(), Version::new(0, 1, 0), vec![]),
+        ];
+
+        let plan = plan_changes(&lock_packs, &["mypkg".to_owned()], &installed);
+        let json: serde_json::Value = serde_json::to_value(&plan).unwrap();
+
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {
+                    "action": "install",
+                    "name": "requests",
+                    "version": "2.25.1",
+                    "source": "pypi+https://pypi.org/pypi/requests/2.25.1/json",
+                },
+                {
+                    "action": "upgrade",
+                    "name": "urllib3",
+                    "from": "1.25.0",
+                    "to": "1.26.4",
+                    "source": "pypi+https://pypi.org/pypi/urllib3/1.26.4/json",
+                },
+                {
+                    "action": "downgrade",
+                    "name": "idna",
+                    "from": "3.1.0",
+                    "to": "2.10.0",
+                    "source": "pypi+https://pypi.org/pypi/idna/2.10.0/json",
+                },
+                { "action": "remove", "name": "chardet", "version": "4.0.0" },
+            ])
+        );
+    }
+
+    #[test]
+    fn upgrade_leaves_siblings_locked() {
+        let mut requests = lock_pack("requests", "2.24.0", &[]);
+        requests.dependencies = Some(vec![
+            "urllib3 1.25.11 pypi+https://pypi.org/pypi/urllib3/1.25.11/json".into(),
+        ]);
+        let lockpacks = vec![
+            requests,
+            lock_pack("urllib3", "1.25.11", &[]),
+            lock_pack("idna", "2.10", &[]),
+        ];
+
+        let locked = locked_packages(&lockpacks, &[]);
+        assert_eq!(locked.len(), 3);
+        assert_eq!(locked[0].deps[0].1, "urllib3");
+
+        // Only the upgraded package is left for the resolver to pick a new version of.
+        let locked = locked_packages(&lockpacks, &["Requests".to_owned()]);
+        let names: Vec<(&str, String)> = locked
+            .iter()
+            .map(|p| (p.name.as_str(), p.version.to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("urllib3", "1.25.11".to_owned()),
+                ("idna", "2.10".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn custom_index_source_locked() {
+        let page = |name: &str| {
+            format!(
+                r#"{{"meta": {{"api-version": "1.0"}}, "name": "{0}", "files": [
+                    {{"filename": "{1}-1.0-py3-none-any.whl", "url": "/files/{1}-1.0-py3-none-any.whl",
+                     "hashes": {{}}, "core-metadata": true}}
+                ]}}"#,
+                name,
+                name.replace('-', "_")
+            )
+            .into_bytes()
+        };
+        let json = "application/vnd.pypi.simple.v1+json";
+        let routes = vec![
+            ("/simple/src-app/", (json, page("src-app"))),
+            (
+                "/files/src_app-1.0-py3-none-any.whl.metadata",
+                (
+                    "text/plain",
+                    b"Name: src-app\nVersion: 1.0\nRequires-Dist: src-lib\n".to_vec(),
+                ),
+            ),
+            ("/simple/src-lib/", (json, page("src-lib"))),
+            (
+                "/files/src_lib-1.0-py3-none-any.whl.metadata",
+                ("text/plain", b"Name: src-lib\nVersion: 1.0\n".to_vec()),
+            ),
+        ];
+        let (base, _) =
+            crate::dep_resolution::tests::serve_mock_routes(routes.into_iter().collect());
+        CliConfig {
+            index_url: Some(format!("{}/simple/", base.replace("://", "://user:pass@"))),
+            ..Default::default()
+        }
+        .make_current();
+
+        let tmp = tempfile::tempdir().unwrap();
+        let resolved = resolve_lock(
+            &test_paths(tmp.path()),
+            &[],
+            &[Req::new("src-app".into(), vec![])],
+            &[],
+            &[],
+            &[],
+            util::Os::Linux,
+            &Version::new(3, 9, 0),
+        );
+
+        let app = resolved.iter().find(|lp| lp.name == "src-app").unwrap();
+        assert_eq!(app.source, Some(format!("pypi+{}/simple/src-app/", base)));
+        assert_eq!(
+            app.dependencies,
+            Some(vec![format!("src-lib 1.0 pypi+{}/simple/src-lib/", base)])
+        );
+    }
+
+    #[test]
+    fn graph_from_installed_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lib = tmp.path();
+        let packages = [
+            (
+                "requests-2.25.1.dist-info",
+                "Name: requests\nVersion: 2.25.1\n\
+                 Requires-Dist: urllib3 (<1.27,>=1.21.1)\n\
+                 Requires-Dist: idna (<3,>=2.5)\n\
+                 Requires-Dist: PySocks (!=1.5.7,>=1.5.6) ; extra == 'socks'\n",
+            ),
+            (
+                "urllib3-1.26.4.dist-info",
+                "Name: urllib3\nVersion: 1.26.4\n",
+            ),
+            ("idna-2.10.dist-info", "Name: idna\nVersion: 2.10\n"),
+            // The name comes from `METADATA`, not the folder.
+            (
+                "ruamel_yaml-0.17.4.dist-info",
+                "Name: ruamel.yaml\nVersion: 0.17.4\n",
+            ),
+            // Without `METADATA`, fall back to the folder name.
+            ("six-1.16.0.dist-info", ""),
+            // `click` isn't installed, so `flask` needs resolving.
+            (
+                "Flask-1.1.2.dist-info",
+                "Name: Flask\nVersion: 1.1.2\nRequires-Dist: click (>=5.1)\n",
+            ),
+        ];
+        for (folder, metadata) in packages.iter() {
+            std::fs::create_dir_all(lib.join(folder)).unwrap();
+            if !metadata.is_empty() {
+                std::fs::write(lib.join(folder).join("METADATA"), metadata).unwrap();
+            }
+        }
+
+        let lock_packs = installed_lock_packs(lib, util::Os::Linux, &Version::new(3, 8, 0));
+
+        let names: Vec<&str> = lock_packs.iter().map(|lp| lp.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec!["idna", "requests", "ruamel.yaml", "six", "urllib3"]
+        );
+        assert_eq!(lock_packs[3].version, "1.16.0");
+        assert_eq!(
+            lock_packs[1].dependencies,
+            Some(vec![
+                "urllib3 1.26.4 pypi+https://pypi.org/pypi/urllib3/1.26.4/json".to_owned(),
+                "idna 2.10 pypi+https://pypi.org/pypi/idna/2.10/json".to_owned(),
+            ])
+        );
+
+        // The resolver reuses them like lock file entries.
+        let locked = locked_packages(&lock_packs, &[]);
+        assert_eq!(locked[1].deps[0].1, "urllib3");
+        assert_eq!(locked[1].deps[0].2, Version::new(1, 26, 4));
+    }
+
+    #[test]
+    fn dev_only_packages_marked() {
+        let mut lock_packs = vec![
+            lock_pack("requests", "2.25.1", &["urllib3 1.26.4 pypi+x"]),
+            lock_pack("urllib3", "1.26.4", &[]),
+            lock_pack(
+                "pytest",
+                "6.2.2",
+                &["pluggy 0.13.1 pypi+x", "urllib3 1.26.4 pypi+x"],
+            ),
+            lock_pack("pluggy", "0.13.1", &[]),
+        ];
+
+        mark_dev_only(&mut lock_packs, &[Req::new("Requests".into(), vec![])]);
+        let dev: Vec<bool> = lock_packs.iter().map(|lp| lp.dev).collect();
+        assert_eq!(dev, vec![false, false, true, true]);
+
+        let names =
+            |lps: Vec<LockPackage>| -> Vec<String> { lps.into_iter().map(|lp| lp.name).collect() };
+        assert_eq!(names(without_dev(&lock_packs, true, &[])).len(), 4);
+        assert_eq!(
+            names(without_dev(&lock_packs, false, &[])),
+            vec!["requests", "urllib3"]
+        );
+        // Dev-only packages already installed are kept.
+        let installed = [("Pytest".to_owned(), Version::new(6, 2, 2), vec![])];
+        assert_eq!(
+            names(without_dev(&lock_packs, false, &installed)),
+            vec!["requests", "urllib3", "pytest"]
+        );
+    }
+
+    #[test]
+    fn dev_packages_locked_separately() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lock_path = tmp.path().join("pyflow.lock");
+        let mut pytest = lock_pack("pytest", "6.2.2", &[]);
+        pytest.dev = true;
+        write_lock_packs(&lock_path, &[lock_pack("six", "1.16.0", &[]), pytest]);
+
+        let lock = util::read_lock(&lock_path).unwrap();
+        let names = |lps: &Option<Vec<LockPackage>>| -> Vec<String> {
+            lps.iter().flatten().map(|lp| lp.name.clone()).collect()
+        };
+        assert_eq!(names(&lock.package), vec!["six"]);
+        assert_eq!(names(&lock.dev_package), vec!["pytest"]);
+        assert!(!fs::read_to_string(&lock_path).unwrap().contains("dev ="));
+
+        let dev: Vec<(String, bool)> = lock
+            .packages()
+            .into_iter()
+            .map(|lp| (lp.name, lp.dev))
+            .collect();
+        assert_eq!(
+            dev,
+            vec![("six".to_owned(), false), ("pytest".to_owned(), true)]
+        );
+    }
+
+    #[test]
+    fn locked_hash_checked() {
+        let releases = vec![
+            release("requests-2.25.1-py2.py3-none-any.whl", "abcd"),
+            release("requests-2.25.1.tar.gz", "ef01"),
+        ];
+        let version = Version::new(2, 25, 1);
+
+        assert!(check_locked_hash("requests", &version, "sha256:ABCD", &releases).is_ok());
+        // Eg the lock was written on a platform that installed the source archive.
+        assert!(check_locked_hash("requests", &version, "sha256:ef01", &releases).is_ok());
+
+        let err = check_locked_hash("requests", &version, "sha256:9999", &releases).unwrap_err();
+        assert!(err.contains("match its hash in `pyflow.lock`"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn post_resolve_hook_rejects() {
+        let lock_packs = vec![
+            lock_pack("requests", "2.25.1", &[]),
+            lock_pack("gpl-thing", "1.0.0", &[]),
+        ];
+        let hook = r#"if grep -q '"name":"gpl-thing"'; then echo 'gpl-thing is GPL-licensed' >&2; exit 1; fi"#;
+
+        assert_eq!(
+            run_post_resolve_hook(hook, &lock_packs),
+            Err("gpl-thing is GPL-licensed".to_owned())
+        );
+        assert_eq!(
+            run_post_resolve_hook(hook, &lock_packs[..1]),
+            Ok(String::new())
+        );
+        assert_eq!(
+            run_post_resolve_hook("cat > /dev/null; echo 'Looks good'", &lock_packs),
+            Ok("Looks good".to_owned())
+        );
+    }
+
+    #[test]
+    fn offline_uses_cache() {
+        let tmp = tempfile::tempdir().unwrap();
+        let cache = tmp.path();
+        // The SHA256 of `hello`.
+        let sha = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        let dir = cache.join(sha);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hello-1.0-py3-none-any.whl"), "hello").unwrap();
+
+        let found = cached_release(cache, &format!("sha256:{}", sha.to_uppercase()));
+        let missing = cached_release(cache, "sha256:abcd");
+
+        let (release, package_type) = found.unwrap();
+        assert_eq!(release.filename, "hello-1.0-py3-none-any.whl");
+        assert_eq!(release.digests.sha256, sha);
+        assert!(matches!(package_type, PackageType::Wheel));
+        assert!(missing.is_none());
+
+        let locked = vec![lock_pack("hello", "1.0", &[])];
+        let reqs = vec![
+            Req::from_str("hello>=1.0", true).unwrap(),
+            Req::from_str("hello>=2.0", true).unwrap(),
+            Req::from_str("goodbye", false).unwrap(),
+        ];
+        assert_eq!(unsatisfied_reqs(&reqs, &locked).len(), 2);
+        assert!(unsatisfied_reqs(&reqs[..1], &locked).is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+//! Prints log records from the `log` macros, at the level set with `-v`, `-vv`, or `-q`.
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        match record.level() {
+            // Progress messages, eg "Installing requests". These go where they always have.
+            Level::Info => println!("{}", record.args()),
+            Level::Error | Level::Warn => {
+                eprintln!(
+                    "{}: {}",
+                    record.level().as_str().to_lowercase(),
+                    record.args()
+                )
+            }
+            Level::Debug | Level::Trace => eprintln!(
+                "[{} {}] {}",
+                record.level().as_str().to_lowercase(),
+                record.target(),
+                record.args()
+            ),
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: Logger = Logger;
+
+/// Show progress messages by default; diagnostics with `-v`, and everything, including
+/// internal state dumps, with `-vv`. `-q` leaves only warnings and errors.
+pub fn level_filter(verbose: u8, quiet: bool) -> LevelFilter {
+    if quiet {
+        return LevelFilter::Warn;
+    }
+    match verbose {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    }
+}
+
+pub fn init(verbose: u8, quiet: bool) {
+    // This only fails if a logger's already set, in which case, keep it.
+    let _ = log::set_logger(&LOGGER);
+    log::set_max_level(level_filter(verbose, quiet));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_from_flags() {
+        assert_eq!(level_filter(0, false), LevelFilter::Info);
+        assert_eq!(level_filter(1, false), LevelFilter::Debug);
+        assert_eq!(level_filter(2, false), LevelFilter::Trace);
+        assert_eq!(level_filter(3, false), LevelFilter::Trace);
+        assert_eq!(level_filter(0, true), LevelFilter::Warn);
+    }
+}
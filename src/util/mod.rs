@@ -1,9 +1,17 @@
 pub mod deps;
+pub mod http;
+pub mod logger;
 pub mod paths;
+pub mod progress;
 pub mod prompts;
+pub mod rollback;
 
 mod os;
-pub use os::{get_os, Os};
+pub use os::{
+    get_glibc_version, get_macos_version, get_os, glibc_from_tag, macos_from_tag, Arch, Os,
+};
+mod wheel_tag;
+use wheel_tag::WheelTag;
 
 #[mockall_double::double]
 use crate::dep_resolution::res;
@@ -12,7 +20,7 @@ use crate::dep_resolution::WarehouseRelease;
 use crate::dep_types::Extras;
 use crate::{
     commands,
-    dep_types::{Constraint, DependencyError, Lock, Req, ReqType, Version},
+    dep_types::{Constraint, Lock, LockPackage, Req, ReqType, Version},
     files,
     install::{self, PackageType},
     py_versions, util, CliConfig,
@@ -21,11 +29,11 @@ use ini::Ini;
 use regex::Regex;
 
 use std::fs;
-use std::io::{self, BufRead, BufReader, Read, Write};
+use std::io::{self, BufRead, BufReader, Write};
 use std::path::Component;
 use std::str::FromStr;
 use std::{
-    env,
+    cmp, env,
     error::Error,
     path::{Path, PathBuf},
     process, thread, time,
@@ -91,6 +99,8 @@ fn print_color_res_(message: &str, color: Color) -> io::Result<()> {
 /// We use `expect`, `panic!` etc for problems that indicate a bug in this program.
 pub fn abort(message: &str) -> ! {
     print_color(message, Color::Red);
+    // Don't leave a partly-synced environment behind.
+    rollback::restore();
     process::exit(1)
 }
 
@@ -123,9 +133,13 @@ pub fn find_venvs(pypackages_dir: &Path) -> Vec<(u32, u32)> {
 
     let mut result = vec![];
     for (maj, mi) in py_versions.iter() {
-        let venv_path = pypackages_dir.join(&format!("{}.{}/.venv", maj, mi));
+        let vers_path = pypackages_dir.join(format!("{}.{}", maj, mi));
+        let venv_path = vers_path.join(".venv");
 
-        if venv_path.join("bin/python").exists() || venv_path.join("Scripts/python.exe").exists() {
+        if venv_path.join("bin/python").exists()
+            || venv_path.join("Scripts/python.exe").exists()
+            || vers_path.join(PEP582_PYTHON).exists()
+        {
             result.push((*maj, *mi))
         }
     }
@@ -133,9 +147,52 @@ pub fn find_venvs(pypackages_dir: &Path) -> Vec<(u32, u32)> {
     result
 }
 
+/// In an environment without a venv, per PEP 582, the folder that points to its interpreter.
+pub const PEP582_PYTHON: &str = ".python";
+
+/// Point an environment without a venv at the interpreter it runs, from its own `bin` folder:
+/// with a symlink, as a venv would use, or on Windows, where those need special permissions, a
+/// junction to the interpreter's folder. The interpreter finds its DLLs and standard library
+/// next to it there.
+pub fn link_python(interpreter: &Path, vers_path: &Path) -> io::Result<()> {
+    let link_path = vers_path.join(PEP582_PYTHON);
+    if link_path.exists() {
+        fs::remove_dir_all(&link_path)?;
+    }
+    #[cfg(target_os = "windows")]
+    {
+        fs::create_dir_all(&link_path)?;
+        let home = interpreter.parent().unwrap_or(interpreter);
+        let status = std::process::Command::new("cmd")
+            .args(["/C", "mklink", "/J"])
+            .arg(link_path.join("bin"))
+            .arg(home)
+            .stdout(std::process::Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("Problem linking to {}", home.display()),
+            ));
+        }
+        Ok(())
+    }
+    #[cfg(not(target_os = "windows"))]
+    {
+        fs::create_dir_all(link_path.join("bin"))?;
+        std::os::unix::fs::symlink(interpreter, link_path.join("bin/python"))
+    }
+}
+
 /// Checks whether the path is under `/bin` (Linux generally) or `/Scripts` (Windows generally)
-/// Returns the bin path (ie under the venv)
+/// Returns the bin path (ie under the venv), or for an environment without one, the folder with
+/// its `python`.
 pub fn find_bin_path(vers_path: &Path) -> PathBuf {
+    let link_path = vers_path.join(PEP582_PYTHON);
+    if link_path.exists() {
+        return link_path.join("bin");
+    }
+
     #[cfg(target_os = "windows")]
     return vers_path.join(".venv/Scripts");
     #[cfg(target_os = "linux")]
@@ -166,6 +223,30 @@ pub fn wait_for_dirs(dirs: &[PathBuf]) -> Result<(), crate::py_versions::AliasEr
     })
 }
 
+/// Wait for a newly-created interpreter to run; `venv` may return before it's usable.
+/// Polls `python --version` until it succeeds, or the timeout passes.
+pub fn wait_for_python(
+    py: &Path,
+    timeout: time::Duration,
+) -> Result<(), crate::py_versions::AliasError> {
+    let start = time::Instant::now();
+    while start.elapsed() < timeout {
+        if py.exists() {
+            let ready = commands::python_command(py)
+                .arg("--version")
+                .output()
+                .is_ok_and(|o| o.status.success());
+            if ready {
+                return Ok(());
+            }
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    }
+    Err(crate::py_versions::AliasError {
+        details: format!("Timed out waiting for {} to run", py.display()),
+    })
+}
+
 /// Sets the `PYTHONPATH` environment variable, causing Python to look for
 /// dependencies in `__pypackages__`,
 pub fn set_pythonpath(paths: &[PathBuf]) {
@@ -177,6 +258,47 @@ pub fn set_pythonpath(paths: &[PathBuf]) {
     env::set_var("PYTHONPATH", formatted_paths);
 }
 
+/// Read an installed package's name and version from its `.dist-info` folder's `METADATA`. The
+/// folder's name, eg `requests-2.25.1.dist-info`, is only a fallback, since installers don't agree
+/// on how to spell the name in it.
+pub fn read_dist_info(dist_info_path: &Path) -> Option<(String, Version)> {
+    let mut name = None;
+    let mut version = None;
+    if let Ok(data) = fs::read_to_string(dist_info_path.join("METADATA")) {
+        // The headers end at the first blank line; the description follows.
+        for line in data.lines().take_while(|l| !l.trim().is_empty()) {
+            if let Some(val) = line.strip_prefix("Name:") {
+                name.get_or_insert_with(|| val.trim().to_owned());
+            } else if let Some(val) = line.strip_prefix("Version:") {
+                version = version.or_else(|| Version::from_str(val.trim()).ok());
+            }
+        }
+    }
+
+    if name.is_none() || version.is_none() {
+        let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
+        let folder_name = dist_info_path.file_name()?.to_str()?;
+        let caps = re_dist.captures(folder_name)?;
+        name = name.or_else(|| Some(caps[1].to_owned()));
+        version = version.or_else(|| Version::from_str(&caps[2]).ok());
+    }
+    Some((name?, version?))
+}
+
+/// Find the `.dist-info` folder of an installed package, comparing names as PEP 503 does.
+pub fn find_dist_info(lib_path: &Path, name: &str, version: &Version) -> Option<PathBuf> {
+    if !lib_path.exists() {
+        return None;
+    }
+    find_folders(lib_path)
+        .into_iter()
+        .filter(|f| f.ends_with(".dist-info"))
+        .map(|f| lib_path.join(f))
+        .find(|path| {
+            read_dist_info(path).is_some_and(|(n, v)| compare_names(&n, name) && &v == version)
+        })
+}
+
 /// Find the packages installed, by browsing the lib folder for metadata.
 /// Returns package-name, version, folder names
 pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
@@ -187,17 +309,10 @@ pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
     let mut result = vec![];
 
     for folder_name in &find_folders(lib_path) {
-        let re_dist = Regex::new(r"^(.*?)-(.*?)\.dist-info$").unwrap();
-
-        if let Some(caps) = re_dist.captures(folder_name) {
-            let name = caps.get(1).unwrap().as_str();
-            let vers = Version::from_str(
-                caps.get(2)
-                    .expect("Problem parsing version in folder name")
-                    .as_str(),
-            )
-            .expect("Problem parsing version in package folder");
-
+        if !folder_name.ends_with(".dist-info") {
+            continue;
+        }
+        if let Some((name, vers)) = read_dist_info(&lib_path.join(folder_name)) {
             let top_level = lib_path.join(folder_name).join("top_level.txt");
 
             let mut tops = vec![];
@@ -210,26 +325,56 @@ pub fn find_installed(lib_path: &Path) -> Vec<(String, Version, Vec<String>)> {
                 Err(_) => tops.push(folder_name.to_owned()),
             }
 
-            result.push((name.to_owned(), vers, tops));
+            result.push((name, vers, tops));
         }
     }
     result
 }
-/// Handle reqs added via the CLI. Result is (normal reqs, dev reqs)
+/// Find installed packages whose metadata doesn't include a license, eg for compliance checks.
+pub fn find_unlicensed(lib_path: &Path) -> Vec<(String, Version)> {
+    if !lib_path.exists() {
+        return vec![];
+    }
+
+    let mut result = vec![];
+    for folder_name in &find_folders(lib_path) {
+        if !folder_name.ends_with(".dist-info") {
+            continue;
+        }
+        let dist_info_path = lib_path.join(folder_name);
+        let metadata = fs::read_to_string(dist_info_path.join("METADATA"));
+        if metadata.ok().and_then(|m| parse_license(&m)).is_some() {
+            continue;
+        }
+        if let Some(installed) = read_dist_info(&dist_info_path) {
+            result.push(installed);
+        }
+    }
+    result
+}
+
+/// Handle reqs added via the CLI. Result is (normal reqs, dev reqs). New reqs are saved to
+/// the config at `cfg_path`, if passed.
 pub fn merge_reqs(
     added: &[String],
     dev: bool,
     cfg: &crate::Config,
-    cfg_path: &Path,
+    cfg_path: Option<&Path>,
 ) -> (Vec<Req>, Vec<Req>) {
     let mut added_reqs = vec![];
     for p in added.iter() {
-        let trimmed = p.replace(',', "");
-        match Req::from_str(&trimmed, false) {
-            Ok(r) => added_reqs.push(r),
-            Err(_) => abort(&format!("Unable to parse this package: {}. \
-                    Note that installing a specific version via the CLI is currently unsupported. If you need to specify a version,\
-                     edit `pyproject.toml`", &p)),
+        let trimmed = p.trim_end_matches(',');
+        // eg `requests`, or with a version as pip takes it: `django==4.2`, `requests>=2.28,<3`
+        match Req::from_str(trimmed, false)
+            .ok()
+            .or_else(|| Req::from_pip_str(trimmed))
+        {
+            Some(r) => added_reqs.push(r),
+            None => abort(&format!(
+                "Unable to parse this package: {}. Specify a version as you would with pip, eg \
+                 `django==4.2`, or `\"requests>=2.28,<3\"`",
+                &p
+            )),
         }
     }
 
@@ -245,8 +390,8 @@ pub fn merge_reqs(
 
             for cr in existing.iter() {
                 if cr == ar
-                    || (cr.name.to_lowercase() == ar.name.to_lowercase()
-                        && ar.constraints.is_empty())
+                    || (compare_names(&cr.name, &ar.name)
+                        && (ar.constraints.is_empty() || cr.constraints == ar.constraints))
                 {
                     // Same req/version exists
                     add = false;
@@ -306,25 +451,44 @@ pub fn merge_reqs(
     result.append(&mut added_reqs_unique.clone());
 
     if dev {
-        if !added_reqs_unique.is_empty() {
+        if let Some(cfg_path) = cfg_path.filter(|_| !added_reqs_unique.is_empty()) {
             files::add_reqs_to_cfg(cfg_path, &[], &added_reqs_unique);
         }
         (cfg.reqs.clone(), result)
     } else {
-        if !added_reqs_unique.is_empty() {
+        if let Some(cfg_path) = cfg_path.filter(|_| !added_reqs_unique.is_empty()) {
             files::add_reqs_to_cfg(cfg_path, &added_reqs_unique, &[]);
         }
         (result, cfg.dev_reqs.clone())
     }
 }
 
+/// Convert a package name to the form of its folder in `lib`, eg `Typing-Extensions` to
+/// `typing_extensions`: its normalized name, with `_` for `-`. Use `normalize_name` to compare
+/// names.
 pub fn standardize_name(name: &str) -> String {
-    name.to_lowercase().replace('-', "_").replace('.', "_")
+    normalize_name(name).replace('-', "_")
+}
+
+/// Normalize a package name as PEP 503 does: lowercase, with each run of `-`, `_` and `.` as a
+/// single `-`. So `Flask` and `FLASK` are the same, as are `zope.interface` and `zope-interface`.
+pub fn normalize_name(name: &str) -> String {
+    let mut result = String::with_capacity(name.len());
+    for c in name.chars() {
+        if matches!(c, '-' | '_' | '.') {
+            if !result.ends_with('-') {
+                result.push('-');
+            }
+        } else {
+            result.extend(c.to_lowercase());
+        }
+    }
+    result
 }
 
 // PyPi naming isn't consistent; it capitalization and _ vs -
 pub fn compare_names(name1: &str, name2: &str) -> bool {
-    standardize_name(name1) == standardize_name(name2)
+    normalize_name(name1) == normalize_name(name2)
 }
 
 /// Extract the wheel or zip.
@@ -394,6 +558,9 @@ pub fn extract_zip(
                     fs::create_dir_all(&p).unwrap();
                 }
             }
+            // Replace, rather than overwrite, existing files; they may be hardlinked to a
+            // snapshot we'd restore if the install fails.
+            let _ = fs::remove_file(&outpath);
             let mut outfile = fs::File::create(&outpath).unwrap();
             io::copy(&mut file, &mut outfile).unwrap();
         }
@@ -410,28 +577,20 @@ pub fn extract_zip(
     }
 }
 
+/// Decompress and unpack a `.tar.xz` as it's read, so the archive's never all in memory.
 pub fn unpack_tar_xz(archive_path: &Path, dest: &Path) {
-    let archive_bytes = fs::read(archive_path).expect("Problem reading archive as bytes");
+    let archive_file = fs::File::open(archive_path).expect("Problem opening the archive");
+    let decompressor = XzDecoder::new(BufReader::new(archive_file));
 
-    let mut tar: Vec<u8> = Vec::new();
-    let mut decompressor = XzDecoder::new(&archive_bytes[..]);
-    if decompressor.read_to_end(&mut tar).is_err() {
+    let mut archive = Archive::new(decompressor);
+    if archive.unpack(dest).is_err() {
         abort(&format!(
-            "Problem decompressing the archive: {:?}. This may be due to a failed download. \
+            "Problem unpacking the archive: {:?}. This may be due to a failed download. \
         Try deleting it, then try again. Note that Pyflow will only install officially-released \
         Python versions. If you'd like to use a pre-release, you must install it manually.",
             archive_path
         ))
     }
-
-    // We've decompressed the .xz; now unpack the tar.
-    let mut archive = Archive::new(&tar[..]);
-    if archive.unpack(dest).is_err() {
-        abort(&format!(
-            "Problem unpacking tar: {}",
-            archive_path.to_str().unwrap()
-        ))
-    }
 }
 
 /// Find venv info, creating a venv as required.
@@ -440,6 +599,7 @@ pub fn find_or_create_venv(
     pypackages_dir: &Path,
     pyflow_dir: &Path,
     dep_cache_path: &Path,
+    pep582: bool,
 ) -> (PathBuf, Version) {
     let venvs = find_venvs(pypackages_dir);
     // The version's explicitly specified; check if an environment for that version
@@ -452,8 +612,13 @@ pub fn find_or_create_venv(
     let py_vers;
     match compatible_venvs.len() {
         0 => {
-            let vers =
-                py_versions::create_venv(cfg_vers, pypackages_dir, pyflow_dir, dep_cache_path);
+            let vers = py_versions::create_venv(
+                cfg_vers,
+                pypackages_dir,
+                pyflow_dir,
+                dep_cache_path,
+                pep582,
+            );
             vers_path = pypackages_dir.join(vers.to_string_med());
             py_vers = Version::new_opt(vers.major, vers.minor, None); // Don't include patch.
         }
@@ -463,6 +628,33 @@ pub fn find_or_create_venv(
                 compatible_venvs[0].0, compatible_venvs[0].1
             ));
             py_vers = Version::new_short(compatible_venvs[0].0, compatible_venvs[0].1);
+
+            if vers_path.join(PEP582_PYTHON).exists() != pep582 {
+                print_color(
+                    &format!(
+                        "This environment was created {} a venv; run `pyflow reset --keep-lock` \
+                         to recreate it {} one, as `use_pep582` now specifies",
+                        if pep582 { "with" } else { "without" },
+                        if pep582 { "without" } else { "with" },
+                    ),
+                    Color::Yellow,
+                );
+            }
+
+            // Environments are per minor version, so check an exact version requested still
+            // matches the one this environment was created with.
+            if cfg_vers.patch.is_some() {
+                let venv_python = find_bin_path(&vers_path).join("python");
+                if let Some(found) = commands::find_py_version(venv_python.to_str().unwrap()) {
+                    if !py_versions::matches_requested(cfg_vers, &found) {
+                        abort(&format!(
+                            "This environment uses Python {}, but `pyproject.toml` requires {}. \
+                             Run `pyflow reset` to recreate it.",
+                            found, cfg_vers
+                        ));
+                    }
+                }
+            }
         }
         _ => {
             abort(
@@ -534,31 +726,55 @@ pub fn fallible_v_parse(vers: &str) -> Version {
     }
 }
 
-/// Find the operating system from a wheel filename. This doesn't appear to be available
-/// anywhere else on the Pypi Warehouse.
-fn os_from_wheel_fname(filename: &str) -> Result<Os, DependencyError> {
-    // Format is "name-version-pythonversion-mobileversion?-os.whl"
-    // Also works with formats like this:
-    // `PyQt5-5.13.0-5.13.0-cp35.cp36.cp37.cp38-none-win32.whl` too.
-    // The point is, pull the last part before ".whl".
-    let re = Regex::new(r"^(?:.*?-)+(.*).whl$").unwrap();
-    if let Some(caps) = re.captures(filename) {
-        let parsed = caps.get(1).unwrap().as_str();
-        return Ok(
-            Os::from_str(parsed).unwrap_or_else(|_| panic!("Problem parsing Os: {}", parsed))
-        );
-    }
+/// Rank a compatible wheel by how specific it is to this environment; higher is better.
+/// In order of precedence:
+/// 1. Python tag: built for this exact version, eg `cp39` for Python 3.9, then for the major
+///    version, eg `py3` or `py2.py3`, or the stable ABI from an older version, then anything else
+/// 2. ABI tag: a specific ABI like `cp39`, then the stable ABI, `abi3`, then `none`
+/// 3. Platform tag: a specific platform like `manylinux2014_x86_64`, then `any`
+fn wheel_rank(tag: &WheelTag, python_vers: &Version) -> (u8, u8, u8) {
+    let major = python_vers.major.unwrap_or(3);
+    let exact = [
+        format!("cp{}{}", major, python_vers.minor.unwrap_or(0)),
+        format!("py{}{}", major, python_vers.minor.unwrap_or(0)),
+    ];
+    let major_only = [format!("cp{}", major), format!("py{}", major)];
+    let has_abi = |a: &str| tag.abi.iter().any(|t| t == a);
 
-    Err(DependencyError::new("Problem parsing os from wheel name"))
+    let py_rank = if tag.python.iter().any(|t| exact.contains(t)) {
+        2
+    } else if tag.python.iter().any(|t| major_only.contains(t)) || has_abi("abi3") {
+        1
+    } else {
+        0
+    };
+
+    let abi_rank = if tag.abi.iter().any(|t| t != "none" && t != "abi3") {
+        2
+    } else if has_abi("abi3") {
+        1
+    } else {
+        0
+    };
+    let platform_rank = if tag.platform.iter().all(|t| t == "any") {
+        0
+    } else {
+        1
+    };
+
+    (py_rank, abi_rank, platform_rank)
 }
 
 /// Find the most appropriate release to download. Ie Windows vs Linux, wheel vs source.
+/// If several wheels are compatible, pick the most specific, as ranked by `wheel_rank`.
 pub fn find_best_release(
     data: &[WarehouseRelease],
     name: &str,
     version: &Version,
     os: Os,
     python_vers: &Version,
+    glibc_vers: Option<(u32, u32)>,
+    macos_vers: Option<(u32, u32)>,
 ) -> (WarehouseRelease, PackageType) {
     // Find which release we should download. Preferably wheels, and if so, for the right OS and
     // Python version.
@@ -566,7 +782,10 @@ pub fn find_best_release(
     // Store source releases as a fallback, for if no wheels are found.
     let mut source_releases = vec![];
 
-    for rel in data.iter() {
+    // Per PEP 592, skip yanked files, eg a broken wheel for one platform. If they're all yanked,
+    // the version was pinned exactly, which allows them.
+    let all_yanked = data.iter().all(|rel| rel.yanked);
+    for rel in data.iter().filter(|rel| all_yanked || !rel.yanked) {
         let mut compatible = true;
         match rel.packagetype.as_ref() {
             "bdist_wheel" => {
@@ -583,50 +802,38 @@ pub fn find_best_release(
                     }
                 }
 
-                let wheel_os =
-                    os_from_wheel_fname(&rel.filename).expect("Problem getting os from wheel name");
-                if wheel_os != os && wheel_os != Os::Any {
-                    compatible = false;
-                }
-
                 // Packages that use C code(eg numpy) may fail to load C extensions if installing
                 // for the wrong version of python (eg  cp35 when python 3.7 is installed), even
-                // if `requires_python` doesn't indicate an incompatibility. Check `python_version`
-                // instead of `requires_python`.
-                // Note that the result of this parse is an any match.
-                if let Ok(constrs) = Constraint::from_wh_py_vers(&rel.python_version) {
-                    let mut compat_py_v = false;
-                    for constr in &constrs {
-                        if constr.is_compatible(python_vers) {
-                            compat_py_v = true;
-                        }
+                // if `requires_python` doesn't indicate an incompatibility, so check the tags too.
+                let tag = match WheelTag::from_filename(&rel.filename) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        log::debug!("{}", e.details);
+                        continue;
                     }
-                    if !compat_py_v {
-                        compatible = false;
-                    }
-                } else {
-                    println!(
-                        "Unable to match python version from python_version: {}",
-                        &rel.python_version
-                    )
                 };
+                if !wheel_tag::is_compatible(&tag, python_vers, os, glibc_vers, macos_vers) {
+                    compatible = false;
+                }
 
                 if compatible {
-                    compatible_releases.push(rel.clone());
+                    compatible_releases.push((tag, rel.clone()));
                 }
             }
             "sdist" => source_releases.push(rel.clone()),
             "bdist_wininst" | "bdist_msi" | "bdist_egg" => (), // Don't execute Windows installers
             _ => {
-                println!("Found surprising package type: {}", rel.packagetype);
+                log::debug!("Found surprising package type: {}", rel.packagetype);
                 continue;
             }
         }
     }
 
+    // Stable, so equally-ranked wheels stay in the order the index listed them.
+    compatible_releases.sort_by_key(|(tag, _)| cmp::Reverse(wheel_rank(tag, python_vers)));
+
     let best_release;
     let package_type;
-    // todo: Sort further / try to match exact python_version if able.
     if compatible_releases.is_empty() {
         if source_releases.is_empty() {
             abort(&format!(
@@ -639,7 +846,7 @@ pub fn find_best_release(
             package_type = install::PackageType::Source;
         }
     } else {
-        best_release = compatible_releases[0].clone();
+        best_release = compatible_releases[0].1.clone();
         package_type = install::PackageType::Wheel;
     }
 
@@ -705,11 +912,16 @@ pub fn open_archive(path: &Path) -> fs::File {
 
 /// Parse a wheel's `METADATA` file.
 pub fn parse_metadata(path: &Path) -> Metadata {
+    let data = fs::read_to_string(path).expect("Problem reading METADATA");
+    parse_metadata_str(&data)
+}
+
+/// Parse the contents of a `METADATA` file.
+pub fn parse_metadata_str(data: &str) -> Metadata {
     let re = |key: &str| Regex::new(&format!(r"^{}:\s*(.*)$", key)).unwrap();
 
     let mut result = Metadata::default();
 
-    let data = fs::read_to_string(path).expect("Problem reading METADATA");
     for line in data.lines() {
         if let Some(caps) = re("Version").captures(line) {
             let val = caps.get(1).unwrap().as_str();
@@ -723,10 +935,33 @@ pub fn parse_metadata(path: &Path) -> Metadata {
             result.requires_dist.push(req);
         }
     }
-    // todo: For now, just pull version and requires_dist. Add more as-required.
+    result.license = parse_license(data);
+    // todo: For now, just pull version, license and requires_dist. Add more as-required.
     result
 }
 
+/// Find the license in a `METADATA` file's contents, from its `License` or
+/// `License-Expression` field, or failing that, a `License ::` classifier.
+fn parse_license(data: &str) -> Option<String> {
+    let re_field = Regex::new(r"^License(?:-Expression)?:\s*(.*)$").unwrap();
+    let re_classifier = Regex::new(r"^Classifier:\s*License :: (.*)$").unwrap();
+
+    let mut classifier = None;
+    for line in data.lines() {
+        if let Some(caps) = re_field.captures(line) {
+            let val = caps[1].trim();
+            // `UNKNOWN` is what setuptools writes when no license is given.
+            if !val.is_empty() && val != "UNKNOWN" {
+                return Some(val.to_owned());
+            }
+        }
+        if let Some(caps) = re_classifier.captures(line) {
+            classifier.get_or_insert_with(|| caps[1].trim().to_owned());
+        }
+    }
+    classifier
+}
+
 pub fn find_folders(path: &Path) -> Vec<String> {
     let mut result = vec![];
     for entry in path.read_dir().expect("Can't open lib path").flatten() {
@@ -795,6 +1030,24 @@ pub(crate) fn check_command_output_with(output: &process::Output, f: impl Fn(&st
     }
 }
 
+/// Resolve `.` and `..` in a path without touching the filesystem, since the files it names may
+/// not exist. Eg `lib/../bin/black` becomes `bin/black`.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut result = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir
+                if matches!(result.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                result.pop();
+            }
+            _ => result.push(component),
+        }
+    }
+    result
+}
+
 /// Take the canonicalized `path` and join `extend` onto it
 pub fn canon_join(path: &Path, extend: &str) -> PathBuf {
     let ex_path = Path::new(extend);
@@ -815,23 +1068,48 @@ pub fn canon_join(path: &Path, extend: &str) -> PathBuf {
 
 /// Install git requirements and collect their downstream dependencies.
 ///
-/// The git requirements are removed from the `reqs` vector, and are replaced
-/// by all their downstream requirements.
-pub fn process_reqs(reqs: Vec<Req>, git_path: &Path, paths: &util::Paths) -> Vec<Req> {
+/// Path requirements are removed from the `reqs` vector; unless they're editable, we build and
+/// install them, and add their downstream requirements. They're local, so aren't locked. Git
+/// requirements are pinned to the version and commit we built, so they can be locked, and their
+/// downstream requirements are added. If `lockpacks` has a commit for a git requirement, we
+/// build that one, instead of the newest on its branch. If `install` is false, we build them
+/// to find their dependencies, but don't install them, eg for `pyflow lock`.
+pub fn process_reqs(
+    reqs: Vec<Req>,
+    git_path: &Path,
+    paths: &util::Paths,
+    lockpacks: &[LockPackage],
+    install: bool,
+) -> Vec<Req> {
     // git_reqs is used to store requirements from packages installed via git.
     let mut git_reqs = vec![]; // For path reqs too.
     for req in reqs.iter().filter(|r| r.git.is_some()) {
-        // todo: as_ref() would be better than clone, if we can get it working.
-        let mut metadata = install::download_and_install_git(
+        let url = req.git.as_ref().unwrap();
+        let rev = match locked_commit(lockpacks, &req.name, url) {
+            Some(commit) => commit,
+            None => format!("origin/{}", req.branch.as_deref().unwrap_or("HEAD")),
+        };
+        let (mut metadata, commit) =
+            install::download_and_install_git(&req.name, url, &rev, git_path, paths, install);
+
+        git_reqs.push(Req {
+            constraints: vec![Constraint::new(ReqType::Exact, metadata.version.clone())],
+            branch: Some(commit),
+            ..req.clone()
+        });
+        git_reqs.append(&mut metadata.requires_dist);
+    }
+    for req in reqs.iter().filter(|r| r.path.is_some() && !r.develop) {
+        let mut metadata = install::install_path(
             &req.name,
-            //  util::GitPath::Git(req.git.clone().unwrap()),
-            &req.git.clone().unwrap(),
-            git_path,
+            Path::new(req.path.as_ref().unwrap()),
+            &git_path.join("builds"),
             paths,
+            install,
         );
         git_reqs.append(&mut metadata.requires_dist);
     }
-    // We don't pass the git requirement itself, since we've directly installed it,
+    // We don't resolve the git requirement itself, since we've directly installed it,
     // but we do pass its requirements.
     let mut updated_reqs: Vec<Req> = reqs
         .into_iter()
@@ -843,6 +1121,60 @@ pub fn process_reqs(reqs: Vec<Req>, git_path: &Path, paths: &util::Paths) -> Vec
     updated_reqs
 }
 
+/// The lock file's source for a package built from git, eg
+/// `git+https://github.com/foo/bar#<commit hash>`.
+pub fn git_source(url: &str, commit: &str) -> String {
+    format!("git+{}#{}", url, commit)
+}
+
+/// The lock file's source for a package from an index, eg
+/// `pypi+https://pypi.org/pypi/requests/2.25.1/json`, or for a custom index, its project page, eg
+/// `pypi+https://pypi.example.com/simple/requests/`. Credentials in the index URL are left out.
+pub fn index_source(index_url: Option<&str>, name: &str, version: &str) -> String {
+    match index_url {
+        Some(index_url) => format!(
+            "pypi+{}/{}/",
+            http::strip_credentials(index_url).trim_end_matches('/'),
+            normalize_name(name)
+        ),
+        None => format!("pypi+https://pypi.org/pypi/{}/{}/json", name, version),
+    }
+}
+
+/// The lock file's source for a package downloaded directly, eg `url+https://example.com/...`.
+pub fn url_source(url: &str) -> String {
+    format!("url+{}", url)
+}
+
+/// Find the filename and version of the wheel or sdist a direct URL points to. Returns `None`
+/// if it isn't a `.whl` or `.tar.gz` file.
+pub fn artifact_from_url(url: &str) -> Option<(String, Version)> {
+    let path = url.split(['?', '#']).next()?;
+    let filename = path.rsplit('/').next()?.to_owned();
+
+    let version = if let Some(stem) = filename.strip_suffix(".whl") {
+        // Format is "name-version(-build)?-pythontag-abitag-platformtag.whl"
+        let parts: Vec<&str> = stem.split('-').collect();
+        if parts.len() < 5 {
+            return None;
+        }
+        parts[1]
+    } else {
+        filename.strip_suffix(".tar.gz")?.rsplit_once('-')?.1
+    };
+    let version = Version::from_str(version).ok()?;
+    Some((filename, version))
+}
+
+/// Find the commit locked for a package built from the git repo at `url`.
+fn locked_commit(lockpacks: &[LockPackage], name: &str, url: &str) -> Option<String> {
+    let prefix = git_source(url, "");
+    lockpacks
+        .iter()
+        .filter(|lp| compare_names(&lp.name, name))
+        .find_map(|lp| lp.source.as_ref()?.strip_prefix(&prefix).map(str::to_owned))
+}
+
 /// Read dependency data from a lock file.
 pub fn read_lock(path: &Path) -> Result<Lock, Box<dyn Error>> {
     let data = fs::read_to_string(path)?;
@@ -876,11 +1208,143 @@ mod tests {
     use rstest::rstest;
 
     use super::*;
-    use crate::dep_types;
+    use crate::{dep_types, tests::lock_pack};
 
     #[test]
     fn dummy_test() {}
 
+    #[test]
+    fn direct_urls() {
+        assert_eq!(
+            artifact_from_url("https://example.com/dist/foo-1.0-py3-none-any.whl"),
+            Some(("foo-1.0-py3-none-any.whl".into(), Version::new(1, 0, 0)))
+        );
+        assert_eq!(
+            artifact_from_url("https://example.com/foo_bar-2.1.3.tar.gz?token=abc"),
+            Some(("foo_bar-2.1.3.tar.gz".into(), Version::new(2, 1, 3)))
+        );
+        assert!(artifact_from_url("https://example.com/foo-1.0.zip").is_none());
+        assert!(artifact_from_url("https://example.com/foo-1.0.exe").is_none());
+        assert!(artifact_from_url("https://example.com/download").is_none());
+    }
+
+    #[test]
+    fn git_commit_locked() {
+        let url = "https://github.com/foo/bar";
+        let mut bar = lock_pack("bar", "0.1.0", &[]);
+        bar.source = Some(git_source(url, "9fceb02d0ae598e95dc970b74767f19372d61af8"));
+        let lockpacks = vec![bar];
+
+        assert_eq!(
+            locked_commit(&lockpacks, "Bar", url),
+            Some("9fceb02d0ae598e95dc970b74767f19372d61af8".to_owned())
+        );
+        // The requirement points somewhere else now, so the lock's stale.
+        assert!(locked_commit(&lockpacks, "bar", "https://github.com/foo/bar-fork").is_none());
+    }
+
+    fn wheel(filename: &str, python_version: &str) -> WarehouseRelease {
+        WarehouseRelease {
+            filename: filename.into(),
+            has_sig: false,
+            digests: crate::dep_resolution::WarehouseDigests {
+                md5: "".into(),
+                sha256: "".into(),
+            },
+            packagetype: "bdist_wheel".into(),
+            python_version: python_version.into(),
+            requires_python: None,
+            url: "".into(),
+            dependencies: None,
+            yanked: false,
+            core_metadata: false,
+        }
+    }
+
+    #[test]
+    fn most_specific_wheel_chosen() {
+        let py_vers = Version::new(3, 9, 0);
+        let universal = wheel("pkg-1.0-py2.py3-none-any.whl", "py2.py3");
+        let abi3 = wheel("pkg-1.0-cp36-abi3-manylinux2014_x86_64.whl", "cp36");
+        let cp39 = wheel("pkg-1.0-cp39-cp39-manylinux2014_x86_64.whl", "cp39");
+        let cp38 = wheel("pkg-1.0-cp38-cp38-manylinux2014_x86_64.whl", "cp38");
+
+        let rank = |rel: &WarehouseRelease| {
+            wheel_rank(&WheelTag::from_filename(&rel.filename).unwrap(), &py_vers)
+        };
+        assert!(rank(&cp39) > rank(&abi3));
+        assert!(rank(&abi3).1 > rank(&universal).1);
+
+        let releases = vec![universal.clone(), cp38, cp39];
+        let (best, _) = find_best_release(
+            &releases,
+            "pkg",
+            &Version::new_short(1, 0),
+            Os::Linux,
+            &py_vers,
+            None,
+            None,
+        );
+        assert_eq!(best.filename, "pkg-1.0-cp39-cp39-manylinux2014_x86_64.whl");
+
+        // Without an exact match, fall back to the universal wheel.
+        let releases = vec![universal];
+        let (best, _) = find_best_release(
+            &releases,
+            "pkg",
+            &Version::new_short(1, 0),
+            Os::Linux,
+            &py_vers,
+            None,
+            None,
+        );
+        assert_eq!(best.filename, "pkg-1.0-py2.py3-none-any.whl");
+    }
+
+    #[test]
+    fn yanked_files_skipped() {
+        let mut cp39 = wheel("pkg-1.0-cp39-cp39-manylinux2014_x86_64.whl", "cp39");
+        cp39.yanked = true;
+        let universal = wheel("pkg-1.0-py2.py3-none-any.whl", "py2.py3");
+        let best = |releases: &[WarehouseRelease]| {
+            find_best_release(
+                releases,
+                "pkg",
+                &Version::new_short(1, 0),
+                Os::Linux,
+                &Version::new(3, 9, 0),
+                None,
+                None,
+            )
+            .0
+            .filename
+        };
+
+        assert_eq!(
+            best(&[cp39.clone(), universal]),
+            "pkg-1.0-py2.py3-none-any.whl"
+        );
+        // Unless they're all yanked.
+        assert_eq!(best(&[cp39]), "pkg-1.0-cp39-cp39-manylinux2014_x86_64.whl");
+    }
+
+    #[test]
+    fn stable_abi_wheel_compatible() {
+        let abi3 = wheel("pkg-1.0-cp37-abi3-manylinux2014_x86_64.whl", "cp37");
+        let cp37 = wheel("pkg-1.0-cp37-cp37m-manylinux2014_x86_64.whl", "cp37");
+        let releases = vec![cp37, abi3];
+        let (best, _) = find_best_release(
+            &releases,
+            "pkg",
+            &Version::new_short(1, 0),
+            Os::Linux,
+            &Version::new(3, 11, 2),
+            None,
+            None,
+        );
+        assert_eq!(best.filename, "pkg-1.0-cp37-abi3-manylinux2014_x86_64.whl");
+    }
+
     #[rstest(
         input,
         expected,
@@ -892,15 +1356,34 @@ mod tests {
         case("linux2", Ok(Os::Linux)),
         case("manylinux1_x86_64", Ok(Os::Linux)),
         case("manylinux2010_x86_64", Ok(Os::Linux)),
-        case("manylinux2014_aarch64", Ok(Os::Linux)),
+        case("manylinux2014_aarch64", Ok(Os::LinuxArm64)),
+        case("manylinux_2_17_aarch64", Ok(Os::LinuxArm64)),
+        case("linux_armv7l", Ok(Os::LinuxArmv7)),
+        case("musllinux_1_1_x86_64", Ok(Os::LinuxMusl)),
+        case("musllinux_1_2_aarch64", Ok(Os::LinuxMuslArm64)),
+        case(
+            "musllinux_1_1_i686",
+            Err(dep_types::DependencyError::new(
+                "Unsupported musllinux architecture: musllinux_1_1_i686"
+            ))
+        ),
+        case(
+            "musllinux_1_2_armv7l",
+            Err(dep_types::DependencyError::new(
+                "Unsupported musllinux architecture: musllinux_1_2_armv7l"
+            ))
+        ),
         case("manylinux2014_ppc64le", Ok(Os::Linux)),
         case("manylinux2014_x86_64", Ok(Os::Linux)),
+        case("manylinux_2_28_x86_64", Ok(Os::Linux)),
         case("win32", Ok(Os::Windows32)),
         case("windows", Ok(Os::Windows)),
         case("win", Ok(Os::Windows)),
         case("win_amd64", Ok(Os::Windows)),
         case("macosx_10_6_intel", Ok(Os::Mac)),
         case("darwin", Ok(Os::Mac)),
+        case("macosx_11_0_arm64", Ok(Os::MacArm64)),
+        case("macosx_10_9_universal2", Ok(Os::MacUniversal)),
         case("openbsd6", Ok(Os::Linux)),
         case("any", Ok(Os::Any)),
         case("some other bsd name", Ok(Os::Linux)),
@@ -909,4 +1392,193 @@ mod tests {
     fn test_os_from_str(input: &str, expected: Result<Os, dep_types::DependencyError>) {
         assert_eq!(Os::from_str(input), expected);
     }
+
+    #[rstest(
+        wheel_os,
+        host,
+        expected,
+        case(Os::Any, Os::LinuxArm64, true),
+        case(Os::Linux, Os::LinuxArm64, false),
+        case(Os::LinuxArm64, Os::LinuxArm64, true),
+        case(Os::LinuxMusl, Os::Linux, false),
+        case(Os::Linux, Os::LinuxMusl, false),
+        case(Os::LinuxMuslArm64, Os::LinuxMuslArm64, true),
+        case(Os::MacUniversal, Os::Mac, true),
+        case(Os::MacUniversal, Os::MacArm64, true),
+        case(Os::Mac, Os::MacArm64, false),
+        case(Os::MacArm64, Os::Mac, false)
+    )]
+    fn test_os_compatible(wheel_os: Os, host: Os, expected: bool) {
+        assert_eq!(wheel_os.is_compatible(host), expected);
+    }
+
+    #[rstest(
+        tag,
+        expected,
+        case("manylinux1_x86_64", Some((2, 5))),
+        case("manylinux2010_i686", Some((2, 12))),
+        case("manylinux2014_aarch64", Some((2, 17))),
+        case("manylinux_2_28_x86_64", Some((2, 28))),
+        case("manylinux_2_17_x86_64.manylinux2014_x86_64", Some((2, 17))),
+        case("musllinux_1_1_x86_64", None),
+        case("win_amd64", None)
+    )]
+    fn test_glibc_from_tag(tag: &str, expected: Option<(u32, u32)>) {
+        assert_eq!(glibc_from_tag(tag), expected);
+    }
+
+    #[rstest(
+        tag,
+        expected,
+        case("macosx_10_9_x86_64", Some((10, 9))),
+        case("macosx_10_15_universal2", Some((10, 15))),
+        case("macosx_11_0_arm64", Some((11, 0))),
+        case("macosx_12_3_x86_64", Some((12, 0))),
+        case("manylinux2014_x86_64", None)
+    )]
+    fn test_macos_from_tag(tag: &str, expected: Option<(u32, u32)>) {
+        assert_eq!(macos_from_tag(tag), expected);
+    }
+
+    #[rstest(
+        host,
+        arch,
+        expected,
+        case(Os::MacArm64, Arch::X86_64, Os::Mac),
+        case(Os::Mac, Arch::Arm64, Os::MacArm64),
+        case(Os::MacArm64, Arch::Arm64, Os::MacArm64),
+        case(Os::Linux, Arch::Arm64, Os::Linux)
+    )]
+    fn test_os_with_arch(host: Os, arch: Arch, expected: Os) {
+        assert_eq!(host.with_arch(arch), expected);
+    }
+
+    #[test]
+    fn unlicensed_packages() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lib = tmp.path();
+        let packages = [
+            ("licensed-1.0.0.dist-info", "Name: licensed\nLicense: MIT\n"),
+            (
+                "classified-2.0.0.dist-info",
+                "Name: classified\nLicense: UNKNOWN\nClassifier: License :: OSI Approved :: BSD License\n",
+            ),
+            ("unlicensed-0.3.0.dist-info", "Name: unlicensed\nLicense: UNKNOWN\n"),
+        ];
+        for (folder, metadata) in packages.iter() {
+            fs::create_dir_all(lib.join(folder)).unwrap();
+            fs::write(lib.join(folder).join("METADATA"), metadata).unwrap();
+        }
+
+        let unlicensed = find_unlicensed(lib);
+
+        assert_eq!(
+            unlicensed,
+            vec![("unlicensed".to_owned(), Version::new(0, 3, 0))]
+        );
+    }
+
+    #[rstest(
+        name,
+        expected,
+        case("Flask", "flask"),
+        case("zope.interface", "zope-interface"),
+        case("typing_extensions", "typing-extensions"),
+        case("Foo__Bar.-baz", "foo-bar-baz")
+    )]
+    fn names_normalized(name: &str, expected: &str) {
+        assert_eq!(normalize_name(name), expected);
+        assert!(compare_names(name, expected));
+    }
+
+    #[test]
+    fn installed_read_from_metadata() {
+        let tmp = tempfile::tempdir().unwrap();
+        let lib = tmp.path();
+        let packages = [
+            // The folder's spelling of the name differs from the project's.
+            (
+                "ruamel_yaml-0.17.4.dist-info",
+                "Metadata-Version: 2.1\nName: ruamel.yaml\nVersion: 0.17.4\n\nName: not-this\n",
+            ),
+            ("Pillow-8.0.dist-info", "Name: Pillow\nVersion: 8.0.1\n"),
+            // Without `METADATA`, fall back to the folder name.
+            ("six-1.15.0.dist-info", ""),
+        ];
+        for (folder, metadata) in packages.iter() {
+            fs::create_dir_all(lib.join(folder)).unwrap();
+            if !metadata.is_empty() {
+                fs::write(lib.join(folder).join("METADATA"), metadata).unwrap();
+            }
+        }
+        fs::create_dir_all(lib.join("PIL")).unwrap();
+
+        let mut installed: Vec<(String, Version)> = find_installed(lib)
+            .into_iter()
+            .map(|(name, version, _)| (name, version))
+            .collect();
+        installed.sort();
+        let dist_info = find_dist_info(lib, "Ruamel-YAML", &Version::new(0, 17, 4));
+        let missing = find_dist_info(lib, "pillow", &Version::new(8, 0, 0));
+
+        assert_eq!(
+            installed,
+            vec![
+                ("Pillow".to_owned(), Version::new(8, 0, 1)),
+                ("ruamel.yaml".to_owned(), Version::new(0, 17, 4)),
+                ("six".to_owned(), Version::new(1, 15, 0)),
+            ]
+        );
+        assert_eq!(dist_info, Some(lib.join("ruamel_yaml-0.17.4.dist-info")));
+        assert_eq!(missing, None);
+
+        assert!(compare_names("zope.interface", "Zope-Interface"));
+        assert!(compare_names("foo--bar", "FOO_.bar"));
+        assert_eq!(standardize_name("Ruamel.YAML"), "ruamel_yaml");
+        assert_eq!(standardize_name("foo--bar"), standardize_name("FOO_.bar"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn python_readiness_polled() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let py = dir.join("python");
+        let timeout = time::Duration::from_millis(200);
+
+        assert!(wait_for_python(&py, timeout).is_err());
+
+        // Exists, but doesn't run yet.
+        fs::write(&py, "#!/bin/sh\nexit 1\n").unwrap();
+        fs::set_permissions(&py, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(wait_for_python(&py, timeout).is_err());
+
+        fs::write(&py, "#!/bin/sh\necho Python 3.9.1\n").unwrap();
+        let result = wait_for_python(&py, timeout);
+        assert!(result.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pep582_env_found() {
+        let tmp = tempfile::tempdir().unwrap();
+        let pypackages = tmp.path();
+        let vers_path = pypackages.join("3.9");
+        fs::create_dir_all(vers_path.join("lib")).unwrap();
+        let interpreter = pypackages.join("python3.9");
+        fs::write(&interpreter, "").unwrap();
+
+        link_python(&interpreter, &vers_path).unwrap();
+        // Linking again replaces the link.
+        link_python(&interpreter, &vers_path).unwrap();
+        let bin_path = find_bin_path(&vers_path);
+        let linked = bin_path.join("python").exists();
+        let venvs = find_venvs(pypackages);
+
+        assert_eq!(bin_path, vers_path.join(".python/bin"));
+        assert!(linked);
+        assert_eq!(venvs, vec![(3, 9)]);
+    }
 }
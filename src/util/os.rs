@@ -1,4 +1,4 @@
-use std::str::FromStr;
+use std::{fmt, fs, path::Path, process::Command, str::FromStr};
 
 use regex::Regex;
 use serde::Deserialize;
@@ -6,31 +6,96 @@ use serde::Deserialize;
 use crate::dep_types::DependencyError;
 
 #[derive(Copy, Clone, Debug, Deserialize, PartialEq)]
-/// Used to determine which version of a binary package to download. Assume 64-bit.
+/// Used to determine which version of a binary package to download. Assume 64-bit, unless
+/// the variant specifies an architecture.
 pub enum Os {
     Linux32,
     Linux,
+    LinuxArm64,
+    LinuxArmv7,
+    /// musl-based distros, eg Alpine. These can't use glibc (`manylinux`) wheels.
+    LinuxMusl,
+    LinuxMuslArm64,
     Windows32,
     Windows,
     //    Mac32,
     Mac,
+    MacArm64,
+    /// `universal2` wheels contain both x86_64 and arm64 binaries.
+    MacUniversal,
     Any,
 }
 
+impl Os {
+    /// Determine if a wheel built for this platform can be installed on `host`.
+    pub fn is_compatible(self, host: Self) -> bool {
+        match self {
+            Self::Any => true,
+            Self::MacUniversal => matches!(host, Self::Mac | Self::MacArm64 | Self::MacUniversal),
+            _ => self == host,
+        }
+    }
+
+    /// The same operating system, on a different architecture. Used when running under
+    /// emulation, eg an x86_64 environment on Apple Silicon via Rosetta. Only macOS is
+    /// supported.
+    pub fn with_arch(self, arch: Arch) -> Self {
+        match (self.family(), arch) {
+            (Self::Mac, Arch::X86_64) => Self::Mac,
+            (Self::Mac, Arch::Arm64) => Self::MacArm64,
+            _ => self,
+        }
+    }
+
+    /// The operating system, ignoring architecture. Eg for evaluating `sys_platform` markers.
+    pub fn family(self) -> Self {
+        match self {
+            Self::Linux32
+            | Self::LinuxArm64
+            | Self::LinuxArmv7
+            | Self::LinuxMusl
+            | Self::LinuxMuslArm64 => Self::Linux,
+            Self::Windows32 => Self::Windows,
+            Self::MacArm64 | Self::MacUniversal => Self::Mac,
+            _ => self,
+        }
+    }
+}
+
 impl FromStr for Os {
     type Err = DependencyError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let re_musl_arm64 = Regex::new(r"musllinux.*_aarch64$").unwrap();
+        let re_musl = Regex::new(r"musllinux.*_x86_64$").unwrap();
         let re_linux32 = Regex::new(r"(many)?linux.*i686").unwrap();
+        let re_linux_arm64 = Regex::new(r"(many)?linux.*aarch64").unwrap();
+        let re_linux_armv7 = Regex::new(r"(many)?linux.*armv7l").unwrap();
         let re_linux = Regex::new(r"((many)?linux.*|cygwin|(open)?bsd6*)").unwrap();
         let re_win = Regex::new(r"^win(dows|_amd64)?").unwrap();
+        let re_mac_arm64 = Regex::new(r"macosx.*arm64").unwrap();
+        let re_mac_universal = Regex::new(r"macosx.*universal2").unwrap();
         let re_mac = Regex::new(r"(macosx.*|darwin|.*mac.*)").unwrap();
 
         Ok(match s {
+            x if re_musl_arm64.is_match(x) => Self::LinuxMuslArm64,
+            x if re_musl.is_match(x) => Self::LinuxMusl,
+            // Other musl architectures, eg i686 or armv7l, don't have a variant. They mustn't fall
+            // through to the glibc ones below.
+            x if x.starts_with("musllinux") => {
+                return Err(DependencyError::new(&format!(
+                    "Unsupported musllinux architecture: {}",
+                    s
+                )));
+            }
             x if re_linux32.is_match(x) => Self::Linux32,
+            x if re_linux_arm64.is_match(x) => Self::LinuxArm64,
+            x if re_linux_armv7.is_match(x) => Self::LinuxArmv7,
             x if re_linux.is_match(x) => Self::Linux,
             "win32" => Self::Windows32,
             x if re_win.is_match(x) => Self::Windows,
+            x if re_mac_arm64.is_match(x) => Self::MacArm64,
+            x if re_mac_universal.is_match(x) => Self::MacUniversal,
             x if re_mac.is_match(x) => Self::Mac,
             "any" => Self::Any,
             _ => {
@@ -40,11 +105,163 @@ impl FromStr for Os {
     }
 }
 
-pub const fn get_os() -> Os {
+/// A CPU architecture an environment can be created for, with `--arch`.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Arch {
+    X86_64,
+    Arm64,
+}
+
+/// The environment's architecture is recorded in this file, in `__pypackages__/3.x/`.
+const ENV_ARCH_FILENAME: &str = ".arch";
+
+impl Arch {
+    /// The architecture pyflow itself was built for.
+    pub fn host() -> Self {
+        if cfg!(target_arch = "aarch64") {
+            Self::Arm64
+        } else {
+            Self::X86_64
+        }
+    }
+
+    /// The architecture an environment was created for, if it was created with `--arch`.
+    pub fn from_env(vers_path: &Path) -> Option<Self> {
+        let arch = fs::read_to_string(vers_path.join(ENV_ARCH_FILENAME)).ok()?;
+        Self::from_str(arch.trim()).ok()
+    }
+
+    pub fn write_env(self, vers_path: &Path) {
+        fs::write(vers_path.join(ENV_ARCH_FILENAME), self.to_string())
+            .expect("Problem recording the environment's architecture");
+    }
+}
+
+impl fmt::Display for Arch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::X86_64 => write!(f, "x86_64"),
+            Self::Arm64 => write!(f, "arm64"),
+        }
+    }
+}
+
+impl FromStr for Arch {
+    type Err = DependencyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "x86_64" | "amd64" => Ok(Self::X86_64),
+            "arm64" | "aarch64" => Ok(Self::Arm64),
+            _ => Err(DependencyError::new(&format!(
+                "Problem parsing architecture: {}. Must be `x86_64` or `arm64`",
+                s
+            ))),
+        }
+    }
+}
+
+/// Find the minimum glibc version a `manylinux` wheel requires, from its platform tag.
+/// (PEP 513, 571, 599 and 600). Wheels may list several tags separated by `.`; any of them
+/// matching is enough, so use the least restrictive. Returns `None` for non-glibc tags.
+pub fn glibc_from_tag(tag: &str) -> Option<(u32, u32)> {
+    let re = Regex::new(r"^manylinux(?:_(\d+)_(\d+)|(1|2010|2014))_").unwrap();
+
+    tag.split('.')
+        .filter_map(|t| {
+            let caps = re.captures(t)?;
+            match caps.get(3).map(|m| m.as_str()) {
+                Some("1") => Some((2, 5)),
+                Some("2010") => Some((2, 12)),
+                Some("2014") => Some((2, 17)),
+                _ => Some((caps[1].parse().ok()?, caps[2].parse().ok()?)),
+            }
+        })
+        .min()
+}
+
+/// Find the minimum macOS version a wheel supports, from its platform tag, eg `macosx_10_9_x86_64`.
+/// From macOS 11, only the major version counts; `macosx_11_3` wheels work on 11.0. Returns
+/// `None` for other platforms.
+pub fn macos_from_tag(tag: &str) -> Option<(u32, u32)> {
+    let re = Regex::new(r"^macosx_(\d+)_(\d+)_").unwrap();
+    let caps = re.captures(tag)?;
+    normalize_macos_version(caps[1].parse().ok()?, caps[2].parse().ok()?)
+}
+
+fn normalize_macos_version(major: u32, minor: u32) -> Option<(u32, u32)> {
+    Some(if major >= 11 {
+        (major, 0)
+    } else {
+        (major, minor)
+    })
+}
+
+/// Find the host's macOS version from `sw_vers -productVersion`, eg `10.13.6`. Returns `None` if
+/// it can't be found, or if not on macOS.
+pub fn get_macos_version() -> Option<(u32, u32)> {
+    if !cfg!(target_os = "macos") {
+        return None;
+    }
+    let output = Command::new("sw_vers")
+        .arg("-productVersion")
+        .output()
+        .ok()?;
+    let version = std::str::from_utf8(&output.stdout).ok()?.trim();
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next().map_or(Some(0), |m| m.parse().ok())?;
+
+    normalize_macos_version(major, minor)
+}
+
+/// Find the host's glibc version from `ldd --version`, eg `ldd (GNU libc) 2.31`. Returns `None`
+/// if it can't be found, eg on musl systems, or if not on Linux.
+pub fn get_glibc_version() -> Option<(u32, u32)> {
+    if !cfg!(target_os = "linux") {
+        return None;
+    }
+    let output = Command::new("ldd").arg("--version").output().ok()?;
+    let re = Regex::new(r"(?m)^ldd .*?(\d+)\.(\d+)\s*$").unwrap();
+    let caps = re.captures(std::str::from_utf8(&output.stdout).ok()?)?;
+
+    Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+}
+
+pub fn get_os() -> Os {
     #[cfg(target_os = "windows")]
     return Os::Windows;
     #[cfg(target_os = "linux")]
-    return Os::Linux;
-    #[cfg(target_os = "macos")]
+    return get_linux_os();
+    #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+    return Os::MacArm64;
+    #[cfg(all(target_os = "macos", not(target_arch = "aarch64")))]
     return Os::Mac;
 }
+
+/// Linux wheels depend on the architecture, and on whether the system's C library is glibc or
+/// musl. We can't tell the latter at compile time, since a static build of pyflow runs on both.
+#[cfg(target_os = "linux")]
+fn get_linux_os() -> Os {
+    let musl = std::fs::read_dir("/lib")
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .any(|e| e.file_name().to_string_lossy().starts_with("ld-musl-"))
+        })
+        .unwrap_or(false);
+
+    if cfg!(target_arch = "aarch64") {
+        if musl {
+            Os::LinuxMuslArm64
+        } else {
+            Os::LinuxArm64
+        }
+    } else if cfg!(target_arch = "arm") {
+        Os::LinuxArmv7
+    } else if musl {
+        Os::LinuxMusl
+    } else {
+        Os::Linux
+    }
+}
@@ -8,8 +8,14 @@ pub fn pyflow_path() -> PathBuf {
         .join("pyflow")
 }
 
-pub fn dep_cache_path(pyflow_path: &Path) -> PathBuf {
-    pyflow_path.join("dependency_cache")
+/// Downloaded package archives, shared between projects. This is in the platform's cache
+/// directory, since it's safe to delete.
+pub fn dep_cache_path() -> PathBuf {
+    directories::BaseDirs::new()
+        .expect("Problem finding base directory")
+        .cache_dir()
+        .join("pyflow")
+        .join("packages")
 }
 
 pub fn script_env_path(pyflow_path: &Path) -> PathBuf {
@@ -22,7 +28,7 @@ pub fn git_path(pyflow_path: &Path) -> PathBuf {
 
 pub fn get_paths() -> (PathBuf, PathBuf, PathBuf, PathBuf) {
     let pyflow_path = pyflow_path();
-    let dep_cache_path = dep_cache_path(&pyflow_path);
+    let dep_cache_path = dep_cache_path();
     let script_env_path = script_env_path(&pyflow_path);
     let git_path = git_path(&pyflow_path);
     (pyflow_path, dep_cache_path, script_env_path, git_path)
@@ -0,0 +1,231 @@
+//! Download progress: a bar for each file in flight, and a count of those finished, redrawn in
+//! place on the terminal.
+
+use std::{
+    io::{self, Read, Write},
+    iter,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The most often we redraw as data arrives.
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+const NAME_WIDTH: usize = 32;
+const BAR_WIDTH: usize = 24;
+
+pub struct Progress {
+    total: usize,
+    show: bool,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    done: usize,
+    bytes: u64,
+    next_id: usize,
+    /// Each download in flight: an id, its name, the bytes received, and its size, if known.
+    active: Vec<(usize, String, u64, Option<u64>)>,
+    lines_drawn: usize,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    /// Track `total` downloads. Unless `show` is set, nothing's printed.
+    pub fn new(total: usize, show: bool) -> Self {
+        Self {
+            total,
+            show,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Add a bar for a download that's started. `len` is its size, if the server sent it.
+    pub fn start(&self, name: &str, len: Option<u64>) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.active.push((id, name.to_owned(), 0, len));
+        self.draw(&mut state, true);
+        id
+    }
+
+    fn advance(&self, id: usize, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(download) = state.active.iter_mut().find(|d| d.0 == id) {
+            download.2 += n as u64;
+        }
+        self.draw(&mut state, false);
+    }
+
+    /// Remove a download's bar, whether it finished, or failed.
+    pub fn end(&self, id: usize) {
+        self.state.lock().unwrap().active.retain(|d| d.0 != id);
+    }
+
+    /// Count a finished download.
+    pub fn finished(&self, bytes: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.done += 1;
+        state.bytes += bytes;
+        self.draw(&mut state, true);
+    }
+
+    /// Leave the count on its own line, once the downloads are done.
+    pub fn close(&self) {
+        let state = self.state.lock().unwrap();
+        // Elsewhere, the count's last line ends with a newline.
+        if self.show && cfg!(target_os = "windows") && state.last_draw.is_some() {
+            println!();
+        }
+    }
+
+    /// Wrap a download's body, to advance its bar as it's read.
+    pub fn reader<R: Read>(&self, id: usize, inner: R) -> ProgressReader<'_, R> {
+        ProgressReader {
+            inner,
+            progress: self,
+            id,
+        }
+    }
+
+    fn draw(&self, state: &mut State, force: bool) {
+        if !self.show
+            || (!force
+                && state
+                    .last_draw
+                    .is_some_and(|t| t.elapsed() < REDRAW_INTERVAL))
+        {
+            return;
+        }
+        state.last_draw = Some(Instant::now());
+
+        let summary = format!(
+            "Downloaded {}/{} packages ({})",
+            state.done,
+            self.total,
+            format_size(state.bytes)
+        );
+        // The Windows console may not understand the escape codes we redraw the bars with.
+        if cfg!(target_os = "windows") {
+            print!("\r{}", summary);
+            let _ = io::stdout().flush();
+            return;
+        }
+
+        let lines: Vec<String> = state
+            .active
+            .iter()
+            .map(|(_, name, received, len)| bar_line(name, *received, *len))
+            .chain(iter::once(summary))
+            .collect();
+
+        let mut out = String::new();
+        // Go back up to the first line we drew last time, and draw over it.
+        if state.lines_drawn > 0 {
+            out.push_str(&format!("\x1b[{}A", state.lines_drawn));
+        }
+        for line in &lines {
+            out.push_str(&format!("\r\x1b[2K{}\n", line));
+        }
+        // Clear what's left of a longer drawing, eg from a download that's since finished.
+        let leftover = state.lines_drawn.saturating_sub(lines.len());
+        if leftover > 0 {
+            out.push_str(&"\x1b[2K\n".repeat(leftover));
+            out.push_str(&format!("\x1b[{}A", leftover));
+        }
+        state.lines_drawn = lines.len();
+
+        print!("{}", out);
+        let _ = io::stdout().flush();
+    }
+}
+
+pub struct ProgressReader<'a, R> {
+    inner: R,
+    progress: &'a Progress,
+    id: usize,
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.progress.advance(self.id, n);
+        Ok(n)
+    }
+}
+
+/// A download's name, with a bar filled in proportion to how much has arrived, if we know its
+/// size.
+fn bar_line(name: &str, received: u64, len: Option<u64>) -> String {
+    let name: String = if name.chars().count() > NAME_WIDTH {
+        name.chars()
+            .take(NAME_WIDTH - 1)
+            .chain(iter::once('…'))
+            .collect()
+    } else {
+        name.to_owned()
+    };
+
+    match len {
+        Some(len) if len > 0 => {
+            let fraction = (received as f64 / len as f64).min(1.);
+            let filled = (fraction * BAR_WIDTH as f64) as usize;
+            format!(
+                "{:w$} {}{} {:>3}% {}/{}",
+                name,
+                "█".repeat(filled),
+                "░".repeat(BAR_WIDTH - filled),
+                (fraction * 100.) as u32,
+                format_size(received),
+                format_size(len),
+                w = NAME_WIDTH
+            )
+        }
+        _ => format!("{:w$} {}", name, format_size(received), w = NAME_WIDTH),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const KB: f64 = 1024.;
+    let b = bytes as f64;
+    if b < KB {
+        format!("{} B", bytes)
+    } else if b < KB * KB {
+        format!("{:.0} KB", b / KB)
+    } else if b < KB * KB * KB {
+        format!("{:.1} MB", b / (KB * KB))
+    } else {
+        format!("{:.2} GB", b / (KB * KB * KB))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bars_drawn() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(300 * 1024), "300 KB");
+        assert_eq!(format_size(5 * 1024 * 1024 + 512 * 1024), "5.5 MB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.00 GB");
+
+        assert_eq!(
+            bar_line("numpy-1.21.0.whl", 512 * 1024, Some(2 * 1024 * 1024)),
+            format!(
+                "{:32} {}{}  25% 512 KB/2.0 MB",
+                "numpy-1.21.0.whl",
+                "█".repeat(6),
+                "░".repeat(18)
+            )
+        );
+        // Without a `Content-Length`, there's no bar.
+        assert_eq!(
+            bar_line("requests-2.25.1.tar.gz", 2048, None),
+            format!("{:32} 2 KB", "requests-2.25.1.tar.gz")
+        );
+        let long = bar_line("torch-1.9.0-cp39-cp39-manylinux1_x86_64.whl", 0, Some(1));
+        assert!(long.starts_with("torch-1.9.0-cp39-cp39-manylinux… "));
+    }
+}
@@ -0,0 +1,103 @@
+//! Undo a sync that fails partway, so the environment's left as it was before the command.
+//! Before changing anything, we snapshot the environment's folders with hardlinks, which is
+//! cheap; if we abort or panic before the sync finishes, we put the snapshot back.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::install;
+
+/// Each folder being changed, and its snapshot, while a sync's in progress.
+static SNAPSHOTS: Mutex<Vec<(PathBuf, PathBuf)>> = Mutex::new(Vec::new());
+
+/// Snapshot `dirs` in `backup_path`, to restore if the sync fails.
+pub fn begin(dirs: &[&Path], backup_path: &Path) -> io::Result<()> {
+    if backup_path.exists() {
+        fs::remove_dir_all(backup_path)?;
+    }
+
+    let mut snapshots = SNAPSHOTS.lock().unwrap_or_else(|e| e.into_inner());
+    for (i, dir) in dirs.iter().enumerate() {
+        // A folder that doesn't exist yet has no snapshot; restoring it removes it.
+        let snapshot = backup_path.join(i.to_string());
+        if dir.exists() {
+            install::link_tree(dir, &snapshot)?;
+        }
+        snapshots.push((dir.to_path_buf(), snapshot));
+    }
+    Ok(())
+}
+
+/// The sync finished; keep its changes, and remove the snapshot.
+pub fn commit(backup_path: &Path) {
+    SNAPSHOTS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    let _ = fs::remove_dir_all(backup_path);
+}
+
+/// Put the folders back as they were when the sync started. Does nothing if there's no sync in
+/// progress.
+pub fn restore() {
+    let mut snapshots = SNAPSHOTS.lock().unwrap_or_else(|e| e.into_inner());
+    if snapshots.is_empty() {
+        return;
+    }
+    log::warn!("Undoing the changes made to the environment");
+
+    for (dir, snapshot) in snapshots.drain(..) {
+        let _ = fs::remove_dir_all(&dir);
+        if snapshot.exists() && fs::rename(&snapshot, &dir).is_err() {
+            log::error!(
+                "Problem restoring {}; its previous contents are in {}",
+                dir.display(),
+                snapshot.display()
+            );
+            continue;
+        }
+        if let Some(backup_path) = snapshot.parent() {
+            let _ = fs::remove_dir(backup_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_undone() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let lib = root.join("lib");
+        let bin = root.join("bin");
+        let backup = root.join(".sync-backup");
+        fs::create_dir_all(lib.join("requests")).unwrap();
+        fs::write(lib.join("requests/__init__.py"), "old").unwrap();
+
+        begin(&[&lib, &bin], &backup).unwrap();
+        // Uninstall, then partially install.
+        fs::remove_dir_all(lib.join("requests")).unwrap();
+        fs::create_dir_all(lib.join("urllib3")).unwrap();
+        fs::create_dir_all(&bin).unwrap();
+        fs::write(bin.join("tool"), "").unwrap();
+        restore();
+
+        let restored = fs::read_to_string(lib.join("requests/__init__.py")).unwrap();
+        let added = lib.join("urllib3").exists() || bin.exists();
+        let backup_left = backup.exists();
+
+        // Once committed, there's nothing to restore.
+        begin(&[&lib, &bin], &backup).unwrap();
+        fs::create_dir_all(lib.join("idna")).unwrap();
+        commit(&backup);
+        restore();
+        let committed = lib.join("idna").exists() && !backup.exists();
+
+        assert_eq!(restored, "old");
+        assert!(!added);
+        assert!(!backup_left);
+        assert!(committed);
+    }
+}
@@ -0,0 +1,262 @@
+//! Wheel compatibility tags, from [PEP 425](https://www.python.org/dev/peps/pep-0425/): the
+//! Python versions, ABIs and platforms a wheel is built for.
+
+use crate::dep_types::{DependencyError, Version};
+
+use super::os::{glibc_from_tag, macos_from_tag, Os};
+
+/// The tags from a wheel's filename. Each may list several, separated by `.`, eg
+/// `cp35.cp36.cp37`; the wheel supports every combination of them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WheelTag {
+    pub python: Vec<String>,
+    pub abi: Vec<String>,
+    pub platform: Vec<String>,
+}
+
+impl WheelTag {
+    /// Parse the tags from a filename like `name-version(-build)?-python-abi-platform.whl`.
+    pub fn from_filename(filename: &str) -> Result<Self, DependencyError> {
+        let err = || DependencyError::new(&format!("Problem parsing wheel tags: {}", filename));
+
+        let mut parts = filename.strip_suffix(".whl").ok_or_else(err)?.rsplit('-');
+        let mut next = || -> Result<Vec<String>, DependencyError> {
+            match parts.next() {
+                Some(p) if !p.is_empty() => Ok(p.split('.').map(str::to_owned).collect()),
+                _ => Err(err()),
+            }
+        };
+        let platform = next()?;
+        let abi = next()?;
+        let python = next()?;
+        // The name and version come first.
+        if parts.count() < 2 {
+            return Err(err());
+        }
+
+        Ok(Self {
+            python,
+            abi,
+            platform,
+        })
+    }
+}
+
+/// A Python tag's major version, and minor version if it has one. Eg `cp310` is (3, Some(10)).
+/// Only CPython and generic (`py`) tags are understood.
+fn parse_python_tag(tag: &str) -> Option<(&str, u32, Option<u32>)> {
+    let (impl_, rest) = if let Some(rest) = tag.strip_prefix("cp") {
+        ("cp", rest)
+    } else {
+        ("py", tag.strip_prefix("py")?)
+    };
+    if rest.is_empty() || !rest.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let major = rest[..1].parse().ok()?;
+    let minor = if rest.len() > 1 {
+        Some(rest[1..].parse().ok()?)
+    } else {
+        None
+    };
+    Some((impl_, major, minor))
+}
+
+/// Whether a wheel built for this Python, ABI and platform tag can be installed.
+fn combination_compatible(python: &str, abi: &str, python_vers: &Version) -> bool {
+    let (impl_, major, minor) = match parse_python_tag(python) {
+        Some(t) => t,
+        None => return false,
+    };
+    let host_major = python_vers.major.unwrap_or(3);
+    let host_minor = python_vers.minor.unwrap_or(0);
+    if major != host_major {
+        return false;
+    }
+
+    match abi {
+        // Pure Python, or code that doesn't use the Python ABI. Generic tags for older minor
+        // versions still work, eg `py36` on 3.9; CPython ones are specific to their version.
+        "none" => match (impl_, minor) {
+            (_, None) => true,
+            ("py", Some(mi)) => mi <= host_minor,
+            (_, Some(mi)) => mi == host_minor,
+        },
+        // The stable ABI works with the version tagged, and any newer one.
+        "abi3" => impl_ == "cp" && minor.is_some_and(|mi| mi <= host_minor),
+        // eg `cp39`, or `cp37m` for older versions built with pymalloc.
+        _ => {
+            let abi_vers = abi
+                .strip_prefix("cp")
+                .map(|a| a.trim_end_matches(['m', 'd', 'u']));
+            impl_ == "cp"
+                && minor == Some(host_minor)
+                && abi_vers == Some(&format!("{}{}", host_major, host_minor))
+        }
+    }
+}
+
+/// Whether a wheel can be installed on this Python version and operating system. At least one
+/// combination of its Python and ABI tags must work with the interpreter, and one of its
+/// platform tags with the OS. `manylinux` wheels also need a new-enough glibc, and macOS ones a
+/// new-enough macOS, if we know the host's.
+pub fn is_compatible(
+    tag: &WheelTag,
+    python_vers: &Version,
+    os: Os,
+    glibc_vers: Option<(u32, u32)>,
+    macos_vers: Option<(u32, u32)>,
+) -> bool {
+    let python_ok = tag.python.iter().any(|py| {
+        tag.abi
+            .iter()
+            .any(|abi| combination_compatible(py, abi, python_vers))
+    });
+
+    let platform_ok = tag.platform.iter().any(|platform| {
+        let os_ok = platform
+            .parse::<Os>()
+            .is_ok_and(|wheel_os| wheel_os.is_compatible(os));
+        let glibc_ok = match (glibc_from_tag(platform), glibc_vers) {
+            (Some(required), Some(host)) => required <= host,
+            _ => true,
+        };
+        let macos_ok = match (macos_from_tag(platform), macos_vers) {
+            (Some(required), Some(host)) => required <= host,
+            _ => true,
+        };
+        os_ok && glibc_ok && macos_ok
+    });
+
+    python_ok && platform_ok
+}
+
+#[cfg(test)]
+mod tests {
+    use rstest::rstest;
+
+    use super::*;
+
+    #[test]
+    fn tags_parsed() {
+        assert_eq!(
+            WheelTag::from_filename("PyQt5-5.13.0-5.13.0-cp35.cp36.cp37.cp38-none-win32.whl")
+                .unwrap(),
+            WheelTag {
+                python: vec!["cp35".into(), "cp36".into(), "cp37".into(), "cp38".into()],
+                abi: vec!["none".into()],
+                platform: vec!["win32".into()],
+            }
+        );
+        let tag = WheelTag::from_filename(
+            "numpy-1.21.0-cp39-cp39-manylinux_2_12_x86_64.manylinux2010_x86_64.whl",
+        )
+        .unwrap();
+        assert_eq!(tag.python, vec!["cp39"]);
+        assert_eq!(
+            tag.platform,
+            vec!["manylinux_2_12_x86_64", "manylinux2010_x86_64"]
+        );
+
+        assert!(WheelTag::from_filename("requests-2.25.1.tar.gz").is_err());
+        assert!(WheelTag::from_filename("none-any.whl").is_err());
+    }
+
+    #[rstest(
+        filename,
+        python_vers,
+        os,
+        expected,
+        case(
+            "six-1.15.0-py2.py3-none-any.whl",
+            Version::new(3, 9, 1),
+            Os::Linux,
+            true
+        ),
+        case("six-1.15.0-py2-none-any.whl", Version::new(3, 9, 1), Os::Linux, false),
+        case("pkg-1.0-py36-none-any.whl", Version::new(3, 9, 1), Os::Mac, true),
+        case("pkg-1.0-py310-none-any.whl", Version::new(3, 9, 1), Os::Mac, false),
+        case(
+            "numpy-1.21.0-cp39-cp39-manylinux2010_x86_64.whl",
+            Version::new(3, 9, 1),
+            Os::Linux,
+            true
+        ),
+        case(
+            "numpy-1.21.0-cp38-cp38-manylinux2010_x86_64.whl",
+            Version::new(3, 9, 1),
+            Os::Linux,
+            false
+        ),
+        case(
+            "numpy-1.21.0-cp39-cp39-manylinux2010_x86_64.whl",
+            Version::new(3, 9, 1),
+            Os::Windows,
+            false
+        ),
+        case(
+            "pkg-1.0-cp37-cp37m-win_amd64.whl",
+            Version::new(3, 7, 9),
+            Os::Windows,
+            true
+        ),
+        case(
+            "cryptography-3.4.7-cp36-abi3-manylinux2014_x86_64.whl",
+            Version::new(3, 11, 2),
+            Os::Linux,
+            true
+        ),
+        case(
+            "cryptography-3.4.7-cp36-abi3-manylinux2014_x86_64.whl",
+            Version::new(3, 5, 2),
+            Os::Linux,
+            false
+        ),
+        case(
+            "PyQt5-5.13.0-5.13.0-cp35.cp36.cp37.cp38-none-win32.whl",
+            Version::new(3, 7, 4),
+            Os::Windows32,
+            true
+        ),
+        case(
+            "pkg-1.0-pp37-pypy37_pp73-manylinux2010_x86_64.whl",
+            Version::new(3, 7, 4),
+            Os::Linux,
+            false
+        ),
+        case(
+            "pkg-1.0-cp39-cp39-macosx_10_9_universal2.whl",
+            Version::new(3, 9, 1),
+            Os::MacArm64,
+            true
+        )
+    )]
+    fn tags_matched(filename: &str, python_vers: Version, os: Os, expected: bool) {
+        let tag = WheelTag::from_filename(filename).unwrap();
+        assert_eq!(is_compatible(&tag, &python_vers, os, None, None), expected);
+    }
+
+    #[test]
+    fn glibc_checked() {
+        let tag = WheelTag::from_filename(
+            "pkg-1.0-cp39-cp39-manylinux_2_24_x86_64.manylinux2014_x86_64.whl",
+        )
+        .unwrap();
+        let py = Version::new(3, 9, 1);
+        assert!(is_compatible(&tag, &py, Os::Linux, Some((2, 17)), None));
+        assert!(!is_compatible(&tag, &py, Os::Linux, Some((2, 12)), None));
+    }
+
+    #[test]
+    fn macos_version_checked() {
+        let tag = WheelTag::from_filename("pkg-1.0-cp39-cp39-macosx_12_0_x86_64.whl").unwrap();
+        let py = Version::new(3, 9, 1);
+        assert!(!is_compatible(&tag, &py, Os::Mac, None, Some((10, 13))));
+        assert!(is_compatible(&tag, &py, Os::Mac, None, Some((12, 0))));
+        assert!(is_compatible(&tag, &py, Os::Mac, None, Some((13, 0))));
+
+        // Older wheels work on newer systems.
+        let tag = WheelTag::from_filename("pkg-1.0-cp39-cp39-macosx_10_9_x86_64.whl").unwrap();
+        assert!(is_compatible(&tag, &py, Os::Mac, None, Some((10, 13))));
+    }
+}